@@ -0,0 +1,48 @@
+//! Records the git commit, git tag and time of the build, which the agent reports
+//!     in `--version`, at `/api/v1/agent` and on the status page.
+//!
+//! The commit and tag are read from the `CDAGENT_GIT_COMMIT` and `CDAGENT_GIT_TAG`
+//!     environment variables if they are set, as they are for Docker builds which don't have
+//!     the git repository, and otherwise from the git repository.
+//! The tag is only recorded if the commit is tagged.
+
+use std::process::Command;
+
+fn main() {
+    for path in [".git/HEAD", ".git/refs", ".git/packed-refs"] {
+        if std::path::Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+    let git_commit = git("CDAGENT_GIT_COMMIT", &["rev-parse", "HEAD"]);
+    println!("cargo:rustc-env=CDAGENT_GIT_COMMIT={git_commit}");
+    let git_tag = git("CDAGENT_GIT_TAG", &["describe", "--tags", "--exact-match"]);
+    println!("cargo:rustc-env=CDAGENT_GIT_TAG={git_tag}");
+    let build_timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=CDAGENT_BUILD_TIMESTAMP={build_timestamp}");
+}
+
+/// Returns the value of the environment variable if it is set,
+///     and otherwise the output of the git command, or an empty string if it fails.
+fn git(env: &str, args: &[&str]) -> String {
+    println!("cargo:rerun-if-env-changed={env}");
+    std::env::var(env)
+        .ok()
+        .or_else(|| {
+            let output = Command::new("git")
+                .args(args)
+                .stderr(std::process::Stdio::null())
+                .output()
+                .ok()?;
+            if !output.status.success() {
+                return None;
+            }
+            String::from_utf8(output.stdout).ok()
+        })
+        .unwrap_or_default()
+        .trim()
+        .to_string()
+}
@@ -0,0 +1,441 @@
+//! The versioned JSON API served under `/api/v1/`.
+//!
+//! The types in this module define the API's field names, which are stable within a version:
+//!     fields may be added, but they are not renamed, removed or repurposed.
+//! They are built from the agent's internal types, which can change freely.
+
+use crate::audit;
+use crate::config;
+use crate::github;
+use std::collections::HashMap;
+use std::sync;
+use std::time;
+
+/// The OpenAPI document describing the HTTP API, served at `/api/v1/openapi.json`.
+///
+/// It is maintained by hand, so changes to the types in this module must be made there too.
+pub const OPENAPI_DOT_JSON: &str = include_str!("openapi.json");
+
+/// A project, as returned by `/api/v1/projects` and `/api/v1/projects/{name}`.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Project {
+    pub name: String,
+    /// The GitHub repository, as `{user}/{repo}`.
+    pub repository: String,
+    pub branch: String,
+    /// Whether the project is paused, either in the config or through the API.
+    pub paused: bool,
+    /// The most recent workflow run that was redeployed or considered for redeployment.
+    pub latest_workflow_run: Option<WorkflowRun>,
+    /// The most recent redeployment, without its steps.
+    pub last_run: Option<RunSummary>,
+    /// The redeployment being held, if any.
+    pub pending: Option<Pending>,
+    /// The total number of redeployments in the project's history.
+    pub total_runs: usize,
+}
+
+/// A redeployment that is being held.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Pending {
+    pub workflow_run: WorkflowRun,
+    /// When the redeployment was first held.
+    pub since: String,
+    /// Why the redeployment is being held.
+    pub reason: String,
+    /// Whether the redeployment is waiting for approval.
+    pub needs_approval: bool,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct WorkflowRun {
+    pub id: u64,
+    pub number: u64,
+    pub title: String,
+    /// The SHA of the commit the workflow ran on.
+    pub commit: String,
+    pub url: String,
+    pub created_at: String,
+}
+
+impl From<&github::WorkflowRun> for WorkflowRun {
+    fn from(workflow_run: &github::WorkflowRun) -> Self {
+        Self {
+            id: workflow_run.id,
+            number: workflow_run.run_number,
+            title: workflow_run.display_title.clone(),
+            commit: workflow_run.head_sha.clone(),
+            url: workflow_run.html_url.clone(),
+            created_at: workflow_run.created_at.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Succeeded,
+    Failed,
+}
+
+impl Status {
+    pub fn new(success: bool) -> Self {
+        if success {
+            Status::Succeeded
+        } else {
+            Status::Failed
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "succeeded" => Some(Status::Succeeded),
+            "failed" => Some(Status::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// A redeployment, as returned by `/api/v1/projects/{name}/runs`.
+#[derive(Clone, serde::Serialize)]
+pub struct Run {
+    #[serde(flatten)]
+    pub summary: RunSummary,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct RunSummary {
+    pub workflow_run: WorkflowRun,
+    pub status: Status,
+    pub started: String,
+    pub finished: String,
+    /// For rollbacks, the ID of the workflow run that was rolled back from.
+    pub rollback_of: Option<u64>,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct Step {
+    pub name: String,
+    pub command: String,
+    /// The phase of the redeployment the step was run in, like `main` or `canary`.
+    pub phase: String,
+    pub status: Status,
+    /// Both stdout and stderr, interleaved in the order the output was produced.
+    pub output: String,
+}
+
+/// A GitHub API rate limit, as returned by `/api/v1/rate_limits`.
+#[derive(Clone, serde::Serialize)]
+pub struct RateLimit {
+    pub resource: String,
+    pub limit: u64,
+    pub remaining: u64,
+    pub used: u64,
+    /// When the rate limit resets, as an RFC 3339 timestamp.
+    pub reset: String,
+}
+
+impl From<&github::RateLimitInfo> for RateLimit {
+    fn from(info: &github::RateLimitInfo) -> Self {
+        let reset = chrono::DateTime::from_timestamp(info.reset as i64, 0)
+            .unwrap_or_default()
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        Self {
+            resource: info.resource.clone(),
+            limit: info.limit,
+            remaining: info.remaining,
+            used: info.used,
+            reset,
+        }
+    }
+}
+
+/// A manual action, as returned by `/api/v1/audit`.
+#[derive(Clone, serde::Serialize)]
+pub struct AuditEntry {
+    pub time: String,
+    /// Who took the action: a username, a token's name, or how an anonymous actor was identified.
+    pub actor: String,
+    /// Where the action came from: the client's IP address, `slack` or `cli`.
+    pub source: String,
+    /// The action, like `pause`, `deploy` or `approve`.
+    pub action: String,
+    pub project: Option<String>,
+    pub details: String,
+}
+
+impl From<&audit::Entry> for AuditEntry {
+    fn from(entry: &audit::Entry) -> Self {
+        Self {
+            time: entry.time.clone(),
+            actor: entry.actor.clone(),
+            source: entry.source.clone(),
+            action: entry.action.clone(),
+            project: entry.project.clone(),
+            details: entry.details.clone(),
+        }
+    }
+}
+
+/// The agent itself, as returned by `/api/v1/agent`.
+#[derive(Clone, serde::Serialize)]
+pub struct Agent {
+    pub version: String,
+    /// The git commit the agent was built from, if known.
+    pub git_commit: Option<String>,
+    /// The git tag of the commit the agent was built from, if it is tagged.
+    pub git_tag: Option<String>,
+    /// When the agent was built, as an RFC 3339 timestamp.
+    pub build_time: Option<String>,
+    /// When the agent started, as an RFC 3339 timestamp.
+    pub started: String,
+    pub uptime_seconds: u64,
+    pub poll_interval_seconds: u64,
+    pub database: Database,
+    pub projects: ProjectCounts,
+}
+
+/// The agent's database.
+#[derive(Clone, serde::Serialize)]
+pub struct Database {
+    /// How the database is stored: `in_memory`, `snapshot`, `journal` or `redis`.
+    pub backend: String,
+    /// The path of the database file, or the key prefix of a Redis database.
+    ///
+    /// The URL of a Redis database is not included, as it may contain a password.
+    pub path: Option<String>,
+    pub read_only: bool,
+}
+
+impl Database {
+    pub fn new(location: Option<&str>, config: &config::DatabaseConfig, read_only: bool) -> Self {
+        let (backend, path) = match (location, &config.redis, &config.snapshot) {
+            (_, Some(redis), _) => ("redis", Some(redis.key_prefix.clone())),
+            (None, None, None) => ("in_memory", None),
+            (None, None, Some(snapshot)) => ("snapshot", Some(snapshot.path.clone())),
+            (Some(location), None, _) => match config.format {
+                config::DatabaseFormat::Snapshot => ("snapshot", Some(location.to_string())),
+                config::DatabaseFormat::Journal => ("journal", Some(location.to_string())),
+            },
+        };
+        Self {
+            backend: backend.to_string(),
+            path,
+            read_only,
+        }
+    }
+}
+
+/// The number of projects in each state.
+///
+/// Each project is counted in the first state that applies:
+///     paused, pending, the status of its last redeployment, or never run.
+#[derive(Clone, Default, serde::Serialize)]
+pub struct ProjectCounts {
+    pub total: usize,
+    pub paused: usize,
+    pub pending: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub never_run: usize,
+}
+
+impl ProjectCounts {
+    fn new(projects: &[Project]) -> Self {
+        let mut counts = Self {
+            total: projects.len(),
+            ..Default::default()
+        };
+        for project in projects {
+            let count = if project.paused {
+                &mut counts.paused
+            } else if project.pending.is_some() {
+                &mut counts.pending
+            } else {
+                match project.last_run.as_ref().map(|run| run.status) {
+                    Some(Status::Succeeded) => &mut counts.succeeded,
+                    Some(Status::Failed) => &mut counts.failed,
+                    None => &mut counts.never_run,
+                }
+            };
+            *count += 1;
+        }
+        counts
+    }
+}
+
+/// Which runs to return from `/api/v1/projects/{name}/runs`.
+#[derive(Default)]
+pub struct RunFilter {
+    /// Only runs that started at or after this time.
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
+    pub status: Option<Status>,
+}
+
+impl RunFilter {
+    fn matches(&self, run: &Run) -> bool {
+        let since = self.since.is_none_or(|since| {
+            chrono::DateTime::parse_from_rfc3339(&run.summary.started)
+                .is_ok_and(|started| started >= since)
+        });
+        since
+            && self
+                .status
+                .is_none_or(|status| status == run.summary.status)
+    }
+}
+
+/// The most recent state of the API's resources.
+///
+/// Clones share the same underlying state.
+#[derive(Clone, Default)]
+pub struct Resources {
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    projects: Vec<Project>,
+    /// The full history of runs of each project, oldest first, keyed by project name.
+    runs: HashMap<String, Vec<Run>>,
+    rate_limits: Vec<RateLimit>,
+    agent: Option<Start>,
+}
+
+/// What is known about the agent when it starts.
+struct Start {
+    time: time::Instant,
+    started: String,
+    poll_interval: time::Duration,
+    database: Database,
+}
+
+impl Resources {
+    pub fn update(&self, projects: Vec<Project>, rate_limits: Vec<RateLimit>) {
+        let mut state = self.state.lock().unwrap();
+        state.projects = projects;
+        state.rate_limits = rate_limits;
+    }
+
+    /// Publish the full history of runs of the project, oldest first.
+    pub fn update_runs(&self, project: &str, runs: Vec<Run>) {
+        self.state
+            .lock()
+            .unwrap()
+            .runs
+            .insert(project.to_string(), runs);
+    }
+
+    pub fn remove_runs(&self, project: &str) {
+        self.state.lock().unwrap().runs.remove(project);
+    }
+
+    pub fn projects(&self) -> Vec<Project> {
+        self.state.lock().unwrap().projects.clone()
+    }
+
+    pub fn project(&self, name: &str) -> Option<Project> {
+        self.state
+            .lock()
+            .unwrap()
+            .projects
+            .iter()
+            .find(|project| project.name == name)
+            .cloned()
+    }
+
+    /// Returns a page of the project's runs that match the filter, most recent first,
+    ///     along with the total number of matching runs.
+    ///
+    /// Pages are numbered from 1.
+    /// Returns `None` if there is no project with the provided name.
+    pub fn runs(
+        &self,
+        project: &str,
+        filter: &RunFilter,
+        page: usize,
+        per_page: usize,
+    ) -> Option<(Vec<Run>, usize)> {
+        let state = self.state.lock().unwrap();
+        if !state.projects.iter().any(|p| p.name == project) {
+            return None;
+        }
+        let runs: Vec<&Run> = state
+            .runs
+            .get(project)
+            .into_iter()
+            .flatten()
+            .rev()
+            .filter(|run| filter.matches(run))
+            .collect();
+        let page = runs
+            .iter()
+            .skip(page.saturating_sub(1) * per_page)
+            .take(per_page)
+            .map(|run| (*run).clone())
+            .collect();
+        Some((page, runs.len()))
+    }
+
+    pub fn rate_limits(&self) -> Vec<RateLimit> {
+        self.state.lock().unwrap().rate_limits.clone()
+    }
+
+    /// Record that the agent has started.
+    pub fn start(&self, poll_interval: time::Duration, database: Database) {
+        self.state.lock().unwrap().agent = Some(Start {
+            time: time::Instant::now(),
+            started: chrono::offset::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            poll_interval,
+            database,
+        });
+    }
+
+    /// Returns `None` if the agent hasn't started.
+    pub fn agent(&self) -> Option<Agent> {
+        let state = self.state.lock().unwrap();
+        let start = state.agent.as_ref()?;
+        Some(Agent {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_commit: non_empty(env!("CDAGENT_GIT_COMMIT")),
+            git_tag: non_empty(env!("CDAGENT_GIT_TAG")),
+            build_time: build_time(),
+            started: start.started.clone(),
+            uptime_seconds: start.time.elapsed().as_secs(),
+            poll_interval_seconds: start.poll_interval.as_secs(),
+            database: start.database.clone(),
+            projects: ProjectCounts::new(&state.projects),
+        })
+    }
+}
+
+/// Returns the agent's version along with the build metadata that is known,
+///     like `0.1.0 (v0.1.0, commit 0a1b2c3, built 2024-01-23T14:16:24Z)`.
+pub fn long_version() -> String {
+    let details: Vec<String> = [
+        non_empty(env!("CDAGENT_GIT_TAG")),
+        non_empty(env!("CDAGENT_GIT_COMMIT")).map(|commit| format!("commit {commit}")),
+        build_time().map(|time| format!("built {time}")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    match details.as_slice() {
+        [] => env!("CARGO_PKG_VERSION").to_string(),
+        details => format!("{} ({})", env!("CARGO_PKG_VERSION"), details.join(", ")),
+    }
+}
+
+/// When the agent was built, as an RFC 3339 timestamp.
+fn build_time() -> Option<String> {
+    env!("CDAGENT_BUILD_TIMESTAMP")
+        .parse()
+        .ok()
+        .and_then(|timestamp| chrono::DateTime::from_timestamp(timestamp, 0))
+        .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    (!value.is_empty()).then(|| value.to_string())
+}
@@ -0,0 +1,256 @@
+//! Approval of redeployments for projects with approval gates.
+//!
+//! Approval requests contain signed links to the agent's `/approval` endpoint.
+//! Opening a link shows a confirmation page, and submitting it records the decision.
+//! The decision is picked up by the project the next time it runs.
+
+use crate::audit;
+use hmac::Mac;
+use std::collections::HashMap;
+use std::sync;
+
+/// A decision about a pending redeployment.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    Approve,
+    Reject,
+}
+
+impl Decision {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Decision::Approve => "approve",
+            Decision::Reject => "reject",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "approve" => Some(Decision::Approve),
+            "reject" => Some(Decision::Reject),
+            _ => None,
+        }
+    }
+}
+
+/// Redeployments awaiting approval, and the decisions made about them.
+///
+/// Clones share the same underlying state.
+#[derive(Clone)]
+pub struct Approvals {
+    secret: String,
+    /// Keyed by project name and workflow run ID.
+    /// The value is `None` until a decision has been made.
+    pending: sync::Arc<sync::Mutex<HashMap<Key, Option<Decision>>>>,
+    audit: audit::Log,
+}
+
+/// Project name and workflow run ID.
+type Key = (String, u64);
+
+impl Approvals {
+    pub fn new(secret: String, audit: audit::Log) -> Self {
+        Self {
+            secret,
+            pending: Default::default(),
+            audit,
+        }
+    }
+
+    /// Record that the redeployment of the workflow run for the project is awaiting approval.
+    pub fn expect(&self, project: &str, run_id: u64) {
+        self.pending
+            .lock()
+            .unwrap()
+            .entry((project.to_string(), run_id))
+            .or_insert(None);
+    }
+
+    /// Returns the decision made about the redeployment, if any.
+    ///
+    /// Once a decision is returned the redeployment is no longer awaiting approval.
+    pub fn take(&self, project: &str, run_id: u64) -> Option<Decision> {
+        let mut pending = self.pending.lock().unwrap();
+        let key = (project.to_string(), run_id);
+        let decision = (*pending.get(&key)?)?;
+        pending.remove(&key);
+        Some(decision)
+    }
+
+    /// Returns a signed link that makes the decision about the redeployment.
+    ///
+    /// The base URL is the URL the agent's HTTP server is reachable at.
+    pub fn link(&self, base_url: &str, project: &str, run_id: u64, decision: Decision) -> String {
+        format!(
+            "{}/approval?project={}&run={run_id}&decision={}&signature={}",
+            base_url.trim_end_matches('/'),
+            percent_encode(project),
+            decision.as_str(),
+            self.signature(project, run_id, decision)
+        )
+    }
+
+    fn signature(&self, project: &str, run_id: u64, decision: Decision) -> String {
+        self.mac(project, run_id, decision)
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    fn mac(&self, project: &str, run_id: u64, decision: Decision) -> hmac::Hmac<sha2::Sha256> {
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(format!("{}\n{project}\n{run_id}", decision.as_str()).as_bytes());
+        mac
+    }
+
+    /// Handle a request to the `/approval` endpoint.
+    ///
+    /// GET requests show a confirmation page, so that links being prefetched
+    ///     (e.g., by email security scanners) don't make decisions.
+    /// POST requests make the decision.
+    ///
+    /// The source is the client's IP address, for the audit log.
+    /// Returns the status code and HTML body of the response.
+    pub fn handle(&self, method: &tiny_http::Method, url: &str, source: &str) -> (u16, String) {
+        let query = url.split_once('?').map(|(_, query)| query).unwrap_or("");
+        let params: HashMap<String, String> = query
+            .split('&')
+            .filter_map(|param| param.split_once('='))
+            .map(|(key, value)| (key.to_string(), percent_decode(value)))
+            .collect();
+        let param = |key: &str| params.get(key).map(String::as_str).unwrap_or("");
+        let project = param("project");
+        let (Ok(run_id), Some(decision)) = (
+            param("run").parse::<u64>(),
+            Decision::parse(param("decision")),
+        ) else {
+            return (400, page("Invalid approval link."));
+        };
+        if !self.verify(project, run_id, decision, param("signature")) {
+            return (403, page("Invalid approval link."));
+        }
+        let key = (project.to_string(), run_id);
+        match method {
+            tiny_http::Method::Get => {
+                if self.pending.lock().unwrap().get(&key) != Some(&None) {
+                    return (410, page("This link has expired or has already been used."));
+                }
+                let action = match decision {
+                    Decision::Approve => "Approve",
+                    Decision::Reject => "Reject",
+                };
+                (
+                    200,
+                    page(&format!(
+                        "<form method=\"post\">{action} the redeployment of workflow run {run_id} \
+                        for {}? <button type=\"submit\">{action}</button></form>",
+                        handlebars::html_escape(project)
+                    )),
+                )
+            }
+            tiny_http::Method::Post => {
+                // Anyone with the link can make the decision.
+                if !self.decide(project, run_id, decision, "approval link", source) {
+                    return (410, page("This link has expired or has already been used."));
+                }
+                let outcome = match decision {
+                    Decision::Approve => "approved; it will start shortly",
+                    Decision::Reject => "rejected",
+                };
+                (200, page(&format!("The redeployment has been {outcome}.")))
+            }
+            _ => (405, page("Method not allowed.")),
+        }
+    }
+
+    /// Record a decision about a redeployment awaiting approval.
+    ///
+    /// The actor and source of the decision are recorded in the audit log.
+    /// Returns false if the redeployment is not awaiting approval,
+    ///     or if a decision has already been made.
+    pub fn decide(
+        &self,
+        project: &str,
+        run_id: u64,
+        decision: Decision,
+        actor: &str,
+        source: &str,
+    ) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(entry @ None) = pending.get_mut(&(project.to_string(), run_id)) else {
+            return false;
+        };
+        *entry = Some(decision);
+        eprintln!(
+            "[{project}] Redeployment of workflow run {run_id} received decision: {}",
+            decision.as_str()
+        );
+        self.audit.record(
+            audit::Entry::new(actor, source, decision.as_str(), Some(project))
+                .with_details(format!("workflow run {run_id}")),
+        );
+        true
+    }
+
+    fn verify(&self, project: &str, run_id: u64, decision: Decision, signature: &str) -> bool {
+        let Some(signature) = hex_decode(signature) else {
+            return false;
+        };
+        self.mac(project, run_id, decision)
+            .verify_slice(&signature)
+            .is_ok()
+    }
+}
+
+pub fn page(content: &str) -> String {
+    format!("<!doctype html><html lang=\"en\"><head><meta charset=\"utf-8\"><title>Continuous deployment agent</title></head><body><p>{content}</p></body></html>")
+}
+
+pub fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => match s
+                .get(i + 1..i + 3)
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+            {
+                Some(b) => {
+                    decoded.push(b);
+                    i += 3;
+                    continue;
+                }
+                None => decoded.push(b'%'),
+            },
+            b'+' => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,43 @@
+//! Static files used by the status pages, embedded in the binary and served under `/static/`.
+//!
+//! Links to the files include a hash of their content, so that browsers can cache them
+//!     indefinitely and still pick up new versions when the agent is upgraded.
+
+use sha2::Digest;
+
+struct Asset {
+    name: &'static str,
+    content_type: &'static str,
+    content: &'static [u8],
+}
+
+static ASSETS: &[Asset] = &[
+    Asset {
+        name: "style.css",
+        content_type: "text/css; charset=UTF-8",
+        content: include_bytes!("static/style.css"),
+    },
+    Asset {
+        name: "app.js",
+        content_type: "text/javascript; charset=UTF-8",
+        content: include_bytes!("static/app.js"),
+    },
+];
+
+/// Returns the content type and content of the static file with the provided name.
+pub fn get(name: &str) -> Option<(&'static str, &'static [u8])> {
+    ASSETS
+        .iter()
+        .find(|asset| asset.name == name)
+        .map(|asset| (asset.content_type, asset.content))
+}
+
+/// Returns the URL of the static file with the provided name.
+pub fn url(name: &str) -> Option<String> {
+    let (_, content) = get(name)?;
+    let hash: String = sha2::Sha256::digest(content)[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    Some(format!("/static/{name}?v={hash}"))
+}
@@ -0,0 +1,142 @@
+//! Audit log of manual actions, like pausing a project or approving a redeployment.
+//!
+//! Actions taken through the HTTP server are recorded in memory
+//!     and written to the database by the project manager at its next checkpoint.
+//! CLI commands that change the database write their entries directly.
+
+use crate::database;
+use std::sync;
+
+/// A manual action.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+    /// When the action was taken, as an RFC 3339 timestamp.
+    pub time: String,
+    /// Who took the action: a username, a token's name, or how an anonymous actor was identified.
+    pub actor: String,
+    /// Where the action came from: the client's IP address, `slack` or `cli`.
+    pub source: String,
+    /// The action, like `pause`, `deploy` or `approve`.
+    pub action: String,
+    pub project: Option<String>,
+    /// More about the action, like the workflow run approved.
+    pub details: String,
+}
+
+impl Entry {
+    pub fn new(actor: &str, source: &str, action: &str, project: Option<&str>) -> Self {
+        Self {
+            time: chrono::offset::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            actor: actor.to_string(),
+            source: source.to_string(),
+            action: action.to_string(),
+            project: project.map(str::to_string),
+            details: String::new(),
+        }
+    }
+
+    pub fn with_details(mut self, details: impl Into<String>) -> Self {
+        self.details = details.into();
+        self
+    }
+}
+
+/// The prefix of the database keys of audit log entries.
+pub const KEY_PREFIX: &str = "audit/";
+
+/// The audit log.
+///
+/// Clones share the same underlying log.
+#[derive(Clone, Default)]
+pub struct Log {
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    /// All entries, oldest first.
+    entries: Vec<Entry>,
+    /// How many of the entries have been written to the database.
+    persisted: usize,
+}
+
+impl Log {
+    /// Record a manual action.
+    pub fn record(&self, entry: Entry) {
+        eprintln!(
+            "Audit: {} from {} took action {} on {}{}",
+            entry.actor,
+            entry.source,
+            entry.action,
+            entry.project.as_deref().unwrap_or("the agent"),
+            match entry.details.as_str() {
+                "" => String::new(),
+                details => format!(" ({details})"),
+            }
+        );
+        self.state.lock().unwrap().entries.push(entry);
+    }
+
+    /// Load the entries in the database, which precede any recorded so far.
+    pub fn load(&self, db: &dyn database::DB) -> Result<(), String> {
+        let mut entries = vec![];
+        for key in db.keys(KEY_PREFIX) {
+            if let Some(entry) = database::get::<Entry>(db, &key)? {
+                entries.push(entry);
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        state.persisted = entries.len();
+        entries.append(&mut state.entries);
+        state.entries = entries;
+        Ok(())
+    }
+
+    /// Write the entries recorded since the last call to the database.
+    pub fn persist(&self, db: &mut dyn database::DB) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        while let Some(entry) = state.entries.get(state.persisted) {
+            append(db, entry)?;
+            state.persisted += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns a page of the entries that match the filter, most recent first,
+    ///     along with the total number of matching entries.
+    ///
+    /// Pages are numbered from 1.
+    pub fn entries(
+        &self,
+        page: usize,
+        per_page: usize,
+        filter: impl Fn(&Entry) -> bool,
+    ) -> (Vec<Entry>, usize) {
+        let state = self.state.lock().unwrap();
+        let entries: Vec<&Entry> = state.entries.iter().rev().filter(|e| filter(e)).collect();
+        let page = entries
+            .iter()
+            .skip(page.saturating_sub(1) * per_page)
+            .take(per_page)
+            .map(|entry| (*entry).clone())
+            .collect();
+        (page, entries.len())
+    }
+}
+
+/// Write the entry to the database after the entries already in it.
+pub fn append(db: &mut dyn database::DB, entry: &Entry) -> Result<(), String> {
+    let next = db
+        .keys(KEY_PREFIX)
+        .last()
+        .and_then(|key| key.strip_prefix(KEY_PREFIX)?.parse::<u64>().ok())
+        .map_or(0, |n| n + 1);
+    database::set(db, &format!("{KEY_PREFIX}{next:020}"), entry)
+}
+
+/// Returns the name of the user running a CLI command, for audit log entries.
+pub fn cli_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
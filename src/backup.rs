@@ -0,0 +1,109 @@
+//! Periodic backups of the database.
+
+use crate::config;
+use crate::database;
+use std::path::{Path, PathBuf};
+
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Periodically snapshots the database to files named `<database>.bak.<timestamp>`.
+pub struct Backups {
+    dir: PathBuf,
+    prefix: String,
+    interval: chrono::Duration,
+    keep: usize,
+    last_backup: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Backups {
+    /// Create a new backup subsystem.
+    ///
+    /// The time of the last backup is recovered from existing backup files.
+    pub fn new(config: &config::BackupConfig, db_location: Option<&str>) -> Result<Self, String> {
+        let db_path = db_location.map(Path::new);
+        let dir = match (&config.directory, db_path.and_then(Path::parent)) {
+            (Some(directory), _) => PathBuf::from(directory),
+            (None, Some(parent)) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            (None, Some(_)) => PathBuf::from("."),
+            (None, None) => {
+                return Err(
+                    "the backup directory must be provided when using an in-memory database"
+                        .to_string(),
+                )
+            }
+        };
+        let prefix = match db_path.and_then(Path::file_name) {
+            None => "db.bak.".to_string(),
+            Some(name) => format!("{}.bak.", name.to_string_lossy()),
+        };
+        let mut backups = Self {
+            dir,
+            prefix,
+            interval: chrono::Duration::seconds(match config.interval_seconds {
+                None | Some(0) => 24 * 60 * 60,
+                Some(d) => d as i64,
+            }),
+            keep: match config.keep {
+                None | Some(0) => 7,
+                Some(keep) => keep,
+            },
+            last_backup: None,
+        };
+        backups.last_backup = backups.existing().last().map(|(_, timestamp)| *timestamp);
+        Ok(backups)
+    }
+
+    /// Back up the database if the backup interval has elapsed since the last backup.
+    pub fn run_if_due(&mut self, db: &dyn database::DB) {
+        let now = chrono::Utc::now();
+        if let Some(last_backup) = self.last_backup {
+            if now < last_backup + self.interval {
+                return;
+            }
+        }
+        let path = self
+            .dir
+            .join(format!("{}{}", self.prefix, now.format(TIMESTAMP_FORMAT)));
+        if let Err(err) = std::fs::write(&path, database::export(db)) {
+            eprintln!("Failed to back up database to {}: {err}", path.display());
+            return;
+        }
+        eprintln!("Backed up database to {}", path.display());
+        self.last_backup = Some(now);
+        let existing = self.existing();
+        for (path, _) in existing
+            .iter()
+            .take(existing.len().saturating_sub(self.keep))
+        {
+            if let Err(err) = std::fs::remove_file(path) {
+                eprintln!("Failed to remove old backup {}: {err}", path.display());
+            }
+        }
+    }
+
+    /// Returns the time of the last backup in RFC 3339 format.
+    pub fn last_backup(&self) -> Option<String> {
+        self.last_backup
+            .map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+    }
+
+    /// Returns the existing backups, oldest first.
+    fn existing(&self) -> Vec<(PathBuf, chrono::DateTime<chrono::Utc>)> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+        let mut backups: Vec<(PathBuf, chrono::DateTime<chrono::Utc>)> = entries
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let timestamp = name.strip_prefix(&self.prefix)?;
+                let timestamp =
+                    chrono::NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()?;
+                Some((entry.path(), timestamp.and_utc()))
+            })
+            .collect();
+        backups.sort_by_key(|(_, timestamp)| *timestamp);
+        backups
+    }
+}
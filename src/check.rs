@@ -0,0 +1,116 @@
+//! Validation of a config file without running the agent, for the `cdagent check` command.
+//!
+//! This catches problems that would otherwise only be found when the agent restarts,
+//!     or when a project is first redeployed.
+
+use crate::config;
+use crate::email;
+use crate::project;
+
+/// Returns the problems with the projects in the config.
+pub fn projects(config: &config::Config) -> Vec<String> {
+    let mut problems = vec![];
+    for project_config in &config.projects {
+        for problem in check_project(project_config) {
+            problems.push(format!("project {}: {problem}", project_config.name));
+        }
+    }
+    let projects: Vec<project::Project> = config
+        .projects
+        .iter()
+        .cloned()
+        .map(project::Project::new)
+        .collect();
+    if let Err(err) = project::dependency_order(&projects) {
+        problems.push(err);
+    }
+    let notifications = config.notifications.as_ref();
+    if let Err(err) = project::check_approvals(
+        &config.projects,
+        notifications.is_some_and(|n| n.approval_secret.is_some()),
+        notifications.and_then(|n| n.status_page_url.as_deref()),
+    ) {
+        problems.push(err);
+    }
+    if let Some(auth) = &config.auth {
+        if let Err(err) = auth.check_projects(&config.projects) {
+            problems.push(err);
+        }
+    }
+    problems
+}
+
+fn check_project(config: &config::ProjectConfig) -> Vec<String> {
+    let mut problems = vec![];
+    // GitHub usernames only contain alphanumeric characters and hyphens.
+    if config.github_user.is_empty()
+        || !config
+            .github_user
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        problems.push(format!("invalid GitHub user {:?}", config.github_user));
+    }
+    if config.repo.is_empty()
+        || matches!(config.repo.as_str(), "." | "..")
+        || !config
+            .repo
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        problems.push(format!("invalid repository name {:?}", config.repo));
+    }
+    if config.mainline_branch.is_empty() {
+        problems.push("the mainline branch is empty".to_string());
+    }
+    let canary = config.canary.iter();
+    let steps = config
+        .steps
+        .iter()
+        .chain(canary.clone().flat_map(|canary| &canary.steps))
+        .chain(canary.flat_map(|canary| &canary.health_checks))
+        .chain(
+            config
+                .releases
+                .iter()
+                .flat_map(|releases| &releases.restart),
+        );
+    for step in steps {
+        let shell = step
+            .shell
+            .unwrap_or_else(config::Shell::default_for_platform);
+        if step.run.trim().is_empty() {
+            problems.push(format!("the command of step {} is empty", step.name));
+        } else if shell == config::Shell::None && shlex::split(&step.run).is_none() {
+            problems.push(format!(
+                "the command of step {} can't be parsed: {}",
+                step.name, step.run
+            ));
+        }
+    }
+    if config.releases.is_none() {
+        if let Some(dir) = &config.working_directory {
+            if !std::path::Path::new(dir).is_dir() {
+                problems.push(format!("the working directory {dir} does not exist"));
+            }
+        }
+    }
+    problems
+}
+
+/// Returns the problems connecting to the SMTP servers of the email notifiers in the config.
+pub fn smtp(config: &config::Config) -> Vec<String> {
+    let mut problems = vec![];
+    let email_configs = config.notifications.iter().flat_map(|n| &n.email);
+    for email_config in email_configs {
+        let Some(smtp_config) = &email_config.smtp else {
+            continue;
+        };
+        let result =
+            email::Client::new(email_config.clone()).and_then(|client| client.test_connection());
+        if let Err(err) = result {
+            problems.push(format!("SMTP server {}: {err}", smtp_config.host));
+        }
+    }
+    problems
+}
@@ -0,0 +1,181 @@
+//! The command line interface.
+//!
+//! Each thing the agent can do is a subcommand, like `cdagent run <config>`.
+//! For compatibility with the CLI before there were subcommands,
+//!     `cdagent <config>` is the same as `cdagent run <config>`.
+
+use crate::api;
+use clap::{CommandFactory, FromArgMatches};
+
+#[derive(clap::Parser)]
+#[command(
+    version,
+    about = "Redeploys projects when their CI workflows succeed on GitHub",
+    after_long_help = EXIT_STATUSES
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(clap::Subcommand)]
+pub enum Command {
+    /// Run the agent.
+    Run(RunArgs),
+    /// Validate a config file, reporting every problem found.
+    Check {
+        /// Paths to the config files or config directories, which are merged.
+        #[arg(required = true)]
+        config: Vec<String>,
+    },
+    /// Send a test message with every notifier in a config file.
+    NotifyTest {
+        /// Path to the config file or config directory; can be repeated to merge several.
+        #[arg(long, required = true)]
+        config: Vec<String>,
+    },
+    /// Print the state of the projects of a running agent.
+    Status(AgentArgs),
+    /// Ask a running agent to redeploy a project now, and print the output of its steps.
+    ///
+    /// By default the project is polled and only redeployed if there is a new workflow run.
+    Deploy {
+        /// Name of the project.
+        project: String,
+        /// Redeploy the workflow run of the project's most recent redeployment again.
+        #[arg(long, conflicts_with = "sha")]
+        force: bool,
+        /// Redeploy the workflow run for the commit with this full SHA.
+        #[arg(long)]
+        sha: Option<String>,
+        #[command(flatten)]
+        agent: AgentArgs,
+    },
+    /// Encrypt a value read from stdin, to paste into a config file.
+    Encrypt,
+    /// Export, import or check a database.
+    #[command(subcommand)]
+    Db(DbCommand),
+    /// Print a completion script for a shell.
+    ///
+    /// For example, for bash: `cdagent completions bash > /etc/bash_completion.d/cdagent`.
+    Completions {
+        /// The shell to complete commands in.
+        shell: clap_complete::Shell,
+    },
+}
+
+const EXIT_STATUSES: &str = "\
+Exit statuses:
+  0   Success
+  1   The agent or the subcommand failed
+  2   The command line arguments are invalid
+  3   The config couldn't be read or is invalid
+  4   The database couldn't be opened, e.g. because another agent is using it
+  75  The agent installed an update and exited to be restarted";
+
+/// How to reach a running agent.
+#[derive(clap::Args)]
+pub struct AgentArgs {
+    /// Address of the agent, as a URL or `host:port`.
+    ///
+    /// Defaults to the status page URL in the config, if `--config` is given,
+    /// and otherwise to `localhost:8000`.
+    #[arg(long)]
+    pub addr: Option<String>,
+    /// Config file of the agent, to find its address and an auth token in.
+    #[arg(long)]
+    pub config: Option<String>,
+    /// Bearer token to authenticate with, if the agent requires authentication.
+    #[arg(long, env = "CDAGENT_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+}
+
+#[derive(clap::Args)]
+pub struct RunArgs {
+    /// Path to the config file or config directory.
+    ///
+    /// The file name can contain `*` and `?` wildcards, like `'configs/*.yml'`,
+    /// to merge all of the matching files.
+    /// Without one, a single project is configured with `CDAGENT_*` environment variables.
+    pub config: Option<String>,
+    /// Another config file, config directory or pattern to merge into the config;
+    /// can be repeated.
+    #[arg(long = "config", value_name = "PATH")]
+    pub more_config: Vec<String>,
+    /// Path to the database, to persist state across restarts.
+    pub database: Option<String>,
+    /// Load the database without ever writing to it,
+    /// and serve the status page without running any projects.
+    #[arg(long)]
+    pub db_read_only: bool,
+    /// Also reload the config whenever it changes, rather than only on SIGHUP.
+    #[arg(long)]
+    pub watch_config: bool,
+    /// Override a value in the config, like `projects.myapp.paused=true`.
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+    /// Poll and redeploy every project once, then exit,
+    /// with a non-zero status if any project failed to redeploy or couldn't be polled.
+    ///
+    /// The status page is not served, so this can run alongside an agent that serves it.
+    #[arg(long, conflicts_with_all = ["db_read_only", "watch_config", "daemon"])]
+    pub once: bool,
+    /// Fork into the background once the config has been read.
+    #[arg(long)]
+    pub daemon: bool,
+    /// Run as a Windows service, for the service control manager to start and stop.
+    #[arg(long, conflicts_with_all = ["once", "daemon"])]
+    pub service: bool,
+    /// Write the agent's process ID to this file, and remove it when the agent exits.
+    #[arg(long)]
+    pub pid_file: Option<String>,
+    /// Write the agent's output to this file rather than to stderr.
+    ///
+    /// This takes precedence over the log file in the config,
+    /// but the file is still rotated as configured there.
+    #[arg(long)]
+    pub log_file: Option<String>,
+}
+
+#[derive(clap::Subcommand)]
+pub enum DbCommand {
+    /// Write all values in the database to a file, or to stdout if the file is `-`.
+    Export { database: String, file: String },
+    /// Replace the contents of the database with a file, or with stdin if the file is `-`.
+    Import { database: String, file: String },
+    /// Report values in the database that are corrupt or unknown.
+    Check {
+        database: String,
+        /// Delete the bad values.
+        #[arg(long)]
+        fix: bool,
+    },
+}
+
+/// Parse the command line arguments, exiting with a usage message if they are invalid.
+pub fn parse() -> Cli {
+    let mut args: Vec<String> = std::env::args().collect();
+    let subcommands: Vec<String> = Cli::command()
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_string())
+        .collect();
+    let explicit = args.get(1).is_some_and(|arg| {
+        subcommands.contains(arg)
+            || matches!(arg.as_str(), "help" | "-h" | "--help" | "-V" | "--version")
+    });
+    if !explicit {
+        args.insert(1.min(args.len()), "run".to_string());
+    }
+    let matches = Cli::command()
+        .version(api::long_version())
+        .get_matches_from(args);
+    Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit())
+}
+
+/// Write the completion script for the shell to stdout.
+pub fn print_completions(shell: clap_complete::Shell) {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+}
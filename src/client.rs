@@ -0,0 +1,180 @@
+//! A client for the HTTP API of a running agent, used by CLI subcommands like `cdagent status`.
+
+use crate::api;
+use crate::approval;
+use crate::control;
+use std::time::Duration;
+
+pub struct Client {
+    agent: ureq::Agent,
+    /// The agent's base URL, without a trailing slash.
+    base_url: String,
+    authorization: Option<String>,
+}
+
+/// An event in the stream of a redeployment's output; see the [crate::live] module.
+pub struct Event {
+    pub name: String,
+    pub id: Option<String>,
+    pub data: serde_json::Value,
+}
+
+impl Client {
+    /// Create a client for the agent at the address, which is a URL or a `host:port` pair.
+    pub fn new(address: &str, token: Option<&str>) -> Self {
+        // Log stream requests wait for new output for up to 15 seconds.
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(30))
+            .build();
+        let base_url = if address.contains("://") {
+            address.trim_end_matches('/').to_string()
+        } else {
+            format!("http://{}", address.trim_end_matches('/'))
+        };
+        Self {
+            agent,
+            base_url,
+            authorization: token.map(|token| format!("Bearer {token}")),
+        }
+    }
+
+    /// Returns all of the projects the agent is running.
+    pub fn projects(&self) -> Result<Vec<api::Project>, String> {
+        #[derive(serde::Deserialize)]
+        struct Projects {
+            projects: Vec<api::Project>,
+        }
+        let projects: Projects = self.call(self.request("GET", "/api/v1/projects"))?;
+        Ok(projects.projects)
+    }
+
+    /// Request a deployment of the project, returning its ID.
+    ///
+    /// See the [crate::http] module for what `force` and `sha` do.
+    pub fn deploy(&self, project: &str, force: bool, sha: Option<&str>) -> Result<u64, String> {
+        #[derive(serde::Deserialize)]
+        struct Accepted {
+            deployment: u64,
+        }
+        let path = format!("/api/projects/{}/deploy", approval::percent_encode(project));
+        let mut request = self.request("POST", &path);
+        if force {
+            request = request.query("force", "true");
+        }
+        if let Some(sha) = sha {
+            request = request.query("sha", sha);
+        }
+        let accepted: Accepted = self.call(request)?;
+        Ok(accepted.deployment)
+    }
+
+    /// Returns the requested deployment with the provided ID.
+    pub fn deployment(&self, id: u64) -> Result<control::Deployment, String> {
+        self.call(self.request("GET", &format!("/api/deployments/{id}")))
+    }
+
+    /// Returns the events of the redeployment's output after the event with the provided ID,
+    ///     waiting for new events if there are none yet.
+    ///
+    /// Returns `None` once the stream is over.
+    pub fn log_events(
+        &self,
+        project: &str,
+        workflow_run: u64,
+        last_event_id: Option<&str>,
+    ) -> Result<Option<Vec<Event>>, String> {
+        let path = format!(
+            "/api/projects/{}/runs/{workflow_run}/logs/stream",
+            approval::percent_encode(project)
+        );
+        let mut request = self.request("GET", &path);
+        if let Some(id) = last_event_id {
+            request = request.set("Last-Event-ID", id);
+        }
+        let body = match self.send(request)? {
+            (204, _) => return Ok(None),
+            (_, body) => body,
+        };
+        let mut events = vec![];
+        for block in body.split("\n\n") {
+            let mut event = Event {
+                name: String::new(),
+                id: None,
+                data: serde_json::Value::Null,
+            };
+            for line in block.lines() {
+                match line.split_once(": ") {
+                    Some(("event", name)) => event.name = name.to_string(),
+                    Some(("id", id)) => event.id = Some(id.to_string()),
+                    Some(("data", data)) => {
+                        event.data = serde_json::from_str(data).unwrap_or_default()
+                    }
+                    _ => {}
+                }
+            }
+            if !event.name.is_empty() {
+                events.push(event);
+            }
+        }
+        Ok(Some(events))
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = self
+            .agent
+            .request(method, &format!("{}{path}", self.base_url));
+        match &self.authorization {
+            None => request,
+            Some(authorization) => request.set("Authorization", authorization),
+        }
+    }
+
+    /// Make the request and parse the JSON response.
+    fn call<T: serde::de::DeserializeOwned>(&self, request: ureq::Request) -> Result<T, String> {
+        let url = request.url().to_string();
+        let (_, body) = self.send(request)?;
+        match serde_json::from_str(&body) {
+            Ok(value) => Ok(value),
+            Err(err) => Err(format!("failed to parse the response from {url}: {err}")),
+        }
+    }
+
+    /// Make the request, returning the status code and body of a successful response.
+    ///
+    /// For error responses, the message in the body is returned as the error if there is one.
+    fn send(&self, request: ureq::Request) -> Result<(u16, String), String> {
+        let url = request.url().to_string();
+        let response = match request.call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(401, _)) => {
+                return Err(format!(
+                    "the agent at {} requires authentication; pass a token with --token",
+                    self.base_url
+                ))
+            }
+            Err(ureq::Error::Status(403, _)) => {
+                return Err(format!(
+                    "the agent at {} refused the request; the token may lack the access needed",
+                    self.base_url
+                ))
+            }
+            Err(ureq::Error::Status(status, response)) => {
+                let message = response
+                    .into_string()
+                    .ok()
+                    .and_then(|body| serde_json::from_str::<serde_json::Value>(&body).ok())
+                    .and_then(|body| Some(body["message"].as_str()?.to_string()));
+                return Err(match message {
+                    Some(message) => message,
+                    None => format!("the agent responded to {url} with status {status}"),
+                });
+            }
+            Err(err) => return Err(format!("failed to query the agent: {err}")),
+        };
+        let status = response.status();
+        match response.into_string() {
+            Ok(body) => Ok((status, body)),
+            Err(err) => Err(format!("failed to read the response from {url}: {err}")),
+        }
+    }
+}
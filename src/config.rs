@@ -1,27 +1,1411 @@
 //! Configuration for the agent.
 
+use std::collections::BTreeMap;
+
 /// Configuration for the agent.
+///
+/// The config file can include other config files by listing them under `include`,
+///     like `include: [projects/*.yml]`.
+/// Paths are relative to the directory containing the config file,
+///     and `*` and `?` wildcards can be used in file names.
+/// The projects and templates in the included files are added to the ones in the config file.
+/// Other settings can only be set in one of the files.
+/// Included files can't include other files.
+///
+/// Alternatively the agent can be given a directory instead of a config file,
+///     in which case all of the `.yml` and `.yaml` files in the directory are merged.
+/// It can also be given several config files, or a pattern like `configs/*.yml`,
+///     which are merged in the same way.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// List of projects to run the agent for.
+    pub projects: Vec<ProjectConfig>,
+
+    /// Defaults for the settings of all projects.
+    #[serde(default)]
+    pub defaults: ProjectDefaults,
+
+    /// Named templates for projects, keyed by name.
+    ///
+    /// Each template contains any of the settings of a project,
+    ///     and projects that extend the template inherit the settings they don't set themselves.
+    /// Settings are overridden as a whole; e.g., a project that sets `steps` replaces
+    ///     all of the template's steps.
+    /// A template can itself extend another template.
+    #[serde(default)]
+    pub templates: BTreeMap<String, serde_yaml::Value>,
+
+    /// How often to poll the GitHub API to check for new successful CI runs.
+    ///
+    /// The default is 300 seconds (5 minutes).
+    ///
+    /// With a smaller number, the agent will notice new pushes faster.
+    /// However, GitHub imposes rate limits on API requests.
+    /// If this number is too small and too many requests are made,
+    ///     these rate limits may be reached.
+    /// If this happens the agent with stop polling GitHub until the cooling off period elapses.
+    /// The cooling off period is up to one hour.
+    ///
+    /// The rate limits are 60 non-cached requests per-hour if no auth token is provided,
+    ///     or 5000 non-cached requests per-hour per-GitHub-user if an auth token is provided.
+    /// Note that if there is no new information from the API (i.e., no new CI runs on mainline),
+    ///     GitHub returns a cached response that does not count towards the limit.
+    #[serde(default, deserialize_with = "optional_seconds")]
+    pub poll_interval_seconds: Option<u64>,
+
+    /// Configuration for the database.
+    #[serde(default)]
+    pub database: DatabaseConfig,
+
+    /// Configuration for the GitHub API.
+    #[serde(default)]
+    pub github: GithubConfig,
+
+    /// Send notifications about redeployments.
+    ///
+    /// By default no notifications are sent.
+    pub notifications: Option<NotificationConfig>,
+
+    /// Require authentication to access the status page and API.
+    ///
+    /// By default anyone who can reach the agent's HTTP server can access them.
+    /// The approval and Slack endpoints are not affected,
+    ///     as requests to them are signed.
+    pub auth: Option<AuthConfig>,
+
+    /// Configuration for the agent's HTTP server.
+    #[serde(default)]
+    pub http: HttpConfig,
+
+    /// Write the agent's log to a file rather than to stderr.
+    ///
+    /// The file can also be set with `--log-file`, which takes precedence.
+    pub log: Option<LogConfig>,
+
+    /// Update the agent itself when a new release of it is published on GitHub.
+    ///
+    /// By default the agent is not updated.
+    pub self_update: Option<SelfUpdateConfig>,
+
+    /// Export metrics about the agent, like redeployments and GitHub API latency,
+    ///     to a monitoring system.
+    ///
+    /// By default the metrics are not exported.
+    pub metrics: Option<MetricsConfig>,
+
+    /// Export traces of redeployments to an OpenTelemetry collector.
+    ///
+    /// By default traces are not exported.
+    pub tracing: Option<TracingConfig>,
+}
+
+impl Config {
+    /// Read the secrets that are provided in files, like `auth_token_file`,
+    ///     into the corresponding fields, like `auth_token`.
+    ///
+    /// This allows secrets to be provided using Docker secrets or systemd credentials.
+    /// Leading and trailing whitespace in the files is ignored.
+    pub fn read_secret_files(&mut self) -> Result<(), String> {
+        read_secret_file(
+            "GitHub auth token",
+            &mut self.github.auth_token,
+            &self.github.auth_token_file,
+        )?;
+        read_secret_file(
+            "default auth token",
+            &mut self.defaults.auth_token,
+            &self.defaults.auth_token_file,
+        )?;
+        for project in &mut self.projects {
+            project.read_secret_files()?;
+        }
+        if let Some(notifications) = &mut self.notifications {
+            read_secret_file(
+                "approval secret",
+                &mut notifications.approval_secret,
+                &notifications.approval_secret_file,
+            )?;
+            read_notifier_secret_files(
+                &mut notifications.webhook,
+                &mut notifications.ntfy,
+                &mut notifications.slack,
+            )?;
+        }
+        if let Some(auth) = &mut self.auth {
+            for (i, token) in auth.tokens.iter_mut().enumerate() {
+                let what = match &token.name {
+                    Some(name) => format!("token {name}"),
+                    None => format!("token #{}", i + 1),
+                };
+                read_secret_file(&what, &mut token.token, &token.token_file)?;
+            }
+            for user in &mut auth.users {
+                read_secret_file(
+                    &format!("password for user {}", user.username),
+                    &mut user.password,
+                    &user.password_file,
+                )?;
+            }
+            if let Some(oidc) = &mut auth.oidc {
+                read_secret_file(
+                    "OIDC client secret",
+                    &mut oidc.client_secret,
+                    &oidc.client_secret_file,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a copy of the config with the values of its secrets replaced, so that it can be logged.
+    ///
+    /// This includes secrets that were decrypted or read from files.
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        redact(&mut config.github.auth_token);
+        redact(&mut config.defaults.auth_token);
+        for project in &mut config.projects {
+            project.redact_secrets();
+        }
+        if let Some(notifications) = &mut config.notifications {
+            redact(&mut notifications.approval_secret);
+            redact_notifier_secrets(
+                &mut notifications.email,
+                &mut notifications.webhook,
+                &mut notifications.ntfy,
+                &mut notifications.slack,
+            );
+        }
+        if let Some(auth) = &mut config.auth {
+            for token in &mut auth.tokens {
+                redact(&mut token.token);
+            }
+            for user in &mut auth.users {
+                redact(&mut user.password);
+            }
+            if let Some(oidc) = &mut auth.oidc {
+                redact(&mut oidc.client_secret);
+            }
+        }
+        // Headers are how hosted tracing backends are given API keys.
+        if let Some(tracing) = &mut config.tracing {
+            for value in tracing.headers.values_mut() {
+                *value = REDACTED.to_string();
+            }
+        }
+        config
+    }
+
+    /// Returns the problems with the config, each with the index of the project it's about, if any.
+    pub fn validate(&self) -> Vec<(Option<usize>, String)> {
+        let mut problems = vec![];
+        if self.defaults.retention == Some(0) {
+            problems.push((None, "the default retention must be positive".to_string()));
+        }
+        if let Some(self_update) = &self.self_update {
+            for problem in self_update.validate() {
+                problems.push((None, problem));
+            }
+        }
+        if let Some(metrics) = &self.metrics {
+            for problem in metrics.validate() {
+                problems.push((None, problem));
+            }
+        }
+        if let Some(tracing) = &self.tracing {
+            if !tracing.endpoint.starts_with("http://") && !tracing.endpoint.starts_with("https://")
+            {
+                problems.push((
+                    None,
+                    format!(
+                        "tracing: the endpoint {:?} must be an http:// or https:// URL",
+                        tracing.endpoint
+                    ),
+                ));
+            }
+        }
+        let mut names = std::collections::HashSet::new();
+        for (i, project) in self.projects.iter().enumerate() {
+            let mut problem = |problem: String| problems.push((Some(i), problem));
+            let name = &project.name;
+            if name.trim().is_empty() {
+                problem("project with no name: the name must not be empty".to_string());
+            } else if !names.insert(name) {
+                problem(format!(
+                    "project {name}: there is more than one project with this name"
+                ));
+            }
+            for project_problem in project.validate() {
+                problem(project_problem);
+            }
+        }
+        problems
+    }
+
+    /// Apply the defaults to the settings that projects don't set themselves.
+    ///
+    /// This also splits repositories that include their owner into `github_user` and `repo`.
+    pub fn apply_defaults(&mut self) {
+        let mut projects = std::mem::take(&mut self.projects);
+        for project in &mut projects {
+            self.apply_project_defaults(project);
+        }
+        self.projects = projects;
+    }
+
+    /// Apply the defaults to the settings that the project doesn't set itself.
+    pub fn apply_project_defaults(&self, project: &mut ProjectConfig) {
+        let defaults = &self.defaults;
+        if let Some((owner, name)) = crate::github::parse_repo(&project.repo) {
+            (project.github_user, project.repo) = (owner.to_string(), name.to_string());
+        }
+        if project.auth_token.is_empty() {
+            project.auth_token = defaults
+                .auth_token
+                .as_ref()
+                .or(self.github.auth_token.as_ref())
+                .cloned()
+                .unwrap_or_default();
+        }
+        project.retention = project.retention.or(defaults.retention);
+        project.retention_days = project.retention_days.or(defaults.retention_days);
+        if project.notify_on.is_none() {
+            project.notify_on = defaults.notify_on.clone();
+        }
+        if let (Some(root), None) = (&defaults.working_directory_root, &project.releases) {
+            let root = std::path::Path::new(root);
+            project.working_directory = Some(
+                match &project.working_directory {
+                    None => root.to_path_buf(),
+                    Some(dir) => root.join(dir),
+                }
+                .to_string_lossy()
+                .into_owned(),
+            );
+        }
+    }
+
+    /// Returns the poll interval, applying the default.
+    pub fn poll_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(match self.poll_interval_seconds {
+            None | Some(0) => 300,
+            Some(d) => d,
+        })
+    }
+}
+
+/// Configuration for the GitHub API.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct GithubConfig {
+    /// Auth token to use for projects that don't set their own `auth_token`,
+    ///     and for which `defaults` doesn't set one either.
+    #[serde(default, skip_serializing)]
+    pub auth_token: Option<String>,
+
+    /// Path to a file containing the auth token, instead of `auth_token`.
+    pub auth_token_file: Option<String>,
+}
+
+/// Defaults for the settings of projects.
+///
+/// Each project inherits these settings unless it sets them itself.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct ProjectDefaults {
+    /// Auth token to use for making GitHub API requests; see [ProjectConfig::auth_token].
+    #[serde(default, skip_serializing)]
+    pub auth_token: Option<String>,
+
+    /// Path to a file containing the auth token, instead of `auth_token`.
+    pub auth_token_file: Option<String>,
+
+    /// Number of prior deployments to retain.
+    pub retention: Option<usize>,
+
+    /// Number of days to retain prior deployments for.
+    pub retention_days: Option<u64>,
+
+    /// Kinds of notifications to send about each project.
+    pub notify_on: Option<Vec<NotifyOn>>,
+
+    /// Directory that the working directories of projects are relative to.
+    ///
+    /// Projects without a working directory run their steps in this directory.
+    /// Projects using managed release directories are not affected.
+    pub working_directory_root: Option<String>,
+}
+
+/// Configuration for authentication to the agent's HTTP server.
+///
+/// Requests can authenticate with any of the configured tokens, users or the OIDC provider.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct AuthConfig {
+    /// Bearer tokens, sent in an `Authorization: Bearer <token>` header.
+    ///
+    /// These are intended for scripts and other programs using the API.
+    #[serde(default)]
+    pub tokens: Vec<TokenConfig>,
+
+    /// Users authenticating with HTTP basic auth.
+    ///
+    /// These are intended for people viewing the status page in a browser,
+    ///     which prompts for the username and password.
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+
+    /// Log in to the status page through an OpenID Connect (OIDC) provider,
+    ///     like a company's single sign-on.
+    ///
+    /// Browsers without credentials are redirected to the provider to log in,
+    ///     and are then given a session cookie.
+    pub oidc: Option<OidcConfig>,
+
+    /// Serve the badges of projects without authentication, so that they can be embedded in READMEs.
+    ///
+    /// Badges show the outcome and time of a project's most recent redeployment,
+    ///     so anyone who can reach the agent can then see that for any project whose name they know.
+    /// By default, viewing a badge requires permission to view the project.
+    #[serde(default)]
+    pub public_badges: bool,
+}
+
+/// An OpenID Connect provider for logging in to the status page.
+///
+/// The agent must be registered with the provider as a confidential client
+///     using the authorization code flow.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct OidcConfig {
+    /// URL of the provider, like `https://accounts.google.com`.
+    ///
+    /// The provider's endpoints are discovered from
+    ///     `{issuer_url}/.well-known/openid-configuration`.
+    pub issuer_url: String,
+
+    pub client_id: String,
+
+    /// The client secret.
+    ///
+    /// Exactly one of `client_secret`, `client_secret_env` or `client_secret_file` must be set.
+    pub client_secret: Option<String>,
+
+    /// Name of an environment variable containing the client secret.
+    pub client_secret_env: Option<String>,
+
+    /// Path to a file containing the client secret.
+    pub client_secret_file: Option<String>,
+
+    /// URL of the agent's `/auth/callback` endpoint as seen by browsers,
+    ///     like `https://cd.example.com/auth/callback`.
+    ///
+    /// This must be registered with the provider as a redirect URL.
+    pub redirect_url: String,
+
+    /// Scopes to request; defaults to `openid`, `profile` and `email`.
+    ///
+    /// Some providers only include the user's groups if a scope like `groups` is requested.
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+
+    /// Groups allowed to log in.
+    ///
+    /// If empty, anyone who can log in with the provider is allowed.
+    #[serde(default)]
+    pub allowed_groups: Vec<String>,
+
+    /// Claim of the ID token containing the user's groups; defaults to `groups`.
+    #[serde(default = "default_oidc_groups_claim")]
+    pub groups_claim: String,
+
+    /// What users who log in can access; defaults to `read_only`.
+    #[serde(default)]
+    pub access: Access,
+
+    /// Actions users who log in can take on each project, replacing `access`;
+    ///     see [TokenConfig::projects].
+    pub projects: Option<ProjectPermissions>,
+
+    /// How long users stay logged in; defaults to 12 hours.
+    ///
+    /// Sessions also end when the agent restarts.
+    #[serde(default, deserialize_with = "optional_seconds")]
+    pub session_duration_seconds: Option<u64>,
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    ["openid", "profile", "email"].map(String::from).to_vec()
+}
+
+fn default_oidc_groups_claim() -> String {
+    "groups".to_string()
+}
+
+/// A bearer token for the agent's HTTP server.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct TokenConfig {
+    /// Name of the token, like the name of the script using it, shown in the audit log.
+    ///
+    /// Tokens without names are identified by their position in this list.
+    pub name: Option<String>,
+
+    /// The token.
+    ///
+    /// Exactly one of `token`, `token_env` or `token_file` must be set.
+    pub token: Option<String>,
+
+    /// Name of an environment variable containing the token.
+    pub token_env: Option<String>,
+
+    /// Path to a file containing the token.
+    pub token_file: Option<String>,
+
+    /// What the token can access; defaults to `read_only`.
+    #[serde(default)]
+    pub access: Access,
+
+    /// Actions the token can take on each project, keyed by project name,
+    ///     with the key `*` applying to projects that aren't listed;
+    ///     e.g., `{staging: [view, deploy], "*": [view]}`.
+    ///
+    /// If set, this replaces `access`: the token can only see and change projects as listed,
+    ///     and otherwise has read-only access.
+    pub projects: Option<ProjectPermissions>,
+}
+
+/// A basic auth user for the agent's HTTP server.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct UserConfig {
+    pub username: String,
+
+    /// The user's password.
+    ///
+    /// Exactly one of `password`, `password_env` or `password_file` must be set.
+    pub password: Option<String>,
+
+    /// Name of an environment variable containing the password.
+    pub password_env: Option<String>,
+
+    /// Path to a file containing the password.
+    pub password_file: Option<String>,
+
+    /// What the user can access; defaults to `read_only`.
+    #[serde(default)]
+    pub access: Access,
+
+    /// Actions the user can take on each project, replacing `access`;
+    ///     see [TokenConfig::projects].
+    pub projects: Option<ProjectPermissions>,
+}
+
+/// What an authenticated token or user can access.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, PartialOrd, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    /// Can view the status page and read data from the API.
+    #[default]
+    ReadOnly,
+    /// Can additionally perform actions, like pausing projects.
+    Admin,
+}
+
+/// Actions allowed on each project, keyed by project name or `*`.
+pub type ProjectPermissions = BTreeMap<String, Vec<Permission>>;
+
+/// An action on a project that a token or user can be allowed to take.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// See the project on the status pages and in the API.
+    View,
+    /// Deploy, pause and resume the project.
+    Deploy,
+    /// Approve or reject redeployments of the project that require approval.
+    Approve,
+    /// Roll back the project.
+    Rollback,
+}
+
+impl AuthConfig {
+    /// Check that the projects that tokens and users are given permissions on exist.
+    pub fn check_projects(&self, projects: &[ProjectConfig]) -> Result<(), String> {
+        let permissions = self
+            .tokens
+            .iter()
+            .filter_map(|token| token.projects.as_ref())
+            .chain(self.users.iter().filter_map(|user| user.projects.as_ref()))
+            .chain(self.oidc.iter().filter_map(|oidc| oidc.projects.as_ref()));
+        for name in permissions.flat_map(|permissions| permissions.keys()) {
+            if name != "*" && !projects.iter().any(|project| project.name == *name) {
+                return Err(format!(
+                    "auth config gives permissions on unknown project {name}"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Configuration for notifications.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct NotificationConfig {
+    /// Send notifications by email.
+    ///
+    /// This and the other notifiers accept either a single config or a list of configs;
+    ///     e.g., to send to two Slack channels with different `notify_on` settings.
+    /// All notifiers are sent to at the same time,
+    ///     and a notifier failing does not affect the others.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub email: Vec<EmailConfig>,
+
+    /// Send notifications to Slack.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub slack: Vec<SlackConfig>,
+
+    /// Send notifications to a webhook.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub webhook: Vec<WebhookConfig>,
+
+    /// Send notifications to an ntfy topic.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub ntfy: Vec<NtfyConfig>,
+
+    /// Kinds of notifications to send; defaults to `[failure, recovery, approval]`.
+    ///
+    /// The kinds are `failure`, `recovery`, `success`, `lifecycle` and `approval`.
+    /// Each notifier can override this.
+    #[serde(default = "default_notify_on")]
+    pub notify_on: Vec<NotifyOn>,
+
+    /// URL of the agent's status page, used to link to the status page from notifications.
+    pub status_page_url: Option<String>,
+
+    /// How often to send reminders about a failure that keeps recurring.
+    ///
+    /// The default is 86400 seconds (one day).
+    ///
+    /// A project failing in the same way repeatedly (e.g., the same step failing for every new
+    ///     commit, or the same error on every poll) only produces one notification,
+    ///     and then reminders at most this often until the project is redeployed successfully
+    ///     or fails in a different way.
+    #[serde(default, deserialize_with = "optional_seconds")]
+    pub realert_interval_seconds: Option<u64>,
+
+    /// How long to keep retrying a notification that could not be sent.
+    ///
+    /// The default is 86400 seconds (one day).
+    ///
+    /// Notifications that fail to send are queued in the database and retried with backoff
+    ///     on subsequent iterations, so they survive transient outages and restarts of the agent.
+    #[serde(default, deserialize_with = "optional_seconds")]
+    pub retry_ttl_seconds: Option<u64>,
+
+    /// Send a reminder when a redeployment has been held for longer than this.
+    ///
+    /// A redeployment is held when there is a new successful CI run that cannot be deployed yet;
+    ///     e.g., because an upstream project in `depends_on` has not redeployed.
+    /// One reminder is sent for each held CI run, as a failure notification.
+    /// By default no reminders are sent.
+    #[serde(default, deserialize_with = "optional_seconds")]
+    pub pending_reminder_seconds: Option<u64>,
+
+    /// Secret used to sign the approve and reject links in approval requests.
+    ///
+    /// Links point to the `/approval` endpoint of the agent's HTTP server,
+    ///     relative to `status_page_url`.
+    /// Anyone with a link can use it, so approval requests should only be sent to trusted channels.
+    pub approval_secret: Option<String>,
+
+    /// Path to a file containing the approval secret, instead of `approval_secret`.
+    pub approval_secret_file: Option<String>,
+
+    /// Custom templates for notifications about redeployments.
+    pub templates: Option<NotificationTemplates>,
+}
+
+/// Custom templates for notifications about redeployments.
+///
+/// Templates are Handlebars templates, the same as the status page.
+/// They are rendered with the result of the redeployment as context;
+///     i.e., the same data as each entry in `run_results` in the status page's JSON.
+/// The context additionally has the following fields:
+///     `kind` (`failure`, `recovery` or `success`),
+///     `failed_step` (the first step that failed, if any)
+///     and `status_page_url`.
+///
+/// Templates are used by all notifiers.
+/// Other notifications, like database write failures, always use the built-in templates.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct NotificationTemplates {
+    /// Path to a template for the subject of notifications.
+    pub subject: Option<String>,
+
+    /// Path to a template for the body of notifications.
+    pub body: Option<String>,
+}
+
+pub fn default_notify_on() -> Vec<NotifyOn> {
+    vec![NotifyOn::Failure, NotifyOn::Recovery, NotifyOn::Approval]
+}
+
+/// A kind of notification.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyOn {
+    /// A redeployment failed, or writes to the database are failing.
+    Failure,
+    /// A redeployment succeeded after the previous redeployment of the project failed.
+    Recovery,
+    /// A redeployment succeeded.
+    ///
+    /// This includes recoveries.
+    Success,
+    /// The agent started or shut down cleanly.
+    ///
+    /// If the agent crashes a failure notification is sent.
+    Lifecycle,
+    /// A redeployment is awaiting approval.
+    Approval,
+}
+
+impl NotifyOn {
+    /// Returns whether a notification of this kind should be sent under the provided policy.
+    pub fn allowed_by(self, policy: &[NotifyOn]) -> bool {
+        match self {
+            NotifyOn::Recovery => {
+                policy.contains(&NotifyOn::Recovery) || policy.contains(&NotifyOn::Success)
+            }
+            _ => policy.contains(&self),
+        }
+    }
+}
+
+/// Configuration for sending emails.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct EmailConfig {
+    /// Address to send emails from.
+    pub from: String,
+
+    /// Addresses to send emails to.
+    ///
+    /// This and the `cc` and `bcc` settings accept either a single address or a list of addresses.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub to: Vec<String>,
+
+    /// Addresses to copy on emails.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub cc: Vec<String>,
+
+    /// Addresses to blind copy on emails.
+    ///
+    /// These are passed to the sendmail command in a `Bcc` header,
+    ///     which `sendmail -t` removes before sending the email.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub bcc: Vec<String>,
+
+    /// Address that replies to emails should go to.
+    pub reply_to: Option<String>,
+
+    /// Sendmail-compatible command used to send emails; defaults to `sendmail -t`.
+    ///
+    /// The full email, including headers, is written to the command's stdin.
+    /// This is not used if `smtp` is set.
+    #[serde(default = "default_sendmail_command")]
+    pub sendmail_command: String,
+
+    /// Send emails directly to an SMTP server instead of using the sendmail command.
+    pub smtp: Option<SmtpConfig>,
+
+    /// Kinds of notifications to send with this notifier.
+    ///
+    /// Defaults to the `notify_on` setting of the notifications config.
+    pub notify_on: Option<Vec<NotifyOn>>,
+}
+
+/// Configuration for sending emails through an SMTP server.
+///
+/// The agent connects to the server when an email is sent,
+///     so the server being unreachable at startup is not an error.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SmtpConfig {
+    /// Hostname of the SMTP server.
+    pub host: String,
+
+    /// Port of the SMTP server.
+    ///
+    /// Defaults to 587 for STARTTLS, 465 for implicit TLS and 25 for no encryption.
+    pub port: Option<u16>,
+
+    /// How the connection to the server is encrypted; defaults to `starttls`.
+    #[serde(default)]
+    pub security: SmtpSecurity,
+
+    /// Username to authenticate with.
+    ///
+    /// If set, a password must be provided with exactly one of
+    ///     `password`, `password_env` or `password_file`.
+    pub username: Option<String>,
+
+    /// Password to authenticate with.
+    pub password: Option<String>,
+
+    /// Name of an environment variable containing the password.
+    pub password_env: Option<String>,
+
+    /// Path to a file containing the password.
+    ///
+    /// Leading and trailing whitespace in the file is ignored.
+    pub password_file: Option<String>,
+
+    /// Timeout for connecting to and communicating with the server; defaults to 10 seconds.
+    #[serde(default, deserialize_with = "optional_seconds")]
+    pub timeout_seconds: Option<u64>,
+
+    /// Accept invalid TLS certificates, such as self-signed certificates.
+    ///
+    /// This makes the connection vulnerable to man-in-the-middle attacks.
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+}
+
+/// How the connection to an SMTP server is encrypted.
+#[derive(Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpSecurity {
+    /// Connect unencrypted and upgrade the connection with STARTTLS.
+    #[default]
+    Starttls,
+    /// Connect with TLS.
+    Tls,
+    /// Don't encrypt the connection.
+    None,
+}
+
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::Deserialize<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+    Ok(match serde::Deserialize::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+/// Deserialize a duration in seconds,
+///     given either as a number of seconds or as a string like `90s`, `10m` or `1h30m`.
+fn seconds<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let seconds: Seconds = serde::Deserialize::deserialize(deserializer)?;
+    Ok(seconds.0)
+}
+
+fn optional_seconds<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let seconds: Option<Seconds> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(seconds.map(|seconds| seconds.0))
+}
+
+struct Seconds(u64);
+
+impl<'de> serde::Deserialize<'de> for Seconds {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct Visitor;
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = Seconds;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a number of seconds or a duration like 10m or 1h30m")
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Seconds, E> {
+                Ok(Seconds(v))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Seconds, E> {
+                match u64::try_from(v) {
+                    Ok(v) => Ok(Seconds(v)),
+                    Err(_) => Err(E::custom(format!("duration {v} is negative"))),
+                }
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Seconds, E> {
+                parse_duration(v).map(Seconds).map_err(E::custom)
+            }
+        }
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// Parse a duration like `90s`, `10m`, `1h30m` or `2d` into a number of seconds.
+///
+/// A number without a unit is a number of seconds.
+fn parse_duration(s: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid duration {s:?}; expected something like 90s, 10m or 1h30m");
+    let mut rest = s.trim();
+    if let Ok(seconds) = rest.parse::<u64>() {
+        return Ok(seconds);
+    }
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    let mut total: u64 = 0;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let number: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = &rest[digits..];
+        let unit_len = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let unit = match &rest[..unit_len] {
+            "s" | "sec" | "secs" => 1,
+            "m" | "min" | "mins" => 60,
+            "h" | "hr" | "hrs" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        rest = rest[unit_len..].trim_start();
+        total = number
+            .checked_mul(unit)
+            .and_then(|seconds| total.checked_add(seconds))
+            .ok_or_else(invalid)?;
+    }
+    Ok(total)
+}
+
+fn default_sendmail_command() -> String {
+    "sendmail -t".to_string()
+}
+
+/// Configuration for sending notifications to a webhook.
+///
+/// Each notification is sent as a POST request with a JSON body containing
+///     `subject`, `body`, `kind` and `success` fields,
+///     where `success` is only true for successful redeployments,
+///     and an `event` field with a structured description of the event.
+/// The `type` field of the event is `redeployment`, `error` (the project could not be checked
+///     for redeployments), `pending` (a redeployment has been held for a long time),
+///     `approval` (a redeployment is awaiting approval),
+///     `database_write_failure`, `github_rate_limit`, `started`, `stopped`, `crashed`
+///     or `test` (sent by `cdagent notify-test`).
+///
+/// The request has a `X-Cdagent-Signature-256` header containing `sha256=`
+///     followed by the hex-encoded HMAC-SHA256 of the body, keyed by the secret.
+/// Receivers should verify this signature before trusting the payload.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct WebhookConfig {
+    /// URL to send notifications to.
+    pub url: String,
+
+    /// Secret used to sign the notifications.
+    ///
+    /// Exactly one of `secret` or `secret_file` must be set.
+    #[serde(default)]
+    pub secret: String,
+
+    /// Path to a file containing the secret.
+    pub secret_file: Option<String>,
+
+    /// Kinds of notifications to send with this notifier.
+    ///
+    /// Defaults to the `notify_on` setting of the notifications config.
+    pub notify_on: Option<Vec<NotifyOn>>,
+}
+
+/// Configuration for publishing notifications to an ntfy topic.
+///
+/// At most one of `access_token`, or `username` and `password`, can be provided.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct NtfyConfig {
+    /// URL of the ntfy server; defaults to `https://ntfy.sh`.
+    #[serde(default = "default_ntfy_server")]
+    pub server: String,
+
+    /// Topic to publish notifications to.
+    pub topic: String,
+
+    /// Access token used to authenticate with the server.
+    pub access_token: Option<String>,
+
+    /// Path to a file containing the access token, instead of `access_token`.
+    pub access_token_file: Option<String>,
+
+    /// Username used to authenticate with the server.
+    pub username: Option<String>,
+
+    /// Password used to authenticate with the server.
+    pub password: Option<String>,
+
+    /// Path to a file containing the password, instead of `password`.
+    pub password_file: Option<String>,
+
+    /// Kinds of notifications to send with this notifier.
+    ///
+    /// Defaults to the `notify_on` setting of the notifications config.
+    pub notify_on: Option<Vec<NotifyOn>>,
+}
+
+fn default_ntfy_server() -> String {
+    "https://ntfy.sh".to_string()
+}
+
+/// Configuration for sending messages to Slack.
+///
+/// Either `webhook_url`, or both `bot_token` and `channel`, must be provided.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SlackConfig {
+    /// URL of an incoming webhook to post messages to.
+    pub webhook_url: Option<String>,
+
+    /// Path to a file containing the webhook URL, instead of `webhook_url`.
+    ///
+    /// The URL contains a secret, so it may need to be kept out of the config file.
+    pub webhook_url_file: Option<String>,
+
+    /// Token of a bot user to post messages as.
+    pub bot_token: Option<String>,
+
+    /// Path to a file containing the bot token, instead of `bot_token`.
+    pub bot_token_file: Option<String>,
+
+    /// Channel for the bot user to post messages in; e.g., `#deployments`.
+    pub channel: Option<String>,
+
+    /// Signing secret of the Slack app, used to verify clicks on approval buttons.
+    ///
+    /// If set, approval requests have interactive Approve and Reject buttons.
+    /// The request URL in the app's interactivity settings must be `/slack/interactions`
+    ///     relative to `status_page_url`.
+    /// Otherwise the buttons open the approve and reject links in the browser.
+    pub signing_secret: Option<String>,
+
+    /// Path to a file containing the signing secret, instead of `signing_secret`.
+    pub signing_secret_file: Option<String>,
+
+    /// Kinds of notifications to send with this notifier.
+    ///
+    /// Defaults to the `notify_on` setting of the notifications config.
+    pub notify_on: Option<Vec<NotifyOn>>,
+}
+
+/// Writing the agent's log to a file, which is rotated as it grows.
+///
+/// When the file is rotated it is renamed to `<file>.1`, the previous `<file>.1` to `<file>.2`,
+///     and so on.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    /// Path to the log file.
+    #[serde(default)]
+    pub file: String,
+
+    /// Rotate the log file once it is larger than this many bytes.
+    ///
+    /// The default is 10485760 (10 MiB), and 0 disables rotating the file by size.
+    pub max_size_bytes: Option<u64>,
+
+    /// Also rotate the log file once it has been written to for this long.
+    ///
+    /// By default the log file is only rotated by size.
+    #[serde(default, deserialize_with = "optional_seconds")]
+    pub rotate_interval_seconds: Option<u64>,
+
+    /// Number of rotated log files to keep.
+    ///
+    /// The default is 5.
+    pub keep: Option<usize>,
+}
+
+/// Updating the agent when a new release of it is published on GitHub.
+///
+/// The agent checks the latest release of the repository, downloads the binary attached to it,
+///     verifies the binary's checksum and replaces its own executable with it.
+/// It then waits until no redeployment is in progress and restarts.
+/// Releases are compared by tag, so the agent must be built with `CDAGENT_GIT_TAG` set
+///     to the tag of its release unless the tags are the version in `Cargo.toml`, like `v0.1.0`.
+///
+/// The GitHub auth token in the `github` settings is used, if there is one.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SelfUpdateConfig {
+    /// Repository to install releases of, like `jamespfennell/cdagent`.
+    ///
+    /// The default is the agent's own repository.
+    pub repo: Option<String>,
+
+    /// Name of the release asset that is the agent's binary, like `cdagent-x86_64-linux`.
+    pub asset: String,
+
+    /// Name of the release asset containing the SHA-256 checksum of the binary,
+    ///     either on its own or in the format written by `sha256sum`.
+    ///
+    /// The default is the name of the binary followed by `.sha256`.
+    /// Releases without the checksum are not installed.
+    pub checksum_asset: Option<String>,
+
+    /// How often to check for a new release.
+    ///
+    /// The default is 3600 seconds (1 hour).
+    #[serde(default, deserialize_with = "optional_seconds")]
+    pub interval_seconds: Option<u64>,
+
+    /// How the agent restarts once it has been updated.
+    #[serde(default)]
+    pub restart: RestartMode,
+}
+
+impl SelfUpdateConfig {
+    /// Returns the repository to install releases of, split into its owner and name.
+    pub fn repo(&self) -> Option<(&str, &str)> {
+        match &self.repo {
+            None => Some(("jamespfennell", "cdagent")),
+            Some(repo) => crate::github::parse_repo(repo),
+        }
+    }
+
+    pub fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(match self.interval_seconds {
+            None | Some(0) => 3600,
+            Some(d) => d,
+        })
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.asset.trim().is_empty() {
+            problems.push("self update: the asset must not be empty".to_string());
+        }
+        if self.repo().is_none() {
+            problems.push(format!(
+                "self update: the repo {:?} must be of the form <owner>/<name>",
+                self.repo.as_deref().unwrap_or_default()
+            ));
+        }
+        problems
+    }
+}
+
+/// How the agent restarts once it has been updated.
+#[derive(Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartMode {
+    /// Replace the agent's process with the new binary, keeping its process ID.
+    #[default]
+    Exec,
+    /// Exit with status 75, for a service manager like systemd to start the new binary.
+    ///
+    /// The service must be configured to restart on failure.
+    Exit,
+}
+
+/// Exporters of the agent's metrics; see the `metrics` module for the metrics recorded.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Serve the metrics on the agent's HTTP server for Prometheus to scrape.
+    ///
+    /// Like the JSON API the endpoint requires read-only access,
+    ///     and metrics about projects the token or user can't view are left out.
+    pub prometheus: Option<PrometheusConfig>,
+
+    /// Send the metrics to a StatsD server or Datadog agent as they are recorded.
+    pub statsd: Option<StatsdConfig>,
+}
+
+impl MetricsConfig {
+    fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if let Some(prometheus) = &self.prometheus {
+            if !prometheus.path().starts_with('/') {
+                problems.push(format!(
+                    "metrics: the Prometheus path {:?} must start with /",
+                    prometheus.path()
+                ));
+            }
+        }
+        if let Some(statsd) = &self.statsd {
+            if statsd.address.trim().is_empty() {
+                problems.push("metrics: the StatsD address must not be empty".to_string());
+            }
+        }
+        problems
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrometheusConfig {
+    /// Path to serve the metrics at; defaults to `/metrics`.
+    pub path: Option<String>,
+}
+
+impl PrometheusConfig {
+    pub fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or("/metrics")
+    }
+}
+
+/// Where to send metrics with StatsD.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatsdConfig {
+    /// Address of the StatsD server or Datadog agent, like `localhost:8125`.
+    ///
+    /// Metrics are sent over UDP.
+    pub address: String,
+
+    /// Prefix of the metric names; defaults to `cdagent`.
+    pub prefix: Option<String>,
+
+    /// Tags to add to every metric, like `env:production`.
+    ///
+    /// Each metric is also tagged with its labels, like `project:<name>`.
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl StatsdConfig {
+    pub fn prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or("cdagent")
+    }
+}
+
+/// Where to export traces of redeployments to.
+///
+/// Each redeployment is a trace with a span for each step and each GitHub API request.
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
-pub struct Config {
-    /// List of projects to run the agent for.
-    pub projects: Vec<ProjectConfig>,
+#[serde(deny_unknown_fields)]
+pub struct TracingConfig {
+    /// URL of the collector's OTLP/HTTP traces endpoint, like `http://localhost:4318/v1/traces`.
+    ///
+    /// Traces are sent in the JSON encoding of OTLP.
+    pub endpoint: String,
 
-    /// How often to poll the GitHub API to check for new successful CI runs.
+    /// Headers to send with each export, like an API key for a hosted tracing backend.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+
+    /// The `service.name` of the traces; defaults to `cdagent`.
+    pub service_name: Option<String>,
+}
+
+impl TracingConfig {
+    pub fn service_name(&self) -> &str {
+        self.service_name.as_deref().unwrap_or("cdagent")
+    }
+}
+
+/// Configuration for the agent's HTTP server.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct HttpConfig {
+    /// Directory to load templates for the status pages from.
+    ///
+    /// Each of the following files in the directory replaces the built-in template of the same name:
+    ///     `status.html` (the main status page),
+    ///     `project.html` (the page of each project),
+    ///     `audit.html` (the audit log)
+    ///     and `head.html` (the start of each page, included in the others as the `head` partial).
+    /// Templates are Handlebars templates, and the built-in templates in the agent's source code
+    ///     are a good starting point.
+    /// Forms that change projects must include the `csrf_token` variable
+    ///     in a hidden input named `csrf_token`, as the built-in project page does.
+    /// Templates are loaded when the agent starts.
+    pub template_dir: Option<String>,
+
+    /// Cross-origin resource sharing (CORS) for the JSON API.
+    ///
+    /// If set, pages on the allowed origins can read `/api/v1/` from the browser,
+    ///     for example to show the state of projects on a dashboard.
+    pub cors: Option<CorsConfig>,
+
+    /// Protection of the endpoints for changing projects, like `POST /api/projects/{name}/deploy`.
+    #[serde(default)]
+    pub actions: ActionsConfig,
+}
+
+/// Restrictions on requests to the endpoints for changing projects.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct ActionsConfig {
+    /// IP addresses, like `192.168.1.10`, or CIDR ranges, like `10.0.0.0/8`,
+    ///     allowed to make requests.
+    ///
+    /// If empty, requests are allowed from any address.
+    /// Addresses are those of the TCP connection;
+    ///     behind a reverse proxy, this is the address of the proxy.
+    #[serde(default)]
+    pub allowed_ips: Vec<String>,
+
+    /// Maximum rate of requests, applied separately to each client IP address
+    ///     and to each token or user.
+    ///
+    /// Requests over the limit are answered with 429 Too Many Requests.
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
+/// A maximum number of requests in a rolling period.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RateLimitConfig {
+    pub requests: usize,
+    #[serde(deserialize_with = "seconds")]
+    pub period_seconds: u64,
+}
+
+/// Configuration for cross-origin requests to the JSON API.
+///
+/// Cross-origin requests still need credentials if authentication is configured;
+///     they can send a bearer token in the `Authorization` header.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct CorsConfig {
+    /// Origins allowed to make requests, like `https://dashboard.example.com`.
+    ///
+    /// The origin `*` allows requests from any origin.
+    pub allowed_origins: Vec<String>,
+
+    /// Methods allowed in cross-origin requests; defaults to `GET`.
+    #[serde(default = "default_cors_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+}
+
+fn default_cors_allowed_methods() -> Vec<String> {
+    vec!["GET".to_string()]
+}
+
+/// Configuration for the database.
+///
+/// The location of the database is provided as a CLI argument.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct DatabaseConfig {
+    /// Whether to make writes to the on-disk database durable; defaults to false.
+    ///
+    /// If true, the database file and its parent directory are fsynced on every write.
+    /// This guarantees the database survives a power loss or OS crash,
+    ///     at the cost of slower writes.
+    #[serde(default)]
+    pub durable_writes: bool,
+
+    /// Store the database in Redis instead of in a file.
+    ///
+    /// This allows agents running in ephemeral containers to persist state
+    ///     without a mounted volume.
+    /// If provided, the database path CLI argument must not be provided.
+    pub redis: Option<RedisConfig>,
+
+    /// Periodically dump the in-memory database to a file, and load it from the file on startup.
+    ///
+    /// This is a middle ground between an in-memory database, whose state is lost on restart,
+    ///     and an on-disk database, which is written on every change.
+    /// Only valid if neither a database path nor Redis is provided.
+    pub snapshot: Option<SnapshotConfig>,
+
+    /// The format of the on-disk database; defaults to `snapshot`.
+    #[serde(default)]
+    pub format: DatabaseFormat,
+
+    /// How often to write changes to the database.
+    ///
+    /// By default every change is written immediately.
+    /// If provided, changes are buffered in memory and written in a single batch
+    ///     at most this often, and when the agent shuts down.
+    /// This reduces disk writes for agents with many projects,
+    ///     at the cost of losing recent changes if the agent crashes.
+    #[serde(default, deserialize_with = "optional_seconds")]
+    pub flush_interval_seconds: Option<u64>,
+
+    /// Periodically back up the database.
+    ///
+    /// By default no backups are made.
+    pub backup: Option<BackupConfig>,
+}
+
+/// Format of the on-disk database.
+#[derive(Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DatabaseFormat {
+    /// The database is a single JSON object that is rewritten in full on every change.
+    ///
+    /// This format is easy to inspect and edit by hand.
+    #[default]
+    Snapshot,
+
+    /// The database is an append-only journal of changes that is periodically compacted.
+    ///
+    /// Each change only appends to the file, which is much cheaper for large databases.
+    /// A database in the snapshot format is automatically converted to a journal when opened.
+    /// To convert back, use `cdagent db export` and `cdagent db import`.
+    Journal,
+}
+
+/// Configuration for dumping the in-memory database to a file.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SnapshotConfig {
+    /// Path to the snapshot file.
+    ///
+    /// The file has the same format as an on-disk database in the `snapshot` format.
+    pub path: String,
+
+    /// How often to dump the database to the file.
     ///
     /// The default is 300 seconds (5 minutes).
+    /// The database is also dumped when the agent shuts down.
+    #[serde(default, deserialize_with = "optional_seconds")]
+    pub interval_seconds: Option<u64>,
+}
+
+/// Configuration for storing the database in Redis.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RedisConfig {
+    /// URL of the Redis server; e.g., `redis://127.0.0.1:6379/0`.
+    pub url: String,
+
+    /// Prefix added to all keys written to Redis; defaults to `cdagent/`.
     ///
-    /// With a smaller number, the agent will notice new pushes faster.
-    /// However, GitHub imposes rate limits on API requests.
-    /// If this number is too small and too many requests are made,
-    ///     these rate limits may be reached.
-    /// If this happens the agent with stop polling GitHub until the cooling off period elapses.
-    /// The cooling off period is up to one hour.
+    /// Multiple agents can share a Redis server by using different prefixes.
+    #[serde(default = "default_redis_key_prefix")]
+    pub key_prefix: String,
+}
+
+fn default_redis_key_prefix() -> String {
+    "cdagent/".to_string()
+}
+
+/// Configuration for periodic database backups.
+///
+/// Each backup is a JSON export of the database written to a file named
+///     `<database file name>.bak.<timestamp>`.
+/// The export can be restored using `cdagent db import`.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct BackupConfig {
+    /// Directory to write backups to.
     ///
-    /// The rate limits are 60 non-cached requests per-hour if no auth token is provided,
-    ///     or 5000 non-cached requests per-hour per-GitHub-user if an auth token is provided.
-    /// Note that if there is no new information from the API (i.e., no new CI runs on mainline),
-    ///     GitHub returns a cached response that does not count towards the limit.
-    pub poll_interval_seconds: Option<u64>,
+    /// Defaults to the directory containing the database file.
+    /// Must be provided if the database is in-memory.
+    pub directory: Option<String>,
+
+    /// How often to back up the database.
+    ///
+    /// The default is 86400 seconds (one day).
+    #[serde(default, deserialize_with = "optional_seconds")]
+    pub interval_seconds: Option<u64>,
+
+    /// Number of backups to keep.
+    ///
+    /// The default is 7.
+    pub keep: Option<usize>,
 }
 
 /// A project to run the agent for.
@@ -30,18 +1414,36 @@ pub struct Config {
 /// Whenever there is a new successful CI run on the specified GitHub repository branch,
 ///     the agent will run the specified command.
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ProjectConfig {
     /// Name of the project. Used for debugging.
     pub name: String,
 
+    /// Name of a template in `templates` to inherit settings from.
+    pub extends: Option<String>,
+
     /// If the project is paused; defaults to false.
     #[serde(default)]
     pub paused: bool,
 
+    /// Require each redeployment to be approved; defaults to false.
+    ///
+    /// When there is a new successful CI run, an approval request is sent with links
+    ///     to approve or reject the redeployment, and the redeployment is held until one is used.
+    /// This requires `approval_secret` and `status_page_url` to be set in the notifications config.
+    #[serde(default)]
+    pub require_approval: bool,
+
     /// Name of GitHub user that owns the GitHub repository.
+    ///
+    /// This can be omitted if `repo` includes the owner.
+    #[serde(default)]
     pub github_user: String,
 
     /// Name of the GitHub repository.
+    ///
+    /// This can also include the owner, as `owner/name`, a URL like `https://github.com/owner/name.git`
+    ///     or an SSH remote like `git@github.com:owner/name.git`.
     pub repo: String,
 
     /// Mainline branch which will be watched for new successful CI runs.
@@ -57,33 +1459,1092 @@ pub struct ProjectConfig {
     ///
     /// If provided, the auth token must have GitHub actions read permission
     ///     on the repository.
-    #[serde(default)]
+    /// If not set, the auth token in `defaults` or `github` is used.
+    ///
+    /// The auth token is never serialized, so that it doesn't appear on the status pages,
+    ///     in notifications or in the database.
+    #[serde(default, skip_serializing)]
     pub auth_token: String,
 
+    /// Path to a file containing the auth token, instead of `auth_token`.
+    pub auth_token_file: Option<String>,
+
     /// Working directory in which to run the redeployment steps.
     ///
     /// Defaults to the working directory of the cdagent invocation.
+    /// Ignored if managed release directories are used.
     pub working_directory: Option<String>,
 
+    /// Use managed release directories.
+    ///
+    /// If provided, each redeployment is performed in its own release directory
+    ///     `<root>/releases/<sha>`, and once the steps succeed the `<root>/current` symlink
+    ///     is switched to point at the new release and the restart steps are run.
+    /// If the restart steps fail, the symlink is switched back to the previous release
+    ///     and the restart steps are run again.
+    pub releases: Option<Releases>,
+
     /// Steps to perform during a redeployment.
     #[serde(default)]
     pub steps: Vec<Step>,
 
+    /// Verification to perform on the artifacts before redeploying.
+    ///
+    /// If any verification fails, the redeployment fails without running any steps.
+    pub verify: Option<Verification>,
+
+    /// Canary configuration for the redeployment.
+    ///
+    /// If provided, the redeployment uses a canary strategy.
+    /// The canary steps are run first, and then the agent waits for the bake time
+    ///     while periodically running the health checks.
+    /// If the canary steps and all of the health checks succeed,
+    ///     the agent proceeds with the regular steps.
+    /// Otherwise the rollback steps are run and the redeployment is marked as failed.
+    pub canary: Option<Canary>,
+
+    /// Names of other projects that this project depends on.
+    ///
+    /// When there is a new successful CI run, the project is only redeployed once each of
+    ///     these upstream projects has successfully redeployed its own latest CI run.
+    /// If an upstream project watches the same repository and branch,
+    ///     it must additionally have redeployed the same or a newer CI run.
+    /// Projects are redeployed in dependency order, so upstream projects deployed
+    ///     in the same poll unblock their downstream projects immediately.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+
+    /// Promote deployments from another project instead of watching GitHub directly.
+    ///
+    /// If provided, this project is not triggered by new CI runs on GitHub.
+    /// Instead, whenever the source project (e.g. a staging environment) successfully redeploys,
+    ///     this project (e.g. a production environment) redeploys the same CI run
+    ///     once the soak time has elapsed.
+    /// The GitHub settings of this project are then only used on the status page.
+    pub promote_from: Option<Promotion>,
+
     /// Number of prior deployments to retain in the internal database and show on
     /// the HTML status page.
-    #[serde(default="ten")]
-    pub retention: usize,
+    ///
+    /// Defaults to 10.
+    pub retention: Option<usize>,
+
+    /// Number of days to retain prior deployments for.
+    ///
+    /// Deployments older than this are removed from the internal database and the
+    ///     HTML status page, even if there are fewer than `retention` deployments.
+    /// By default deployments are only limited by number.
+    pub retention_days: Option<u64>,
+
+    /// Kinds of notifications to send about this project.
+    ///
+    /// By default all kinds of notifications are sent,
+    ///     subject to the `notify_on` setting of each notifier.
+    /// This can be used to silence routine notifications for a noisy project.
+    pub notify_on: Option<Vec<NotifyOn>>,
+
+    /// Notifiers for notifications about this project, like those of the team that owns it.
+    ///
+    /// These contain secrets, so they are never serialized.
+    #[serde(default, skip_serializing)]
+    pub notifications: Option<ProjectNotifications>,
+
+    /// URL of the agent's status page to link to from notifications about this project,
+    ///     in place of the `status_page_url` of the notifications config;
+    ///     e.g., if the project's team reaches the agent through a different hostname.
+    pub status_page_url: Option<String>,
+}
+
+/// Notifiers for notifications about a project.
+///
+/// Each notifier is configured the same way as in the notifications config,
+///     and its `notify_on` defaults to the `notify_on` setting of the notifications config.
+/// Notifications about the agent as a whole are not sent with these notifiers.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ProjectNotifications {
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub email: Vec<EmailConfig>,
+
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub slack: Vec<SlackConfig>,
+
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub webhook: Vec<WebhookConfig>,
+
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub ntfy: Vec<NtfyConfig>,
+
+    /// Also send notifications about the project with the notifiers of the notifications config.
+    ///
+    /// By default the project's notifiers replace them.
+    #[serde(default)]
+    pub inherit: bool,
+}
+
+/// Configuration for verifying the artifacts being deployed.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Verification {
+    /// Path to a checksum file in the format output by `sha256sum`.
+    ///
+    /// Every file listed in the checksum file must exist and have the listed SHA-256 checksum.
+    /// Relative paths are resolved against the working directory,
+    ///     or the release root if managed release directories are used,
+    ///     as verification happens before the release directory is created.
+    pub checksum_file: Option<String>,
+
+    /// Verify the signature of a container image using cosign.
+    ///
+    /// This requires the `cosign` binary to be installed on the host.
+    pub cosign: Option<CosignVerification>,
+
+    /// Require that the head commit of the CI run is signed and verified by GitHub.
+    ///
+    /// Both GPG and SSH signatures are supported; the check uses the
+    ///     `verification` field of the GitHub commits API.
+    #[serde(default)]
+    pub signed_commit: bool,
+}
+
+/// Configuration for verifying a container image signature using cosign.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct CosignVerification {
+    /// Reference of the image to verify; e.g., `jamespfennell/transiter:latest`.
+    pub image: String,
+
+    /// Path to the public key to verify the signature with.
+    ///
+    /// If not provided, keyless verification is used and both
+    ///     the certificate identity and the certificate OIDC issuer must be provided.
+    pub key: Option<String>,
+
+    /// Expected identity in the signing certificate, for keyless verification.
+    pub certificate_identity: Option<String>,
+
+    /// Expected OIDC issuer in the signing certificate, for keyless verification.
+    pub certificate_oidc_issuer: Option<String>,
+}
+
+impl CosignVerification {
+    /// Arguments to pass to the `cosign` binary.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec!["verify".to_string()];
+        for (flag, value) in [
+            ("--key", &self.key),
+            ("--certificate-identity", &self.certificate_identity),
+            ("--certificate-oidc-issuer", &self.certificate_oidc_issuer),
+        ] {
+            if let Some(value) = value {
+                args.push(flag.to_string());
+                args.push(value.clone());
+            }
+        }
+        args.push(self.image.clone());
+        args
+    }
+}
+
+/// Configuration for a canary redeployment.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Canary {
+    /// Steps to deploy the canary.
+    pub steps: Vec<Step>,
+
+    /// How long to wait after deploying the canary before proceeding with the regular steps.
+    #[serde(default, deserialize_with = "seconds")]
+    pub bake_time_seconds: u64,
+
+    /// Health checks to run during the bake time.
+    ///
+    /// The health checks are run at the start of the bake time, periodically during it,
+    ///     and once more at the end.
+    /// If any of them fail, the canary is rolled back.
+    #[serde(default)]
+    pub health_checks: Vec<Step>,
+
+    /// How often to run the health checks during the bake time.
+    ///
+    /// The default is 30 seconds.
+    #[serde(default, deserialize_with = "optional_seconds")]
+    pub health_check_interval_seconds: Option<u64>,
+
+    /// Steps to run in order to roll back the canary.
+    #[serde(default)]
+    pub rollback_steps: Vec<Step>,
+}
+
+/// Configuration for managed release directories.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Releases {
+    /// Directory containing the `releases` directory and the `current` symlink.
+    pub root: String,
+
+    /// Number of releases to keep, including the current release.
+    ///
+    /// The default is 5.
+    pub keep: Option<usize>,
+
+    /// Steps to run after the `current` symlink has been switched to a new release.
+    ///
+    /// These steps run in the `current` directory and generally restart the service.
+    #[serde(default)]
+    pub restart: Vec<Step>,
+}
+
+/// Configuration for promoting deployments from another project.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Promotion {
+    /// Name of the project to promote deployments from.
+    pub project: String,
+
+    /// How long a deployment of the source project must have been live before it is promoted.
+    #[serde(default, deserialize_with = "seconds")]
+    pub soak_time_seconds: u64,
+
+    /// Health checks to run before promoting.
+    ///
+    /// These run as the first steps of the promoted redeployment.
+    /// If any of them fail, the redeployment fails and the CI run is not promoted.
+    #[serde(default)]
+    pub health_checks: Vec<Step>,
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Step {
     /// Name of the step.
     pub name: String,
 
     /// Command to run.
     pub run: String,
+
+    /// Shell to run the command with.
+    ///
+    /// By default, on Unix the command is split into a program and its arguments
+    ///     the way a shell would split it, but without running a shell,
+    ///     and on Windows the command is run with `cmd /C`.
+    pub shell: Option<Shell>,
+
+    /// Fail the step if the command produces no output for this many seconds.
+    ///
+    /// When this happens the command is assumed to be hung and is killed.
+    /// This is not a limit on the total running time of the step:
+    ///     a command that keeps producing output can run indefinitely.
+    /// Only the command itself is killed, so commands wrapped in a shell
+    ///     should use `exec` for the kill to reach the underlying program.
+    #[serde(default, deserialize_with = "optional_seconds")]
+    pub idle_timeout_seconds: Option<u64>,
+}
+
+/// A shell to run a step's command with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Shell {
+    /// Run the program directly, splitting the command into the program and its arguments.
+    None,
+    /// `sh -c`.
+    Sh,
+    /// `cmd /C`, on Windows.
+    Cmd,
+    /// `powershell -Command` on Windows, and `pwsh -Command` elsewhere.
+    Powershell,
+}
+
+impl Shell {
+    /// Returns the shell used when a step doesn't set one.
+    pub fn default_for_platform() -> Self {
+        if cfg!(windows) {
+            Shell::Cmd
+        } else {
+            Shell::None
+        }
+    }
+}
+
+impl ProjectConfig {
+    /// Returns the number of prior deployments to retain, applying the default.
+    pub fn retention(&self) -> usize {
+        self.retention.unwrap_or(10)
+    }
+
+    /// Names of the projects that this project needs to run after.
+    pub fn upstreams(&self) -> impl Iterator<Item = &String> {
+        self.depends_on
+            .iter()
+            .chain(self.promote_from.iter().map(|p| &p.project))
+    }
+
+    /// Returns the problems with the project's config,
+    ///     other than those that depend on the other projects.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let name = match self.name.trim() {
+            "" => "with no name",
+            _ => &self.name,
+        };
+        let restart_steps = self.releases.iter().flat_map(|r| &r.restart);
+        let canary_steps = self.canary.iter().flat_map(|c| &c.steps);
+        if self.steps.is_empty() && restart_steps.chain(canary_steps).next().is_none() {
+            problems.push(format!("project {name}: there are no steps to run"));
+        }
+        if self.retention == Some(0) {
+            problems.push(format!("project {name}: retention must be positive"));
+        }
+        match crate::github::parse_repo(&self.repo) {
+            None if self.repo.contains(['/', ':']) => problems.push(format!(
+                "project {name}: invalid repository {:?}",
+                self.repo
+            )),
+            None if self.github_user.is_empty() => problems.push(format!(
+                "project {name}: github_user must be set unless the repository includes its owner"
+            )),
+            Some((owner, _)) if !self.github_user.is_empty() && self.github_user != owner => {
+                problems.push(format!(
+                    "project {name}: github_user {} doesn't match the owner of repository {}",
+                    self.github_user, self.repo
+                ))
+            }
+            _ => {}
+        }
+        problems
+    }
+
+    /// Read the project's secrets that are provided in files.
+    pub fn read_secret_files(&mut self) -> Result<(), String> {
+        let mut auth_token =
+            Some(std::mem::take(&mut self.auth_token)).filter(|auth_token| !auth_token.is_empty());
+        read_secret_file(
+            &format!("auth token of project {}", self.name),
+            &mut auth_token,
+            &self.auth_token_file,
+        )?;
+        self.auth_token = auth_token.unwrap_or_default();
+        if let Some(notifications) = &mut self.notifications {
+            read_notifier_secret_files(
+                &mut notifications.webhook,
+                &mut notifications.ntfy,
+                &mut notifications.slack,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Replace the values of the project's secrets, returning whether it has any.
+    pub fn redact_secrets(&mut self) -> bool {
+        let mut redacted = redact_string(&mut self.auth_token);
+        if let Some(notifications) = &mut self.notifications {
+            redacted |= redact_notifier_secrets(
+                &mut notifications.email,
+                &mut notifications.webhook,
+                &mut notifications.ntfy,
+                &mut notifications.slack,
+            );
+        }
+        redacted
+    }
+}
+
+/// What the values of secrets are replaced with when they are logged.
+const REDACTED: &str = "<redacted>";
+
+/// Replace the secret, if it is set, returning whether it was.
+fn redact(value: &mut Option<String>) -> bool {
+    match value {
+        None => false,
+        Some(value) => redact_string(value),
+    }
+}
+
+fn redact_string(value: &mut String) -> bool {
+    if value.is_empty() {
+        return false;
+    }
+    *value = REDACTED.to_string();
+    true
+}
+
+/// Replace the values of the secrets of notifiers, returning whether there were any.
+fn redact_notifier_secrets(
+    emails: &mut [EmailConfig],
+    webhooks: &mut [WebhookConfig],
+    ntfys: &mut [NtfyConfig],
+    slacks: &mut [SlackConfig],
+) -> bool {
+    let mut redacted = false;
+    for smtp in emails.iter_mut().filter_map(|email| email.smtp.as_mut()) {
+        redacted |= redact(&mut smtp.password);
+    }
+    for webhook in webhooks {
+        redacted |= redact_string(&mut webhook.secret);
+    }
+    for ntfy in ntfys {
+        redacted |= redact(&mut ntfy.access_token);
+        redacted |= redact(&mut ntfy.password);
+    }
+    for slack in slacks {
+        redacted |= redact(&mut slack.webhook_url);
+        redacted |= redact(&mut slack.bot_token);
+        redacted |= redact(&mut slack.signing_secret);
+    }
+    redacted
+}
+
+/// Read the secrets of notifiers that are provided in files.
+fn read_notifier_secret_files(
+    webhooks: &mut [WebhookConfig],
+    ntfys: &mut [NtfyConfig],
+    slacks: &mut [SlackConfig],
+) -> Result<(), String> {
+    for webhook in webhooks {
+        let mut secret =
+            Some(std::mem::take(&mut webhook.secret)).filter(|secret| !secret.is_empty());
+        let what = format!("secret of webhook {}", webhook.url);
+        read_secret_file(&what, &mut secret, &webhook.secret_file)?;
+        match secret {
+            None => return Err(format!("the {what} must be set")),
+            Some(secret) => webhook.secret = secret,
+        }
+    }
+    for ntfy in ntfys {
+        let what = format!("of ntfy topic {}", ntfy.topic);
+        read_secret_file(
+            &format!("access token {what}"),
+            &mut ntfy.access_token,
+            &ntfy.access_token_file,
+        )?;
+        read_secret_file(
+            &format!("password {what}"),
+            &mut ntfy.password,
+            &ntfy.password_file,
+        )?;
+    }
+    for slack in slacks {
+        read_secret_file(
+            "Slack webhook URL",
+            &mut slack.webhook_url,
+            &slack.webhook_url_file,
+        )?;
+        read_secret_file(
+            "Slack bot token",
+            &mut slack.bot_token,
+            &slack.bot_token_file,
+        )?;
+        read_secret_file(
+            "Slack signing secret",
+            &mut slack.signing_secret,
+            &slack.signing_secret_file,
+        )?;
+    }
+    Ok(())
+}
+
+/// Read the secret from the file, if a file is provided.
+fn read_secret_file(
+    what: &str,
+    value: &mut Option<String>,
+    file: &Option<String>,
+) -> Result<(), String> {
+    let Some(path) = file else {
+        return Ok(());
+    };
+    if value.is_some() {
+        return Err(format!(
+            "the {what} and a file containing it can't both be set"
+        ));
+    }
+    match std::fs::read_to_string(path) {
+        Ok(secret) => {
+            *value = Some(secret.trim().to_string());
+            Ok(())
+        }
+        Err(err) => Err(format!("failed to read the {what} from file {path}: {err}")),
+    }
+}
+
+/// Read config files and the files they include, and all of the config files in directories,
+///     merged into one YAML value.
+///
+/// Each path can contain `*` and `?` wildcards in its file name, and must match at least one file.
+/// Projects from all of the files are combined as with `include`.
+///
+/// Also returns where each of the projects in the merged config is defined.
+pub fn read_yaml(paths: &[String]) -> Result<(serde_yaml::Value, Vec<Source>), String> {
+    let mut config = serde_yaml::Value::Mapping(Default::default());
+    let mut sources = vec![];
+    for pattern in paths {
+        let paths = expand_wildcards(std::path::Path::new(pattern))?;
+        if paths.is_empty() {
+            return Err(format!("no configuration files match {pattern}"));
+        }
+        for path in paths {
+            let files = if path.is_dir() {
+                list_files(&path, |name| {
+                    name.ends_with(".yml") || name.ends_with(".yaml")
+                })?
+            } else {
+                vec![path]
+            };
+            for file in files {
+                let (fragment, fragment_sources) = read_yaml_file(&file, true)?;
+                merge_yaml(&mut config, fragment, &file)?;
+                sources.extend(fragment_sources);
+            }
+        }
+    }
+    Ok((config, sources))
+}
+
+/// Where a project is defined in the config files.
+pub struct Source {
+    path: std::path::PathBuf,
+    /// The position of the project in the file's list of projects.
+    index: usize,
+}
+
+impl Source {
+    fn new(path: &std::path::Path, config: &serde_yaml::Value) -> Vec<Self> {
+        let num_projects = config
+            .get("projects")
+            .and_then(serde_yaml::Value::as_sequence)
+            .map_or(0, Vec::len);
+        (0..num_projects)
+            .map(|index| Self {
+                path: path.to_path_buf(),
+                index,
+            })
+            .collect()
+    }
+
+    /// Returns the file, line and column where the project is defined, like `config.yml:12:3`.
+    ///
+    /// The line and column are omitted if they can't be determined.
+    pub fn location(&self) -> String {
+        let display = self.path.display();
+        let Ok(config_file) = std::fs::read_to_string(&self.path) else {
+            return display.to_string();
+        };
+        // Deserializing the project fails, and the error has the project's location.
+        let deserializer = serde_yaml::Deserializer::from_str(&config_file);
+        let err =
+            match serde::de::DeserializeSeed::deserialize(locate::Config(self.index), deserializer)
+            {
+                Ok(()) => return display.to_string(),
+                Err(err) => err,
+            };
+        match err.location() {
+            None => display.to_string(),
+            Some(location) => format!("{display}:{}:{}", location.line(), location.column()),
+        }
+    }
+}
+
+/// Deserializers that fail at a project in a config file, to find where it is.
+mod locate {
+    use serde::de::{self, IgnoredAny};
+    use std::fmt;
+
+    /// Fails at the project at the index of the config's list of projects.
+    pub struct Config(pub usize);
+
+    impl<'de> de::DeserializeSeed<'de> for Config {
+        type Value = ();
+
+        fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+            deserializer.deserialize_map(self)
+        }
+    }
+
+    impl<'de> de::Visitor<'de> for Config {
+        type Value = ();
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a config")
+        }
+
+        fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+            while let Some(key) = map.next_key::<String>()? {
+                if key == "projects" {
+                    map.next_value_seed(Projects(self.0))?;
+                } else {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    struct Projects(usize);
+
+    impl<'de> de::DeserializeSeed<'de> for Projects {
+        type Value = ();
+
+        fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+            deserializer.deserialize_seq(self)
+        }
+    }
+
+    impl<'de> de::Visitor<'de> for Projects {
+        type Value = ();
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a list of projects")
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+            for _ in 0..self.0 {
+                seq.next_element::<IgnoredAny>()?;
+            }
+            seq.next_element_seed(Project)?;
+            Ok(())
+        }
+    }
+
+    struct Project;
+
+    impl<'de> de::DeserializeSeed<'de> for Project {
+        type Value = ();
+
+        fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+            deserializer.deserialize_any(self)
+        }
+    }
+
+    impl<'de> de::Visitor<'de> for Project {
+        type Value = ();
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a project")
+        }
+
+        fn visit_map<A: de::MapAccess<'de>>(self, _: A) -> Result<(), A::Error> {
+            Err(de::Error::custom("found the project"))
+        }
+    }
+}
+
+fn read_yaml_file(
+    path: &std::path::Path,
+    allow_include: bool,
+) -> Result<(serde_yaml::Value, Vec<Source>), String> {
+    let display = path.display();
+    let config_file = match std::fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(err) => {
+            return Err(format!(
+                "failed to read configuration file {display}: {err}"
+            ))
+        }
+    };
+    let mut config: serde_yaml::Value = match serde_yaml::from_str(&config_file) {
+        Ok(config) => config,
+        Err(err) => {
+            return Err(format!(
+                "failed to parse YAML configuration file {display}: {err}"
+            ))
+        }
+    };
+    let mut sources = Source::new(path, &config);
+    let include = match config.as_mapping_mut() {
+        None => None,
+        Some(mapping) => mapping.remove("include"),
+    };
+    let Some(include) = include else {
+        return Ok((config, sources));
+    };
+    if !allow_include {
+        return Err(format!(
+            "{display}: included files can't include other files"
+        ));
+    }
+    let patterns: Vec<String> = match serde_yaml::from_value(include) {
+        Ok(patterns) => patterns,
+        Err(_) => return Err(format!("{display}: include must be a list of paths")),
+    };
+    let dir = path.parent().unwrap_or(std::path::Path::new(""));
+    for pattern in patterns {
+        for file in expand_wildcards(&dir.join(pattern))? {
+            let (fragment, fragment_sources) = read_yaml_file(&file, false)?;
+            merge_yaml(&mut config, fragment, &file)?;
+            sources.extend(fragment_sources);
+        }
+    }
+    Ok((config, sources))
+}
+
+/// Returns the files matching the path if its file name contains wildcards,
+///     and otherwise the path itself.
+fn expand_wildcards(pattern: &std::path::Path) -> Result<Vec<std::path::PathBuf>, String> {
+    let file_pattern = pattern.file_name().and_then(|name| name.to_str());
+    match (pattern.parent(), file_pattern) {
+        (Some(parent), Some(file_pattern)) if file_pattern.contains(['*', '?']) => {
+            let parent = match parent.as_os_str().is_empty() {
+                true => std::path::Path::new("."),
+                false => parent,
+            };
+            list_files(parent, |name| {
+                wildcard_match(file_pattern.as_bytes(), name.as_bytes())
+            })
+        }
+        _ => Ok(vec![pattern.to_path_buf()]),
+    }
+}
+
+/// Returns the files in the directory whose names match, in name order.
+fn list_files(
+    dir: &std::path::Path,
+    matches: impl Fn(&str) -> bool,
+) -> Result<Vec<std::path::PathBuf>, String> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => return Err(format!("failed to read directory {}: {err}", dir.display())),
+    };
+    let mut files: Vec<std::path::PathBuf> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_str().is_some_and(&matches))
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Returns whether the name matches the pattern, in which `*` matches any sequence of characters
+///     and `?` matches any one character.
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| wildcard_match(rest, &name[i..])),
+        Some((b'?', rest)) => !name.is_empty() && wildcard_match(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && wildcard_match(rest, &name[1..]),
+    }
+}
+
+/// Merge the settings in a config file into the config so far.
+fn merge_yaml(
+    config: &mut serde_yaml::Value,
+    fragment: serde_yaml::Value,
+    path: &std::path::Path,
+) -> Result<(), String> {
+    use serde_yaml::Value;
+    let display = path.display();
+    let fragment = match fragment {
+        // An empty file.
+        Value::Null => return Ok(()),
+        Value::Mapping(fragment) => fragment,
+        _ => return Err(format!("{display}: expected a map of config settings")),
+    };
+    let Value::Mapping(config) = config else {
+        return Err("expected a map of config settings".to_string());
+    };
+    for (key, value) in fragment {
+        match (config.get_mut(&key), value) {
+            (None, value) => {
+                config.insert(key, value);
+            }
+            (Some(Value::Sequence(projects)), Value::Sequence(more)) if key == "projects" => {
+                projects.extend(more);
+            }
+            (Some(Value::Mapping(templates)), Value::Mapping(more)) if key == "templates" => {
+                for (name, template) in more {
+                    if templates.contains_key(&name) {
+                        return Err(format!(
+                            "{display}: template {} is defined in more than one file",
+                            name.as_str().unwrap_or_default()
+                        ));
+                    }
+                    templates.insert(name, template);
+                }
+            }
+            (Some(_), _) => {
+                return Err(format!(
+                    "{display}: {} is set in more than one file",
+                    key.as_str().unwrap_or_default()
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Merge the templates in a parsed config file into the projects that extend them.
+pub fn apply_templates(config: &mut serde_yaml::Value) -> Result<(), String> {
+    use serde_yaml::Value;
+    let templates = match config.get("templates") {
+        None => serde_yaml::Mapping::new(),
+        Some(Value::Mapping(templates)) => templates.clone(),
+        Some(_) => return Err("templates must be a map from names to project settings".to_string()),
+    };
+    let Some(Value::Sequence(projects)) = config.get_mut("projects") else {
+        return Ok(());
+    };
+    for project in projects {
+        let Value::Mapping(project) = project else {
+            continue;
+        };
+        let name = match project.get("name") {
+            Some(Value::String(name)) => name.clone(),
+            _ => "with no name".to_string(),
+        };
+        let extends = project.get("extends").cloned();
+        let mut extended = vec![];
+        while let Some(template_name) = project.remove("extends") {
+            let Value::String(template_name) = template_name else {
+                return Err(format!(
+                    "project {name}: extends must be the name of a template"
+                ));
+            };
+            if extended.contains(&template_name) {
+                return Err(format!(
+                    "project {name}: template {template_name} extends itself"
+                ));
+            }
+            let Some(Value::Mapping(template)) = templates.get(&template_name) else {
+                return Err(format!("project {name}: unknown template {template_name}"));
+            };
+            for (key, value) in template {
+                if !project.contains_key(key) {
+                    project.insert(key.clone(), value.clone());
+                }
+            }
+            extended.push(template_name);
+        }
+        if let Some(extends) = extends {
+            project.insert("extends".into(), extends);
+        }
+    }
+    Ok(())
+}
+
+/// Add a suggestion to an error about an unknown field in the config,
+///     if the field looks like a misspelling of a known field.
+pub fn suggest_field(err: &str) -> String {
+    let Some(rest) = err
+        .strip_prefix("unknown field ")
+        .or_else(|| err.split_once(": unknown field ").map(|(_, rest)| rest))
+    else {
+        return err.to_string();
+    };
+    // The error is like "unknown field `nmae`, expected one of `name`, `paused`, ...".
+    let mut fields = rest.split('`').skip(1).step_by(2);
+    let Some(unknown) = fields.next() else {
+        return err.to_string();
+    };
+    let suggestion = fields
+        .map(|field| (edit_distance(unknown, field), field))
+        .filter(|(distance, field)| *distance <= (field.len() / 3).max(2))
+        .min();
+    match suggestion {
+        None => err.to_string(),
+        Some((_, field)) => format!("{err}; did you mean `{field}`?"),
+    }
+}
+
+/// Returns the number of single character insertions, deletions and substitutions
+///     needed to change one string into the other.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Expand references to environment variables in the string values of a parsed config file.
+///
+/// `${VAR}` is replaced by the value of the environment variable `VAR`,
+///     and `${VAR:-default}` by `default` if `VAR` is unset or empty.
+/// `$$` is replaced by a single `$`, so `$${VAR}` is left as the literal `${VAR}`;
+///     e.g., for shell variables in steps.
+/// Other uses of `$` are left as they are.
+///
+/// Values are expanded after the YAML is parsed,
+///     so the value of a variable can't change the structure of the config.
+pub fn expand_env_vars(value: &mut serde_yaml::Value) -> Result<(), String> {
+    match value {
+        serde_yaml::Value::String(s) => *s = expand(s)?,
+        serde_yaml::Value::Sequence(values) => {
+            for value in values {
+                expand_env_vars(value)?;
+            }
+        }
+        serde_yaml::Value::Mapping(mapping) => {
+            for (_, value) in mapping.iter_mut() {
+                expand_env_vars(value)?;
+            }
+        }
+        serde_yaml::Value::Tagged(tagged) => expand_env_vars(&mut tagged.value)?,
+        serde_yaml::Value::Null | serde_yaml::Value::Bool(_) | serde_yaml::Value::Number(_) => {}
+    }
+    Ok(())
+}
+
+fn expand(s: &str) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        expanded.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        let Some(reference) = rest.strip_prefix("${") else {
+            expanded.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let Some(end) = reference.find('}') else {
+            return Err(format!(
+                "unterminated environment variable reference in {s:?}"
+            ));
+        };
+        let (name, default) = match reference[..end].split_once(":-") {
+            None => (&reference[..end], None),
+            Some((name, default)) => (name, Some(default)),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!(
+                "invalid environment variable name {name:?} in {s:?}"
+            ));
+        }
+        let value = match (std::env::var(name), default) {
+            (Ok(value), Some(default)) if value.is_empty() => default.to_string(),
+            (Ok(value), _) => value,
+            (Err(_), Some(default)) => default.to_string(),
+            (Err(std::env::VarError::NotPresent), None) => {
+                return Err(format!("environment variable {name} is not set"))
+            }
+            (Err(err), None) => {
+                return Err(format!("failed to read environment variable {name}: {err}"))
+            }
+        };
+        expanded.push_str(&value);
+        rest = &reference[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Apply an override from the command line, like `projects.myapp.paused=true`, to a parsed config file.
+///
+/// The key is a path of fields separated by dots.
+/// Items of the `projects` list are selected by their name and items of other lists by their index.
+/// The value is parsed as YAML, so `true` and `10` are a boolean and a number.
+pub fn apply_override(config: &mut serde_yaml::Value, assignment: &str) -> Result<(), String> {
+    let Some((key, value)) = assignment.split_once('=') else {
+        return Err(format!(
+            "override {assignment:?} must have the form key=value"
+        ));
+    };
+    let value: serde_yaml::Value = match serde_yaml::from_str(value) {
+        Ok(value) => value,
+        Err(err) => {
+            return Err(format!(
+                "failed to parse the value of override {key}: {err}"
+            ))
+        }
+    };
+    let mut target = config;
+    let mut path = String::new();
+    for field in key.split('.') {
+        if field.is_empty() {
+            return Err(format!("override key {key:?} has an empty field"));
+        }
+        let parent = target;
+        if parent.is_null() {
+            *parent = serde_yaml::Value::Mapping(Default::default());
+        }
+        target = match parent {
+            serde_yaml::Value::Mapping(mapping) => mapping
+                .entry(serde_yaml::Value::String(field.to_string()))
+                .or_insert(serde_yaml::Value::Null),
+            serde_yaml::Value::Sequence(items) => {
+                let item = if path == "projects" {
+                    items
+                        .iter_mut()
+                        .find(|item| item["name"].as_str() == Some(field))
+                } else {
+                    field.parse::<usize>().ok().and_then(|i| items.get_mut(i))
+                };
+                match item {
+                    None => return Err(format!("override {key}: {path} has no item {field}")),
+                    Some(item) => item,
+                }
+            }
+            _ => return Err(format!("override {key}: {path} is not a mapping or list")),
+        };
+        if !path.is_empty() {
+            path.push('.');
+        }
+        path.push_str(field);
+    }
+    *target = value;
+    Ok(())
 }
 
-fn ten() -> usize {
-    10
+/// Build the config of an agent with a single project from environment variables,
+///     for running the agent without a config file.
+///
+/// - `CDAGENT_REPO` is the repository, as `owner/repo` or a GitHub URL.
+/// - `CDAGENT_BRANCH` is the mainline branch, `main` by default.
+/// - `CDAGENT_STEP_1`, `CDAGENT_STEP_2`, ... are the commands of the steps, run in order.
+/// - `CDAGENT_NAME` is the name of the project, the repository's name by default.
+/// - `CDAGENT_WORKING_DIRECTORY` is the directory the steps run in.
+/// - `CDAGENT_AUTH_TOKEN` or `CDAGENT_AUTH_TOKEN_FILE` is the GitHub auth token.
+/// - `CDAGENT_POLL_INTERVAL_SECONDS` is the poll interval.
+///
+/// The path to the database is in `CDAGENT_DATABASE`.
+pub fn from_env() -> Result<serde_yaml::Value, String> {
+    let var = |name: &str| match std::env::var(name) {
+        Ok(value) if !value.is_empty() => Ok(Some(value)),
+        Ok(_) | Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(err) => Err(format!("failed to read environment variable {name}: {err}")),
+    };
+    let Some(repo) = var("CDAGENT_REPO")? else {
+        return Err("environment variable CDAGENT_REPO is not set".to_string());
+    };
+    let Some((github_user, repo)) = crate::github::parse_repo(&repo) else {
+        return Err(format!(
+            "environment variable CDAGENT_REPO must have the form owner/repo, not {repo:?}"
+        ));
+    };
+    let mut steps = vec![];
+    while let Some(run) = var(&format!("CDAGENT_STEP_{}", steps.len() + 1))? {
+        steps.push(serde_json::json!({
+            "name": format!("Step {}", steps.len() + 1),
+            "run": run,
+        }));
+    }
+    let mut project = serde_json::json!({
+        "name": var("CDAGENT_NAME")?.unwrap_or_else(|| repo.to_string()),
+        "github_user": github_user,
+        "repo": repo,
+        "mainline_branch": var("CDAGENT_BRANCH")?.unwrap_or_else(|| "main".to_string()),
+        "steps": steps,
+    });
+    if let Some(working_directory) = var("CDAGENT_WORKING_DIRECTORY")? {
+        project["working_directory"] = working_directory.into();
+    }
+    let mut config = serde_json::json!({
+        "projects": [project],
+        "github": {
+            "auth_token": var("CDAGENT_AUTH_TOKEN")?,
+            "auth_token_file": var("CDAGENT_AUTH_TOKEN_FILE")?,
+        },
+    });
+    if let Some(poll_interval) = var("CDAGENT_POLL_INTERVAL_SECONDS")? {
+        let Ok(poll_interval) = poll_interval.parse::<u64>() else {
+            return Err(format!(
+                "environment variable CDAGENT_POLL_INTERVAL_SECONDS must be a number of seconds, not {poll_interval:?}"
+            ));
+        };
+        config["poll_interval_seconds"] = poll_interval.into();
+    }
+    serde_yaml::to_value(config).map_err(|err| err.to_string())
 }
@@ -0,0 +1,331 @@
+//! Changes to projects requested at runtime through the HTTP API.
+//!
+//! Requests are queued by the HTTP server and applied by the project manager
+//!     at the start of its next iteration.
+//! Projects added and removed through the API are applied before that,
+//!     as the config they are merged into is owned by the main loop.
+
+use crate::config;
+use crate::project;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync;
+
+/// A change to a project.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    Pause,
+    Resume,
+    /// Poll the project for a new workflow run and redeploy it if there is one,
+    ///     or redeploy a specific workflow run.
+    Deploy {
+        /// Skip the soak time of projects promoted from another project.
+        skip_wait: bool,
+        target: Target,
+    },
+    /// Redeploy the workflow run of the most recent successful redeployment
+    ///     before the current one.
+    Rollback,
+    /// Switch the `current` symlink of a project with managed releases back to the previous release
+    ///     and run the restart steps, without redeploying it.
+    RollbackRelease,
+}
+
+/// The workflow run a deploy request redeploys.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    /// The latest successful workflow run, if it hasn't been seen before.
+    Latest,
+    /// The workflow run that was redeployed most recently, again.
+    Current,
+    /// The most recent successful workflow run for the commit with the provided SHA,
+    ///     even if it is not the latest.
+    Commit(String),
+}
+
+/// A requested change to a project.
+pub struct Request {
+    pub project: String,
+    pub action: Action,
+    /// For deploy and rollback requests, the ID of the deployment.
+    pub deployment: Option<u64>,
+}
+
+/// A deployment requested through the HTTP API.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Deployment {
+    pub id: u64,
+    pub project: String,
+    pub requested: String,
+    #[serde(flatten)]
+    pub status: DeploymentStatus,
+}
+
+/// The outcome of a requested deployment.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum DeploymentStatus {
+    /// The project has not been polled yet.
+    Queued,
+    /// The workflow run is being redeployed.
+    ///
+    /// This is not recorded by the project manager,
+    ///     but reported by the HTTP server while the redeployment's output is live.
+    Running {
+        workflow_run: u64,
+    },
+    /// There is no new workflow run to redeploy.
+    UpToDate,
+    /// The project is paused.
+    Paused,
+    /// The redeployment of a new workflow run is being held; e.g., for approval.
+    Held {
+        reason: String,
+    },
+    Succeeded {
+        workflow_run: u64,
+    },
+    Failed {
+        workflow_run: u64,
+    },
+    /// There is no earlier successful redeployment to roll back to.
+    NoRollbackTarget,
+    /// Polling or redeploying the project failed.
+    Error {
+        error: String,
+    },
+}
+
+/// A change to which projects the agent runs.
+pub enum ProjectChange {
+    /// Add a project that is not in the config file.
+    Add(Box<config::ProjectConfig>),
+    /// Remove a project that was added through the HTTP API.
+    Remove(String),
+}
+
+/// Why a request was not accepted.
+pub enum Rejection {
+    UnknownProject,
+    /// The request conflicts with the config; e.g., resuming a project paused in the config.
+    Conflict(String),
+}
+
+/// Queue of requested changes to projects.
+///
+/// Clones share the same underlying queue.
+#[derive(Clone)]
+pub struct Controls {
+    /// The projects, along with the projects each depends on.
+    projects: sync::Arc<sync::Mutex<HashMap<String, Vec<String>>>>,
+    paused_in_config: sync::Arc<sync::Mutex<HashSet<String>>>,
+    /// The projects with managed releases, which are the only ones whose release can be rolled back.
+    with_releases: sync::Arc<sync::Mutex<HashSet<String>>>,
+    /// The projects that were added through the HTTP API, which are the only ones that can be removed.
+    added: sync::Arc<sync::Mutex<HashSet<String>>>,
+    requests: sync::Arc<sync::Mutex<Vec<Request>>>,
+    project_changes: sync::Arc<sync::Mutex<Vec<ProjectChange>>>,
+    deployments: sync::Arc<sync::Mutex<BTreeMap<u64, Deployment>>>,
+    /// Whether approval can be requested, which projects that require approval need.
+    ///
+    /// Notifications aren't reloaded, so this is as configured at startup.
+    approvals_enabled: bool,
+    status_page_url: Option<String>,
+}
+
+impl Controls {
+    pub fn new(config: &config::Config) -> Self {
+        let controls = Self {
+            projects: Default::default(),
+            paused_in_config: Default::default(),
+            with_releases: Default::default(),
+            added: Default::default(),
+            requests: Default::default(),
+            project_changes: Default::default(),
+            deployments: Default::default(),
+            approvals_enabled: config
+                .notifications
+                .as_ref()
+                .is_some_and(|n| n.approval_secret.is_some()),
+            status_page_url: config
+                .notifications
+                .as_ref()
+                .and_then(|n| n.status_page_url.clone()),
+        };
+        controls.update_projects(config);
+        controls
+    }
+
+    /// Replace the projects that requests can be made for with the projects in the config.
+    pub fn update_projects(&self, config: &config::Config) {
+        let projects: HashMap<String, Vec<String>> = config
+            .projects
+            .iter()
+            .map(|p| (p.name.clone(), p.upstreams().cloned().collect()))
+            .collect();
+        self.added
+            .lock()
+            .unwrap()
+            .retain(|name| projects.contains_key(name));
+        *self.projects.lock().unwrap() = projects;
+        *self.paused_in_config.lock().unwrap() = config
+            .projects
+            .iter()
+            .filter(|p| p.paused)
+            .map(|p| p.name.clone())
+            .collect();
+        *self.with_releases.lock().unwrap() = config
+            .projects
+            .iter()
+            .filter(|p| p.releases.is_some())
+            .map(|p| p.name.clone())
+            .collect();
+    }
+
+    /// Replace the projects that were added through the HTTP API.
+    pub fn update_added_projects<'a>(&self, names: impl Iterator<Item = &'a String>) {
+        *self.added.lock().unwrap() = names.cloned().collect();
+    }
+
+    /// Queue adding the project.
+    ///
+    /// The project is checked against the other projects and the config here,
+    ///     so that adding it can't fail once it is applied.
+    /// A project can only depend on projects that already exist, so it can't create a cycle.
+    pub fn add_project(&self, project: config::ProjectConfig) -> Result<(), Rejection> {
+        let name = &project.name;
+        let mut projects = self.projects.lock().unwrap();
+        if projects.contains_key(name) {
+            return Err(Rejection::Conflict(format!(
+                "there is already a project {name}"
+            )));
+        }
+        if let Some(upstream) = project.upstreams().find(|u| !projects.contains_key(*u)) {
+            return Err(Rejection::Conflict(format!(
+                "project {name} depends on unknown project {upstream}"
+            )));
+        }
+        project::check_approvals(
+            std::slice::from_ref(&project),
+            self.approvals_enabled,
+            self.status_page_url.as_deref(),
+        )
+        .map_err(Rejection::Conflict)?;
+        projects.insert(name.clone(), project.upstreams().cloned().collect());
+        self.added.lock().unwrap().insert(name.clone());
+        self.project_changes
+            .lock()
+            .unwrap()
+            .push(ProjectChange::Add(Box::new(project)));
+        Ok(())
+    }
+
+    /// Queue removing the project with the provided name.
+    ///
+    /// Only projects that were added through the HTTP API can be removed.
+    pub fn remove_project(&self, name: &str) -> Result<(), Rejection> {
+        let mut projects = self.projects.lock().unwrap();
+        if !projects.contains_key(name) {
+            return Err(Rejection::UnknownProject);
+        }
+        let mut added = self.added.lock().unwrap();
+        if !added.contains(name) {
+            return Err(Rejection::Conflict(format!(
+                "project {name} is in the config file and can only be removed by editing it"
+            )));
+        }
+        if let Some((dependent, _)) = projects
+            .iter()
+            .find(|(_, upstreams)| upstreams.iter().any(|u| u == name))
+        {
+            return Err(Rejection::Conflict(format!(
+                "project {dependent} depends on project {name}, so it must be removed first"
+            )));
+        }
+        added.remove(name);
+        projects.remove(name);
+        self.project_changes
+            .lock()
+            .unwrap()
+            .push(ProjectChange::Remove(name.to_string()));
+        Ok(())
+    }
+
+    /// Remove and return all queued changes to the projects, in the order they were made.
+    pub fn take_project_changes(&self) -> Vec<ProjectChange> {
+        std::mem::take(&mut *self.project_changes.lock().unwrap())
+    }
+
+    /// The number of requested deployments whose status is kept.
+    const MAX_DEPLOYMENTS: usize = 100;
+
+    /// Queue the action for the project with the provided name.
+    ///
+    /// For deploy and rollback requests, returns the ID of the deployment.
+    pub fn request(&self, project: &str, action: Action) -> Result<Option<u64>, Rejection> {
+        if !self.projects.lock().unwrap().contains_key(project) {
+            return Err(Rejection::UnknownProject);
+        }
+        if action == Action::Resume && self.paused_in_config.lock().unwrap().contains(project) {
+            return Err(Rejection::Conflict(format!(
+                "project {project} is paused in the config and can only be resumed by editing it"
+            )));
+        }
+        if action == Action::RollbackRelease
+            && !self.with_releases.lock().unwrap().contains(project)
+        {
+            return Err(Rejection::Conflict(format!(
+                "project {project} doesn't have managed releases to roll back"
+            )));
+        }
+        let deployment = match action {
+            Action::Deploy { .. } | Action::Rollback | Action::RollbackRelease => {
+                let mut deployments = self.deployments.lock().unwrap();
+                let id = deployments.keys().next_back().map_or(1, |id| id + 1);
+                deployments.insert(
+                    id,
+                    Deployment {
+                        id,
+                        project: project.to_string(),
+                        requested: chrono::offset::Utc::now()
+                            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                        status: DeploymentStatus::Queued,
+                    },
+                );
+                while deployments.len() > Self::MAX_DEPLOYMENTS {
+                    deployments.pop_first();
+                }
+                Some(id)
+            }
+            _ => None,
+        };
+        self.requests.lock().unwrap().push(Request {
+            project: project.to_string(),
+            action,
+            deployment,
+        });
+        Ok(deployment)
+    }
+
+    /// Returns the requested deployment with the provided ID, if it is still known.
+    pub fn deployment(&self, id: u64) -> Option<Deployment> {
+        self.deployments.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Record the outcome of the requested deployment with the provided ID.
+    pub fn update(&self, id: u64, status: DeploymentStatus) {
+        if let Some(deployment) = self.deployments.lock().unwrap().get_mut(&id) {
+            deployment.status = status;
+        }
+    }
+
+    /// Returns whether there are queued requests.
+    pub fn pending(&self) -> bool {
+        !self.requests.lock().unwrap().is_empty()
+            || !self.project_changes.lock().unwrap().is_empty()
+    }
+
+    /// Remove and return all queued requests, in the order they were made.
+    pub fn take(&self) -> Vec<Request> {
+        std::mem::take(&mut *self.requests.lock().unwrap())
+    }
+}
@@ -0,0 +1,60 @@
+//! Protection against cross-site request forgery (CSRF) of the endpoints for changing projects.
+//!
+//! Browsers send cached basic auth credentials with every request to the agent,
+//!     including form submissions from other sites.
+//! So requests from browsers must include a token that only pages served by the agent contain.
+//! The token is derived from the request's credentials, so that one user can't use their token
+//!     to forge requests from another.
+//!
+//! Requests authenticated with bearer tokens, which browsers don't send automatically,
+//!     and requests from programs other than browsers don't need a CSRF token.
+
+use crate::approval;
+use crate::encryption;
+use hmac::Mac;
+
+/// Placeholder for the CSRF token in rendered pages.
+///
+/// Pages are rendered once for all users, so the HTTP server replaces the placeholder
+///     with the token for each request's credentials.
+pub const PLACEHOLDER: &str = "%CSRF_TOKEN%";
+
+pub struct Csrf {
+    key: Vec<u8>,
+}
+
+impl Csrf {
+    /// Create a CSRF protector with a random key.
+    ///
+    /// Tokens are only valid until the agent restarts.
+    pub fn new() -> Self {
+        Self {
+            key: encryption::random_bytes(32),
+        }
+    }
+
+    /// Returns the CSRF token for requests with the provided `Authorization` header.
+    pub fn token(&self, authorization: Option<&str>) -> String {
+        self.mac(authorization)
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    /// Returns whether the CSRF token is valid for requests with the provided `Authorization` header.
+    pub fn verify(&self, authorization: Option<&str>, token: &str) -> bool {
+        let Some(token) = approval::hex_decode(token) else {
+            return false;
+        };
+        self.mac(authorization).verify_slice(&token).is_ok()
+    }
+
+    fn mac(&self, authorization: Option<&str>) -> hmac::Hmac<sha2::Sha256> {
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(&self.key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(authorization.unwrap_or_default().as_bytes());
+        mac
+    }
+}
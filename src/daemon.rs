@@ -0,0 +1,107 @@
+//! Running the agent in the background, for hosts without a service manager like systemd.
+//!
+//! With `--daemon` the agent forks after reading its config, so that config errors are still
+//!     reported in the terminal, and the parent process exits.
+//! The working directory is not changed, as relative paths in the config are relative to it.
+
+use std::fs;
+#[cfg(unix)]
+use std::os::fd::AsRawFd;
+
+/// A file containing the agent's process ID, which is removed when the agent exits.
+pub struct PidFile {
+    path: String,
+}
+
+impl PidFile {
+    /// Claim the pid file at the path.
+    ///
+    /// Fails if the file names a process that is still running,
+    ///     which is most likely another instance of the agent.
+    pub fn new(path: &str) -> Result<Self, String> {
+        let pid = fs::read_to_string(path)
+            .ok()
+            .and_then(|pid| pid.trim().parse::<i32>().ok());
+        if let Some(pid) = pid.filter(|pid| is_running(*pid)) {
+            return Err(format!(
+                "the pid file {path} names process {pid}, which is still running"
+            ));
+        }
+        Ok(Self {
+            path: path.to_string(),
+        })
+    }
+
+    /// Write the process ID to the file.
+    pub fn write(&self, pid: u32) -> Result<(), String> {
+        match fs::write(&self.path, format!("{pid}\n")) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(format!("failed to write pid file {}: {err}", self.path)),
+        }
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Fork into the background, discarding output unless it is written to a log file later.
+///
+/// Only the child process returns; the parent writes the child's ID to the pid file and exits.
+/// This must be called before any threads are started, as only the calling thread is forked.
+#[cfg(unix)]
+pub fn daemonize(pid_file: Option<&PidFile>) -> Result<(), String> {
+    let null = match fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+    {
+        Ok(null) => null,
+        Err(err) => return Err(format!("failed to open /dev/null: {err}")),
+    };
+    // SAFETY: the process is single threaded, so the child is a complete copy of it.
+    match unsafe { libc::fork() } {
+        -1 => Err(format!(
+            "failed to fork into the background: {}",
+            std::io::Error::last_os_error()
+        )),
+        0 => {
+            // SAFETY: these only operate on file descriptors that are open.
+            unsafe {
+                libc::setsid();
+                libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO);
+                libc::dup2(null.as_raw_fd(), libc::STDOUT_FILENO);
+                libc::dup2(null.as_raw_fd(), libc::STDERR_FILENO);
+            }
+            Ok(())
+        }
+        pid => {
+            if let Some(Err(err)) = pid_file.map(|pid_file| pid_file.write(pid as u32)) {
+                // SAFETY: the child was just forked, so the process ID can't have been reused.
+                unsafe { libc::kill(pid, libc::SIGTERM) };
+                return Err(err);
+            }
+            eprintln!("Started the agent in the background with process ID {pid}");
+            // Exiting skips destructors, so the child's pid file is not removed.
+            std::process::exit(0);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_: Option<&PidFile>) -> Result<(), String> {
+    Err("running in the background is only supported on Unix".to_string())
+}
+
+#[cfg(unix)]
+fn is_running(pid: i32) -> bool {
+    // SAFETY: signal 0 only checks whether the process exists.
+    pid > 0 && unsafe { libc::kill(pid, 0) } == 0
+}
+
+#[cfg(not(unix))]
+fn is_running(_: i32) -> bool {
+    false
+}
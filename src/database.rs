@@ -1,116 +1,944 @@
-//! A simple database for persisting data across runs of the agent.
-
-use std::collections::HashMap;
-use std::sync;
-
-static STATUS_DOT_HTML: &str = include_str!("status.html");
-
-/// A simple database for persisting data across runs of the agent.
-#[derive(serde::Serialize, serde::Deserialize)]
-pub struct Database {
-    #[serde(skip)]
-    path: Option<String>,
-    #[serde(skip)]
-    json_data: sync::Arc<sync::Mutex<String>>,
-    #[serde(skip)]
-    html_data: sync::Arc<sync::Mutex<String>>,
-    pub config: crate::config::Config,
-    pub github_client: crate::github::Data,
-    pub projects: Vec<crate::project::Project>,
-}
-
-impl Database {
-    /// Create a new in-memory database.
-    pub fn new_in_memory(config: crate::config::Config) -> Self {
-        Self {
-            path: None,
-            json_data: Default::default(),
-            html_data: Default::default(),
-            config,
-            github_client: Default::default(),
-            projects: Default::default(),
+//! A simple key-value database for persisting data across runs of the agent.
+//!
+//! Values are JSON values.
+//! The typed helpers [get] and [set] convert between JSON values and Rust types.
+
+use crate::config;
+use crate::metrics;
+use crate::migrations;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+/// A simple key-value database for persisting data across runs of the agent.
+pub trait DB: Send {
+    /// Get the value for the key, if it exists.
+    fn get(&self, key: &str) -> Option<serde_json::Value>;
+
+    /// Set the value for the key.
+    ///
+    /// Returns an error if the change could not be persisted.
+    /// In this case the change is still visible to reads,
+    ///     and persisting it is retried on the next write or [DB::flush].
+    fn set(&mut self, key: &str, value: serde_json::Value) -> Result<(), String>;
+
+    /// Delete the key, if it exists.
+    ///
+    /// Errors are handled in the same way as for [DB::set].
+    fn delete(&mut self, key: &str) -> Result<(), String>;
+
+    /// List all keys that start with the provided prefix, in sorted order.
+    fn keys(&self, prefix: &str) -> Vec<String>;
+
+    /// Apply a batch of changes.
+    ///
+    /// A value of `None` deletes the key.
+    /// Backends that persist data should override this to persist the batch in one write.
+    fn write_batch(
+        &mut self,
+        changes: Vec<(String, Option<serde_json::Value>)>,
+    ) -> Result<(), String> {
+        for (key, value) in changes {
+            match value {
+                None => self.delete(&key)?,
+                Some(value) => self.set(&key, value)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Persist any buffered changes, including changes that previously failed to persist.
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Get the value for the key and deserialize it.
+///
+/// Returns an error if the value cannot be deserialized into the requested type.
+pub fn get<T: serde::de::DeserializeOwned>(db: &dyn DB, key: &str) -> Result<Option<T>, String> {
+    match db.get(key) {
+        None => Ok(None),
+        Some(value) => match serde_json::from_value(value) {
+            Ok(t) => Ok(Some(t)),
+            Err(err) => Err(format!("database value for key {key} is corrupt: {err}")),
+        },
+    }
+}
+
+/// Serialize the value and set it for the key.
+pub fn set<T: serde::Serialize>(db: &mut dyn DB, key: &str, value: &T) -> Result<(), String> {
+    let value = serde_json::to_value(value).expect("failed to serialize database value");
+    db.set(key, value)
+}
+
+/// Open the database at the provided location, or an in-memory database if no location is provided.
+///
+/// If the in-memory database has a snapshot configured,
+///     it is loaded from the snapshot and periodically dumped to it.
+///
+/// The database is migrated to the current schema version.
+/// If `read_only` is true the database is instead required to be at the current schema version,
+///     and all writes to it are refused.
+///
+/// Unless it is read-only, an on-disk database is locked while it is open,
+///     and opening it fails if another agent has it locked.
+pub fn open(
+    location: Option<&str>,
+    config: &config::DatabaseConfig,
+    read_only: bool,
+) -> Result<Box<dyn DB>, String> {
+    if config.snapshot.is_some() && (location.is_some() || config.redis.is_some()) {
+        return Err("a snapshot can only be configured when the database is in-memory".to_string());
+    }
+    let path = location.or(config.snapshot.as_ref().map(|s| s.path.as_str()));
+    let lock = match path {
+        Some(path) if !read_only => Some(lock(path)?),
+        _ => None,
+    };
+    let mut flush_interval_seconds = config.flush_interval_seconds;
+    let mut db: Box<dyn DB> = match (location, &config.redis) {
+        (Some(_), Some(_)) => {
+            return Err(
+                "a database path cannot be provided when the database is stored in Redis"
+                    .to_string(),
+            )
+        }
+        (None, Some(redis_config)) => Box::new(RedisDB::new(redis_config)?),
+        (None, None) => match &config.snapshot {
+            None => Box::<InMemoryDB>::default(),
+            // The snapshot is an on-disk database whose writes are all deferred to flushes.
+            Some(snapshot) => {
+                flush_interval_seconds = Some(snapshot.interval_seconds.unwrap_or(300));
+                Box::new(OnDiskDB::new(&snapshot.path, config.durable_writes)?)
+            }
+        },
+        (Some(location), None) => match config.format {
+            config::DatabaseFormat::Snapshot => {
+                Box::new(OnDiskDB::new(location, config.durable_writes)?)
+            }
+            config::DatabaseFormat::Journal => {
+                Box::new(JournalDB::new(location, config.durable_writes)?)
+            }
+        },
+    };
+    if read_only {
+        migrations::check(&*db)?;
+        return Ok(Box::new(ReadOnlyDB { db }));
+    }
+    db = Box::new(MeasuredDB { db });
+    migrations::run(&mut *db)?;
+    if let Some(d) = flush_interval_seconds.filter(|d| *d > 0) {
+        db = Box::new(CoalescingDB::new(db, std::time::Duration::from_secs(d)));
+    }
+    match lock {
+        None => Ok(db),
+        Some(lock) => Ok(Box::new(LockedDB { db, _lock: lock })),
+    }
+}
+
+/// Lock the on-disk database at the path against other agents.
+///
+/// The lock is taken on `<path>.lock` rather than the database itself,
+///     as snapshots replace the database file on every write.
+/// The operating system releases the lock when the agent exits, even if it crashes,
+///     so the lock file is left in place.
+fn lock(path: &str) -> Result<std::fs::File, String> {
+    let lock_path = format!("{path}.lock");
+    let mut file = match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+    {
+        Ok(file) => file,
+        Err(err) => {
+            return Err(format!(
+                "failed to open database lock file {lock_path}: {err}"
+            ))
         }
+    };
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) => {
+            let holder = std::fs::read_to_string(&lock_path)
+                .ok()
+                .and_then(|pid| pid.trim().parse::<u32>().ok());
+            let holder = match holder {
+                Some(pid) => format!("another agent (process {pid})"),
+                None => "another agent".to_string(),
+            };
+            return Err(format!(
+                "database {path} is in use by {holder}; agents can't share a database unless they open it with --db-read-only"
+            ));
+        }
+        Err(std::fs::TryLockError::Error(err)) => {
+            return Err(format!("failed to lock database {path}: {err}"))
+        }
+    }
+    // The process ID is only recorded for the error message above.
+    _ = file
+        .set_len(0)
+        .and_then(|()| writeln!(file, "{}", std::process::id()));
+    Ok(file)
+}
+
+/// Detect the format of the on-disk database at the provided location.
+///
+/// Missing and empty files are reported as snapshots.
+pub fn detect_format(location: &str) -> config::DatabaseFormat {
+    match std::fs::read_to_string(location) {
+        Ok(content) if content.trim_start().starts_with('[') => config::DatabaseFormat::Journal,
+        _ => config::DatabaseFormat::Snapshot,
+    }
+}
+
+/// Export all of the values in the database to a canonical JSON string.
+///
+/// The JSON string is an object mapping keys to values, with the keys in sorted order.
+pub fn export(db: &dyn DB) -> String {
+    let values: BTreeMap<String, serde_json::Value> = db
+        .keys("")
+        .into_iter()
+        .filter_map(|key| db.get(&key).map(|value| (key, value)))
+        .collect();
+    serde_json::to_string_pretty(&values).expect("failed to serialize database values")
+}
+
+/// Import values from a JSON string produced by [export].
+///
+/// After the import the database contains exactly the imported values;
+///     all other keys are deleted.
+/// The imported values are then migrated to the current schema version.
+pub fn import(db: &mut dyn DB, json: &str) -> Result<usize, String> {
+    let values: BTreeMap<String, serde_json::Value> = match serde_json::from_str(json) {
+        Ok(values) => values,
+        Err(err) => return Err(format!("failed to parse database export: {err}")),
+    };
+    for key in db.keys("") {
+        if !values.contains_key(&key) {
+            db.delete(&key)?;
+        }
+    }
+    let num_values = values.len();
+    for (key, value) in values {
+        db.set(&key, value)?;
+    }
+    migrations::run(db)?;
+    Ok(num_values)
+}
+
+/// An in-memory database.
+#[derive(Default)]
+pub struct InMemoryDB {
+    values: BTreeMap<String, serde_json::Value>,
+}
+
+impl DB for InMemoryDB {
+    fn get(&self, key: &str) -> Option<serde_json::Value> {
+        self.values.get(key).cloned()
     }
 
-    /// Create a new on-disk database.
+    fn set(&mut self, key: &str, value: serde_json::Value) -> Result<(), String> {
+        self.values.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), String> {
+        self.values.remove(key);
+        Ok(())
+    }
+
+    fn keys(&self, prefix: &str) -> Vec<String> {
+        keys(&self.values, prefix)
+    }
+}
+
+/// An on-disk database.
+///
+/// The database is stored as a single JSON object mapping keys to values.
+/// All values are kept in memory, and the full database is written to disk on every change.
+pub struct OnDiskDB {
+    path: String,
+    durable_writes: bool,
+    values: BTreeMap<String, serde_json::Value>,
+    dirty: bool,
+}
+
+impl OnDiskDB {
+    /// Open an on-disk database.
     ///
-    /// If there is not file at the provided path, a new database will be provisioned.
+    /// If there is no file at the provided path, a new database will be provisioned.
     ///
     /// This constructor fails if there is an IO error when reading the path,
     ///     or if the file is not valid JSON.
-    pub fn new_on_disk(config: crate::config::Config, path: &str) -> Result<Self, String> {
-        let mut database: Self = match std::fs::read_to_string(path) {
-            Ok(json) => {
-                let mut database: Self = match serde_json::from_str(&json) {
-                    Ok(values) => values,
-                    Err(err) => return Err(format!("database file is corrupt: {err}. Consider deleting the file to initialize a new database"))
-                };
-                database.config = config;
-                database
-            }
+    pub fn new(path: &str, durable_writes: bool) -> Result<Self, String> {
+        let values = match std::fs::read_to_string(path) {
+            Ok(json) => match serde_json::from_str(&json) {
+                Ok(values) => values,
+                Err(err) => return Err(format!("database file is corrupt: {err}. Consider deleting the file to initialize a new database")),
+            },
             Err(err) => {
                 if err.kind() == std::io::ErrorKind::NotFound {
                     eprintln!("Database file {path} doesn't exist; initializing new database");
-                    Self::new_in_memory(config)
+                    Default::default()
                 } else {
                     return Err(format!("failed to open database file: {err}"));
                 }
             }
         };
-        database.path = Some(path.to_string());
-        let mut existing_projects: Vec<crate::project::Project> = vec![];
-        std::mem::swap(&mut existing_projects, &mut database.projects);
-        let mut name_to_existing_project: HashMap<String, crate::project::Project> =
-            existing_projects
-                .into_iter()
-                .map(|p| (p.config.name.clone(), p))
-                .collect();
-        database.projects = database
-            .config
-            .projects
-            .iter()
-            .map(|c| match name_to_existing_project.remove(&c.name) {
-                None => crate::project::Project::new(c.clone()),
-                Some(mut project) => {
-                    project.config = c.clone();
-                    project
+        Ok(Self {
+            path: path.to_string(),
+            durable_writes,
+            values,
+            dirty: false,
+        })
+    }
+}
+
+/// Atomically replace the file at the path with the provided content.
+///
+/// The content is written to a temporary file which is then renamed over the path.
+/// If `durable` is true the temporary file and the parent directory are fsynced.
+fn replace_file(path: &str, content: &[u8], durable: bool) -> Result<(), String> {
+    let tmp_path = format!("{path}.tmp");
+    let write = || -> std::io::Result<()> {
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(content)?;
+        if durable {
+            file.sync_all()?;
+        }
+        Ok(())
+    };
+    if let Err(err) = write() {
+        return Err(format!("Failed to write database: {err}"));
+    }
+    if let Err(err) = std::fs::rename(&tmp_path, path) {
+        return Err(format!("Failed to replace database file: {err}"));
+    }
+    if durable {
+        // The rename is only durable once the directory entry is persisted.
+        let dir = match Path::new(path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        if let Err(err) = std::fs::File::open(dir).and_then(|d| d.sync_all()) {
+            return Err(format!("Failed to sync database directory: {err}"));
+        }
+    }
+    Ok(())
+}
+
+impl DB for OnDiskDB {
+    fn get(&self, key: &str) -> Option<serde_json::Value> {
+        self.values.get(key).cloned()
+    }
+
+    fn set(&mut self, key: &str, value: serde_json::Value) -> Result<(), String> {
+        if self.values.get(key) != Some(&value) {
+            self.values.insert(key.to_string(), value);
+            self.dirty = true;
+        }
+        self.flush()
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), String> {
+        if self.values.remove(key).is_some() {
+            self.dirty = true;
+        }
+        self.flush()
+    }
+
+    fn keys(&self, prefix: &str) -> Vec<String> {
+        keys(&self.values, prefix)
+    }
+
+    fn write_batch(
+        &mut self,
+        changes: Vec<(String, Option<serde_json::Value>)>,
+    ) -> Result<(), String> {
+        for (key, value) in changes {
+            self.dirty |= match value {
+                None => self.values.remove(&key).is_some(),
+                Some(value) => self.values.insert(key, value.clone()) != Some(value),
+            };
+        }
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let content = serde_json::to_string_pretty(&self.values)
+            .expect("failed to serialize database values");
+        replace_file(&self.path, content.as_bytes(), self.durable_writes)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// An on-disk database stored as an append-only journal.
+///
+/// Each line of the file is a JSON array describing one change:
+///     `["set", key, value]` or `["delete", key]`.
+/// Changes are appended to the end of the file, so the cost of a write is proportional to the
+///     size of the change rather than the size of the database,
+///     and a partial write cannot destroy existing data.
+/// When the journal grows much larger than the data it describes,
+///     it is compacted by atomically replacing the file with one `set` line per key.
+///
+/// If the file is a snapshot written by [OnDiskDB], it is read as such and converted to a journal
+///     on the next write.
+/// If an append fails, the journal is compacted on the next write.
+pub struct JournalDB {
+    path: String,
+    durable_writes: bool,
+    values: BTreeMap<String, serde_json::Value>,
+    num_entries: usize,
+    needs_compaction: bool,
+}
+
+enum JournalEntry {
+    Set(String, serde_json::Value),
+    Delete(String),
+}
+
+impl JournalEntry {
+    fn parse(line: &str) -> Result<Self, String> {
+        let entry: Vec<serde_json::Value> = match serde_json::from_str(line) {
+            Ok(entry) => entry,
+            Err(err) => return Err(err.to_string()),
+        };
+        match entry.as_slice() {
+            [op, serde_json::Value::String(key), value] if op == "set" => {
+                Ok(JournalEntry::Set(key.clone(), value.clone()))
+            }
+            [op, serde_json::Value::String(key)] if op == "delete" => {
+                Ok(JournalEntry::Delete(key.clone()))
+            }
+            _ => Err(format!("unrecognized entry {line}")),
+        }
+    }
+
+    fn to_line(&self) -> String {
+        let entry = match self {
+            JournalEntry::Set(key, value) => serde_json::json!(["set", key, value]),
+            JournalEntry::Delete(key) => serde_json::json!(["delete", key]),
+        };
+        format!("{entry}\n")
+    }
+}
+
+impl JournalDB {
+    /// The journal is compacted when it has more than this many entries
+    ///     in addition to twice the number of keys in the database.
+    const COMPACTION_SLACK: usize = 1000;
+
+    /// Open a journal database.
+    ///
+    /// If there is no file at the provided path, a new database will be provisioned.
+    ///
+    /// An incomplete final line, which is left behind if the agent crashes in the middle of a write,
+    ///     is discarded and removed from the file on the next write.
+    /// This constructor fails if there is an IO error when reading the path,
+    ///     or if any other line of the journal is corrupt.
+    pub fn new(path: &str, durable_writes: bool) -> Result<Self, String> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    return Err(format!("failed to open database file: {err}"));
+                }
+                eprintln!("Database file {path} doesn't exist; initializing new database");
+                String::new()
+            }
+        };
+        let mut db = Self {
+            path: path.to_string(),
+            durable_writes,
+            values: Default::default(),
+            num_entries: 0,
+            needs_compaction: false,
+        };
+        if let Ok(values) = serde_json::from_str(&content) {
+            eprintln!("Database file {path} is a snapshot; it will be converted to a journal on the next write");
+            db.values = values;
+            db.needs_compaction = true;
+            return Ok(db);
+        }
+        let lines: Vec<&str> = content.lines().collect();
+        for (i, line) in lines.iter().enumerate() {
+            let entry = match JournalEntry::parse(line) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    if i + 1 == lines.len() && !content.ends_with('\n') {
+                        eprintln!("Discarding incomplete final entry in database journal: {err}");
+                        db.needs_compaction = true;
+                        break;
+                    }
+                    return Err(format!("database journal is corrupt at line {}: {err}. Consider deleting the file to initialize a new database", i + 1));
+                }
+            };
+            match entry {
+                JournalEntry::Set(key, value) => {
+                    db.values.insert(key, value);
+                }
+                JournalEntry::Delete(key) => {
+                    db.values.remove(&key);
                 }
-            })
+            }
+            db.num_entries += 1;
+        }
+        Ok(db)
+    }
+
+    fn append(&mut self, entries: &[JournalEntry]) -> Result<(), String> {
+        if self.needs_compaction
+            || self.num_entries + entries.len() > 2 * self.values.len() + Self::COMPACTION_SLACK
+        {
+            return self.compact();
+        }
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let content: String = entries.iter().map(JournalEntry::to_line).collect();
+        let result = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| {
+                file.write_all(content.as_bytes())?;
+                if self.durable_writes {
+                    file.sync_data()?;
+                }
+                Ok(())
+            });
+        match result {
+            Ok(()) => {
+                self.num_entries += entries.len();
+                Ok(())
+            }
+            Err(err) => {
+                // The file may now end with a partial entry, so the only safe way to persist
+                // the changes is to rewrite it.
+                self.needs_compaction = true;
+                Err(format!("Failed to append to database journal: {err}"))
+            }
+        }
+    }
+
+    fn compact(&mut self) -> Result<(), String> {
+        let content: String = self
+            .values
+            .iter()
+            .map(|(key, value)| JournalEntry::Set(key.clone(), value.clone()).to_line())
             .collect();
-        database
-            .projects
-            .sort_by_key(|p| p.config.name.clone().to_lowercase());
-        database.checkpoint()?;
-        Ok(database)
+        replace_file(&self.path, content.as_bytes(), self.durable_writes)?;
+        self.num_entries = self.values.len();
+        self.needs_compaction = false;
+        Ok(())
     }
+}
 
-    /// Checkpoint the database by writing its full state to disk.
-    ///
-    /// This is a no-op for in-memory databases.
-    pub fn checkpoint(&self) -> Result<(), String> {
-        let content =
-            serde_json::to_string_pretty(&self).expect("failed to serialize database values");
-        if let Some(path) = &self.path {
-            match std::fs::write(path, &content) {
-                Ok(()) => (),
-                Err(err) => return Err(format!("failed to write database: {err}")),
+impl DB for JournalDB {
+    fn get(&self, key: &str) -> Option<serde_json::Value> {
+        self.values.get(key).cloned()
+    }
+
+    fn set(&mut self, key: &str, value: serde_json::Value) -> Result<(), String> {
+        self.write_batch(vec![(key.to_string(), Some(value))])
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), String> {
+        self.write_batch(vec![(key.to_string(), None)])
+    }
+
+    fn keys(&self, prefix: &str) -> Vec<String> {
+        keys(&self.values, prefix)
+    }
+
+    fn write_batch(
+        &mut self,
+        changes: Vec<(String, Option<serde_json::Value>)>,
+    ) -> Result<(), String> {
+        let mut entries = vec![];
+        for (key, value) in changes {
+            match value {
+                None => {
+                    if self.values.remove(&key).is_some() {
+                        entries.push(JournalEntry::Delete(key));
+                    }
+                }
+                Some(value) => {
+                    if self.values.get(&key) != Some(&value) {
+                        self.values.insert(key.clone(), value.clone());
+                        entries.push(JournalEntry::Set(key, value));
+                    }
+                }
+            }
+        }
+        self.append(&entries)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        if self.needs_compaction {
+            return self.compact();
+        }
+        Ok(())
+    }
+}
+
+/// A database stored in Redis.
+///
+/// Each key is stored as a Redis string containing the JSON value,
+///     under the configured key prefix.
+/// All values are loaded into memory when the database is opened,
+///     so the agent must be the only writer of keys with the prefix.
+/// Batches of changes are written in a single Redis transaction.
+/// Changes that fail to be written are retried with the next batch.
+pub struct RedisDB {
+    client: redis::Client,
+    connection: Option<redis::Connection>,
+    key_prefix: String,
+    values: BTreeMap<String, serde_json::Value>,
+    pending: BTreeMap<String, Option<serde_json::Value>>,
+}
+
+impl RedisDB {
+    /// Connect to Redis and load all values with the configured key prefix.
+    pub fn new(config: &config::RedisConfig) -> Result<Self, String> {
+        let client = match redis::Client::open(config.url.as_str()) {
+            Ok(client) => client,
+            Err(err) => return Err(format!("invalid Redis URL {}: {err}", config.url)),
+        };
+        let mut db = Self {
+            client,
+            connection: None,
+            key_prefix: config.key_prefix.clone(),
+            values: Default::default(),
+            pending: Default::default(),
+        };
+        if let Err(err) = db.load() {
+            return Err(format!("failed to load database from Redis: {err}"));
+        }
+        eprintln!(
+            "Loaded {} values from Redis at {}",
+            db.values.len(),
+            config.url
+        );
+        Ok(db)
+    }
+
+    fn load(&mut self) -> Result<(), String> {
+        let pattern = format!("{}*", self.key_prefix);
+        let prefix_len = self.key_prefix.len();
+        let connection = self.connection().map_err(|err| err.to_string())?;
+        let mut redis_keys: Vec<String> = vec![];
+        let mut cursor: u64 = 0;
+        loop {
+            let (next_cursor, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .query(connection)
+                .map_err(|err| err.to_string())?;
+            redis_keys.extend(batch);
+            if next_cursor == 0 {
+                break;
+            }
+            cursor = next_cursor;
+        }
+        let mut values = BTreeMap::new();
+        for redis_key in redis_keys {
+            let json: Option<String> = redis::cmd("GET")
+                .arg(&redis_key)
+                .query(connection)
+                .map_err(|err| err.to_string())?;
+            let Some(json) = json else {
+                continue;
+            };
+            match serde_json::from_str(&json) {
+                Ok(value) => {
+                    values.insert(redis_key[prefix_len..].to_string(), value);
+                }
+                Err(err) => return Err(format!("value for key {redis_key} is corrupt: {err}")),
+            }
+        }
+        self.values = values;
+        Ok(())
+    }
+
+    fn connection(&mut self) -> redis::RedisResult<&mut redis::Connection> {
+        if self.connection.is_none() {
+            self.connection = Some(self.client.get_connection()?);
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+}
+
+impl DB for RedisDB {
+    fn get(&self, key: &str) -> Option<serde_json::Value> {
+        self.values.get(key).cloned()
+    }
+
+    fn set(&mut self, key: &str, value: serde_json::Value) -> Result<(), String> {
+        self.write_batch(vec![(key.to_string(), Some(value))])
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), String> {
+        self.write_batch(vec![(key.to_string(), None)])
+    }
+
+    fn keys(&self, prefix: &str) -> Vec<String> {
+        keys(&self.values, prefix)
+    }
+
+    fn write_batch(
+        &mut self,
+        changes: Vec<(String, Option<serde_json::Value>)>,
+    ) -> Result<(), String> {
+        for (key, value) in changes {
+            let is_change = match &value {
+                None => self.values.remove(&key).is_some(),
+                Some(value) => {
+                    self.values.insert(key.clone(), value.clone()).as_ref() != Some(value)
+                }
             };
+            if is_change {
+                self.pending.insert(key, value);
+            }
         }
-        *self.json_data.lock().unwrap() = content;
+        self.flush()
+    }
 
-        let mut tt = handlebars::Handlebars::new();
-        tt.register_template_string("status.html", STATUS_DOT_HTML)
-            .unwrap();
-        let rendered = tt.render("status.html", self).unwrap();
-        *self.html_data.lock().unwrap() = rendered;
+    fn flush(&mut self) -> Result<(), String> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (key, value) in &self.pending {
+            let redis_key = format!("{}{key}", self.key_prefix);
+            match value {
+                None => pipe.del(redis_key).ignore(),
+                Some(value) => pipe.set(redis_key, value.to_string()).ignore(),
+            };
+        }
+        let result = self
+            .connection()
+            .and_then(|connection| pipe.query::<()>(connection));
+        if let Err(err) = result {
+            // Reconnect on the next write in case the connection is broken.
+            self.connection = None;
+            return Err(format!("Failed to write database to Redis: {err}"));
+        }
+        self.pending.clear();
         Ok(())
     }
+}
 
-    pub fn json_data(&self) -> sync::Arc<sync::Mutex<String>> {
-        self.json_data.clone()
+/// A database that refuses all writes to an underlying database.
+///
+/// Writes that would change the database are logged and dropped.
+pub struct ReadOnlyDB {
+    db: Box<dyn DB>,
+}
+
+impl DB for ReadOnlyDB {
+    fn get(&self, key: &str) -> Option<serde_json::Value> {
+        self.db.get(key)
+    }
+
+    fn set(&mut self, key: &str, value: serde_json::Value) -> Result<(), String> {
+        if self.db.get(key).as_ref() != Some(&value) {
+            eprintln!("Database is read-only; refusing to write key {key}");
+        }
+        Ok(())
     }
 
-    pub fn html_data(&self) -> sync::Arc<sync::Mutex<String>> {
-        self.html_data.clone()
+    fn delete(&mut self, key: &str) -> Result<(), String> {
+        if self.db.get(key).is_some() {
+            eprintln!("Database is read-only; refusing to delete key {key}");
+        }
+        Ok(())
     }
+
+    fn keys(&self, prefix: &str) -> Vec<String> {
+        self.db.keys(prefix)
+    }
+}
+
+/// A database that holds the lock on an underlying on-disk database while it is open.
+pub struct LockedDB {
+    db: Box<dyn DB>,
+    _lock: std::fs::File,
+}
+
+impl DB for LockedDB {
+    fn get(&self, key: &str) -> Option<serde_json::Value> {
+        self.db.get(key)
+    }
+
+    fn set(&mut self, key: &str, value: serde_json::Value) -> Result<(), String> {
+        self.db.set(key, value)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), String> {
+        self.db.delete(key)
+    }
+
+    fn keys(&self, prefix: &str) -> Vec<String> {
+        self.db.keys(prefix)
+    }
+
+    fn write_batch(
+        &mut self,
+        changes: Vec<(String, Option<serde_json::Value>)>,
+    ) -> Result<(), String> {
+        self.db.write_batch(changes)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.db.flush()
+    }
+}
+
+/// A database that records how long writes to an underlying database take in the metrics.
+///
+/// It wraps the database that persists changes, so buffered changes are measured when flushed.
+pub struct MeasuredDB {
+    db: Box<dyn DB>,
+}
+
+impl MeasuredDB {
+    fn measure(
+        &mut self,
+        write: impl FnOnce(&mut dyn DB) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let start = std::time::Instant::now();
+        let result = write(&mut *self.db);
+        metrics::observe_since(&metrics::DATABASE_WRITE_DURATION, &[], start);
+        if result.is_err() {
+            metrics::increment(&metrics::DATABASE_WRITE_ERRORS, &[]);
+        }
+        result
+    }
+}
+
+impl DB for MeasuredDB {
+    fn get(&self, key: &str) -> Option<serde_json::Value> {
+        self.db.get(key)
+    }
+
+    fn set(&mut self, key: &str, value: serde_json::Value) -> Result<(), String> {
+        self.measure(|db| db.set(key, value))
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), String> {
+        self.measure(|db| db.delete(key))
+    }
+
+    fn keys(&self, prefix: &str) -> Vec<String> {
+        self.db.keys(prefix)
+    }
+
+    fn write_batch(
+        &mut self,
+        changes: Vec<(String, Option<serde_json::Value>)>,
+    ) -> Result<(), String> {
+        self.measure(|db| db.write_batch(changes))
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.measure(|db| db.flush())
+    }
+}
+
+/// A database that buffers changes and writes them to an underlying database in batches.
+///
+/// Buffered changes are flushed when a change is made after the flush interval has elapsed,
+///     and when [DB::flush] is called.
+pub struct CoalescingDB {
+    db: Box<dyn DB>,
+    dirty: BTreeMap<String, Option<serde_json::Value>>,
+    flush_interval: std::time::Duration,
+    last_flush: std::time::Instant,
+}
+
+impl CoalescingDB {
+    pub fn new(db: Box<dyn DB>, flush_interval: std::time::Duration) -> Self {
+        Self {
+            db,
+            dirty: Default::default(),
+            flush_interval,
+            last_flush: std::time::Instant::now(),
+        }
+    }
+
+    fn flush_if_due(&mut self) -> Result<(), String> {
+        if self.last_flush.elapsed() >= self.flush_interval {
+            return self.flush();
+        }
+        Ok(())
+    }
+}
+
+impl DB for CoalescingDB {
+    fn get(&self, key: &str) -> Option<serde_json::Value> {
+        match self.dirty.get(key) {
+            Some(value) => value.clone(),
+            None => self.db.get(key),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: serde_json::Value) -> Result<(), String> {
+        if self.get(key).as_ref() != Some(&value) {
+            self.dirty.insert(key.to_string(), Some(value));
+        }
+        self.flush_if_due()
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), String> {
+        if self.get(key).is_some() {
+            self.dirty.insert(key.to_string(), None);
+        }
+        self.flush_if_due()
+    }
+
+    fn keys(&self, prefix: &str) -> Vec<String> {
+        let mut keys: std::collections::BTreeSet<String> =
+            self.db.keys(prefix).into_iter().collect();
+        for (key, value) in self.dirty.range(prefix.to_string()..) {
+            if !key.starts_with(prefix) {
+                break;
+            }
+            match value {
+                None => keys.remove(key),
+                Some(_) => keys.insert(key.clone()),
+            };
+        }
+        keys.into_iter().collect()
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.last_flush = std::time::Instant::now();
+        // If the write fails the changes are retained by the underlying database,
+        //     which retries persisting them when it is next flushed.
+        let changes: Vec<_> = std::mem::take(&mut self.dirty).into_iter().collect();
+        if !changes.is_empty() {
+            self.db.write_batch(changes)?;
+        }
+        self.db.flush()
+    }
+}
+
+fn keys(values: &BTreeMap<String, serde_json::Value>, prefix: &str) -> Vec<String> {
+    values
+        .range(prefix.to_string()..)
+        .map(|(key, _)| key)
+        .take_while(|key| key.starts_with(prefix))
+        .cloned()
+        .collect()
 }
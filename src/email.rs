@@ -0,0 +1,350 @@
+//! Email notifications.
+
+use crate::config;
+use crate::metrics;
+use base64::Engine;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// A notification to send to operators of the agent.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Notification {
+    pub subject: String,
+    pub body: String,
+    pub kind: config::NotifyOn,
+    /// Structured description of the event, for notifiers that send machine-readable payloads.
+    pub event: serde_json::Value,
+    /// Files to attach, for notifiers that support attachments.
+    pub attachments: Vec<Attachment>,
+}
+
+/// A text file attached to a notification.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Attachment {
+    pub filename: String,
+    pub content: String,
+}
+
+/// Custom templates for the subject and body of notifications.
+pub struct Templates {
+    handlebars: handlebars::Handlebars<'static>,
+}
+
+impl Templates {
+    /// Load the templates from the files referenced in the config.
+    pub fn load(config: &config::NotificationTemplates) -> Result<Self, String> {
+        let mut handlebars = handlebars::Handlebars::new();
+        // Notifications are plain text, so HTML escaping would corrupt them.
+        handlebars.register_escape_fn(handlebars::no_escape);
+        for (name, path) in [("subject", &config.subject), ("body", &config.body)] {
+            let Some(path) = path else {
+                continue;
+            };
+            let template = match std::fs::read_to_string(path) {
+                Ok(template) => template,
+                Err(err) => {
+                    return Err(format!(
+                        "failed to read notification {name} template {path}: {err}"
+                    ))
+                }
+            };
+            if let Err(err) = handlebars.register_template_string(name, template) {
+                return Err(format!(
+                    "invalid notification {name} template {path}: {err}"
+                ));
+            }
+        }
+        Ok(Self { handlebars })
+    }
+
+    /// Replace the subject and body of the notification with the rendered templates.
+    ///
+    /// If a template fails to render, the existing subject or body is kept.
+    pub fn apply(&self, notification: &mut Notification, context: &serde_json::Value) {
+        for (name, field) in [
+            ("subject", &mut notification.subject),
+            ("body", &mut notification.body),
+        ] {
+            if !self.handlebars.has_template(name) {
+                continue;
+            }
+            match self.handlebars.render(name, context) {
+                Ok(rendered) => *field = rendered,
+                Err(err) => eprintln!("Failed to render notification {name} template: {err}"),
+            }
+        }
+        // Headers can't contain newlines, and template files usually end with one.
+        notification.subject = notification.subject.trim().replace('\n', " ");
+    }
+}
+
+/// A notifier along with the kinds of notifications it should send.
+pub struct Subscription {
+    /// Name of the notifier, used to retry notifications that failed to send.
+    pub name: String,
+    pub notify_on: Vec<config::NotifyOn>,
+    pub notifier: Box<dyn Notifier>,
+}
+
+/// The notifiers that notifications are sent with.
+pub struct Notifiers {
+    /// Notifiers for notifications about the agent, and about projects without their own notifiers.
+    pub agent: Vec<Subscription>,
+    /// Notifiers of the projects that have their own, keyed by the project's name.
+    pub projects: std::collections::HashMap<String, Vec<Subscription>>,
+}
+
+/// Something that can send notifications.
+pub trait Notifier: Send + Sync {
+    /// Send the notification.
+    fn notify(&self, notification: &Notification) -> Result<(), String>;
+}
+
+/// Send each notification with the notifier of its subscription.
+///
+/// The notifications are sent at the same time so that a slow notifier doesn't delay the others.
+/// Returns the result of each send, in order.
+pub fn send_all(sends: &[(&Subscription, &Notification)]) -> Vec<Result<(), String>> {
+    let results: Vec<Result<(), String>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = sends
+            .iter()
+            .map(|(subscription, notification)| {
+                scope.spawn(move || subscription.notifier.notify(notification))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| match handle.join() {
+                Ok(result) => result,
+                Err(_) => Err("notifier panicked".to_string()),
+            })
+            .collect()
+    });
+    for ((subscription, _), result) in sends.iter().zip(&results) {
+        let result = if result.is_ok() { "sent" } else { "failed" };
+        metrics::increment(&metrics::NOTIFICATIONS, &[&subscription.name, result]);
+    }
+    results
+}
+
+/// Client that sends notifications by email.
+///
+/// Emails are sent by piping the message to a sendmail-compatible command,
+///     or directly to an SMTP server if one is configured.
+pub struct Client {
+    config: config::EmailConfig,
+    smtp: Option<Smtp>,
+}
+
+struct Smtp {
+    transport: lettre::SmtpTransport,
+    envelope: lettre::address::Envelope,
+}
+
+impl Client {
+    pub fn new(config: config::EmailConfig) -> Result<Self, String> {
+        if config.to.is_empty() && config.cc.is_empty() && config.bcc.is_empty() {
+            return Err("email notifications require at least one recipient".to_string());
+        }
+        let smtp = match &config.smtp {
+            None => None,
+            Some(smtp_config) => Some(Smtp {
+                transport: smtp_transport(smtp_config)?,
+                envelope: envelope(&config)?,
+            }),
+        };
+        Ok(Self { config, smtp })
+    }
+
+    /// Check that the SMTP server, if one is configured, accepts connections.
+    pub fn test_connection(&self) -> Result<(), String> {
+        let Some(smtp) = &self.smtp else {
+            return Ok(());
+        };
+        match smtp.transport.test_connection() {
+            Ok(true) => Ok(()),
+            Ok(false) => Err("the server did not respond to a NOOP command".to_string()),
+            Err(err) => Err(format!("failed to connect: {err}")),
+        }
+    }
+
+    fn message(&self, notification: &Notification) -> String {
+        let mut headers = format!("From: {}\r\n", self.config.from);
+        // Sendmail removes the Bcc header before sending, but SMTP servers send it as is.
+        let bcc: &[String] = if self.smtp.is_some() {
+            &[]
+        } else {
+            &self.config.bcc
+        };
+        for (name, addresses) in [
+            ("To", &self.config.to[..]),
+            ("Cc", &self.config.cc[..]),
+            ("Bcc", bcc),
+        ] {
+            if !addresses.is_empty() {
+                headers.push_str(&format!("{name}: {}\r\n", addresses.join(", ")));
+            }
+        }
+        if let Some(reply_to) = &self.config.reply_to {
+            headers.push_str(&format!("Reply-To: {reply_to}\r\n"));
+        }
+        headers.push_str(&format!("Subject: {}\r\n", notification.subject));
+        let body = notification.body.replace('\n', "\r\n");
+        if notification.attachments.is_empty() {
+            return format!("{headers}Content-Type: text/plain; charset=UTF-8\r\n\r\n{body}\r\n");
+        }
+        // Attachments are base64 encoded, so the boundary can't appear in them.
+        // It can only appear in the body if someone is trying very hard.
+        let boundary = "cdagent-boundary-b3a1f0c2";
+        let mut message = format!(
+            "{headers}MIME-Version: 1.0\r\nContent-Type: multipart/mixed; boundary=\"{boundary}\"\r\n\r\n\
+            --{boundary}\r\nContent-Type: text/plain; charset=UTF-8\r\n\r\n{body}\r\n"
+        );
+        for attachment in &notification.attachments {
+            let encoded = base64::engine::general_purpose::STANDARD.encode(&attachment.content);
+            message.push_str(&format!(
+                "--{boundary}\r\nContent-Type: text/plain; charset=UTF-8\r\n\
+                Content-Disposition: attachment; filename=\"{}\"\r\n\
+                Content-Transfer-Encoding: base64\r\n\r\n",
+                attachment.filename,
+            ));
+            // Lines in emails are limited in length.
+            for line in encoded.as_bytes().chunks(76) {
+                message.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+                message.push_str("\r\n");
+            }
+        }
+        message.push_str(&format!("--{boundary}--\r\n"));
+        message
+    }
+
+    fn sendmail(&self, message: &str) -> Result<(), String> {
+        let pieces = match shlex::split(&self.config.sendmail_command) {
+            None => {
+                return Err(format!(
+                    "invalid sendmail command {}",
+                    self.config.sendmail_command
+                ))
+            }
+            Some(pieces) => pieces,
+        };
+        let program = match pieces.first() {
+            None => return Err("empty sendmail command".into()),
+            Some(program) => program,
+        };
+        let mut child = match Command::new(program)
+            .args(&pieces[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(err) => return Err(format!("failed to run sendmail command: {err}")),
+        };
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        if let Err(err) = stdin.write_all(message.as_bytes()) {
+            return Err(format!("failed to write email to sendmail command: {err}"));
+        }
+        // Closing stdin signals the end of the message.
+        drop(stdin);
+        let output = match child.wait_with_output() {
+            Ok(output) => output,
+            Err(err) => return Err(format!("failed to run sendmail command: {err}")),
+        };
+        if !output.status.success() {
+            return Err(format!(
+                "sendmail command failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Notifier for Client {
+    fn notify(&self, notification: &Notification) -> Result<(), String> {
+        let message = self.message(notification);
+        match &self.smtp {
+            None => self.sendmail(&message),
+            Some(smtp) => {
+                match lettre::Transport::send_raw(
+                    &smtp.transport,
+                    &smtp.envelope,
+                    message.as_bytes(),
+                ) {
+                    Ok(_) => Ok(()),
+                    Err(err) => Err(format!("failed to send email with SMTP: {err}")),
+                }
+            }
+        }
+    }
+}
+
+fn smtp_transport(config: &config::SmtpConfig) -> Result<lettre::SmtpTransport, String> {
+    use lettre::transport::smtp::client::{Tls, TlsParameters};
+    let tls_parameters = || match TlsParameters::builder(config.host.clone())
+        .dangerous_accept_invalid_certs(config.accept_invalid_certs)
+        .build()
+    {
+        Ok(tls_parameters) => Ok(tls_parameters),
+        Err(err) => Err(format!("invalid TLS configuration for SMTP server: {err}")),
+    };
+    let (tls, default_port) = match config.security {
+        config::SmtpSecurity::Starttls => (Tls::Required(tls_parameters()?), 587),
+        config::SmtpSecurity::Tls => (Tls::Wrapper(tls_parameters()?), 465),
+        config::SmtpSecurity::None => (Tls::None, 25),
+    };
+    let mut builder = lettre::SmtpTransport::builder_dangerous(&config.host)
+        .port(config.port.unwrap_or(default_port))
+        .tls(tls)
+        .timeout(Some(std::time::Duration::from_secs(
+            config.timeout_seconds.unwrap_or(10),
+        )));
+    if let Some(username) = &config.username {
+        let password = smtp_password(config)?;
+        builder = builder.credentials(lettre::transport::smtp::authentication::Credentials::new(
+            username.clone(),
+            password,
+        ));
+    }
+    Ok(builder.build())
+}
+
+fn smtp_password(config: &config::SmtpConfig) -> Result<String, String> {
+    match (&config.password, &config.password_env, &config.password_file) {
+        (Some(password), None, None) => Ok(password.clone()),
+        (None, Some(name), None) => match std::env::var(name) {
+            Ok(password) => Ok(password),
+            Err(err) => Err(format!(
+                "failed to read SMTP password from environment variable {name}: {err}"
+            )),
+        },
+        (None, None, Some(path)) => match std::fs::read_to_string(path) {
+            Ok(password) => Ok(password.trim().to_string()),
+            Err(err) => Err(format!("failed to read SMTP password file {path}: {err}")),
+        },
+        _ => Err(
+            "exactly one of password, password_env or password_file must be set for SMTP authentication"
+                .to_string(),
+        ),
+    }
+}
+
+/// Returns the SMTP envelope for emails sent with the config.
+fn envelope(config: &config::EmailConfig) -> Result<lettre::address::Envelope, String> {
+    let address = |mailbox: &String| match mailbox.parse::<lettre::message::Mailbox>() {
+        Ok(mailbox) => Ok(mailbox.email),
+        Err(err) => Err(format!("invalid email address {mailbox}: {err}")),
+    };
+    let from = address(&config.from)?;
+    let mut recipients = vec![];
+    for mailbox in config.to.iter().chain(&config.cc).chain(&config.bcc) {
+        recipients.push(address(mailbox)?);
+    }
+    match lettre::address::Envelope::new(Some(from), recipients) {
+        Ok(envelope) => Ok(envelope),
+        Err(err) => Err(format!("invalid email envelope: {err}")),
+    }
+}
@@ -0,0 +1,152 @@
+//! Encrypted values in config files, so that configs containing secrets can be committed.
+//!
+//! An encrypted value is a string with the `!encrypted` tag, like `auth_token: !encrypted AbC...`,
+//!     produced by `cdagent encrypt`.
+//! Values are encrypted with ChaCha20-Poly1305 using a 256-bit key,
+//!     given base64 encoded in the `CDAGENT_CONFIG_KEY` environment variable
+//!     or in the file at the path in `CDAGENT_CONFIG_KEY_FILE`.
+//! A key can be generated with `head -c 32 /dev/urandom | base64`.
+
+use base64::Engine;
+use ring::aead;
+use ring::rand::SecureRandom;
+
+/// The YAML tag of encrypted values.
+const TAG: &str = "encrypted";
+
+/// Replace the encrypted values in a parsed config file with their decrypted values.
+///
+/// The key is only read if there are encrypted values.
+pub fn decrypt_values(value: &mut serde_yaml::Value) -> Result<(), String> {
+    let mut key = None;
+    decrypt_values_with(value, &mut key)
+}
+
+fn decrypt_values_with(
+    value: &mut serde_yaml::Value,
+    key: &mut Option<aead::LessSafeKey>,
+) -> Result<(), String> {
+    match value {
+        serde_yaml::Value::Tagged(tagged) if tagged.tag == TAG => {
+            let Some(ciphertext) = tagged.value.as_str() else {
+                return Err("encrypted values must be strings".to_string());
+            };
+            let key = match key {
+                Some(key) => key,
+                None => key.insert(read_key()?),
+            };
+            *value = serde_yaml::Value::String(decrypt(key, ciphertext)?);
+        }
+        serde_yaml::Value::Tagged(tagged) => decrypt_values_with(&mut tagged.value, key)?,
+        serde_yaml::Value::Sequence(values) => {
+            for value in values {
+                decrypt_values_with(value, key)?;
+            }
+        }
+        serde_yaml::Value::Mapping(mapping) => {
+            for (_, value) in mapping.iter_mut() {
+                decrypt_values_with(value, key)?;
+            }
+        }
+        serde_yaml::Value::Null
+        | serde_yaml::Value::Bool(_)
+        | serde_yaml::Value::Number(_)
+        | serde_yaml::Value::String(_) => {}
+    }
+    Ok(())
+}
+
+/// Encrypt a value with the key, returning it in the form to paste into a config file.
+pub fn encrypt(plaintext: &str) -> Result<String, String> {
+    let key = read_key()?;
+    let mut nonce = [0u8; aead::NONCE_LEN];
+    if ring::rand::SystemRandom::new().fill(&mut nonce).is_err() {
+        return Err("failed to generate a nonce".to_string());
+    }
+    let mut data = plaintext.as_bytes().to_vec();
+    if key
+        .seal_in_place_append_tag(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::empty(),
+            &mut data,
+        )
+        .is_err()
+    {
+        return Err("failed to encrypt the value".to_string());
+    }
+    let mut encrypted = nonce.to_vec();
+    encrypted.append(&mut data);
+    Ok(format!(
+        "!{TAG} {}",
+        base64::engine::general_purpose::STANDARD.encode(encrypted)
+    ))
+}
+
+fn decrypt(key: &aead::LessSafeKey, ciphertext: &str) -> Result<String, String> {
+    let invalid = || {
+        "failed to decrypt an encrypted value: it is corrupt or was encrypted with a different key"
+            .to_string()
+    };
+    let Ok(mut data) = base64::engine::general_purpose::STANDARD.decode(ciphertext.trim()) else {
+        return Err(invalid());
+    };
+    if data.len() < aead::NONCE_LEN {
+        return Err(invalid());
+    }
+    let mut nonce = [0u8; aead::NONCE_LEN];
+    nonce.copy_from_slice(&data[..aead::NONCE_LEN]);
+    let Ok(plaintext) = key.open_in_place(
+        aead::Nonce::assume_unique_for_key(nonce),
+        aead::Aad::empty(),
+        &mut data[aead::NONCE_LEN..],
+    ) else {
+        return Err(invalid());
+    };
+    match String::from_utf8(plaintext.to_vec()) {
+        Ok(plaintext) => Ok(plaintext),
+        Err(_) => Err(invalid()),
+    }
+}
+
+fn read_key() -> Result<aead::LessSafeKey, String> {
+    let encoded = match (
+        std::env::var("CDAGENT_CONFIG_KEY"),
+        std::env::var("CDAGENT_CONFIG_KEY_FILE"),
+    ) {
+        (Ok(key), _) => key,
+        (Err(_), Ok(path)) => match std::fs::read_to_string(&path) {
+            Ok(key) => key,
+            Err(err) => return Err(format!("failed to read config key file {path}: {err}")),
+        },
+        (Err(_), Err(_)) => {
+            return Err(
+                "encrypted values need a key, but neither CDAGENT_CONFIG_KEY nor CDAGENT_CONFIG_KEY_FILE is set"
+                    .to_string(),
+            )
+        }
+    };
+    let key = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|key| aead::UnboundKey::new(&aead::CHACHA20_POLY1305, &key).ok());
+    match key {
+        Some(key) => Ok(aead::LessSafeKey::new(key)),
+        None => Err("the config key must be 32 bytes, base64 encoded".to_string()),
+    }
+}
+
+/// Returns the provided number of bytes from the operating system's secure random number generator.
+///
+/// This is used for keys and tokens, as well as for IDs that only need to be unique.
+pub fn random_bytes(n: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; n];
+    ring::rand::SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("the operating system's random number generator failed");
+    bytes
+}
+
+/// Returns the provided number of random bytes in hex.
+pub fn random_hex(n: usize) -> String {
+    random_bytes(n).iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -0,0 +1,60 @@
+//! The exit statuses of the agent.
+//!
+//! Each kind of failure has its own status so that wrapper scripts and service units can react to it;
+//!     e.g., restarting the agent won't fix an invalid config, but might fix a network failure.
+
+use std::fmt;
+
+/// The agent failed while running, or a subcommand failed.
+pub const FAILURE: i32 = 1;
+
+// Status 2 is for invalid command line arguments, which clap exits with.
+
+/// The config couldn't be read or is invalid.
+pub const CONFIG: i32 = 3;
+
+/// The database couldn't be opened, e.g. because another agent is using it.
+pub const DATABASE: i32 = 4;
+
+/// The agent installed an update and exited to be restarted by its service manager.
+///
+/// This is `EX_TEMPFAIL`, which service managers treat as a failure and so restart the agent.
+pub const RESTART: i32 = 75;
+
+/// An error that the agent exits with.
+#[derive(Debug)]
+pub struct Error {
+    pub status: i32,
+    pub message: String,
+}
+
+impl Error {
+    pub fn config(message: String) -> Self {
+        Self {
+            status: CONFIG,
+            message,
+        }
+    }
+
+    pub fn database(message: String) -> Self {
+        Self {
+            status: DATABASE,
+            message,
+        }
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Self {
+            status: FAILURE,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
@@ -1,10 +1,11 @@
 //! A GitHub client.
 
+use crate::metrics;
+use crate::trace;
+use sha2::Digest;
 use std::time;
 use std::{collections::HashMap, time::Duration};
 
-use crate::database;
-
 /// A GitHub client.
 ///
 /// This is a "good citizen" client that honors rate limiting information,
@@ -12,22 +13,28 @@ use crate::database;
 pub struct Client {
     agent: ureq::Agent,
     data: Data,
+    /// Whether the most recent request reached GitHub, or `None` if no request has been made.
+    reachable: Option<Result<(), String>>,
 }
 
 #[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
 pub struct Data {
     cache: HashMap<String, (String, WorkflowRun)>,
     rate_limit_resource_to_infos: HashMap<String, RateLimitInfo>,
-    auth_token_to_rate_limit_resource: HashMap<String, String>,
+    /// Keyed by [fingerprint]s of the auth tokens, as the data is shown on the status pages.
+    auth_token_fingerprint_to_rate_limit_resource: HashMap<String, String>,
 }
 
 impl Client {
-    pub fn new(database: &database::Database) -> Self {
+    pub fn new(data: Data) -> Self {
         let agent = ureq::AgentBuilder::new()
             .timeout(Duration::from_millis(1000))
             .build();
-        let data = database.github_client.clone();
-        Self { agent, data }
+        Self {
+            agent,
+            data,
+            reachable: None,
+        }
     }
 
     /// Get the latest successful workflow run for the provided repo in branch.
@@ -46,14 +53,8 @@ impl Client {
         self.check_for_rate_limiting(auth_token)?;
 
         let url = format!["https://api.github.com/repos/{user}/{repo}/actions/runs?branch={branch}&event=push&status=success&per_page=1&exclude_pull_requests=true"];
-        let mut request = self
-            .agent
-            .get(&url)
-            .set("Accept", "application/vnd.github+json")
-            .set("X-GitHub-Api-Version", "2022-11-28");
-        if !auth_token.is_empty() {
-            request = request.set("Authorization", &format!["Bearer {auth_token}"]);
-        }
+        let mut request = self.request(&url, auth_token);
+        let cached = self.data.cache.contains_key(&url);
         if let Some((etag, _)) = self.data.cache.get(&url) {
             request = request.set("if-none-match", etag);
             // Adding an authorization header with a dummy value seems
@@ -62,24 +63,17 @@ impl Client {
             // https://stackoverflow.com/questions/60885496/github-304-responses-seem-to-count-against-rate-limit
             request = request.set("authorization", "none");
         }
-        let response = match request.call() {
-            Ok(response) => response,
-            Err(err) => return Err(format!("failed to make GitHub API request: {err}")),
-        };
-        if let Some(rate_limit_info) = RateLimitInfo::build(&response) {
-            self.data
-                .auth_token_to_rate_limit_resource
-                .insert(auth_token.to_string(), rate_limit_info.resource.clone());
-            self.data
-                .rate_limit_resource_to_infos
-                .insert(rate_limit_info.resource.clone(), rate_limit_info);
-        }
+        let response = self.call(request, auth_token, "workflow_runs")?;
 
         if response.status() == 304 {
             if let Some((_, workflow_run)) = self.data.cache.get(&url) {
+                metrics::increment(&metrics::GITHUB_CACHE_REQUESTS, &["hit"]);
                 return Ok(workflow_run.clone());
             }
         }
+        if cached {
+            metrics::increment(&metrics::GITHUB_CACHE_REQUESTS, &["miss"]);
+        }
 
         let etag = response.header("etag").map(str::to_string);
         let body: String = match response.into_string() {
@@ -110,32 +104,229 @@ impl Client {
         Ok(workflow_run)
     }
 
-    fn check_for_rate_limiting(&self, auth_token: &str) -> Result<(), String> {
-        let resource = match self.data.auth_token_to_rate_limit_resource.get(auth_token) {
-            None => return Ok(()),
-            Some(resource) => resource,
+    /// Get the most recent successful workflow run for the commit with the provided full SHA.
+    ///
+    /// Returns an error if there have been no successful workflow runs for the commit.
+    pub fn get_successful_workflow_run_for_commit(
+        &mut self,
+        user: &str,
+        repo: &str,
+        sha: &str,
+        auth_token: &str,
+    ) -> Result<WorkflowRun, String> {
+        self.check_for_rate_limiting(auth_token)?;
+
+        let url = format!["https://api.github.com/repos/{user}/{repo}/actions/runs?head_sha={sha}&status=success&per_page=1&exclude_pull_requests=true"];
+        let request = self.request(&url, auth_token);
+        let response = self.call(request, auth_token, "workflow_runs")?;
+        let body: String = match response.into_string() {
+            Ok(body) => body,
+            Err(err) => return Err(format!("failed to read GitHub API response: {err}")),
+        };
+        let mut build: Build = match serde_json::from_str(&body) {
+            Ok(build) => build,
+            Err(err) => {
+                return Err(format!(
+                    "failed to deserialize GitHub API json response: {err}"
+                ))
+            }
+        };
+        match build.workflow_runs.pop() {
+            Some(workflow_run) => Ok(workflow_run),
+            None => Err(format!(
+                "GitHub actions has no successful runs for commit {sha}"
+            )),
+        }
+    }
+
+    /// Get the signature verification status of a commit.
+    pub fn get_commit_verification(
+        &mut self,
+        user: &str,
+        repo: &str,
+        sha: &str,
+        auth_token: &str,
+    ) -> Result<CommitVerification, String> {
+        self.check_for_rate_limiting(auth_token)?;
+
+        let url = format!["https://api.github.com/repos/{user}/{repo}/commits/{sha}"];
+        let request = self.request(&url, auth_token);
+        let response = self.call(request, auth_token, "commits")?;
+        let body: String = match response.into_string() {
+            Ok(body) => body,
+            Err(err) => return Err(format!("failed to read GitHub API response: {err}")),
+        };
+        let commit: Commit = match serde_json::from_str(&body) {
+            Ok(commit) => commit,
+            Err(err) => {
+                return Err(format!(
+                    "failed to deserialize GitHub API json response: {err}"
+                ))
+            }
+        };
+        Ok(commit.commit.verification)
+    }
+
+    /// Get the latest release of the repo, which excludes drafts and prereleases.
+    pub fn get_latest_release(
+        &mut self,
+        user: &str,
+        repo: &str,
+        auth_token: &str,
+    ) -> Result<Release, String> {
+        self.check_for_rate_limiting(auth_token)?;
+
+        let url = format!["https://api.github.com/repos/{user}/{repo}/releases/latest"];
+        let request = self.request(&url, auth_token);
+        let response = self.call(request, auth_token, "releases")?;
+        let body: String = match response.into_string() {
+            Ok(body) => body,
+            Err(err) => return Err(format!("failed to read GitHub API response: {err}")),
+        };
+        match serde_json::from_str(&body) {
+            Ok(release) => Ok(release),
+            Err(err) => Err(format!(
+                "failed to deserialize GitHub API json response: {err}"
+            )),
+        }
+    }
+
+    fn request(&self, url: &str, auth_token: &str) -> ureq::Request {
+        let mut request = self
+            .agent
+            .get(url)
+            .set("Accept", "application/vnd.github+json")
+            .set("X-GitHub-Api-Version", "2022-11-28");
+        if !auth_token.is_empty() {
+            request = request.set("Authorization", &format!["Bearer {auth_token}"]);
+        }
+        request
+    }
+
+    /// Make the request and record the rate limiting information in the response.
+    ///
+    /// The endpoint names the kind of request in the request latency metric.
+    fn call(
+        &mut self,
+        request: ureq::Request,
+        auth_token: &str,
+        endpoint: &str,
+    ) -> Result<ureq::Response, String> {
+        let mut span = trace::Span::child(&format!("GitHub {endpoint}"), trace::Kind::Client);
+        span.attribute("http.request.method", request.method());
+        span.attribute("url.full", request.url());
+        let start = time::Instant::now();
+        let result = request.call();
+        metrics::observe_since(&metrics::GITHUB_REQUEST_DURATION, &[endpoint], start);
+        match &result {
+            Ok(response) => span.attribute("http.response.status_code", response.status()),
+            Err(err) => {
+                if let ureq::Error::Status(status, _) = err {
+                    span.attribute("http.response.status_code", *status);
+                }
+                span.fail(&err.to_string());
+            }
+        }
+        // Error statuses are responses from GitHub, so only transport errors count.
+        self.reachable = Some(match &result {
+            Err(ureq::Error::Transport(err)) => Err(err.to_string()),
+            _ => Ok(()),
+        });
+        let response = match result {
+            Ok(response) => response,
+            Err(err) => return Err(format!("failed to make GitHub API request: {err}")),
         };
-        let info = match self.data.rate_limit_resource_to_infos.get(resource) {
+        if let Some(rate_limit_info) = RateLimitInfo::build(&response) {
+            metrics::set(
+                &metrics::GITHUB_RATE_LIMIT_REMAINING,
+                &[&rate_limit_info.resource],
+                rate_limit_info.remaining as f64,
+            );
+            self.data
+                .auth_token_fingerprint_to_rate_limit_resource
+                .insert(fingerprint(auth_token), rate_limit_info.resource.clone());
+            self.data
+                .rate_limit_resource_to_infos
+                .insert(rate_limit_info.resource.clone(), rate_limit_info);
+        }
+        Ok(response)
+    }
+
+    fn check_for_rate_limiting(&self, auth_token: &str) -> Result<(), String> {
+        let (info, seconds_to_reset) = match self.exhausted_rate_limit(auth_token) {
             None => return Ok(()),
             Some(info) => info,
         };
+        Err(format!("reached GitHub API rate limit for this auth token; resource={}, limit={}, seconds_to_reset={seconds_to_reset}", info.resource, info.limit))
+    }
+
+    /// Returns the rate limit the auth token has exhausted, if any,
+    ///     along with the number of seconds until it resets.
+    ///
+    /// While the rate limit is exhausted, requests with the auth token are rejected without being made.
+    pub fn exhausted_rate_limit(&self, auth_token: &str) -> Option<(&RateLimitInfo, u64)> {
+        let resource = self
+            .data
+            .auth_token_fingerprint_to_rate_limit_resource
+            .get(&fingerprint(auth_token))?;
+        let info = self.data.rate_limit_resource_to_infos.get(resource)?;
         if info.remaining > 0 {
-            return Ok(());
+            return None;
         }
         let current_timestamp = time::SystemTime::now()
             .duration_since(time::UNIX_EPOCH)
             .expect("current time should be after the Unix epoch")
             .as_secs();
-        let seconds_to_reset = match info.reset.checked_sub(current_timestamp) {
-            None => return Ok(()),
-            Some(s) => s,
-        };
-        Err(format!("reached GitHub API rate limit for this auth token; resource={resource}, limit={}, seconds_to_reset={seconds_to_reset}", info.limit))
+        let seconds_to_reset = info.reset.checked_sub(current_timestamp)?;
+        Some((info, seconds_to_reset))
+    }
+
+    /// Returns whether the most recent request reached GitHub, or `None` if no request has been made.
+    pub fn reachable(&self) -> Option<&Result<(), String>> {
+        self.reachable.as_ref()
+    }
+
+    /// The most recent rate limit information for each resource.
+    pub fn rate_limits(&self) -> impl Iterator<Item = &RateLimitInfo> {
+        self.data.rate_limit_resource_to_infos.values()
+    }
+
+    /// Data to persist across runs of the agent.
+    pub fn data(&self) -> &Data {
+        &self.data
     }
+}
+
+/// Returns an identifier of the auth token that doesn't reveal it.
+fn fingerprint(auth_token: &str) -> String {
+    sha2::Sha256::digest(auth_token.as_bytes())[..8]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
 
-    pub fn persist(&self, database: &mut database::Database) {
-        database.github_client = self.data.clone();
+/// Parse a reference to a repository that includes its owner into the owner and name.
+///
+/// The reference can be `owner/name`, a URL like `https://github.com/owner/name.git`,
+///     or an SSH remote like `git@github.com:owner/name.git`.
+/// Returns `None` if the reference is not in one of these forms.
+pub fn parse_repo(reference: &str) -> Option<(&str, &str)> {
+    let path = [
+        "https://github.com/",
+        "http://github.com/",
+        "ssh://git@github.com/",
+        "git@github.com:",
+    ]
+    .iter()
+    .find_map(|prefix| reference.strip_prefix(prefix))
+    .unwrap_or(reference);
+    let path = path.strip_suffix('/').unwrap_or(path);
+    let path = path.strip_suffix(".git").unwrap_or(path);
+    let (owner, name) = path.split_once('/')?;
+    if owner.is_empty() || name.is_empty() || name.contains('/') || owner.contains(':') {
+        return None;
     }
+    Some((owner, name))
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -154,6 +345,37 @@ pub struct WorkflowRun {
     pub updated_at: String,
 }
 
+#[derive(Debug, serde::Deserialize)]
+struct Commit {
+    commit: CommitDetails,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CommitDetails {
+    verification: CommitVerification,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    pub assets: Vec<ReleaseAsset>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct ReleaseAsset {
+    pub name: String,
+    /// API URL of the asset, which serves its contents when `application/octet-stream` is accepted.
+    pub url: String,
+}
+
+/// Signature verification status of a commit.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct CommitVerification {
+    pub verified: bool,
+    /// GitHub's reason for the verification status; e.g., `valid` or `unsigned`.
+    pub reason: String,
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct RateLimitInfo {
     pub limit: u64,
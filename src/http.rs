@@ -0,0 +1,1430 @@
+//! The agent's HTTP server.
+//!
+//! This serves the status page, the JSON API, and the endpoints used to approve redeployments.
+//!
+//! The versioned JSON API has the following endpoints, which require read-only access.
+//! Its field names are stable; see the [api] module for the types returned.
+//!
+//! - `GET /api/v1/projects` returns all of the projects.
+//! - `GET /api/v1/projects/{name}` returns the project.
+//! - `GET /api/v1/projects/{name}/runs` returns a page of the project's runs, most recent first.
+//!   The following query parameters are supported:
+//!   - `page`, numbered from 1.
+//!   - `since`, an RFC 3339 timestamp like `2024-01-23T14:16:24Z`;
+//!     only runs that started at or after it are returned.
+//!   - `status`, either `succeeded` or `failed`.
+//! - `GET /api/v1/rate_limits` returns the state of the GitHub API rate limits.
+//! - `GET /api/v1/agent` returns the agent's version, uptime and configuration,
+//!   and the number of projects in each state.
+//! - `GET /api/v1/audit` returns a page of the audit log of manual actions, most recent first.
+//!   The `page` query parameter is supported.
+//! - `GET /api/v1/openapi.json` returns an OpenAPI document describing the HTTP API,
+//!   without requiring authentication.
+//!
+//! If CORS is configured, responses from these endpoints allow the configured origins to read them,
+//! and `OPTIONS` preflight requests are answered without requiring authentication.
+//!
+//! The API has the following endpoints for changing projects, which require admin access:
+//!
+//! - `POST /api/projects/{name}/pause` pauses the project.
+//! - `POST /api/projects/{name}/resume` resumes the project.
+//!   Projects paused in the config can't be resumed.
+//! - `POST /api/projects/{name}/deploy` polls the project immediately and redeploys it
+//!   if there is a new workflow run.
+//!   With `?skip_wait=true` the soak time of promoted projects is skipped.
+//!   With `?force=true` the workflow run of the most recent redeployment is redeployed again,
+//!   and with `?sha={sha}` the workflow run for the commit with the full SHA is redeployed,
+//!   in both cases without waiting for upstream projects or approval.
+//!   The response contains the ID of the deployment,
+//!   whose status can be read at `GET /api/deployments/{id}` with read-only access.
+//! - `POST /api/projects/{name}/rollback` redeploys the workflow run of the most recent
+//!   successful redeployment before the current one.
+//!   Like deploy requests, the response contains the ID of the deployment.
+//! - `POST /api/projects/{name}/approve` approves the project's redeployment awaiting approval,
+//!   and `POST /api/projects/{name}/reject` rejects it.
+//! - `POST /api/v1/projects` adds a project that is not in the config file.
+//!   The body is the project's config as JSON, with the same fields as in the config file,
+//!   except that templates can't be used and secrets can only be provided in files.
+//!   Added projects are persisted in the database and run alongside the projects in the config file.
+//! - `DELETE /api/v1/projects/{name}` removes a project that was added through the API.
+//!
+//! Tokens and users can instead be given permissions on specific projects,
+//! in which case they can only see and change those projects;
+//! see [config::ProjectPermissions].
+//!
+//! Requests from browsers, which accept HTML, are redirected back to the project's page.
+//! These endpoints can be restricted to allowed IP addresses and rate limited;
+//! see [config::ActionsConfig].
+//! Requests from browsers must include a CSRF token; see the [csrf] module.
+//!
+//! `GET /api/projects/{name}/runs/{id}/logs/stream` streams the output of the redeployment
+//! of the workflow run with the provided ID as server-sent events, with read-only access.
+//! If the redeployment is in progress its output is streamed as it is produced;
+//! otherwise the output of its most recent redeployment is replayed.
+//! See the [live] module for the events and how clients reconnect.
+//!
+//! `GET /api/projects/{name}/runs/{id}/steps/{n}/log` downloads the output of the `n`th step,
+//! numbered from 0, of the most recent redeployment of the workflow run with the provided ID
+//! as a plain text file, with read-only access.
+//!
+//! `GET /events` pushes an `update` server-sent event whenever the state of a project changes,
+//! with read-only access.
+//! The status pages subscribe to it so that they update without being refreshed.
+//! The `since` query parameter is the version of the state the client has,
+//! which the pages are rendered with; see [status::Pages::events].
+//!
+//! `GET /static/{name}` returns the static files used by the status pages,
+//! without requiring authentication.
+//!
+//! `GET /audit` shows the audit log of manual actions, with read-only access.
+//!
+//! If an OIDC provider is configured, browsers without credentials are redirected
+//! to `GET /auth/login`, which sends them to the provider to log in.
+//! The provider sends them back to `GET /auth/callback`, which gives them a session cookie;
+//! see the [oidc] module.
+//! `GET /auth/logout` ends the session.
+//!
+//! `GET /metrics` returns the agent's metrics for Prometheus to scrape, if configured,
+//! with read-only access; see the [metrics] module.
+//!
+//! `GET /projects/{name}/badge.svg` returns a badge with the outcome and time of the project's
+//! most recent redeployment, with read-only access to the project.
+//! If `public_badges` is set in the auth config, badges don't require authentication,
+//! so that they can be embedded in READMEs.
+
+use crate::api;
+use crate::approval;
+use crate::assets;
+use crate::audit;
+use crate::config;
+use crate::control;
+use crate::csrf;
+use crate::live;
+use crate::metrics;
+use crate::oidc;
+use crate::slack;
+use crate::status;
+use crate::throttle;
+use base64::Engine;
+use std::sync;
+use std::thread;
+use std::time;
+
+type Response = tiny_http::Response<std::io::Cursor<Vec<u8>>>;
+
+/// The number of threads serving requests.
+const WORKERS: usize = 8;
+
+pub struct Service {
+    pages: status::Pages,
+    approvals: Option<approval::Approvals>,
+    slack_interactions: Option<slack::Interactions>,
+    controls: Option<control::Controls>,
+    auth: Option<Auth>,
+    cors: Option<config::CorsConfig>,
+    throttle: throttle::Throttle,
+    csrf: csrf::Csrf,
+    /// The metrics exporters, keyed by the path they are served at.
+    metrics: Vec<(String, Box<dyn metrics::Exporter>)>,
+}
+
+impl Service {
+    pub fn new(
+        pages: status::Pages,
+        approvals: Option<approval::Approvals>,
+        slack_interactions: Option<slack::Interactions>,
+        controls: Option<control::Controls>,
+        auth: Option<&config::AuthConfig>,
+        http_config: &config::HttpConfig,
+        metrics_config: Option<&config::MetricsConfig>,
+    ) -> Result<Self, String> {
+        let auth = match auth {
+            None => None,
+            Some(config) => Some(Auth::new(config)?),
+        };
+        Ok(Self {
+            pages,
+            approvals,
+            slack_interactions,
+            controls,
+            auth,
+            cors: http_config.cors.clone(),
+            throttle: throttle::Throttle::new(&http_config.actions)?,
+            csrf: csrf::Csrf::new(),
+            metrics: metrics::exporters(metrics_config),
+        })
+    }
+
+    /// Start serving requests on the provided address.
+    ///
+    /// Requests are served concurrently by a pool of [WORKERS] threads.
+    pub fn start(self, address: &str) -> Result<Server, String> {
+        let server = match tiny_http::Server::http(address) {
+            Ok(server) => sync::Arc::new(server),
+            Err(err) => return Err(format!("failed to listen on {address}: {err}")),
+        };
+        let service = sync::Arc::new(self);
+        let workers: sync::Arc<(sync::Mutex<usize>, sync::Condvar)> = Default::default();
+        *workers.0.lock().unwrap() = WORKERS;
+        for _ in 0..WORKERS {
+            let server = server.clone();
+            let service = service.clone();
+            let workers = workers.clone();
+            thread::spawn(move || {
+                service.serve(&server);
+                let (running, stopped) = &*workers;
+                *running.lock().unwrap() -= 1;
+                stopped.notify_all();
+            });
+        }
+        Ok(Server { server, workers })
+    }
+
+    /// Serve requests received by the server until it shuts down.
+    fn serve(&self, server: &tiny_http::Server) {
+        for mut request in server.incoming_requests() {
+            // Requests for events wait for something to happen,
+            // so they are served on their own threads rather than occupying a worker.
+            if let Some(wait) = self.long_poll(&request) {
+                thread::spawn(move || {
+                    if let Err(err) = request.respond(wait()) {
+                        eprintln!("Failed to respond to HTTP request: {err}");
+                    }
+                });
+                continue;
+            }
+            let response = self.handle(&mut request);
+            if let Err(err) = request.respond(response) {
+                eprintln!("Failed to respond to HTTP request: {err}");
+            }
+        }
+    }
+
+    fn handle(&self, request: &mut tiny_http::Request) -> Response {
+        let path = request.url().split('?').next().unwrap_or("");
+        match path {
+            // Health checks are made by orchestrators and monitors without credentials.
+            "/healthz" | "/readyz" => {
+                let (status, body) = match path {
+                    "/healthz" => self.pages.healthz(),
+                    _ => self.pages.readyz(),
+                };
+                return with_content_type(
+                    tiny_http::Response::from_string(body).with_status_code(status),
+                    "text/plain; charset=UTF-8",
+                );
+            }
+            "/auth/login" | "/auth/callback" | "/auth/logout" => return self.oidc(request, path),
+            // These endpoints authenticate requests using signatures instead.
+            "/slack/interactions" => return self.slack_interaction(request),
+            "/approval" => {
+                let (status, html) = match &self.approvals {
+                    None => (404, "".to_string()),
+                    Some(approvals) => {
+                        approvals.handle(request.method(), request.url(), &client_ip(request))
+                    }
+                };
+                return with_content_type(
+                    tiny_http::Response::from_string(html).with_status_code(status),
+                    "text/html; charset=UTF-8",
+                );
+            }
+            _ => {}
+        }
+        if matches!(
+            request.method(),
+            tiny_http::Method::Post | tiny_http::Method::Delete
+        ) && (path == "/api/v1/projects" || path.starts_with("/api/v1/projects/"))
+        {
+            // The request's body is read to get the project's config.
+            let rest = path["/api/v1/projects".len()..].to_string();
+            let response = self.change_projects(request, &rest);
+            return self.with_cors(request, response);
+        }
+        if let Some(rest) = path.strip_prefix("/api/v1/") {
+            return self.with_cors(request, self.api_v1(request, rest));
+        }
+        if let Some((name, id)) = log_stream_path(path) {
+            return self.replayed_log(request, name, id);
+        }
+        if let Some((name, id, step)) = log_download_path(path) {
+            return self.log_download(request, name, id, step);
+        }
+        if let Some(rest) = path.strip_prefix("/api/projects/") {
+            // The request's body is read to check the CSRF token.
+            let rest = rest.to_string();
+            return self.project_action(request, &rest);
+        }
+        if let Some(name) = path
+            .strip_prefix("/projects/")
+            .and_then(|rest| rest.strip_suffix("/badge.svg"))
+        {
+            if *request.method() != tiny_http::Method::Get {
+                return empty(405);
+            }
+            let name = approval::percent_decode(name);
+            if self.auth.as_ref().is_some_and(|auth| !auth.public_badges) {
+                if let Err(response) =
+                    self.authorize_project(request, &name, config::Permission::View)
+                {
+                    return response;
+                }
+            }
+            let Some(badge) = self.pages.badge(&name) else {
+                return empty(404);
+            };
+            // Otherwise image proxies, like GitHub's, cache stale badges.
+            let cache_control = tiny_http::Header::from_bytes("Cache-Control", "no-cache").unwrap();
+            return with_content_type(
+                tiny_http::Response::from_string(badge).with_header(cache_control),
+                "image/svg+xml; charset=UTF-8",
+            );
+        }
+        if let Some(name) = path.strip_prefix("/static/") {
+            if *request.method() != tiny_http::Method::Get {
+                return empty(405);
+            }
+            let Some((content_type, content)) = assets::get(name) else {
+                return empty(404);
+            };
+            // Links to static files include a hash of their content.
+            let cache_control = tiny_http::Header::from_bytes(
+                "Cache-Control",
+                "public, max-age=31536000, immutable",
+            )
+            .unwrap();
+            return with_content_type(
+                tiny_http::Response::from_data(content).with_header(cache_control),
+                content_type,
+            );
+        }
+        let identity = match self.identify(request) {
+            Ok(identity) => identity,
+            Err(response) => return response,
+        };
+        if *request.method() != tiny_http::Method::Get {
+            return empty(405);
+        }
+        let can_view = |project: &str| can_view(&identity, project);
+        if let Some((_, exporter)) = self.metrics.iter().find(|(p, _)| p == path) {
+            let mut families = metrics::snapshot();
+            for family in &mut families {
+                let labels = family.metric.labels;
+                if let Some(i) = labels.iter().position(|label| *label == "project") {
+                    family.values.retain(|values, _| can_view(&values[i]));
+                }
+            }
+            return with_content_type(
+                tiny_http::Response::from_string(exporter.export(&families)),
+                exporter.content_type(),
+            );
+        }
+        if let Some(id) = path.strip_prefix("/api/deployments/") {
+            let deployment = id
+                .parse()
+                .ok()
+                .and_then(|id| self.controls.as_ref()?.deployment(id));
+            return match deployment {
+                None => empty(404),
+                Some(deployment) if !can_view(&deployment.project) => empty(403),
+                Some(mut deployment) => {
+                    if matches!(deployment.status, control::DeploymentStatus::Queued) {
+                        if let Some(workflow_run) = self
+                            .pages
+                            .live()
+                            .running(&deployment.project, &deployment.requested)
+                        {
+                            deployment.status = control::DeploymentStatus::Running { workflow_run };
+                        }
+                    }
+                    json(200, &deployment)
+                }
+            };
+        }
+        if path == "/audit" {
+            let html = self.pages.audit_html(page_param(request.url()), |entry| {
+                entry.project.as_deref().is_none_or(can_view)
+            });
+            return self.html(request, html);
+        }
+        if let Some(name) = path.strip_prefix("/projects/") {
+            let name = approval::percent_decode(name);
+            if !can_view(&name) {
+                return empty(403);
+            }
+            let page = page_param(request.url());
+            return match self.pages.project_html(&name, page) {
+                None => empty(404),
+                Some(html) => self.html(request, html),
+            };
+        }
+        // The status page's filter is kept in the query string.
+        match path {
+            "/" | "/index.html" => {
+                let html = match &identity {
+                    Some(identity) if identity.projects.is_some() => self.pages.html_for(can_view),
+                    _ => self.pages.html(),
+                };
+                self.html(request, html)
+            }
+            _ => empty(404),
+        }
+    }
+
+    /// Returns a response containing the rendered page,
+    ///     with the CSRF token for the request's credentials filled in.
+    fn html(&self, request: &tiny_http::Request, html: String) -> Response {
+        let token = self.csrf.token(credentials(request).as_deref());
+        with_content_type(
+            tiny_http::Response::from_string(html.replace(csrf::PLACEHOLDER, &token)),
+            "text/html; charset=UTF-8",
+        )
+    }
+
+    /// Check that a request to change a project is not a forged request from a browser.
+    ///
+    /// Requests from browsers are recognized by the headers browsers send with every POST request.
+    /// The CSRF token is read from the `X-CSRF-Token` header or the `csrf_token` form field.
+    fn check_csrf(&self, request: &mut tiny_http::Request, authorization: Option<&str>) -> bool {
+        let bearer = authorization
+            .and_then(|authorization| authorization.trim().split_once(' '))
+            .is_some_and(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"));
+        let from_browser =
+            header(request, "Origin").is_some() || header(request, "Sec-Fetch-Site").is_some();
+        if bearer || !from_browser {
+            return true;
+        }
+        let token = match header(request, "X-CSRF-Token") {
+            Some(token) => token,
+            None => {
+                let mut body = String::new();
+                if request.as_reader().read_to_string(&mut body).is_err() {
+                    return false;
+                }
+                form_param(&body, "csrf_token").unwrap_or_default()
+            }
+        };
+        self.csrf.verify(authorization, &token)
+    }
+
+    /// Handle a request for a page of the run results of the project with the provided name.
+    fn runs(&self, request: &tiny_http::Request, name: &str) -> Response {
+        let name = approval::percent_decode(name);
+        if let Err(response) = self.authorize_project(request, &name, config::Permission::View) {
+            return response;
+        }
+        let url = request.url();
+        let mut filter = api::RunFilter::default();
+        if let Some(since) = query_param(url, "since") {
+            match chrono::DateTime::parse_from_rfc3339(&since) {
+                Ok(since) => filter.since = Some(since.to_utc()),
+                Err(_) => {
+                    let message = format!("invalid since {since}; expected an RFC 3339 timestamp");
+                    return json(400, &serde_json::json!({ "message": message }));
+                }
+            }
+        }
+        if let Some(status) = query_param(url, "status") {
+            match api::Status::parse(&status) {
+                Some(status) => filter.status = Some(status),
+                None => {
+                    let message = format!("invalid status {status}; expected succeeded or failed");
+                    return json(400, &serde_json::json!({ "message": message }));
+                }
+            }
+        }
+        let page = page_param(url);
+        let per_page = status::RUN_RESULTS_PER_PAGE;
+        let Some((runs, total)) = self.pages.api().runs(&name, &filter, page, per_page) else {
+            return empty(404);
+        };
+        json(
+            200,
+            &serde_json::json!({
+                "project": name,
+                "page": page,
+                "per_page": per_page,
+                "total": total,
+                "runs": runs,
+            }),
+        )
+    }
+
+    /// Handle a request to the versioned JSON API.
+    ///
+    /// The path is the part of the URL path after `/api/v1/`.
+    fn api_v1(&self, request: &tiny_http::Request, path: &str) -> Response {
+        // Browsers send preflight requests without credentials.
+        if *request.method() == tiny_http::Method::Options && self.cors.is_some() {
+            return empty(204);
+        }
+        // The document describes the API, not the agent's state.
+        if path == "openapi.json" {
+            if *request.method() != tiny_http::Method::Get {
+                return empty(405);
+            }
+            return with_content_type(
+                tiny_http::Response::from_string(api::OPENAPI_DOT_JSON),
+                "application/json; charset=UTF-8",
+            );
+        }
+        let identity = match self.identify(request) {
+            Ok(identity) => identity,
+            Err(response) => return response,
+        };
+        if *request.method() != tiny_http::Method::Get {
+            return empty(405);
+        }
+        let can_view = |project: &str| can_view(&identity, project);
+        let api = self.pages.api();
+        match path {
+            "projects" => {
+                let projects: Vec<api::Project> = api
+                    .projects()
+                    .into_iter()
+                    .filter(|project| can_view(&project.name))
+                    .collect();
+                return json(200, &serde_json::json!({ "projects": projects }));
+            }
+            "audit" => {
+                let page = page_param(request.url());
+                let per_page = status::AUDIT_ENTRIES_PER_PAGE;
+                let (entries, total) = self.pages.audit().entries(page, per_page, |entry| {
+                    entry.project.as_deref().is_none_or(can_view)
+                });
+                let entries: Vec<api::AuditEntry> = entries.iter().map(Into::into).collect();
+                return json(
+                    200,
+                    &serde_json::json!({
+                        "page": page,
+                        "per_page": per_page,
+                        "total": total,
+                        "entries": entries,
+                    }),
+                );
+            }
+            "agent" => {
+                return match api.agent() {
+                    None => empty(503),
+                    Some(agent) => json(200, &agent),
+                }
+            }
+            "rate_limits" => {
+                return json(
+                    200,
+                    &serde_json::json!({ "rate_limits": api.rate_limits() }),
+                )
+            }
+            _ => {}
+        }
+        let Some(rest) = path.strip_prefix("projects/") else {
+            return empty(404);
+        };
+        if let Some(name) = rest.strip_suffix("/runs") {
+            return self.runs(request, name);
+        }
+        let name = approval::percent_decode(rest);
+        if !can_view(&name) {
+            return empty(403);
+        }
+        match api.project(&name) {
+            None => empty(404),
+            Some(project) => json(200, &project),
+        }
+    }
+
+    /// Add CORS headers to the response if the request is from an allowed origin.
+    fn with_cors(&self, request: &tiny_http::Request, mut response: Response) -> Response {
+        let Some(cors) = &self.cors else {
+            return response;
+        };
+        response.add_header(tiny_http::Header::from_bytes("Vary", "Origin").unwrap());
+        let Some(origin) = header(request, "Origin") else {
+            return response;
+        };
+        if !cors
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || *allowed == origin)
+        {
+            return response;
+        }
+        for (field, value) in [
+            ("Access-Control-Allow-Origin", origin),
+            (
+                "Access-Control-Allow-Methods",
+                cors.allowed_methods.join(", "),
+            ),
+            ("Access-Control-Allow-Headers", "Authorization".to_string()),
+        ] {
+            response.add_header(tiny_http::Header::from_bytes(field, value).unwrap());
+        }
+        response
+    }
+
+    /// If the request is for events that may have to be waited for,
+    ///     returns a function that waits for them and returns the response.
+    ///
+    /// Other requests to the same endpoints, including unauthorized ones, are handled normally.
+    fn long_poll(
+        &self,
+        request: &tiny_http::Request,
+    ) -> Option<Box<dyn FnOnce() -> Response + Send>> {
+        if *request.method() != tiny_http::Method::Get
+            || self.authorize(request, config::Access::ReadOnly).is_err()
+        {
+            return None;
+        }
+        let last_event_id = header(request, "Last-Event-ID");
+        let path = request.url().split('?').next().unwrap_or("");
+        if path == "/events" {
+            let since = last_event_id
+                .or_else(|| query_param(request.url(), "since"))
+                .and_then(|version| version.parse().ok());
+            let pages = self.pages.clone();
+            return Some(Box::new(move || event_stream(pages.events(since))));
+        }
+        let (name, id) = log_stream_path(path)?;
+        let name = approval::percent_decode(name);
+        let id = id.parse().ok()?;
+        if last_event_id.as_deref() == Some(live::DONE_ID)
+            || !self.pages.live().contains(&name, id)
+            || self
+                .authorize_project(request, &name, config::Permission::View)
+                .is_err()
+        {
+            return None;
+        }
+        let live = self.pages.live().clone();
+        Some(Box::new(move || {
+            event_stream(live.events(&name, id, last_event_id.as_deref()))
+        }))
+    }
+
+    /// Handle a request for the log stream of a redeployment whose output is not live.
+    fn replayed_log(&self, request: &tiny_http::Request, name: &str, id: &str) -> Response {
+        let name = approval::percent_decode(name);
+        if let Err(response) = self.authorize_project(request, &name, config::Permission::View) {
+            return response;
+        }
+        if *request.method() != tiny_http::Method::Get {
+            return empty(405);
+        }
+        if header(request, "Last-Event-ID").as_deref() == Some(live::DONE_ID) {
+            return empty(204);
+        }
+        let Some(run_result) = id
+            .parse()
+            .ok()
+            .and_then(|id| self.pages.run_result(&name, id))
+        else {
+            return empty(404);
+        };
+        event_stream(live::replay(&run_result))
+    }
+
+    /// Handle a request to download the output of a step of a redeployment.
+    fn log_download(
+        &self,
+        request: &tiny_http::Request,
+        name: &str,
+        id: &str,
+        step: &str,
+    ) -> Response {
+        let name = approval::percent_decode(name);
+        if let Err(response) = self.authorize_project(request, &name, config::Permission::View) {
+            return response;
+        }
+        if *request.method() != tiny_http::Method::Get {
+            return empty(405);
+        }
+        let (Ok(id), Ok(step)) = (id.parse::<u64>(), step.parse::<usize>()) else {
+            return empty(404);
+        };
+        let Some(run_result) = self.pages.run_result(&name, id) else {
+            return empty(404);
+        };
+        let Some(step) = run_result["steps"].get(step) else {
+            return empty(404);
+        };
+        let file_name: String = format!(
+            "{name}-{id}-{}.log",
+            step["config"]["name"].as_str().unwrap_or_default()
+        )
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+        let disposition = tiny_http::Header::from_bytes(
+            "Content-Disposition",
+            format!("attachment; filename=\"{file_name}\""),
+        )
+        .unwrap();
+        with_content_type(
+            tiny_http::Response::from_string(step["combined"].as_str().unwrap_or_default())
+                .with_header(disposition),
+            "text/plain; charset=UTF-8",
+        )
+    }
+
+    /// Handle a request to change a project.
+    ///
+    /// The path is the part of the URL path after `/api/projects/`.
+    fn project_action(&self, request: &mut tiny_http::Request, path: &str) -> Response {
+        let ip = request.remote_addr().map(|addr| addr.ip());
+        if !self.throttle.allows(ip) {
+            return empty(403);
+        }
+        // Which projects the request can change is checked once the project is known.
+        if let Err(response) = self.authorize(request, config::Access::ReadOnly) {
+            return response;
+        }
+        let authorization = credentials(request);
+        if let Err(retry_after) = self.throttle.count(ip, authorization.as_deref()) {
+            let message = "too many requests; try again later";
+            let retry_after = tiny_http::Header::from_bytes(
+                "Retry-After",
+                retry_after.as_secs_f64().ceil().to_string(),
+            )
+            .unwrap();
+            return json(429, &serde_json::json!({ "message": message })).with_header(retry_after);
+        }
+        if !self.check_csrf(request, authorization.as_deref()) {
+            let message = "missing or invalid CSRF token; reload the page and try again";
+            return json(403, &serde_json::json!({ "message": message }));
+        }
+        let Some((name, action_name)) = path.rsplit_once('/') else {
+            return empty(404);
+        };
+        let url = request.url();
+        let skip_wait = query_param(url, "skip_wait").as_deref() == Some("true");
+        let target = match (
+            query_param(url, "force").as_deref(),
+            query_param(url, "sha"),
+        ) {
+            _ if action_name != "deploy" => control::Target::Latest,
+            (Some("true"), None) => control::Target::Current,
+            (_, None) => control::Target::Latest,
+            (Some("true"), Some(_)) => {
+                let message = "force and sha can't both be provided";
+                return json(400, &serde_json::json!({ "message": message }));
+            }
+            (_, Some(sha)) if sha.len() == 40 && sha.chars().all(|c| c.is_ascii_hexdigit()) => {
+                control::Target::Commit(sha.to_ascii_lowercase())
+            }
+            (_, Some(sha)) => {
+                let message = format!("{sha} is not the full SHA of a commit");
+                return json(400, &serde_json::json!({ "message": message }));
+            }
+        };
+        let details_of_target = match &target {
+            control::Target::Latest => None,
+            control::Target::Current => Some("redeploying the current workflow run".to_string()),
+            control::Target::Commit(sha) => Some(format!("commit {sha}")),
+        };
+        let action = match action_name {
+            "pause" => control::Action::Pause,
+            "resume" => control::Action::Resume,
+            "deploy" => control::Action::Deploy { skip_wait, target },
+            "rollback" => control::Action::Rollback,
+            "rollback-release" => control::Action::RollbackRelease,
+            "approve" | "reject" => return self.decide(request, name, action_name),
+            _ => return empty(404),
+        };
+        let Some(controls) = &self.controls else {
+            return empty(404);
+        };
+        if *request.method() != tiny_http::Method::Post {
+            return empty(405);
+        }
+        let name = approval::percent_decode(name);
+        let permission = match action {
+            control::Action::Rollback | control::Action::RollbackRelease => {
+                config::Permission::Rollback
+            }
+            _ => config::Permission::Deploy,
+        };
+        if let Err(response) = self.authorize_project(request, &name, permission) {
+            return response;
+        }
+        let (status, message, deployment) = match controls.request(&name, action) {
+            Ok(deployment) => (
+                202,
+                "the request will be applied shortly".to_string(),
+                deployment,
+            ),
+            Err(control::Rejection::UnknownProject) => {
+                (404, format!("unknown project {name}"), None)
+            }
+            Err(control::Rejection::Conflict(message)) => (409, message, None),
+        };
+        if status == 202 {
+            let mut details = vec![];
+            if let Some(id) = deployment {
+                details.push(format!("deployment {id}"));
+            }
+            if action_name == "deploy" {
+                details.extend(details_of_target);
+                if skip_wait {
+                    details.push("skipping the soak time".to_string());
+                }
+            }
+            self.audit(request, action_name, &name, details.join(", "));
+        }
+        if status == 202 && accepts_html(request) {
+            return redirect(&format!("/projects/{}", approval::percent_encode(&name)));
+        }
+        let mut body = serde_json::json!({"project": name, "message": message});
+        if let Some(id) = deployment {
+            body["deployment"] = id.into();
+            body["status_url"] = format!("/api/deployments/{id}").into();
+        }
+        json(status, &body)
+    }
+
+    /// Handle a request to add or remove a project.
+    ///
+    /// The path is the part of the URL path after `/api/v1/projects`.
+    fn change_projects(&self, request: &mut tiny_http::Request, path: &str) -> Response {
+        let ip = request.remote_addr().map(|addr| addr.ip());
+        if !self.throttle.allows(ip) {
+            return empty(403);
+        }
+        if let Err(response) = self.authorize(request, config::Access::Admin) {
+            return response;
+        }
+        let Some(controls) = &self.controls else {
+            return empty(404);
+        };
+        let authorization = credentials(request);
+        if let Err(retry_after) = self.throttle.count(ip, authorization.as_deref()) {
+            let message = "too many requests; try again later";
+            let retry_after = tiny_http::Header::from_bytes(
+                "Retry-After",
+                retry_after.as_secs_f64().ceil().to_string(),
+            )
+            .unwrap();
+            return json(429, &serde_json::json!({ "message": message })).with_header(retry_after);
+        }
+        if !self.check_csrf(request, authorization.as_deref()) {
+            let message = "missing or invalid CSRF token; reload the page and try again";
+            return json(403, &serde_json::json!({ "message": message }));
+        }
+        let (action, name, result) = match (request.method(), path.strip_prefix('/')) {
+            (tiny_http::Method::Post, None) => {
+                let project = match read_added_project(request) {
+                    Ok(project) => project,
+                    Err(message) => return json(400, &serde_json::json!({ "message": message })),
+                };
+                let name = project.name.clone();
+                ("add", name, controls.add_project(project))
+            }
+            (tiny_http::Method::Delete, Some(name)) if !name.is_empty() => {
+                let name = approval::percent_decode(name);
+                let result = controls.remove_project(&name);
+                ("remove", name, result)
+            }
+            _ => return empty(405),
+        };
+        let (status, message) = match result {
+            Ok(()) => (202, "the request will be applied shortly".to_string()),
+            Err(control::Rejection::UnknownProject) => (404, format!("unknown project {name}")),
+            Err(control::Rejection::Conflict(message)) => (409, message),
+        };
+        if status == 202 {
+            self.audit(request, action, &name, String::new());
+        }
+        json(
+            status,
+            &serde_json::json!({"project": name, "message": message}),
+        )
+    }
+
+    /// Handle a request to approve or reject the redeployment of a project awaiting approval.
+    fn decide(&self, request: &tiny_http::Request, name: &str, decision: &str) -> Response {
+        let (Some(approvals), Some(decision)) =
+            (&self.approvals, approval::Decision::parse(decision))
+        else {
+            return empty(404);
+        };
+        if *request.method() != tiny_http::Method::Post {
+            return empty(405);
+        }
+        let name = approval::percent_decode(name);
+        if let Err(response) = self.authorize_project(request, &name, config::Permission::Approve) {
+            return response;
+        }
+        let Some(project) = self.pages.api().project(&name) else {
+            let message = format!("unknown project {name}");
+            return json(
+                404,
+                &serde_json::json!({"project": name, "message": message}),
+            );
+        };
+        let decided = project
+            .pending
+            .filter(|pending| pending.needs_approval)
+            .is_some_and(|pending| {
+                approvals.decide(
+                    &name,
+                    pending.workflow_run.id,
+                    decision,
+                    &self.actor(request),
+                    &client_ip(request),
+                )
+            });
+        if !decided {
+            let message = "the project has no redeployment awaiting approval";
+            return json(
+                409,
+                &serde_json::json!({"project": name, "message": message}),
+            );
+        }
+        if accepts_html(request) {
+            return redirect(&format!("/projects/{}", approval::percent_encode(&name)));
+        }
+        let message = "the decision will be applied shortly";
+        json(
+            202,
+            &serde_json::json!({"project": name, "message": message}),
+        )
+    }
+
+    fn slack_interaction(&self, request: &mut tiny_http::Request) -> Response {
+        let Some(interactions) = &self.slack_interactions else {
+            return empty(404);
+        };
+        if *request.method() != tiny_http::Method::Post {
+            return empty(405);
+        }
+        let timestamp = header(request, "X-Slack-Request-Timestamp").unwrap_or_default();
+        let signature = header(request, "X-Slack-Signature").unwrap_or_default();
+        let mut body = String::new();
+        if request.as_reader().read_to_string(&mut body).is_err() {
+            return empty(400);
+        }
+        let (status, response) = interactions.handle(&timestamp, &signature, &body);
+        tiny_http::Response::from_string(response).with_status_code(status)
+    }
+
+    /// Handle a request to log in or out through the OIDC provider.
+    fn oidc(&self, request: &tiny_http::Request, path: &str) -> Response {
+        let Some(oidc) = self.auth.as_ref().and_then(|auth| auth.oidc.as_ref()) else {
+            return empty(404);
+        };
+        if *request.method() != tiny_http::Method::Get {
+            return empty(405);
+        }
+        let url = request.url();
+        let result = match path {
+            "/auth/login" => oidc
+                .login(&query_param(url, "next").unwrap_or_default())
+                .map(|(location, login)| {
+                    redirect(&location).with_header(set_cookie(
+                        oidc::LOGIN_COOKIE,
+                        &login,
+                        &oidc.cookie_attributes(oidc::LOGIN_TIMEOUT),
+                    ))
+                }),
+            "/auth/callback" => match query_param(url, "error") {
+                Some(error) => Err(oidc::Failure {
+                    status: 403,
+                    message: format!("the provider rejected the login: {error}"),
+                }),
+                None => oidc
+                    .callback(
+                        query_param(url, "code").as_deref(),
+                        query_param(url, "state").as_deref(),
+                        cookie(request, oidc::LOGIN_COOKIE).as_deref(),
+                    )
+                    .map(|(session, next)| {
+                        redirect(&next)
+                            .with_header(set_cookie(
+                                oidc::SESSION_COOKIE,
+                                &session,
+                                &oidc.cookie_attributes(oidc.session_duration()),
+                            ))
+                            .with_header(set_cookie(
+                                oidc::LOGIN_COOKIE,
+                                "",
+                                &oidc.cookie_attributes(time::Duration::ZERO),
+                            ))
+                    }),
+            },
+            _ => Ok(with_content_type(
+                tiny_http::Response::from_string(approval::page(
+                    "You have logged out. <a href=\"/auth/login\">Log in again</a>",
+                )),
+                "text/html; charset=UTF-8",
+            )
+            .with_header(set_cookie(
+                oidc::SESSION_COOKIE,
+                "",
+                &oidc.cookie_attributes(time::Duration::ZERO),
+            ))),
+        };
+        result.unwrap_or_else(|failure| {
+            let message = format!(
+                "Failed to log in: {}. <a href=\"/auth/login\">Try again</a>",
+                handlebars::html_escape(&failure.message)
+            );
+            with_content_type(
+                tiny_http::Response::from_string(approval::page(&message))
+                    .with_status_code(failure.status),
+                "text/html; charset=UTF-8",
+            )
+        })
+    }
+
+    /// Record the action on the project in the audit log, taken by whoever made the request.
+    fn audit(&self, request: &tiny_http::Request, action: &str, project: &str, details: String) {
+        self.pages.audit().record(
+            audit::Entry::new(
+                &self.actor(request),
+                &client_ip(request),
+                action,
+                Some(project),
+            )
+            .with_details(details),
+        );
+    }
+
+    /// Returns who made the request, for the audit log.
+    fn actor(&self, request: &tiny_http::Request) -> String {
+        match &self.auth {
+            // Without auth, anyone who can reach the server can take actions.
+            None => "anonymous".to_string(),
+            Some(auth) => auth
+                .authenticate(credentials(request).as_deref())
+                .map(|identity| identity.name)
+                .unwrap_or_else(|| "unknown".to_string()),
+        }
+    }
+
+    /// Check that the request is allowed the required access.
+    ///
+    /// If not, returns the response to send instead.
+    fn authorize(
+        &self,
+        request: &tiny_http::Request,
+        required: config::Access,
+    ) -> Result<(), Response> {
+        match self.identify(request)? {
+            Some(identity) if identity.access < required => Err(empty(403)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that the request is allowed to take the action on the project.
+    ///
+    /// If not, returns the response to send instead.
+    fn authorize_project(
+        &self,
+        request: &tiny_http::Request,
+        project: &str,
+        permission: config::Permission,
+    ) -> Result<(), Response> {
+        match self.identify(request)? {
+            Some(identity) if !identity.allows(project, permission) => Err(empty(403)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns who made the request, or `None` if auth is not configured.
+    ///
+    /// If the request isn't authenticated, returns the response to send instead.
+    fn identify(&self, request: &tiny_http::Request) -> Result<Option<Identity>, Response> {
+        let Some(auth) = &self.auth else {
+            return Ok(None);
+        };
+        match auth.authenticate(credentials(request).as_deref()) {
+            Some(identity) => Ok(Some(identity)),
+            // Browsers viewing pages can log in through the OIDC provider.
+            None if auth.oidc.is_some()
+                && *request.method() == tiny_http::Method::Get
+                && accepts_html(request) =>
+            {
+                Err(redirect(&format!(
+                    "/auth/login?next={}",
+                    approval::percent_encode(request.url())
+                )))
+            }
+            None => {
+                let challenge = if auth.users.is_empty() {
+                    "Bearer"
+                } else {
+                    "Basic realm=\"cdagent\", charset=\"UTF-8\""
+                };
+                let header = tiny_http::Header::from_bytes("WWW-Authenticate", challenge).unwrap();
+                Err(empty(401).with_header(header))
+            }
+        }
+    }
+}
+
+/// The scheme of credentials made from OIDC session cookies.
+const SESSION_SCHEME: &str = "Session";
+
+/// Read the config of a project to add from the request's body.
+///
+/// Added projects are persisted in the database, where secrets are not stored,
+///     so secrets must be provided in files.
+fn read_added_project(request: &mut tiny_http::Request) -> Result<config::ProjectConfig, String> {
+    let mut body = String::new();
+    if let Err(err) = request.as_reader().read_to_string(&mut body) {
+        return Err(format!("failed to read the request: {err}"));
+    }
+    let project: config::ProjectConfig = match serde_json::from_str(&body) {
+        Ok(project) => project,
+        Err(err) => return Err(format!("invalid project config: {err}")),
+    };
+    if project.name.trim().is_empty() {
+        return Err("the project's name must not be empty".to_string());
+    }
+    if project.extends.is_some() {
+        return Err("only projects in the config file can extend templates".to_string());
+    }
+    if project.notifications.is_some() {
+        return Err("only projects in the config file can have their own notifiers".to_string());
+    }
+    if project.clone().redact_secrets() {
+        return Err(
+            "secrets of added projects can only be provided in files, like auth_token_file"
+                .to_string(),
+        );
+    }
+    let problems = project.validate();
+    if !problems.is_empty() {
+        return Err(problems.join("; "));
+    }
+    project.clone().read_secret_files()?;
+    Ok(project)
+}
+
+/// Returns the credentials the request was made with.
+///
+/// These are the request's `Authorization` header, if it has one.
+/// Otherwise, the session cookie of a browser logged in through the OIDC provider
+///     is returned as if it were sent in the header using the `Session` scheme.
+fn credentials(request: &tiny_http::Request) -> Option<String> {
+    header(request, "Authorization").or_else(|| {
+        cookie(request, oidc::SESSION_COOKIE).map(|session| format!("{SESSION_SCHEME} {session}"))
+    })
+}
+
+/// Returns the value of the cookie, if the request has it.
+fn cookie(request: &tiny_http::Request, name: &str) -> Option<String> {
+    header(request, "Cookie")?
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+fn set_cookie(name: &str, value: &str, attributes: &str) -> tiny_http::Header {
+    tiny_http::Header::from_bytes("Set-Cookie", format!("{name}={value}; {attributes}")).unwrap()
+}
+
+/// Returns whether the request is from a browser, which accepts HTML.
+fn accepts_html(request: &tiny_http::Request) -> bool {
+    header(request, "Accept").is_some_and(|accept| accept.contains("text/html"))
+}
+
+fn redirect(location: &str) -> Response {
+    empty(303).with_header(tiny_http::Header::from_bytes("Location", location).unwrap())
+}
+
+/// Returns the IP address of the client that made the request, for the audit log.
+fn client_ip(request: &tiny_http::Request) -> String {
+    match request.remote_addr() {
+        Some(addr) => addr.ip().to_string(),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Returns the project name and workflow run ID of a log stream path.
+fn log_stream_path(path: &str) -> Option<(&str, &str)> {
+    path.strip_prefix("/api/projects/")?
+        .strip_suffix("/logs/stream")?
+        .rsplit_once("/runs/")
+}
+
+/// Returns the project name, workflow run ID and step index of a log download path.
+fn log_download_path(path: &str) -> Option<(&str, &str, &str)> {
+    let (rest, step) = path
+        .strip_prefix("/api/projects/")?
+        .strip_suffix("/log")?
+        .rsplit_once("/steps/")?;
+    let (name, id) = rest.rsplit_once("/runs/")?;
+    Some((name, id, step))
+}
+
+fn event_stream(events: String) -> Response {
+    let cache_control = tiny_http::Header::from_bytes("Cache-Control", "no-cache").unwrap();
+    with_content_type(
+        tiny_http::Response::from_string(events).with_header(cache_control),
+        "text/event-stream",
+    )
+}
+
+/// A running HTTP server.
+pub struct Server {
+    server: sync::Arc<tiny_http::Server>,
+    /// The number of worker threads still serving requests.
+    workers: sync::Arc<(sync::Mutex<usize>, sync::Condvar)>,
+}
+
+impl Server {
+    /// Stop accepting requests and wait up to the timeout for the requests being served to finish.
+    ///
+    /// Requests that were already received are served before the workers stop.
+    /// Requests waiting for events are not waited for;
+    ///     clients reconnect to another instance as they do after any other response.
+    pub fn shutdown(self, timeout: time::Duration) {
+        for _ in 0..WORKERS {
+            self.server.unblock();
+        }
+        let (running, stopped) = &*self.workers;
+        let (running, result) = stopped
+            .wait_timeout_while(running.lock().unwrap(), timeout, |running| *running > 0)
+            .unwrap();
+        if result.timed_out() {
+            eprintln!(
+                "Timed out after {timeout:?} waiting for {} HTTP workers to finish",
+                *running
+            );
+        }
+        // Dropping the last reference to the server closes its socket.
+    }
+}
+
+/// Credentials accepted by the HTTP server, with secrets read from the environment.
+struct Auth {
+    /// Each token along with who it identifies.
+    tokens: Vec<(String, Identity)>,
+    /// Each user's password along with who it identifies.
+    users: Vec<(String, Identity)>,
+    oidc: Option<oidc::Oidc>,
+    public_badges: bool,
+}
+
+/// Who made a request, and what they can do.
+#[derive(Clone)]
+struct Identity {
+    /// How the requester is identified in the audit log.
+    name: String,
+    access: config::Access,
+    /// If set, the only projects the requester can see and change.
+    projects: Option<config::ProjectPermissions>,
+}
+
+impl Identity {
+    fn new(
+        name: String,
+        access: config::Access,
+        projects: Option<&config::ProjectPermissions>,
+    ) -> Self {
+        // Permissions on projects replace the configured access.
+        let access = match projects {
+            None => access,
+            Some(_) => config::Access::ReadOnly,
+        };
+        Self {
+            name,
+            access,
+            projects: projects.cloned(),
+        }
+    }
+
+    /// Returns whether the requester is allowed to take the action on the project.
+    fn allows(&self, project: &str, permission: config::Permission) -> bool {
+        match &self.projects {
+            None => permission == config::Permission::View || self.access == config::Access::Admin,
+            Some(projects) => projects
+                .get(project)
+                .or_else(|| projects.get("*"))
+                .is_some_and(|permissions| permissions.contains(&permission)),
+        }
+    }
+}
+
+/// Returns whether the requester can see the project, where `None` means auth is not configured.
+fn can_view(identity: &Option<Identity>, project: &str) -> bool {
+    identity
+        .as_ref()
+        .is_none_or(|identity| identity.allows(project, config::Permission::View))
+}
+
+impl Auth {
+    fn new(config: &config::AuthConfig) -> Result<Self, String> {
+        let mut tokens = vec![];
+        for (i, token) in config.tokens.iter().enumerate() {
+            let value = secret("token", &token.token, &token.token_env)?;
+            let name = match &token.name {
+                Some(name) => format!("token {name}"),
+                None => format!("token #{}", i + 1),
+            };
+            tokens.push((
+                value,
+                Identity::new(name, token.access, token.projects.as_ref()),
+            ));
+        }
+        let mut users = vec![];
+        for user in &config.users {
+            let password = secret(
+                &format!("password for user {}", user.username),
+                &user.password,
+                &user.password_env,
+            )?;
+            let identity =
+                Identity::new(user.username.clone(), user.access, user.projects.as_ref());
+            users.push((password, identity));
+        }
+        let oidc = match &config.oidc {
+            None => None,
+            Some(oidc_config) => {
+                let client_secret = secret(
+                    "OIDC client secret",
+                    &oidc_config.client_secret,
+                    &oidc_config.client_secret_env,
+                )?;
+                Some(oidc::Oidc::new(oidc_config, client_secret))
+            }
+        };
+        if tokens.is_empty() && users.is_empty() && oidc.is_none() {
+            return Err(
+                "auth is configured but no tokens, users or OIDC provider are provided".to_string(),
+            );
+        }
+        Ok(Self {
+            tokens,
+            users,
+            oidc,
+            public_badges: config.public_badges,
+        })
+    }
+
+    /// Returns who is identified by the credentials and the access they are granted, if any.
+    ///
+    /// The credentials are in the format of the `Authorization` header;
+    ///     see [credentials] for how sessions are represented.
+    fn authenticate(&self, credentials: Option<&str>) -> Option<Identity> {
+        let (scheme, credentials) = credentials?.trim().split_once(' ')?;
+        let credentials = credentials.trim();
+        if scheme == SESSION_SCHEME {
+            let oidc = self.oidc.as_ref()?;
+            let session = oidc.session(credentials)?;
+            return Some(Identity::new(session.name, session.access, oidc.projects()));
+        }
+        if scheme.eq_ignore_ascii_case("bearer") {
+            return self
+                .tokens
+                .iter()
+                .filter(|(token, _)| constant_time_eq(token, credentials))
+                .map(|(_, identity)| identity.clone())
+                .reduce(most_access);
+        }
+        if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = base64::engine::general_purpose::STANDARD
+                .decode(credentials)
+                .ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (username, password) = decoded.split_once(':')?;
+            return self
+                .users
+                .iter()
+                .filter(|(p, identity)| identity.name == username && constant_time_eq(p, password))
+                .map(|(_, identity)| identity.clone())
+                .reduce(most_access);
+        }
+        None
+    }
+}
+
+fn secret(what: &str, value: &Option<String>, env: &Option<String>) -> Result<String, String> {
+    match (value, env) {
+        (Some(value), None) => Ok(value.clone()),
+        (None, Some(name)) => match std::env::var(name) {
+            Ok(value) if !value.is_empty() => Ok(value),
+            Ok(_) => Err(format!(
+                "environment variable {name} for the {what} is empty"
+            )),
+            Err(err) => Err(format!(
+                "failed to read the {what} from environment variable {name}: {err}"
+            )),
+        },
+        _ => Err(format!(
+            "exactly one of the {what}, an environment variable or a file for it must be set"
+        )),
+    }
+}
+
+/// Returns whichever identity has more access.
+fn most_access(a: Identity, b: Identity) -> Identity {
+    if a.access >= b.access {
+        a
+    } else {
+        b
+    }
+}
+
+/// Compares two strings in time that doesn't depend on where they differ.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+fn header(request: &tiny_http::Request, name: &'static str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.to_string())
+}
+
+fn empty(status: u16) -> Response {
+    tiny_http::Response::from_string("").with_status_code(status)
+}
+
+/// Returns the decoded value of the query parameter in the URL, if it is present.
+fn query_param(url: &str, key: &str) -> Option<String> {
+    let (_, query) = url.split_once('?')?;
+    form_param(query, key)
+}
+
+/// Returns the decoded value of the parameter in the URL-encoded form data, if it is present.
+fn form_param(form: &str, key: &str) -> Option<String> {
+    form.split('&')
+        .filter_map(|param| param.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| approval::percent_decode(value))
+}
+
+/// Returns the value of the `page` query parameter of the URL, defaulting to 1.
+fn page_param(url: &str) -> usize {
+    query_param(url, "page")
+        .and_then(|page| page.parse().ok())
+        .filter(|page| *page > 0)
+        .unwrap_or(1)
+}
+
+fn json<T: serde::Serialize>(status: u16, data: &T) -> Response {
+    with_content_type(
+        tiny_http::Response::from_string(serde_json::to_string(data).unwrap())
+            .with_status_code(status),
+        "application/json; charset=UTF-8",
+    )
+}
+
+fn with_content_type(response: Response, content_type: &str) -> Response {
+    let header = tiny_http::Header::from_bytes("Content-Type", content_type).unwrap();
+    response.with_header(header)
+}
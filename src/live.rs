@@ -0,0 +1,302 @@
+//! Live output of redeployments, streamed to clients as server-sent events.
+//!
+//! The stream of a redeployment consists of the following events, whose data is JSON:
+//!
+//! - `step` when a step starts, with the `index` and `name` of the step.
+//! - `output` when a step produces output, with the `step` index and the output `text`.
+//! - `done` when the redeployment finishes, with whether it was a `success`.
+//!   This is the last event of the stream.
+//!
+//! While a redeployment is in progress, only the steps that run commands are streamed.
+//! Finished redeployments are replayed from their run results, which include all of the steps.
+//!
+//! The HTTP server can't stream a response body of unknown length,
+//!     so each response contains the events produced since the client's last event,
+//!     waiting for new events if there are none.
+//! Clients reconnect with the `Last-Event-ID` header set to the ID of the last event received,
+//!     as browsers' `EventSource` does, until the `done` event.
+
+use std::collections::HashMap;
+use std::sync;
+use std::time;
+
+/// The ID of the `done` event.
+///
+/// Requests made after the `done` event are answered with 204 No Content,
+///     which stops `EventSource` from reconnecting.
+pub const DONE_ID: &str = "done";
+
+/// How long a request waits for new events before returning without any.
+pub const POLL_TIMEOUT: time::Duration = time::Duration::from_secs(15);
+
+/// How long clients wait before reconnecting.
+pub const RECONNECTION_DELAY: time::Duration = time::Duration::from_millis(100);
+
+/// The output of the most recent redeployment of each project, including the one in progress.
+///
+/// Clones share the same underlying state.
+#[derive(Clone, Default)]
+pub struct Log {
+    runs: sync::Arc<(sync::Mutex<HashMap<String, Run>>, sync::Condvar)>,
+}
+
+struct Run {
+    workflow_run: u64,
+    /// When the redeployment started.
+    ///
+    /// This distinguishes a rollback from the original redeployment of the workflow run.
+    started: String,
+    /// The name and output so far of each step that has started, in order.
+    steps: Vec<(String, Vec<u8>)>,
+    /// Whether the redeployment succeeded, once it has finished.
+    success: Option<bool>,
+}
+
+impl Log {
+    /// Record that a step of the project's redeployment has started.
+    ///
+    /// The first step of a redeployment replaces the output of the project's previous one.
+    pub fn start_step(&self, project: &str, workflow_run: u64, started: &str, step: &str) {
+        self.update(|runs| {
+            let run = runs.get_mut(project).filter(|run| {
+                run.workflow_run == workflow_run && run.started == started && run.success.is_none()
+            });
+            let run = match run {
+                Some(run) => run,
+                None => {
+                    runs.insert(
+                        project.to_string(),
+                        Run {
+                            workflow_run,
+                            started: started.to_string(),
+                            steps: vec![],
+                            success: None,
+                        },
+                    );
+                    runs.get_mut(project).expect("run was just inserted")
+                }
+            };
+            run.steps.push((step.to_string(), vec![]));
+        });
+    }
+
+    /// Record output of the current step of the project's redeployment.
+    pub fn output(&self, project: &str, chunk: &[u8]) {
+        self.update(|runs| {
+            if let Some((_, output)) = runs
+                .get_mut(project)
+                .filter(|run| run.success.is_none())
+                .and_then(|run| run.steps.last_mut())
+            {
+                output.extend_from_slice(chunk);
+            }
+        });
+    }
+
+    /// Record that the project's redeployment has finished.
+    pub fn finish(&self, project: &str, success: bool) {
+        self.update(|runs| {
+            if let Some(run) = runs.get_mut(project).filter(|run| run.success.is_none()) {
+                run.success = Some(success);
+            }
+        });
+    }
+
+    fn update(&self, f: impl FnOnce(&mut HashMap<String, Run>)) {
+        let (runs, changed) = &*self.runs;
+        f(&mut runs.lock().unwrap());
+        changed.notify_all();
+    }
+
+    /// Returns the workflow run of the project's redeployment in progress,
+    ///     if it started at or after the provided RFC 3339 timestamp.
+    pub fn running(&self, project: &str, since: &str) -> Option<u64> {
+        let (runs, _) = &*self.runs;
+        runs.lock()
+            .unwrap()
+            .get(project)
+            .filter(|run| run.success.is_none() && run.started.as_str() >= since)
+            .map(|run| run.workflow_run)
+    }
+
+    /// Returns whether the workflow run is the project's most recent redeployment.
+    pub fn contains(&self, project: &str, workflow_run: u64) -> bool {
+        let (runs, _) = &*self.runs;
+        runs.lock()
+            .unwrap()
+            .get(project)
+            .is_some_and(|run| run.workflow_run == workflow_run)
+    }
+
+    /// Returns the events for output of the project's most recent redeployment
+    ///     produced after the event with the provided ID.
+    ///
+    /// If there are no such events yet, waits up to [POLL_TIMEOUT] for some.
+    /// If the workflow run is not the project's most recent redeployment,
+    ///     or has been replaced by a new one, the stream is done without a known outcome.
+    pub fn events(&self, project: &str, workflow_run: u64, last_event_id: Option<&str>) -> String {
+        let (runs, changed) = &*self.runs;
+        let mut cursor = Cursor::parse(workflow_run, last_event_id);
+        let runs = runs.lock().unwrap();
+        let (runs, _) = changed
+            .wait_timeout_while(runs, POLL_TIMEOUT, |runs| {
+                cursor.clone().advance(runs.get(project)).is_empty()
+            })
+            .unwrap();
+        format!(
+            "retry: {}\n\n{}",
+            RECONNECTION_DELAY.as_millis(),
+            cursor.advance(runs.get(project))
+        )
+    }
+}
+
+/// The position of a client in the output of a redeployment.
+#[derive(Clone)]
+struct Cursor {
+    workflow_run: u64,
+    /// When the redeployment being streamed started, once it has been found.
+    started: Option<String>,
+    /// The step whose output is being streamed.
+    step: usize,
+    /// Whether the `step` event has been sent for the step.
+    announced: bool,
+    /// How much of the step's output has been sent.
+    offset: usize,
+}
+
+impl Cursor {
+    /// Returns the position after the event with the provided ID.
+    fn parse(workflow_run: u64, last_event_id: Option<&str>) -> Self {
+        let mut cursor = Cursor {
+            workflow_run,
+            started: None,
+            step: 0,
+            announced: false,
+            offset: 0,
+        };
+        let mut pieces = last_event_id.unwrap_or_default().rsplitn(3, '/');
+        if let (Some(Ok(offset)), Some(Ok(step)), Some(started)) = (
+            pieces.next().map(str::parse),
+            pieces.next().map(str::parse),
+            pieces.next(),
+        ) {
+            cursor.started = Some(started.to_string());
+            cursor.step = step;
+            cursor.announced = true;
+            cursor.offset = offset;
+        }
+        cursor
+    }
+
+    /// The ID of the event ending at this position.
+    fn id(&self) -> String {
+        format!(
+            "{}/{}/{}",
+            self.started.as_deref().unwrap_or_default(),
+            self.step,
+            self.offset
+        )
+    }
+
+    /// Returns the events for output produced after this position, and advances past them.
+    fn advance(&mut self, run: Option<&Run>) -> String {
+        let run = run.filter(|run| {
+            run.workflow_run == self.workflow_run
+                && self
+                    .started
+                    .as_ref()
+                    .is_none_or(|started| *started == run.started)
+        });
+        let Some(run) = run else {
+            return done_event(None);
+        };
+        self.started.get_or_insert_with(|| run.started.clone());
+        let mut events = String::new();
+        while let Some((name, output)) = run.steps.get(self.step) {
+            if !self.announced {
+                self.announced = true;
+                events.push_str(&event(
+                    "step",
+                    Some(&self.id()),
+                    &serde_json::json!({"index": self.step, "name": name}),
+                ));
+            }
+            let in_progress = self.step + 1 == run.steps.len() && run.success.is_none();
+            let pending = output.get(self.offset..).unwrap_or_default();
+            // Output in progress may end part of the way through a character.
+            let end = if in_progress {
+                utf8_prefix_len(pending)
+            } else {
+                pending.len()
+            };
+            if end > 0 {
+                self.offset += end;
+                events.push_str(&output_event(self.step, Some(&self.id()), &pending[..end]));
+            }
+            if self.step + 1 == run.steps.len() {
+                break;
+            }
+            self.step += 1;
+            self.announced = false;
+            self.offset = 0;
+        }
+        if let Some(success) = run.success {
+            events.push_str(&done_event(Some(success)));
+        }
+        events
+    }
+}
+
+/// Returns all of the events for a finished redeployment, given its run result.
+pub fn replay(run_result: &serde_json::Value) -> String {
+    let mut events = String::new();
+    let steps = run_result["steps"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    for (index, step) in steps.iter().enumerate() {
+        events.push_str(&event(
+            "step",
+            None,
+            &serde_json::json!({"index": index, "name": step["config"]["name"]}),
+        ));
+        let output = step["combined"].as_str().unwrap_or_default();
+        if !output.is_empty() {
+            events.push_str(&output_event(index, None, output.as_bytes()));
+        }
+    }
+    events.push_str(&done_event(run_result["success"].as_bool()));
+    events
+}
+
+fn event(name: &str, id: Option<&str>, data: &serde_json::Value) -> String {
+    match id {
+        None => format!("event: {name}\ndata: {data}\n\n"),
+        Some(id) => format!("event: {name}\nid: {id}\ndata: {data}\n\n"),
+    }
+}
+
+fn output_event(step: usize, id: Option<&str>, output: &[u8]) -> String {
+    event(
+        "output",
+        id,
+        &serde_json::json!({"step": step, "text": String::from_utf8_lossy(output)}),
+    )
+}
+
+fn done_event(success: Option<bool>) -> String {
+    event(
+        "done",
+        Some(DONE_ID),
+        &serde_json::json!({ "success": success }),
+    )
+}
+
+/// Returns the length of the output excluding an incomplete character at the end.
+fn utf8_prefix_len(output: &[u8]) -> usize {
+    match std::str::from_utf8(output) {
+        Err(err) if err.error_len().is_none() => err.valid_up_to(),
+        _ => output.len(),
+    }
+}
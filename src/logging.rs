@@ -0,0 +1,239 @@
+//! Writing the agent's log to a file, which is rotated as it grows.
+//!
+//! The agent logs by printing to stderr, so rather than changing how it logs,
+//!     stdout and stderr are redirected into a pipe and a thread copies the pipe to the log file.
+//! On Windows the standard handles are replaced rather than the file descriptors.
+//! When the log file is rotated it is renamed to `<file>.1`,
+//!     the previous `<file>.1` is renamed to `<file>.2`, and so on,
+//!     and the oldest rotated file is deleted.
+
+use crate::config;
+use std::fs;
+use std::io::{BufRead, Read, Write};
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd};
+use std::sync;
+use std::thread;
+use std::time;
+#[cfg(windows)]
+use windows_sys::Win32::System::Console;
+
+/// The log file, while output is being copied to it.
+static LOG: sync::Mutex<Option<Redirect>> = sync::Mutex::new(None);
+
+struct Redirect {
+    /// Where stderr pointed before it was redirected.
+    #[cfg(unix)]
+    original_stderr: fs::File,
+    /// The standard output and error handles before they were redirected.
+    #[cfg(windows)]
+    original_handles: [isize; 2],
+    /// The end of the pipe that the standard handles write to, which is closed when finished.
+    #[cfg(windows)]
+    pipe: std::io::PipeWriter,
+    copier: thread::JoinHandle<()>,
+}
+
+/// The log file, which output can be copied to once it is opened.
+pub struct Writer {
+    path: String,
+    file: fs::File,
+    /// The size of the log file so far.
+    size: u64,
+    /// When the log file was started.
+    started: time::SystemTime,
+    max_size: Option<u64>,
+    rotate_interval: Option<time::Duration>,
+    keep: usize,
+}
+
+/// Start writing the agent's output to the log file instead of stdout and stderr.
+///
+/// This starts a thread, so if the agent forks into the background it must do so first.
+#[cfg(unix)]
+pub fn start(writer: Writer) -> Result<(), String> {
+    let mut fds = [0; 2];
+    // SAFETY: the array has room for the two file descriptors created.
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(format!(
+            "failed to create a pipe for the log: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    // SAFETY: the pipe's file descriptors were just created and are owned by nothing else.
+    let (reader, pipe) = unsafe { (fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1])) };
+    // SAFETY: dup returns a new file descriptor, or -1.
+    let original_stderr = match unsafe { libc::dup(libc::STDERR_FILENO) } {
+        -1 => {
+            return Err(format!(
+                "failed to redirect the log: {}",
+                std::io::Error::last_os_error()
+            ))
+        }
+        // SAFETY: the duplicated file descriptor is owned by nothing else.
+        fd => unsafe { fs::File::from_raw_fd(fd) },
+    };
+    let copier = copy(reader, writer);
+    // SAFETY: the pipe is open, and replacing stdout and stderr doesn't invalidate any Rust values.
+    unsafe {
+        libc::dup2(pipe.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(pipe.as_raw_fd(), libc::STDERR_FILENO);
+    }
+    drop(pipe);
+    *LOG.lock().unwrap() = Some(Redirect {
+        original_stderr,
+        copier,
+    });
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn start(writer: Writer) -> Result<(), String> {
+    use std::os::windows::io::AsRawHandle;
+    let (reader, pipe) = match std::io::pipe() {
+        Ok(pipe) => pipe,
+        Err(err) => return Err(format!("failed to create a pipe for the log: {err}")),
+    };
+    let copier = copy(reader, writer);
+    let handles = [Console::STD_OUTPUT_HANDLE, Console::STD_ERROR_HANDLE];
+    // SAFETY: the standard handles are looked up on every write, so replacing them with the pipe,
+    //     which stays open until the handles are restored, redirects all output.
+    let original_handles = unsafe {
+        handles.map(|handle| {
+            let original = Console::GetStdHandle(handle) as isize;
+            Console::SetStdHandle(handle, pipe.as_raw_handle());
+            original
+        })
+    };
+    *LOG.lock().unwrap() = Some(Redirect {
+        original_handles,
+        pipe,
+        copier,
+    });
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn start(_: Writer) -> Result<(), String> {
+    Err("writing the log to a file is not supported on this platform".to_string())
+}
+
+/// Start a thread that copies the lines read from the pipe to the log file.
+fn copy(reader: impl Read + Send + 'static, mut writer: Writer) -> thread::JoinHandle<()> {
+    thread::Builder::new()
+        .name("log".to_string())
+        .spawn(move || {
+            let mut reader = std::io::BufReader::new(reader);
+            let mut line = vec![];
+            loop {
+                line.clear();
+                match reader.read_until(b'\n', &mut line) {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => writer.write(&line),
+                }
+            }
+        })
+        .expect("the log thread can be started")
+}
+
+/// Restore stdout and stderr, once all output so far has been written to the log file.
+///
+/// This must be called before the agent exits, as output may still be in the pipe.
+pub fn finish() {
+    let Some(redirect) = LOG.lock().unwrap().take() else {
+        return;
+    };
+    #[cfg(unix)]
+    {
+        _ = std::io::stdout().flush();
+        let fd = redirect.original_stderr.into_raw_fd();
+        // SAFETY: the original stderr is open; replacing stdout and stderr closes the pipe,
+        //     which ends the thread copying it.
+        unsafe {
+            libc::dup2(fd, libc::STDOUT_FILENO);
+            libc::dup2(fd, libc::STDERR_FILENO);
+            libc::close(fd);
+        }
+    }
+    #[cfg(windows)]
+    {
+        _ = std::io::stdout().flush();
+        let handles = [Console::STD_OUTPUT_HANDLE, Console::STD_ERROR_HANDLE];
+        for (handle, original) in handles.into_iter().zip(redirect.original_handles) {
+            // SAFETY: the original handles were valid when they were replaced.
+            unsafe { Console::SetStdHandle(handle, original as _) };
+        }
+        // Closing the pipe ends the thread copying it.
+        drop(redirect.pipe);
+    }
+    _ = redirect.copier.join();
+}
+
+impl Writer {
+    /// Open the log file, without writing anything to it yet.
+    pub fn open(config: &config::LogConfig) -> Result<Self, String> {
+        let file = match fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.file)
+        {
+            Ok(file) => file,
+            Err(err) => return Err(format!("failed to open log file {}: {err}", config.file)),
+        };
+        let metadata = file.metadata().ok();
+        Ok(Self {
+            path: config.file.clone(),
+            size: metadata.as_ref().map_or(0, |m| m.len()),
+            started: metadata
+                .and_then(|m| m.created().ok())
+                .unwrap_or_else(time::SystemTime::now),
+            file,
+            max_size: match config.max_size_bytes {
+                None => Some(10 * 1024 * 1024),
+                Some(0) => None,
+                Some(max_size) => Some(max_size),
+            },
+            rotate_interval: config
+                .rotate_interval_seconds
+                .filter(|seconds| *seconds > 0)
+                .map(time::Duration::from_secs),
+            keep: config.keep.unwrap_or(5),
+        })
+    }
+
+    fn write(&mut self, line: &[u8]) {
+        let full = self.max_size.is_some_and(|max_size| self.size >= max_size);
+        let old = self
+            .rotate_interval
+            .is_some_and(|interval| self.started.elapsed().is_ok_and(|age| age >= interval));
+        if (full || old) && self.size > 0 {
+            self.rotate();
+        }
+        if self.file.write_all(line).is_ok() {
+            self.size += line.len() as u64;
+        }
+    }
+
+    /// Rotate the log file, continuing in the current file if that fails.
+    fn rotate(&mut self) {
+        let rotated = |n: usize| format!("{}.{n}", self.path);
+        if self.keep == 0 {
+            _ = fs::remove_file(&self.path);
+        } else {
+            _ = fs::remove_file(rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                _ = fs::rename(rotated(n), rotated(n + 1));
+            }
+            _ = fs::rename(&self.path, rotated(1));
+        }
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path);
+        if let Ok(file) = file {
+            self.file = file;
+            self.size = 0;
+            self.started = time::SystemTime::now();
+        }
+    }
+}
@@ -1,118 +1,299 @@
+mod api;
+mod approval;
+mod assets;
+mod audit;
+mod backup;
+mod check;
+mod cli;
+mod client;
 mod config;
+mod control;
+mod csrf;
+mod daemon;
 mod database;
+mod email;
+mod encryption;
+mod exit;
 mod github;
+mod http;
+mod live;
+mod logging;
+mod metrics;
+mod migrations;
+mod ntfy;
+mod oidc;
+mod process;
 mod project;
-use std::sync::mpsc;
-use std::{thread, time};
+mod releases;
+mod reload;
+mod service;
+mod slack;
+mod statsd;
+mod status;
+mod throttle;
+mod trace;
+mod update;
+mod verify;
+mod webhook;
+use std::collections::HashMap;
+use std::sync::{self, mpsc};
+use std::{backtrace, panic, thread, time};
 
-fn main() {
-    let (tx, rx) = mpsc::channel();
-
-    ctrlc::set_handler(move || {
-        eprintln!("received shut down signal");
-        tx.send(()).expect("Could not send signal on channel.");
-    })
-    .expect("Error setting Ctrl-C handler");
+/// How long to wait for in-flight HTTP requests to finish when shutting down.
+const HTTP_SHUTDOWN_TIMEOUT: time::Duration = time::Duration::from_secs(10);
 
-    if let Err(err) = run(rx) {
+fn main() {
+    let cli = cli::parse();
+    let result: Result<(), exit::Error> = match cli.command {
+        cli::Command::Run(args) if args.service => service::run(args),
+        cli::Command::Run(args) => run(args, None),
+        cli::Command::Check { config } => run_check(&config).map_err(exit::Error::config),
+        cli::Command::NotifyTest { config } => run_notify_test(&config),
+        cli::Command::Status(agent) => run_status(&agent).map_err(Into::into),
+        cli::Command::Deploy {
+            project,
+            force,
+            sha,
+            agent,
+        } => run_deploy(&project, force, sha.as_deref(), &agent).map_err(Into::into),
+        cli::Command::Encrypt => run_encrypt().map_err(Into::into),
+        cli::Command::Db(command) => run_db_command(command),
+        cli::Command::Completions { shell } => {
+            cli::print_completions(shell);
+            Ok(())
+        }
+    };
+    if let Err(err) = &result {
         eprintln!("Failed to run agent: {err}");
-        std::process::exit(1);
+    }
+    // Traces and output may still be on their way to the collector and the log file.
+    trace::finish();
+    logging::finish();
+    if let Err(err) = result {
+        std::process::exit(err.status);
     }
 }
 
-fn run(shutdown: mpsc::Receiver<()>) -> Result<(), String> {
-    let args: Vec<String> = std::env::args().collect();
-    let config_file_path = match args.get(1) {
-        None => {
-            return Err(
-                "the path to the configuration file must be provided as a CLI argument".to_string(),
-            )
-        }
-        Some(s) => s,
+/// Run the agent until it is shut down.
+///
+/// Without a `stop` channel, which the Windows service sends to,
+///     the agent is shut down by Ctrl-C or SIGTERM.
+fn run(args: cli::RunArgs, stop: Option<mpsc::Receiver<()>>) -> Result<(), exit::Error> {
+    let cli::RunArgs {
+        config: config_file_path,
+        more_config,
+        database: database_path,
+        db_read_only,
+        watch_config,
+        overrides,
+        once,
+        daemon,
+        service: _,
+        pid_file,
+        log_file,
+    } = args;
+    // Without a config file, a single project can be configured with environment variables.
+    let config_file_paths: Vec<String> = config_file_path.into_iter().chain(more_config).collect();
+    if config_file_paths.is_empty() && std::env::var_os("CDAGENT_REPO").is_none() {
+        return Err(exit::Error::config(
+            "the path to the configuration file must be provided as a CLI argument, or CDAGENT_REPO must be set".to_string(),
+        ));
+    }
+    let database_path = match config_file_paths.is_empty() {
+        true => database_path.or_else(|| std::env::var("CDAGENT_DATABASE").ok()),
+        false => database_path,
     };
-    let database_path = args.get(2).cloned();
-    let config_file = match std::fs::read_to_string(config_file_path) {
-        Ok(s) => s,
-        Err(err) => {
-            return Err(format!(
-                "failed to read configuration file {config_file_path}: {err}"
+    let mut config = read_config(&config_file_paths, &overrides).map_err(exit::Error::config)?;
+    let pid_file = match &pid_file {
+        None => None,
+        Some(path) => Some(daemon::PidFile::new(path)?),
+    };
+    let log = match (log_file, &config.log) {
+        (Some(file), log) => Some(config::LogConfig {
+            file,
+            ..log.clone().unwrap_or_default()
+        }),
+        (None, Some(log)) if log.file.is_empty() => {
+            return Err(exit::Error::config(
+                "the log file must be set in the config or with --log-file".to_string(),
             ))
         }
+        (None, log) => log.clone(),
     };
-    let config: config::Config = match serde_yaml::from_str(&config_file) {
-        Ok(config) => config,
-        Err(err) => return Err(format!("failed to parse YAML configuration file: {err}")),
+    // The log file is opened before forking so that problems with it are reported in the terminal.
+    let log = match &log {
+        None => None,
+        Some(log) => Some(logging::Writer::open(log)?),
     };
-    eprintln!("Using the following config: {config:#?}");
-
-    let mut database = match database_path {
-        None => database::Database::new_in_memory(config),
-        Some(path) => database::Database::new_on_disk(config, &path)?,
+    if daemon {
+        daemon::daemonize(pid_file.as_ref())?;
+    } else if let Some(pid_file) = &pid_file {
+        pid_file.write(std::process::id())?;
+    }
+    if let Some(log) = log {
+        logging::start(log)?;
+    }
+    // These start threads, so they must come after forking into the background.
+    // Other subcommands are short-lived, or like `deploy` can simply be interrupted.
+    let shutdown = match stop {
+        Some(stop) => stop,
+        None => {
+            let (tx, shutdown) = mpsc::channel();
+            ctrlc::set_handler(move || {
+                eprintln!("received shut down signal");
+                tx.send(()).expect("Could not send signal on channel.");
+            })
+            .expect("Error setting Ctrl-C handler");
+            shutdown
+        }
     };
-    let mut github_client = github::Client::new(&database);
-    let poll_interval = time::Duration::from_secs(match database.config.poll_interval_seconds {
-        None | Some(0) => 300,
-        Some(d) => d,
-    });
+    eprintln!("Using the following config: {:#?}", config.redacted());
+    if let Some(tracing) = &config.tracing {
+        trace::start(tracing);
+    }
+    metrics::start_sinks(config.metrics.as_ref())?;
+
+    let db = database::open(database_path.as_deref(), &config.database, db_read_only)
+        .map_err(exit::Error::database)?;
+    let mut added_projects = project::Manager::added_projects(&*db)?;
+    add_projects(&mut config, &mut added_projects);
+    if let Some(auth) = &config.auth {
+        auth.check_projects(&config.projects)?;
+    }
+    let poll_interval = config.poll_interval();
     eprintln!("Using the following poll interval: {poll_interval:?}");
+    let pages = status::Pages::new(&config.http)?;
+    pages.api().start(
+        poll_interval,
+        api::Database::new(database_path.as_deref(), &config.database, db_read_only),
+    );
+    let backups = match &config.database.backup {
+        // A read-only instance must not rotate out the backups of the instance that owns the database.
+        _ if db_read_only => None,
+        None => None,
+        Some(backup_config) => Some(backup::Backups::new(
+            backup_config,
+            database_path.as_deref(),
+        )?),
+    };
+    // The panic hook gets its own notifiers, as the manager's are not shared between threads.
+    notify_on_panic(subscriptions(&config)?);
+    let approvals = config
+        .notifications
+        .as_ref()
+        .and_then(|n| n.approval_secret.clone())
+        .map(|secret| approval::Approvals::new(secret, pages.audit().clone()));
+    let slack_signing_secrets: Vec<String> = config
+        .notifications
+        .iter()
+        .flat_map(|n| &n.slack)
+        .chain(
+            config
+                .projects
+                .iter()
+                .filter_map(|p| p.notifications.as_ref())
+                .flat_map(|n| &n.slack),
+        )
+        .filter_map(|slack_config| slack_config.signing_secret.clone())
+        .collect();
+    let slack_interactions = match &approvals {
+        Some(approvals) if !slack_signing_secrets.is_empty() => Some(slack::Interactions::new(
+            slack_signing_secrets,
+            approvals.clone(),
+        )),
+        _ => None,
+    };
+    let controls = control::Controls::new(&config);
+    controls.update_added_projects(added_projects.iter().map(|p| &p.name));
+    let service = http::Service::new(
+        pages.clone(),
+        approvals.clone(),
+        slack_interactions,
+        // Requests would never be applied, as no projects are run.
+        (!db_read_only).then(|| controls.clone()),
+        config.auth.as_ref(),
+        &config.http,
+        config.metrics.as_ref(),
+    )?;
+    let mut manager = project::Manager::new(
+        &config,
+        db,
+        pages.clone(),
+        backups,
+        email::Notifiers {
+            agent: subscriptions(&config)?,
+            projects: project_subscriptions(&config)?,
+        },
+        approvals,
+        controls.clone(),
+    )?;
 
-    let json_data = database.json_data();
-    let html_data = database.html_data();
-    thread::spawn(move || {
-        let server = tiny_http::Server::http("0.0.0.0:8000").unwrap();
-        for request in server.incoming_requests() {
-            match request.method() {
-                tiny_http::Method::Get => {}
-                _ => {
-                    let response = tiny_http::Response::empty(tiny_http::StatusCode(405));
-                    request.respond(response).unwrap();
-                    continue;
-                }
-            }
-            let (data, content_type) = match request.url() {
-                "/" | "/index.html" => (
-                    html_data.lock().unwrap().clone(),
-                    "text/html; charset=UTF-8",
-                ),
-                "/data.json" => (
-                    json_data.lock().unwrap().clone(),
-                    "application/json; charset=UTF-8",
-                ),
-                _ => {
-                    let response = tiny_http::Response::empty(tiny_http::StatusCode(404));
-                    request.respond(response).unwrap();
-                    continue;
-                }
-            };
-            let header = tiny_http::Header::from_bytes("Content-Type", content_type).unwrap();
-            let response = tiny_http::Response::from_string(data).with_header(header);
-            request.respond(response).unwrap();
-        }
-    });
+    if once {
+        // Lifecycle notifications aren't sent, as they would be sent on every run.
+        let completed = manager.run_once(&shutdown);
+        manager.shutdown();
+        return match (completed, manager.failures()) {
+            (false, _) => Err("interrupted by a shut down signal".to_string().into()),
+            (true, []) => Ok(()),
+            (true, failures) => Err(format!(
+                "failed to redeploy or poll the following projects: {}",
+                failures.join(", ")
+            )
+            .into()),
+        };
+    }
+
+    let server = service.start("0.0.0.0:8000")?;
 
+    if db_read_only {
+        eprintln!("Database is read-only; serving the status page without running any projects");
+        _ = shutdown.recv();
+        pages.update_health(|health| health.shutting_down = true);
+        server.shutdown(HTTP_SHUTDOWN_TIMEOUT);
+        return Ok(());
+    }
+
+    let mut updater = match &config.self_update {
+        None => None,
+        Some(self_update) => Some(update::Updater::new(
+            self_update,
+            config.github.auth_token.as_deref(),
+        )?),
+    };
+    let mut installed = None;
+    let mut reload = reload::Trigger::new(&config_file_paths, watch_config);
+    manager.notify_lifecycle(true);
     loop {
+        if reload.take() {
+            reload_config(
+                &config_file_paths,
+                &overrides,
+                &mut added_projects,
+                &mut config,
+                &mut manager,
+            );
+            controls.update_added_projects(added_projects.iter().map(|p| &p.name));
+        }
+        let project_changes = controls.take_project_changes();
+        if !project_changes.is_empty() {
+            change_projects(
+                project_changes,
+                &mut added_projects,
+                &mut config,
+                &mut manager,
+            );
+            controls.update_projects(&config);
+            controls.update_added_projects(added_projects.iter().map(|p| &p.name));
+        }
         let start = time::SystemTime::now();
 
-        for project in database.projects.iter_mut() {
-            if shutdown.try_recv().is_ok() {
-                eprintln!(
-                    "running project {} interrupted because of shut down signal",
-                    project.config.name
-                );
-                // We don't return immediately but instead try to persist progress in the database
-                // before exiting.
-                break;
-            }
-            if let Err(err) = project.run(&mut github_client) {
-                eprintln!(
-                    "Failed to run one iteration for project {}: {err}",
-                    project.config.name
-                )
-            }
+        if !manager.run_once(&shutdown) {
+            break;
         }
-        github_client.persist(&mut database);
-        if let Err(err) = database.checkpoint() {
-            eprintln!("Failed to checkpoint database: {err}");
+        // No redeployment is in progress between iterations, so the agent can restart now.
+        installed = updater.as_mut().and_then(update::Updater::check);
+        if installed.is_some() {
+            break;
         }
 
         let end = time::SystemTime::now();
@@ -122,7 +303,7 @@ fn run(shutdown: mpsc::Receiver<()>) -> Result<(), String> {
         };
         match poll_interval.checked_sub(loop_duration) {
             Some(remaining) => {
-                if shutdown.recv_timeout(remaining).is_ok() {
+                if sleep(&shutdown, &controls, &reload, remaining) {
                     eprintln!("sleep interrupted because of shut down signal");
                     break;
                 }
@@ -132,5 +313,803 @@ fn run(shutdown: mpsc::Receiver<()>) -> Result<(), String> {
             }
         }
     }
+    pages.update_health(|health| health.shutting_down = true);
+    manager.shutdown();
+    manager.notify_lifecycle(false);
+    server.shutdown(HTTP_SHUTDOWN_TIMEOUT);
+    if let Some(installed) = installed {
+        // The database and pid file are released before the new binary claims them.
+        drop(manager);
+        drop(pid_file);
+        trace::finish();
+        return Err(installed.restart().into());
+    }
+    Ok(())
+}
+
+/// Reload the projects from the config file, keeping the current config if that fails.
+fn reload_config(
+    paths: &[String],
+    overrides: &[String],
+    added_projects: &mut Vec<config::ProjectConfig>,
+    current: &mut config::Config,
+    manager: &mut project::Manager,
+) {
+    if paths.is_empty() {
+        eprintln!("Not reloading the config, as it comes from environment variables");
+        return;
+    }
+    let path = paths.join(", ");
+    eprintln!("Reloading config file {path}");
+    let mut added = added_projects.clone();
+    let config = match read_config(paths, overrides).and_then(|mut config| {
+        add_projects(&mut config, &mut added);
+        manager.reload(&config, project_subscriptions(&config)?)?;
+        Ok(config)
+    }) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!(
+                "Failed to reload config file {path}; continuing with the current config: {err}"
+            );
+            return;
+        }
+    };
+    let settings = |config: &config::Config| {
+        let mut value = serde_json::to_value(config).expect("failed to serialize config");
+        value["projects"] = serde_json::Value::Null;
+        value["defaults"] = serde_json::Value::Null;
+        value["templates"] = serde_json::Value::Null;
+        value
+    };
+    if settings(&config) != settings(current) {
+        eprintln!("Only projects are reloaded; changes to other settings take effect when the agent restarts");
+    }
+    eprintln!(
+        "Reloaded {} project(s) from config file {path}",
+        config.projects.len()
+    );
+    if added.len() != added_projects.len() {
+        save_added_projects(manager, &added);
+    }
+    *current = config;
+    *added_projects = added;
+}
+
+/// Add the projects that were added through the HTTP API to the config.
+///
+/// The config file takes precedence,
+///     so added projects with the same name as a project in it are dropped.
+fn add_projects(config: &mut config::Config, added_projects: &mut Vec<config::ProjectConfig>) {
+    added_projects.retain(|project| {
+        let name = &project.name;
+        if config.projects.iter().any(|p| p.name == *name) {
+            eprintln!("[{name}] Dropping project that was added through the API, as the config file now has a project with the same name");
+            return false;
+        }
+        let mut project = project.clone();
+        if let Err(err) = project.read_secret_files() {
+            eprintln!("[{name}] Failed to read the secrets of project that was added through the API: {err}");
+        }
+        config.apply_project_defaults(&mut project);
+        config.projects.push(project);
+        true
+    });
+}
+
+/// Add and remove the projects as requested through the HTTP API,
+///     keeping the current projects if that fails.
+fn change_projects(
+    changes: Vec<control::ProjectChange>,
+    added_projects: &mut Vec<config::ProjectConfig>,
+    current: &mut config::Config,
+    manager: &mut project::Manager,
+) {
+    let mut config = current.clone();
+    config
+        .projects
+        .retain(|p| !added_projects.iter().any(|added| added.name == p.name));
+    let mut added = added_projects.clone();
+    for change in changes {
+        match change {
+            control::ProjectChange::Add(project) => {
+                let name = &project.name;
+                if config
+                    .projects
+                    .iter()
+                    .chain(&added)
+                    .any(|p| p.name == *name)
+                {
+                    eprintln!("[{name}] Not adding project as requested through the API, as there is already a project with the same name");
+                    continue;
+                }
+                eprintln!("[{name}] Adding project as requested through the API");
+                added.push(*project);
+            }
+            control::ProjectChange::Remove(name) => {
+                eprintln!("[{name}] Removing project as requested through the API");
+                added.retain(|p| p.name != name);
+            }
+        }
+    }
+    add_projects(&mut config, &mut added);
+    let result = project_subscriptions(&config)
+        .and_then(|project_notifiers| manager.reload(&config, project_notifiers));
+    if let Err(err) = result {
+        eprintln!("Failed to change the projects as requested through the API: {err}");
+        return;
+    }
+    save_added_projects(manager, &added);
+    *current = config;
+    *added_projects = added;
+}
+
+fn save_added_projects(manager: &mut project::Manager, added_projects: &[config::ProjectConfig]) {
+    if let Err(err) = manager.set_added_projects(added_projects) {
+        eprintln!("Failed to persist the projects added through the API: {err}");
+    }
+}
+
+/// Sleep for the provided duration, or until a request is made through the HTTP API
+///     or the config should be reloaded.
+///
+/// Returns true if the sleep was interrupted because of a shut down signal.
+fn sleep(
+    shutdown: &mpsc::Receiver<()>,
+    controls: &control::Controls,
+    reload: &reload::Trigger,
+    duration: time::Duration,
+) -> bool {
+    let deadline = time::Instant::now() + duration;
+    while let Some(remaining) = deadline.checked_duration_since(time::Instant::now()) {
+        if controls.pending() || reload.pending() {
+            return false;
+        }
+        if shutdown
+            .recv_timeout(remaining.min(time::Duration::from_secs(1)))
+            .is_ok()
+        {
+            return true;
+        }
+    }
+    false
+}
+
+/// Read the config files, or the config in environment variables if there are no files,
+///     applying the overrides from the command line.
+fn read_config(
+    config_file_paths: &[String],
+    overrides: &[String],
+) -> Result<config::Config, String> {
+    let (mut config, sources) = match config_file_paths {
+        [] => (config::from_env()?, vec![]),
+        paths => config::read_yaml(paths)?,
+    };
+    if !config_file_paths.is_empty() {
+        if let Err(err) = config::expand_env_vars(&mut config) {
+            return Err(format!("failed to expand configuration file: {err}"));
+        }
+    }
+    config::apply_templates(&mut config)?;
+    for assignment in overrides {
+        config::apply_override(&mut config, assignment)?;
+    }
+    encryption::decrypt_values(&mut config)?;
+    let mut config: config::Config = match serde_yaml::from_value(config.clone()) {
+        Ok(config) => config,
+        Err(err) => {
+            let err = config::suggest_field(&err.to_string());
+            // Errors from parsing the merged config have no location,
+            //     but if the error is in a project the project's location can be found.
+            let projects = config
+                .get("projects")
+                .and_then(serde_yaml::Value::as_sequence);
+            let location = projects
+                .and_then(|projects| {
+                    projects.iter().position(|project| {
+                        serde_yaml::from_value::<config::ProjectConfig>(project.clone()).is_err()
+                    })
+                })
+                .and_then(|i| sources.get(i));
+            return Err(match location {
+                None => format!("failed to parse YAML configuration file: {err}"),
+                Some(source) => format!(
+                    "failed to parse YAML configuration file: {}: {err}",
+                    source.location()
+                ),
+            });
+        }
+    };
+    config.read_secret_files()?;
+    let problems = config.validate();
+    if problems.is_empty() {
+        config.apply_defaults();
+        return Ok(config);
+    }
+    let mut message = "invalid configuration:".to_string();
+    for (i, problem) in problems {
+        match i.and_then(|i| sources.get(i)) {
+            None => message.push_str(&format!("\n  {problem}")),
+            Some(source) => message.push_str(&format!("\n  {}: {problem}", source.location())),
+        }
+    }
+    Err(message)
+}
+
+/// Run the `cdagent check <config>` subcommand.
+fn run_check(config_file_paths: &[String]) -> Result<(), String> {
+    let config = read_config(config_file_paths, &[])?;
+    let config_file_path = config_file_paths.join(", ");
+    let mut problems = check::projects(&config);
+    if let Err(err) = subscriptions(&config) {
+        problems.push(format!("notifications: {err}"));
+    }
+    if let Err(err) = project_subscriptions(&config) {
+        problems.push(format!("notifications: {err}"));
+    }
+    if let Some(templates) = config
+        .notifications
+        .as_ref()
+        .and_then(|n| n.templates.as_ref())
+    {
+        if let Err(err) = email::Templates::load(templates) {
+            problems.push(format!("notification templates: {err}"));
+        }
+    }
+    problems.extend(check::smtp(&config));
+    match status::Pages::new(&config.http) {
+        Err(err) => problems.push(format!("status page templates: {err}")),
+        Ok(pages) => {
+            if let Err(err) = http::Service::new(
+                pages,
+                None,
+                None,
+                None,
+                config.auth.as_ref(),
+                &config.http,
+                config.metrics.as_ref(),
+            ) {
+                problems.push(format!("HTTP server: {err}"));
+            }
+        }
+    }
+    if problems.is_empty() {
+        eprintln!("Config file {config_file_path} is valid");
+        return Ok(());
+    }
+    for problem in &problems {
+        eprintln!("- {problem}");
+    }
+    Err(format!(
+        "found {} problem(s) in config file {config_file_path}",
+        problems.len()
+    ))
+}
+
+/// Build a client for the running agent described by the arguments.
+///
+/// The address and token of the agent can be found in its config:
+///     the address from the status page URL and the token from the first token
+///     in the auth config that has the access needed for every project.
+fn agent_client(agent: &cli::AgentArgs, access: config::Access) -> Result<client::Client, String> {
+    let config = match &agent.config {
+        None => None,
+        Some(path) => Some(read_config(std::slice::from_ref(path), &[])?),
+    };
+    let addr = agent
+        .addr
+        .clone()
+        .or_else(|| {
+            config
+                .as_ref()?
+                .notifications
+                .as_ref()?
+                .status_page_url
+                .clone()
+        })
+        .unwrap_or_else(|| "localhost:8000".to_string());
+    let token = agent.token.clone().or_else(|| {
+        let tokens = &config.as_ref()?.auth.as_ref()?.tokens;
+        let token = tokens
+            .iter()
+            .find(|token| token.projects.is_none() && token.access >= access)?;
+        token.token.clone().or_else(|| {
+            token
+                .token_env
+                .as_ref()
+                .and_then(|env| std::env::var(env).ok())
+        })
+    });
+    Ok(client::Client::new(&addr, token.as_deref()))
+}
+
+/// Run the `cdagent status` subcommand.
+fn run_status(agent: &cli::AgentArgs) -> Result<(), String> {
+    let projects = agent_client(agent, config::Access::ReadOnly)?.projects()?;
+    let now = chrono::offset::Utc::now();
+    let ago = |time: &str| match chrono::DateTime::parse_from_rfc3339(time) {
+        Err(_) => time.to_string(),
+        Ok(time) => {
+            let minutes = (now - time.to_utc()).num_minutes().max(0);
+            match minutes {
+                0 => "just now".to_string(),
+                1..60 => format!("{minutes}m ago"),
+                60..1440 => format!("{}h ago", minutes / 60),
+                _ => format!("{}d ago", minutes / 1440),
+            }
+        }
+    };
+    let mut rows = vec![[
+        "PROJECT".to_string(),
+        "STATE".to_string(),
+        "LAST DEPLOY".to_string(),
+        "WORKFLOW RUN".to_string(),
+        "PENDING".to_string(),
+    ]];
+    for project in &projects {
+        let state = match (&project.last_run, project.paused) {
+            (_, true) => "paused",
+            (None, false) => "never run",
+            (Some(run), false) if run.status == api::Status::Failed => "FAILED",
+            (Some(_), false) => "ok",
+        };
+        let (last_deploy, workflow_run) = match &project.last_run {
+            None => ("-".to_string(), "-".to_string()),
+            Some(run) => (
+                ago(&run.finished),
+                format!(
+                    "#{} {}",
+                    run.workflow_run.number,
+                    &run.workflow_run.commit[..run.workflow_run.commit.len().min(7)]
+                ),
+            ),
+        };
+        let pending = match &project.pending {
+            None => "-".to_string(),
+            Some(pending) => format!(
+                "#{} {} ({})",
+                pending.workflow_run.number,
+                pending.reason,
+                ago(&pending.since)
+            ),
+        };
+        rows.push([
+            project.name.clone(),
+            state.to_string(),
+            last_deploy,
+            workflow_run,
+            pending,
+        ]);
+    }
+    let widths: Vec<usize> = (0..rows[0].len())
+        .map(|i| {
+            rows.iter()
+                .map(|row| row[i].chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    for row in &rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+    let failed = projects
+        .iter()
+        .filter(|p| {
+            p.last_run
+                .as_ref()
+                .is_some_and(|run| run.status == api::Status::Failed)
+        })
+        .count();
+    if failed > 0 {
+        println!("\n{failed} project(s) failed their last redeployment");
+    }
+    Ok(())
+}
+
+/// Run the `cdagent deploy` subcommand.
+///
+/// The deployment's status is polled until the redeployment starts,
+///     and then its output is streamed until it finishes.
+fn run_deploy(
+    project: &str,
+    force: bool,
+    sha: Option<&str>,
+    agent: &cli::AgentArgs,
+) -> Result<(), String> {
+    use control::DeploymentStatus;
+    let client = agent_client(agent, config::Access::Admin)?;
+    let id = client.deploy(project, force, sha)?;
+    eprintln!("Requested deployment {id} of {project}; waiting for it to start");
+    let mut streamed = false;
+    loop {
+        match client.deployment(id)?.status {
+            DeploymentStatus::Running { workflow_run }
+            | DeploymentStatus::Succeeded { workflow_run }
+            | DeploymentStatus::Failed { workflow_run }
+                if !streamed =>
+            {
+                eprintln!("Redeploying workflow run {workflow_run}");
+                stream_log(&client, project, workflow_run)?;
+                streamed = true;
+                continue;
+            }
+            DeploymentStatus::Queued | DeploymentStatus::Running { .. } => {}
+            DeploymentStatus::Succeeded { .. } => {
+                eprintln!("Redeployment of {project} succeeded");
+                return Ok(());
+            }
+            DeploymentStatus::Failed { .. } => {
+                return Err(format!("redeployment of {project} failed"));
+            }
+            DeploymentStatus::UpToDate => {
+                eprintln!("{project} is up to date; pass --force to redeploy it anyway");
+                return Ok(());
+            }
+            DeploymentStatus::Held { reason } => {
+                eprintln!("Redeployment of {project} is being held: {reason}");
+                return Ok(());
+            }
+            DeploymentStatus::Paused => return Err(format!("{project} is paused")),
+            DeploymentStatus::NoRollbackTarget => {
+                return Err(format!("{project} has no redeployment to roll back to"));
+            }
+            DeploymentStatus::Error { error } => return Err(error),
+        }
+        thread::sleep(time::Duration::from_secs(1));
+    }
+}
+
+/// Print the output of the project's redeployment of the workflow run until it finishes.
+fn stream_log(client: &client::Client, project: &str, workflow_run: u64) -> Result<(), String> {
+    use std::io::Write;
+    let mut last_event_id = None;
+    while let Some(events) = client.log_events(project, workflow_run, last_event_id.as_deref())? {
+        for event in events {
+            match event.name.as_str() {
+                "step" => println!("==> {}", event.data["name"].as_str().unwrap_or_default()),
+                "output" => print!("{}", event.data["text"].as_str().unwrap_or_default()),
+                _ => {}
+            }
+            if event.id.is_some() {
+                last_event_id = event.id;
+            }
+        }
+        _ = std::io::stdout().flush();
+        // The `done` event is the last one, and asking for events after it ends the stream.
+        if last_event_id.as_deref() == Some(live::DONE_ID) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Run the `cdagent encrypt` subcommand.
+///
+/// The value to encrypt is read from stdin, so that it doesn't end up in the shell history.
+fn run_encrypt() -> Result<(), String> {
+    let mut value = String::new();
+    if let Err(err) = std::io::Read::read_to_string(&mut std::io::stdin(), &mut value) {
+        return Err(format!("failed to read the value from stdin: {err}"));
+    }
+    let value = value.strip_suffix('\n').unwrap_or(&value);
+    println!("{}", encryption::encrypt(value)?);
+    Ok(())
+}
+
+/// Run the `cdagent notify-test --config <config>` subcommand.
+///
+/// A test message is sent with every notifier in the config, regardless of the kinds of
+///     notifications the notifier is configured to send.
+fn run_notify_test(config_file_paths: &[String]) -> Result<(), exit::Error> {
+    let config = read_config(config_file_paths, &[]).map_err(exit::Error::config)?;
+    let config_file_path = config_file_paths.join(", ");
+    let mut notifiers = subscriptions(&config).map_err(exit::Error::config)?;
+    notifiers.extend(
+        project_subscriptions(&config)
+            .map_err(exit::Error::config)?
+            .into_values()
+            .flatten(),
+    );
+    if notifiers.is_empty() {
+        return Err(exit::Error::config(format!(
+            "no notifiers are configured in {config_file_path}"
+        )));
+    }
+    let notification = email::Notification {
+        subject: "[cdagent] Test notification".to_string(),
+        body: "This is a test notification sent by `cdagent notify-test`.\n".to_string(),
+        kind: config::NotifyOn::Success,
+        event: serde_json::json!({
+            "type": "test",
+        }),
+        attachments: vec![],
+    };
+    let sends: Vec<(&email::Subscription, &email::Notification)> = notifiers
+        .iter()
+        .map(|subscription| (subscription, &notification))
+        .collect();
+    let mut num_failed = 0;
+    for (subscription, result) in notifiers.iter().zip(email::send_all(&sends)) {
+        match result {
+            Ok(()) => eprintln!("{}: sent test notification", subscription.name),
+            Err(err) => {
+                eprintln!(
+                    "{}: failed to send test notification: {err}",
+                    subscription.name
+                );
+                num_failed += 1;
+            }
+        }
+    }
+    if num_failed > 0 {
+        return Err(format!(
+            "{num_failed} of {} notifier(s) failed to send the test notification",
+            notifiers.len()
+        )
+        .into());
+    }
     Ok(())
 }
+
+/// Build the notifiers in the config.
+///
+/// If there are multiple notifiers of the same kind they are numbered; e.g., `slack #2`.
+fn subscriptions(config: &config::Config) -> Result<Vec<email::Subscription>, String> {
+    match &config.notifications {
+        None => Ok(vec![]),
+        Some(notifications) => notifiers(
+            &notifications.email,
+            &notifications.slack,
+            &notifications.webhook,
+            &notifications.ntfy,
+            &notifications.notify_on,
+            "",
+        ),
+    }
+}
+
+/// Returns the notifiers of each project that has its own, keyed by the project's name.
+fn project_subscriptions(
+    config: &config::Config,
+) -> Result<HashMap<String, Vec<email::Subscription>>, String> {
+    let notify_on = match &config.notifications {
+        None => config::default_notify_on(),
+        Some(notifications) => notifications.notify_on.clone(),
+    };
+    let mut subscriptions = HashMap::new();
+    for project_config in &config.projects {
+        let Some(notifications) = &project_config.notifications else {
+            continue;
+        };
+        let notifiers = notifiers(
+            &notifications.email,
+            &notifications.slack,
+            &notifications.webhook,
+            &notifications.ntfy,
+            &notify_on,
+            &format!(" for project {}", project_config.name),
+        );
+        match notifiers {
+            Ok(notifiers) => subscriptions.insert(project_config.name.clone(), notifiers),
+            Err(err) => return Err(format!("project {}: {err}", project_config.name)),
+        };
+    }
+    Ok(subscriptions)
+}
+
+/// Returns the notifiers, named by their kind followed by the suffix.
+fn notifiers(
+    email_configs: &[config::EmailConfig],
+    slack_configs: &[config::SlackConfig],
+    webhook_configs: &[config::WebhookConfig],
+    ntfy_configs: &[config::NtfyConfig],
+    notify_on: &[config::NotifyOn],
+    suffix: &str,
+) -> Result<Vec<email::Subscription>, String> {
+    let mut notifiers: Vec<email::Subscription> = vec![];
+    let mut subscribe = |kind: &str,
+                         (i, num): (usize, usize),
+                         notifier_notify_on: &Option<Vec<config::NotifyOn>>,
+                         notifier| {
+        notifiers.push(email::Subscription {
+            name: if num > 1 {
+                format!("{kind} #{}{suffix}", i + 1)
+            } else {
+                format!("{kind}{suffix}")
+            },
+            notify_on: notifier_notify_on
+                .clone()
+                .unwrap_or_else(|| notify_on.to_vec()),
+            notifier,
+        })
+    };
+    let num = email_configs.len();
+    for (i, email_config) in email_configs.iter().enumerate() {
+        subscribe(
+            "email",
+            (i, num),
+            &email_config.notify_on,
+            Box::new(email::Client::new(email_config.clone())?),
+        );
+    }
+    let num = slack_configs.len();
+    for (i, slack_config) in slack_configs.iter().enumerate() {
+        subscribe(
+            "slack",
+            (i, num),
+            &slack_config.notify_on,
+            Box::new(slack::Client::new(slack_config.clone())?),
+        );
+    }
+    let num = webhook_configs.len();
+    for (i, webhook_config) in webhook_configs.iter().enumerate() {
+        subscribe(
+            "webhook",
+            (i, num),
+            &webhook_config.notify_on,
+            Box::new(webhook::Client::new(webhook_config.clone())),
+        );
+    }
+    let num = ntfy_configs.len();
+    for (i, ntfy_config) in ntfy_configs.iter().enumerate() {
+        subscribe(
+            "ntfy",
+            (i, num),
+            &ntfy_config.notify_on,
+            Box::new(ntfy::Client::new(ntfy_config.clone())?),
+        );
+    }
+    Ok(notifiers)
+}
+
+/// Install a panic hook that sends a notification that the agent crashed.
+fn notify_on_panic(notifiers: Vec<email::Subscription>) {
+    let notifiers = sync::Mutex::new(notifiers);
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        // If the panic happened while sending a crash notification, don't try again.
+        let Ok(notifiers) = notifiers.try_lock() else {
+            return;
+        };
+        let thread = thread::current();
+        let thread = thread.name().unwrap_or("unnamed");
+        let notification = email::Notification {
+            subject: "[cdagent] Agent crashed".to_string(),
+            body: format!(
+                "The agent crashed in thread {thread}.\n\n{info}\n\n{}\n",
+                backtrace::Backtrace::force_capture()
+            ),
+            kind: config::NotifyOn::Failure,
+            event: serde_json::json!({
+                "type": "crashed",
+                "thread": thread,
+                "message": info.to_string(),
+            }),
+            attachments: vec![],
+        };
+        let subscriptions: Vec<&email::Subscription> = notifiers
+            .iter()
+            .filter(|subscription| notification.kind.allowed_by(&subscription.notify_on))
+            .collect();
+        let sends: Vec<(&email::Subscription, &email::Notification)> = subscriptions
+            .iter()
+            .map(|subscription| (*subscription, &notification))
+            .collect();
+        for (subscription, result) in subscriptions.into_iter().zip(email::send_all(&sends)) {
+            if let Err(err) = result {
+                eprintln!(
+                    "Failed to send crash notification with the {} notifier: {err}",
+                    subscription.name
+                );
+            }
+        }
+    }));
+}
+
+/// Run one of the `cdagent db` subcommands.
+///
+/// - `cdagent db export <database> <file>` writes all values in the database to the file.
+/// - `cdagent db import <database> <file>` replaces the contents of the database with the file.
+///
+/// The file can be `-` to use stdout or stdin respectively.
+///
+/// - `cdagent db check <database> [--fix]` reports values in the database that are corrupt or unknown.
+///   With `--fix` the bad values are deleted.
+fn run_db_command(command: cli::DbCommand) -> Result<(), exit::Error> {
+    match command {
+        cli::DbCommand::Check { database, fix } => run_db_check(&database, fix),
+        cli::DbCommand::Export {
+            database: location,
+            file,
+        } => {
+            let (location, file) = (location.as_str(), file.as_str());
+            let config = config::DatabaseConfig {
+                format: database::detect_format(location),
+                ..Default::default()
+            };
+            let db =
+                database::open(Some(location), &config, false).map_err(exit::Error::database)?;
+            let json = database::export(&*db);
+            if file == "-" {
+                println!("{json}");
+                return Ok(());
+            }
+            match std::fs::write(file, json) {
+                Ok(()) => Ok(()),
+                Err(err) => Err(format!("failed to write export file {file}: {err}").into()),
+            }
+        }
+        cli::DbCommand::Import {
+            database: location,
+            file,
+        } => {
+            let (location, file) = (location.as_str(), file.as_str());
+            let json = if file == "-" {
+                std::io::read_to_string(std::io::stdin())
+            } else {
+                std::fs::read_to_string(file)
+            };
+            let json = match json {
+                Ok(json) => json,
+                Err(err) => return Err(format!("failed to read import file {file}: {err}").into()),
+            };
+            // The import is a one-off operation, so it's always worth making it durable.
+            let config = config::DatabaseConfig {
+                durable_writes: true,
+                format: database::detect_format(location),
+                ..Default::default()
+            };
+            let mut db =
+                database::open(Some(location), &config, false).map_err(exit::Error::database)?;
+            let num_values = database::import(&mut *db, &json)?;
+            eprintln!("Imported {num_values} values into {location}");
+            audit::append(
+                &mut *db,
+                &audit::Entry::new(&audit::cli_user(), "cli", "db_import", None)
+                    .with_details(format!("imported {num_values} values from {file}")),
+            )
+            .map_err(Into::into)
+        }
+    }
+}
+
+fn run_db_check(location: &str, fix: bool) -> Result<(), exit::Error> {
+    let config = config::DatabaseConfig {
+        durable_writes: true,
+        format: database::detect_format(location),
+        ..Default::default()
+    };
+    let mut db = database::open(Some(location), &config, !fix).map_err(exit::Error::database)?;
+    let problems = project::Manager::check_database(&*db);
+    for (key, problem) in &problems {
+        eprintln!("{key}: {problem}");
+    }
+    if problems.is_empty() {
+        eprintln!("No problems found in {location}");
+        return Ok(());
+    }
+    if !fix {
+        return Err(format!(
+            "found {} problem(s) in {location}; rerun with --fix to delete the bad values",
+            problems.len()
+        )
+        .into());
+    }
+    for (key, _) in &problems {
+        db.delete(key)?;
+    }
+    eprintln!("Deleted {} bad value(s) from {location}", problems.len());
+    let keys: Vec<&str> = problems.iter().map(|(key, _)| key.as_str()).collect();
+    audit::append(
+        &mut *db,
+        &audit::Entry::new(&audit::cli_user(), "cli", "db_check_fix", None)
+            .with_details(format!("deleted {}", keys.join(", "))),
+    )
+    .map_err(Into::into)
+}
@@ -0,0 +1,356 @@
+//! Metrics about the agent, for monitoring it.
+//!
+//! Metrics are recorded in a registry shared by the whole process,
+//!     so the code that records them doesn't need to be passed anything.
+//! Recording a metric is cheap, so metrics are always recorded;
+//!     exporters and sinks make them available to monitoring systems if configured.
+//! The HTTP server serves the [Prometheus] exporter at `/metrics` by default,
+//!     and the StatsD sink pushes each update to a StatsD server or Datadog agent;
+//!     see the [statsd] module.
+//!
+//! The following metrics are recorded:
+//!
+//! - `cdagent_deployments_total`, a counter of the redeployments of each project
+//!   by outcome, either `succeeded` or `failed`.
+//! - `cdagent_step_duration_seconds`, a histogram of the durations of each project's steps.
+//! - `cdagent_github_request_duration_seconds`, a histogram of the latency of GitHub API requests
+//!   by endpoint.
+//! - `cdagent_github_cache_requests_total`, a counter of the cacheable GitHub API requests
+//!   by result, either `hit` or `miss`.
+//! - `cdagent_github_rate_limit_remaining`, a gauge of the requests remaining
+//!   in each GitHub API rate limit resource.
+//! - `cdagent_database_write_duration_seconds`, a histogram of the time taken to write to the database.
+//! - `cdagent_database_write_errors_total`, a counter of the writes to the database that failed.
+//! - `cdagent_notifications_total`, a counter of the notifications sent with each notifier
+//!   by result, either `sent` or `failed`.
+
+use crate::config;
+use crate::statsd;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync;
+use std::time;
+
+/// A metric, which has a value for each combination of values of its labels.
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: Kind,
+    pub labels: &'static [&'static str],
+}
+
+pub enum Kind {
+    /// A value that only goes up.
+    Counter,
+    /// A value that can go up and down.
+    Gauge,
+    /// A distribution of observed values, counted in buckets with the provided upper bounds.
+    Histogram(&'static [f64]),
+}
+
+/// Buckets for things that take well under a second, like requests.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Buckets for steps, which run for anything from a second to an hour.
+const STEP_BUCKETS: &[f64] = &[
+    1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0,
+];
+
+pub static DEPLOYMENTS: Metric = Metric {
+    name: "cdagent_deployments_total",
+    help: "Redeployments of the project by outcome.",
+    kind: Kind::Counter,
+    labels: &["project", "outcome"],
+};
+
+pub static STEP_DURATION: Metric = Metric {
+    name: "cdagent_step_duration_seconds",
+    help: "Time taken to run the project's steps.",
+    kind: Kind::Histogram(STEP_BUCKETS),
+    labels: &["project", "step"],
+};
+
+pub static GITHUB_REQUEST_DURATION: Metric = Metric {
+    name: "cdagent_github_request_duration_seconds",
+    help: "Latency of GitHub API requests by endpoint.",
+    kind: Kind::Histogram(LATENCY_BUCKETS),
+    labels: &["endpoint"],
+};
+
+pub static GITHUB_CACHE_REQUESTS: Metric = Metric {
+    name: "cdagent_github_cache_requests_total",
+    help: "GitHub API requests that could be answered from the cache, by whether they were.",
+    kind: Kind::Counter,
+    labels: &["result"],
+};
+
+pub static GITHUB_RATE_LIMIT_REMAINING: Metric = Metric {
+    name: "cdagent_github_rate_limit_remaining",
+    help: "Requests remaining in the GitHub API rate limit.",
+    kind: Kind::Gauge,
+    labels: &["resource"],
+};
+
+pub static DATABASE_WRITE_DURATION: Metric = Metric {
+    name: "cdagent_database_write_duration_seconds",
+    help: "Time taken to write to the database.",
+    kind: Kind::Histogram(LATENCY_BUCKETS),
+    labels: &[],
+};
+
+pub static DATABASE_WRITE_ERRORS: Metric = Metric {
+    name: "cdagent_database_write_errors_total",
+    help: "Writes to the database that failed.",
+    kind: Kind::Counter,
+    labels: &[],
+};
+
+pub static NOTIFICATIONS: Metric = Metric {
+    name: "cdagent_notifications_total",
+    help: "Notifications sent with the notifier by result.",
+    kind: Kind::Counter,
+    labels: &["notifier", "result"],
+};
+
+/// The recorded metrics, keyed by name.
+static REGISTRY: sync::Mutex<BTreeMap<&'static str, Family>> = sync::Mutex::new(BTreeMap::new());
+
+/// The values of a metric, keyed by the values of its labels.
+#[derive(Clone)]
+pub struct Family {
+    pub metric: &'static Metric,
+    pub values: BTreeMap<Vec<String>, Value>,
+}
+
+#[derive(Clone)]
+pub enum Value {
+    Counter(f64),
+    Gauge(f64),
+    Histogram {
+        /// The number of observations in each bucket, not including those in earlier buckets.
+        buckets: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+/// A change to the value of a metric.
+#[derive(Clone, Copy)]
+pub enum Update {
+    /// A counter was incremented.
+    Increment,
+    /// A gauge was set to the value.
+    Set(f64),
+    /// The value was observed in a histogram.
+    Observe(f64),
+}
+
+/// Increment a counter.
+///
+/// The label values are in the order of the metric's labels.
+pub fn increment(metric: &'static Metric, labels: &[&str]) {
+    update(metric, labels, Update::Increment);
+}
+
+/// Set the value of a gauge.
+pub fn set(metric: &'static Metric, labels: &[&str], value: f64) {
+    update(metric, labels, Update::Set(value));
+}
+
+/// Record an observation in a histogram.
+pub fn observe(metric: &'static Metric, labels: &[&str], observation: f64) {
+    update(metric, labels, Update::Observe(observation));
+}
+
+/// Record the time elapsed since the start in a histogram, in seconds.
+pub fn observe_since(metric: &'static Metric, labels: &[&str], start: time::Instant) {
+    observe(metric, labels, start.elapsed().as_secs_f64());
+}
+
+fn update(metric: &'static Metric, labels: &[&str], update: Update) {
+    debug_assert_eq!(metric.labels.len(), labels.len(), "{}", metric.name);
+    record(metric, labels, update);
+    for sink in SINKS.lock().unwrap().iter_mut() {
+        sink.push(metric, labels, update);
+    }
+}
+
+fn record(metric: &'static Metric, labels: &[&str], update: Update) {
+    let mut registry = REGISTRY.lock().unwrap();
+    let family = registry.entry(metric.name).or_insert_with(|| Family {
+        metric,
+        values: BTreeMap::new(),
+    });
+    let value = family
+        .values
+        .entry(labels.iter().map(|label| label.to_string()).collect())
+        .or_insert_with(|| match metric.kind {
+            Kind::Counter => Value::Counter(0.0),
+            Kind::Gauge => Value::Gauge(0.0),
+            Kind::Histogram(bounds) => Value::Histogram {
+                buckets: vec![0; bounds.len()],
+                sum: 0.0,
+                count: 0,
+            },
+        });
+    match (value, update, &metric.kind) {
+        (Value::Counter(count), Update::Increment, _) => *count += 1.0,
+        (Value::Gauge(gauge), Update::Set(new_value), _) => *gauge = new_value,
+        (
+            Value::Histogram {
+                buckets,
+                sum,
+                count,
+            },
+            Update::Observe(observation),
+            Kind::Histogram(bounds),
+        ) => {
+            // Observations above the last bound are only counted in the implicit `+Inf` bucket.
+            if let Some(i) = bounds.iter().position(|bound| observation <= *bound) {
+                buckets[i] += 1;
+            }
+            *sum += observation;
+            *count += 1;
+        }
+        _ => {}
+    }
+}
+
+/// Returns the metrics that have been recorded, in order of name.
+pub fn snapshot() -> Vec<Family> {
+    REGISTRY.lock().unwrap().values().cloned().collect()
+}
+
+/// Something that exports the metrics to a monitoring system.
+///
+/// Exporters are served by the HTTP server at their path, for monitoring systems to scrape.
+pub trait Exporter: Send + Sync {
+    /// The content type of the exported metrics.
+    fn content_type(&self) -> &'static str;
+
+    /// Render the metrics.
+    fn export(&self, families: &[Family]) -> String;
+}
+
+/// Something that metrics are pushed to as they are recorded,
+///     unlike exporters, which are read from.
+pub trait Sink: Send {
+    fn push(&mut self, metric: &Metric, labels: &[&str], update: Update);
+}
+
+/// The sinks that updates are pushed to.
+static SINKS: sync::Mutex<Vec<Box<dyn Sink>>> = sync::Mutex::new(vec![]);
+
+/// Start pushing metrics to the configured sinks.
+pub fn start_sinks(config: Option<&config::MetricsConfig>) -> Result<(), String> {
+    let Some(config) = config else {
+        return Ok(());
+    };
+    let mut sinks = SINKS.lock().unwrap();
+    if let Some(statsd) = &config.statsd {
+        sinks.push(Box::new(statsd::Client::new(statsd)?));
+    }
+    Ok(())
+}
+
+/// Build the configured exporters, along with the paths they are served at.
+pub fn exporters(config: Option<&config::MetricsConfig>) -> Vec<(String, Box<dyn Exporter>)> {
+    let mut exporters: Vec<(String, Box<dyn Exporter>)> = vec![];
+    let Some(config) = config else {
+        return exporters;
+    };
+    if let Some(prometheus) = &config.prometheus {
+        exporters.push((prometheus.path().to_string(), Box::new(Prometheus)));
+    }
+    exporters
+}
+
+/// Exports the metrics in the Prometheus text exposition format.
+pub struct Prometheus;
+
+impl Exporter for Prometheus {
+    fn content_type(&self) -> &'static str {
+        "text/plain; version=0.0.4; charset=UTF-8"
+    }
+
+    fn export(&self, families: &[Family]) -> String {
+        let mut s = String::new();
+        for family in families {
+            let metric = family.metric;
+            let kind = match metric.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+                Kind::Histogram(_) => "histogram",
+            };
+            _ = writeln!(s, "# HELP {} {}", metric.name, metric.help);
+            _ = writeln!(s, "# TYPE {} {kind}", metric.name);
+            for (label_values, value) in &family.values {
+                let labels: Vec<(&str, String)> = metric
+                    .labels
+                    .iter()
+                    .zip(label_values)
+                    .map(|(name, value)| (*name, value.clone()))
+                    .collect();
+                match value {
+                    Value::Counter(value) | Value::Gauge(value) => {
+                        _ = writeln!(s, "{}{} {value}", metric.name, prometheus_labels(&labels));
+                    }
+                    Value::Histogram {
+                        buckets,
+                        sum,
+                        count,
+                    } => {
+                        let Kind::Histogram(bounds) = metric.kind else {
+                            continue;
+                        };
+                        // Prometheus buckets are cumulative, ending with one containing everything.
+                        let mut cumulative = 0;
+                        let bounds = bounds.iter().map(|bound| bound.to_string());
+                        for (bound, n) in bounds.zip(buckets) {
+                            cumulative += n;
+                            let mut labels = labels.clone();
+                            labels.push(("le", bound));
+                            _ = writeln!(
+                                s,
+                                "{}_bucket{} {cumulative}",
+                                metric.name,
+                                prometheus_labels(&labels)
+                            );
+                        }
+                        let mut all = labels.clone();
+                        all.push(("le", "+Inf".to_string()));
+                        _ = writeln!(
+                            s,
+                            "{}_bucket{} {count}",
+                            metric.name,
+                            prometheus_labels(&all)
+                        );
+                        let labels = prometheus_labels(&labels);
+                        _ = writeln!(s, "{}_sum{labels} {sum}", metric.name);
+                        _ = writeln!(s, "{}_count{labels} {count}", metric.name);
+                    }
+                }
+            }
+        }
+        s
+    }
+}
+
+fn prometheus_labels(labels: &[(&str, String)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
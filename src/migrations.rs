@@ -0,0 +1,226 @@
+//! Migrations of the database schema.
+//!
+//! The schema version of a database is stored under the key `schema_version`.
+//! Whenever the format of persisted data changes, a migration is added to [MIGRATIONS]
+//!     that upgrades databases written by older versions of the agent.
+//! Migrations refer to keys and fields literally because they describe
+//!     the format at a specific point in time.
+
+use crate::database::DB;
+
+pub const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// The migrations.
+///
+/// The migration at index `i` upgrades a database from schema version `i` to `i+1`.
+/// Schema version 0 corresponds to databases written before schema versioning was introduced.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "convert from the legacy single object format",
+        run: convert_legacy_format,
+    },
+    Migration {
+        description: "populate phases, combined output and timestamps of step results",
+        run: populate_step_result_fields,
+    },
+    Migration {
+        description: "move run results to separate keys",
+        run: split_run_results,
+    },
+    Migration {
+        description: "remove GitHub auth tokens",
+        run: remove_auth_tokens,
+    },
+];
+
+struct Migration {
+    description: &'static str,
+    run: fn(&mut dyn DB) -> Result<(), String>,
+}
+
+/// The schema version written by this version of the agent.
+pub fn current_version() -> u64 {
+    MIGRATIONS.len() as u64
+}
+
+/// Upgrade the database to the current schema version.
+///
+/// Returns an error if the database was written by a newer version of the agent.
+pub fn run(db: &mut dyn DB) -> Result<(), String> {
+    let version = version(db)?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        eprintln!(
+            "Migrating database from schema version {i} to {}: {}",
+            i + 1,
+            migration.description
+        );
+        (migration.run)(db)?;
+        db.set(SCHEMA_VERSION_KEY, (i as u64 + 1).into())?;
+    }
+    if db.get(SCHEMA_VERSION_KEY).is_none() {
+        db.set(SCHEMA_VERSION_KEY, current_version().into())?;
+    }
+    Ok(())
+}
+
+/// Check that the database is at the current schema version, without migrating it.
+pub fn check(db: &dyn DB) -> Result<(), String> {
+    let version = version(db)?;
+    if version < current_version() {
+        return Err(format!(
+            "database schema version {version} is older than the current version {} and must be migrated before it can be opened read-only",
+            current_version()
+        ));
+    }
+    Ok(())
+}
+
+/// Get the schema version of the database.
+///
+/// Returns an error if the database was written by a newer version of the agent.
+fn version(db: &dyn DB) -> Result<u64, String> {
+    let version = match db.get(SCHEMA_VERSION_KEY) {
+        Some(value) => match value.as_u64() {
+            Some(version) => version,
+            None => return Err(format!("database schema version {value} is not valid")),
+        },
+        // A new database doesn't need to be migrated.
+        None if db.keys("").is_empty() => current_version(),
+        None => 0,
+    };
+    if version > current_version() {
+        return Err(format!(
+            "database schema version {version} is newer than the latest version {} supported by this agent. Downgrading the agent is not supported; either upgrade the agent or restore the database from a backup",
+            current_version()
+        ));
+    }
+    Ok(version)
+}
+
+/// Older versions of the agent stored the config, GitHub client data and a list of projects
+///     in a single JSON object.
+fn convert_legacy_format(db: &mut dyn DB) -> Result<(), String> {
+    let projects = match (db.get("config"), db.get("projects")) {
+        (Some(_), Some(serde_json::Value::Array(projects))) => projects,
+        _ => return Ok(()),
+    };
+    for project in projects {
+        if let Some(name) = project.pointer("/config/name").and_then(|n| n.as_str()) {
+            db.set(&format!("project_manager/projects/{name}"), project.clone())?;
+        }
+    }
+    db.delete("config")?;
+    db.delete("projects")
+}
+
+/// Step results gained a phase and a combined output log,
+///     and run results gained start and finish times.
+fn populate_step_result_fields(db: &mut dyn DB) -> Result<(), String> {
+    for key in db.keys("project_manager/projects/") {
+        let mut project = match db.get(&key) {
+            None => continue,
+            Some(project) => project,
+        };
+        let run_results = match project
+            .get_mut("run_results")
+            .and_then(|r| r.as_array_mut())
+        {
+            None => continue,
+            Some(run_results) => run_results,
+        };
+        for run_result in run_results {
+            let run_result = match run_result.as_object_mut() {
+                None => continue,
+                Some(run_result) => run_result,
+            };
+            for field in ["started", "finished"] {
+                run_result.entry(field).or_insert("".into());
+            }
+            let steps = match run_result.get_mut("steps").and_then(|s| s.as_array_mut()) {
+                None => continue,
+                Some(steps) => steps,
+            };
+            for step in steps {
+                let step = match step.as_object_mut() {
+                    None => continue,
+                    Some(step) => step,
+                };
+                step.entry("phase").or_insert("main".into());
+                if !step.contains_key("combined") {
+                    let combined = ["stdout", "stderr"]
+                        .iter()
+                        .filter_map(|field| step.get(*field).and_then(|s| s.as_str()))
+                        .collect::<String>();
+                    step.insert("combined".to_string(), combined.into());
+                }
+            }
+        }
+        db.set(&key, project)?;
+    }
+    Ok(())
+}
+
+/// Run results moved from the project's key to one key per run result.
+fn split_run_results(db: &mut dyn DB) -> Result<(), String> {
+    for key in db.keys("project_manager/projects/") {
+        let mut project = match db.get(&key) {
+            None => continue,
+            Some(project) => project,
+        };
+        let run_results = match project
+            .as_object_mut()
+            .and_then(|p| p.remove("run_results"))
+        {
+            None => continue,
+            Some(run_results) => run_results,
+        };
+        let name = &key["project_manager/projects/".len()..];
+        if let serde_json::Value::Array(run_results) = run_results {
+            for (i, run_result) in run_results.into_iter().enumerate() {
+                let id = run_result
+                    .get("workflow_run")
+                    .and_then(|w| w.get("id"))
+                    .and_then(|id| id.as_u64())
+                    .unwrap_or(i as u64);
+                db.set(
+                    &format!("project_manager/run_results/{name}/{id:020}"),
+                    run_result,
+                )?;
+            }
+        }
+        db.set(&key, project)?;
+    }
+    Ok(())
+}
+
+/// Auth tokens stopped being persisted, as persisted data is shown on the status pages.
+///
+/// The GitHub client's rate limit resources were keyed by auth token;
+///     they are dropped, as the client relearns them from its next requests.
+fn remove_auth_tokens(db: &mut dyn DB) -> Result<(), String> {
+    let keys = db
+        .keys("project_manager/projects/")
+        .into_iter()
+        .chain(db.keys("project_manager/run_results/"));
+    for key in keys {
+        let mut value = match db.get(&key) {
+            None => continue,
+            Some(value) => value,
+        };
+        if let Some(config) = value.get_mut("config").and_then(|c| c.as_object_mut()) {
+            config.remove("auth_token");
+        }
+        db.set(&key, value)?;
+    }
+    if let Some(mut data) = db.get("github_client") {
+        if let Some(data) = data.as_object_mut() {
+            data.remove("auth_token_to_rate_limit_resource");
+            data.insert(
+                "auth_token_fingerprint_to_rate_limit_resource".to_string(),
+                serde_json::json!({}),
+            );
+        }
+        db.set("github_client", data)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,66 @@
+//! ntfy notifications.
+
+use crate::config;
+use crate::email;
+use base64::Engine;
+use std::time::Duration;
+
+/// Client that publishes notifications to a topic on an ntfy server.
+pub struct Client {
+    agent: ureq::Agent,
+    url: String,
+    authorization: Option<String>,
+}
+
+impl Client {
+    pub fn new(config: config::NtfyConfig) -> Result<Self, String> {
+        let authorization = match (config.access_token, config.username, config.password) {
+            (None, None, None) => None,
+            (Some(token), None, None) => Some(format!("Bearer {token}")),
+            (None, Some(username), Some(password)) => {
+                let credentials = base64::engine::general_purpose::STANDARD
+                    .encode(format!("{username}:{password}"));
+                Some(format!("Basic {credentials}"))
+            }
+            _ => return Err(
+                "ntfy notifications require either no authentication, an access_token, or both username and password"
+                    .to_string(),
+            ),
+        };
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .build();
+        Ok(Self {
+            agent,
+            url: format!("{}/{}", config.server.trim_end_matches('/'), config.topic),
+            authorization,
+        })
+    }
+}
+
+impl email::Notifier for Client {
+    fn notify(&self, notification: &email::Notification) -> Result<(), String> {
+        let (priority, tags) = match notification.kind {
+            config::NotifyOn::Failure => ("high", "x"),
+            // Approval requests need someone to act on them.
+            config::NotifyOn::Approval => ("high", "hourglass_flowing_sand"),
+            config::NotifyOn::Lifecycle => ("low", "information_source"),
+            config::NotifyOn::Success | config::NotifyOn::Recovery => {
+                ("default", "white_check_mark")
+            }
+        };
+        let mut request = self
+            .agent
+            .post(&self.url)
+            .set("title", &notification.subject)
+            .set("priority", priority)
+            .set("tags", tags);
+        if let Some(authorization) = &self.authorization {
+            request = request.set("authorization", authorization);
+        }
+        if let Err(err) = request.send_string(&notification.body) {
+            return Err(format!("failed to publish notification to ntfy: {err}"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,374 @@
+//! Logging in to the status pages through an OpenID Connect (OIDC) provider.
+//!
+//! Browsers are sent to the provider to log in using the authorization code flow,
+//!     and when they return the agent exchanges the code for an ID token
+//!     and gives the browser a session cookie.
+//! The ID token is received directly from the provider's token endpoint over TLS,
+//!     so, as the OIDC spec allows, its signature is not checked.
+//!
+//! Sessions are signed with a random key rather than stored,
+//!     so they are only valid until the agent restarts.
+
+use crate::approval;
+use crate::config;
+use crate::encryption;
+use base64::Engine;
+use hmac::Mac;
+use std::sync;
+use std::time;
+
+/// The cookie holding the session of a logged in user.
+pub const SESSION_COOKIE: &str = "cdagent_session";
+
+/// The cookie holding the state of a login in progress.
+pub const LOGIN_COOKIE: &str = "cdagent_login";
+
+/// How long users have to log in with the provider.
+pub const LOGIN_TIMEOUT: time::Duration = time::Duration::from_secs(10 * 60);
+
+const DEFAULT_SESSION_DURATION: time::Duration = time::Duration::from_secs(12 * 60 * 60);
+
+pub struct Oidc {
+    config: config::OidcConfig,
+    client_secret: String,
+    /// The key sessions are signed with.
+    key: Vec<u8>,
+    agent: ureq::Agent,
+    /// The provider's metadata, discovered on the first login.
+    provider: sync::Mutex<Option<Provider>>,
+}
+
+#[derive(Clone, serde::Deserialize)]
+struct Provider {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+/// A logged in user.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Session {
+    pub name: String,
+    pub access: config::Access,
+    /// When the session expires, as a Unix timestamp.
+    expires: i64,
+}
+
+/// A login in progress, stored in the browser while the user logs in with the provider.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Login {
+    state: String,
+    nonce: String,
+    /// The path to return to after logging in.
+    next: String,
+    expires: i64,
+}
+
+/// Why a login failed, along with the HTTP status to respond with.
+pub struct Failure {
+    pub status: u16,
+    pub message: String,
+}
+
+impl Failure {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl Oidc {
+    pub fn new(config: &config::OidcConfig, client_secret: String) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(time::Duration::from_secs(10))
+            .build();
+        Self {
+            config: config.clone(),
+            client_secret,
+            key: encryption::random_bytes(32),
+            agent,
+            provider: Default::default(),
+        }
+    }
+
+    /// Start logging in.
+    ///
+    /// Returns the provider URL to redirect the browser to,
+    ///     and the value of the login cookie to set.
+    pub fn login(&self, next: &str) -> Result<(String, String), Failure> {
+        let provider = self.provider()?;
+        // Only paths on the agent are allowed, so that the login can't be used to redirect elsewhere.
+        // The path ends up in a header, so it can't contain whitespace or other special characters.
+        let next = if next.starts_with('/')
+            && !next.starts_with("//")
+            && !next.starts_with("/\\")
+            && next.chars().all(|c| c.is_ascii_graphic())
+        {
+            next
+        } else {
+            "/"
+        };
+        let login = Login {
+            state: encryption::random_hex(16),
+            nonce: encryption::random_hex(16),
+            next: next.to_string(),
+            expires: now() + LOGIN_TIMEOUT.as_secs() as i64,
+        };
+        let params = [
+            ("response_type", "code"),
+            ("client_id", &self.config.client_id),
+            ("redirect_uri", &self.config.redirect_url),
+            ("scope", &self.config.scopes.join(" ")),
+            ("state", &login.state),
+            ("nonce", &login.nonce),
+        ];
+        let query = params
+            .iter()
+            .map(|(key, value)| format!("{key}={}", approval::percent_encode(value)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let separator = if provider.authorization_endpoint.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        let url = format!("{}{separator}{query}", provider.authorization_endpoint);
+        Ok((url, self.sign("login", &login)))
+    }
+
+    /// Finish logging in, when the provider redirects the browser back to the agent.
+    ///
+    /// Returns the value of the session cookie to set and the path to return to.
+    pub fn callback(
+        &self,
+        code: Option<&str>,
+        state: Option<&str>,
+        login_cookie: Option<&str>,
+    ) -> Result<(String, String), Failure> {
+        let Some(login) = login_cookie.and_then(|cookie| self.verify::<Login>("login", cookie))
+        else {
+            return Err(Failure::new(400, "the login expired; try again"));
+        };
+        if login.expires < now() || state != Some(login.state.as_str()) {
+            return Err(Failure::new(400, "the login expired; try again"));
+        }
+        let Some(code) = code else {
+            return Err(Failure::new(
+                400,
+                "the provider didn't return an authorization code",
+            ));
+        };
+        let provider = self.provider()?;
+        let claims = self.exchange(&provider, code)?;
+        let claim = |name: &str| claims.get(name).and_then(|value| value.as_str());
+        let audience_matches = match claims.get("aud") {
+            Some(serde_json::Value::String(aud)) => *aud == self.config.client_id,
+            Some(serde_json::Value::Array(auds)) => auds
+                .iter()
+                .any(|aud| aud.as_str() == Some(&self.config.client_id)),
+            _ => false,
+        };
+        let valid = claim("iss") == Some(&provider.issuer)
+            && audience_matches
+            && claim("nonce") == Some(&login.nonce)
+            && claims
+                .get("exp")
+                .and_then(|exp| exp.as_i64())
+                .is_some_and(|exp| exp > now());
+        if !valid {
+            return Err(Failure::new(
+                502,
+                "the provider returned an invalid ID token",
+            ));
+        }
+        let Some(name) = claim("preferred_username")
+            .or_else(|| claim("email"))
+            .or_else(|| claim("sub"))
+        else {
+            return Err(Failure::new(502, "the ID token doesn't identify the user"));
+        };
+        if !self.config.allowed_groups.is_empty() {
+            let groups: Vec<&str> = match claims.get(&self.config.groups_claim) {
+                Some(serde_json::Value::String(group)) => vec![group],
+                Some(serde_json::Value::Array(groups)) => {
+                    groups.iter().filter_map(|group| group.as_str()).collect()
+                }
+                _ => vec![],
+            };
+            if !self
+                .config
+                .allowed_groups
+                .iter()
+                .any(|allowed| groups.contains(&allowed.as_str()))
+            {
+                return Err(Failure::new(
+                    403,
+                    format!("{name} is not in any of the groups allowed to log in"),
+                ));
+            }
+        }
+        let session = Session {
+            name: name.to_string(),
+            access: self.config.access,
+            expires: now() + self.session_duration().as_secs() as i64,
+        };
+        Ok((self.sign("session", &session), login.next))
+    }
+
+    /// Returns the user logged in with the session cookie, if it is valid.
+    pub fn session(&self, cookie: &str) -> Option<Session> {
+        self.verify::<Session>("session", cookie)
+            .filter(|session| session.expires > now())
+    }
+
+    /// Returns the attributes of the agent's cookies.
+    pub fn cookie_attributes(&self, max_age: time::Duration) -> String {
+        let secure = if self.config.redirect_url.starts_with("https://") {
+            "; Secure"
+        } else {
+            ""
+        };
+        // Lax cookies are sent when the provider redirects the browser back to the agent.
+        format!(
+            "Path=/; Max-Age={}; HttpOnly; SameSite=Lax{secure}",
+            max_age.as_secs()
+        )
+    }
+
+    /// Actions users who log in can take on each project, if they are restricted.
+    pub fn projects(&self) -> Option<&config::ProjectPermissions> {
+        self.config.projects.as_ref()
+    }
+
+    /// How long users stay logged in.
+    pub fn session_duration(&self) -> time::Duration {
+        self.config
+            .session_duration_seconds
+            .map(time::Duration::from_secs)
+            .unwrap_or(DEFAULT_SESSION_DURATION)
+    }
+
+    /// Returns the provider's metadata, discovering it if it hasn't been already.
+    fn provider(&self) -> Result<Provider, Failure> {
+        let mut provider = self.provider.lock().unwrap();
+        if let Some(provider) = &*provider {
+            return Ok(provider.clone());
+        }
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            self.config.issuer_url.trim_end_matches('/')
+        );
+        let discovered: Provider = match self.agent.get(&url).call() {
+            Ok(response) => match response
+                .into_string()
+                .map(|body| serde_json::from_str(&body))
+            {
+                Ok(Ok(discovered)) => discovered,
+                Ok(Err(err)) => {
+                    eprintln!("Failed to parse the OIDC provider's metadata from {url}: {err}");
+                    return Err(Failure::new(502, "the login provider is misconfigured"));
+                }
+                Err(err) => {
+                    eprintln!("Failed to read the OIDC provider's metadata from {url}: {err}");
+                    return Err(Failure::new(502, "the login provider is unavailable"));
+                }
+            },
+            Err(err) => {
+                eprintln!("Failed to fetch the OIDC provider's metadata from {url}: {err}");
+                return Err(Failure::new(502, "the login provider is unavailable"));
+            }
+        };
+        *provider = Some(discovered.clone());
+        Ok(discovered)
+    }
+
+    /// Exchange the authorization code for an ID token, and return the token's claims.
+    fn exchange(
+        &self,
+        provider: &Provider,
+        code: &str,
+    ) -> Result<serde_json::Map<String, serde_json::Value>, Failure> {
+        let credentials = base64::engine::general_purpose::STANDARD.encode(format!(
+            "{}:{}",
+            approval::percent_encode(&self.config.client_id),
+            approval::percent_encode(&self.client_secret)
+        ));
+        let result = self
+            .agent
+            .post(&provider.token_endpoint)
+            .set("Authorization", &format!("Basic {credentials}"))
+            .send_form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.config.redirect_url),
+            ]);
+        let body = match result.map(|response| response.into_string()) {
+            Ok(Ok(body)) => body,
+            Ok(Err(err)) => {
+                eprintln!("Failed to read the OIDC token response: {err}");
+                return Err(Failure::new(502, "the login provider is unavailable"));
+            }
+            Err(err) => {
+                eprintln!("Failed to exchange the OIDC authorization code: {err}");
+                return Err(Failure::new(502, "the login provider rejected the login"));
+            }
+        };
+        let claims = serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|response| {
+                let id_token = response.get("id_token")?.as_str()?;
+                let payload = id_token.split('.').nth(1)?;
+                let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+                    .decode(payload.trim_end_matches('='))
+                    .ok()?;
+                serde_json::from_slice(&payload).ok()
+            });
+        match claims {
+            Some(claims) => Ok(claims),
+            None => Err(Failure::new(
+                502,
+                "the provider returned an invalid ID token",
+            )),
+        }
+    }
+
+    /// Returns the value, encoded and signed for the provided purpose.
+    fn sign<T: serde::Serialize>(&self, purpose: &str, value: &T) -> String {
+        let json = serde_json::to_string(value).expect("failed to serialize signed value");
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(json);
+        let signature: String = self
+            .mac(purpose, &payload)
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        format!("{payload}.{signature}")
+    }
+
+    /// Returns the value if it was signed by [Oidc::sign] for the provided purpose.
+    fn verify<T: serde::de::DeserializeOwned>(&self, purpose: &str, signed: &str) -> Option<T> {
+        let (payload, signature) = signed.split_once('.')?;
+        let signature = approval::hex_decode(signature)?;
+        self.mac(purpose, payload).verify_slice(&signature).ok()?;
+        let json = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+
+    fn mac(&self, purpose: &str, payload: &str) -> hmac::Hmac<sha2::Sha256> {
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(&self.key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(purpose.as_bytes());
+        mac.update(b".");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+fn now() -> i64 {
+    chrono::offset::Utc::now().timestamp()
+}
@@ -0,0 +1,160 @@
+//! Running subprocesses for redeployment steps.
+
+use crate::config;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// Output of a subprocess.
+pub struct Output {
+    /// Whether the subprocess exited successfully.
+    pub success: bool,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// Both stdout and stderr, interleaved in the order the output was produced.
+    pub combined: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Build the command that runs a step's command line with the shell.
+pub fn command(run: &str, shell: Option<config::Shell>) -> Result<Command, String> {
+    let mut command;
+    match shell.unwrap_or_else(config::Shell::default_for_platform) {
+        config::Shell::None => {
+            let pieces = match shlex::split(run) {
+                None => return Err(format!("invalid run command {run}")),
+                Some(pieces) => pieces,
+            };
+            let Some((program, args)) = pieces.split_first() else {
+                return Err("empty run command".into());
+            };
+            command = Command::new(program);
+            command.args(args);
+        }
+        config::Shell::Sh => {
+            command = Command::new("sh");
+            command.arg("-c").arg(run);
+        }
+        config::Shell::Cmd => {
+            command = Command::new("cmd");
+            command.arg("/C");
+            // cmd parses its command line itself, so the command must be passed on unquoted.
+            #[cfg(windows)]
+            std::os::windows::process::CommandExt::raw_arg(&mut command, run);
+            #[cfg(not(windows))]
+            command.arg(run);
+        }
+        config::Shell::Powershell => {
+            command = Command::new(if cfg!(windows) { "powershell" } else { "pwsh" });
+            command.args(["-NoProfile", "-NonInteractive", "-Command", run]);
+        }
+    }
+    Ok(command)
+}
+
+/// Run the command to completion and capture its output.
+///
+/// Each chunk of output is also passed to the callback as it is produced.
+/// If an idle timeout is provided and the command produces no output for that long,
+///     the command is killed and the run fails.
+pub fn run(
+    mut command: Command,
+    idle_timeout: Option<Duration>,
+    on_output: &mut dyn FnMut(&[u8]),
+) -> Result<Output, String> {
+    let mut child = match command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => return Err(format!("failed to start subprocess: {err}")),
+    };
+    let (tx, rx) = mpsc::channel();
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let readers = [
+        forward(stdout, Stream::Stdout, tx.clone()),
+        forward(stderr, Stream::Stderr, tx),
+    ];
+    let mut output = Output {
+        success: false,
+        stdout: vec![],
+        stderr: vec![],
+        combined: vec![],
+    };
+    loop {
+        let (stream, chunk) = match idle_timeout {
+            None => match rx.recv() {
+                Ok(message) => message,
+                // The channel disconnects once both streams have been closed.
+                Err(mpsc::RecvError) => break,
+            },
+            Some(idle_timeout) => match rx.recv_timeout(idle_timeout) {
+                Ok(message) => message,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    let message = format!(
+                        "\n[cdagent] no output for {idle_timeout:?}; killing the subprocess\n"
+                    );
+                    output.stderr.extend_from_slice(message.as_bytes());
+                    output.combined.extend_from_slice(message.as_bytes());
+                    on_output(message.as_bytes());
+                    if let Err(err) = child.kill() {
+                        return Err(format!("failed to kill hung subprocess: {err}"));
+                    }
+                    // The reader threads are not joined because the streams may be held open
+                    // by descendants of the killed subprocess. The threads exit on their own
+                    // once the streams close.
+                    if let Err(err) = child.wait() {
+                        return Err(format!("failed to wait for subprocess: {err}"));
+                    }
+                    return Ok(output);
+                }
+            },
+        };
+        match stream {
+            Stream::Stdout => output.stdout.extend_from_slice(&chunk),
+            Stream::Stderr => output.stderr.extend_from_slice(&chunk),
+        }
+        output.combined.extend_from_slice(&chunk);
+        on_output(&chunk);
+    }
+    for reader in readers {
+        reader.join().expect("output reader thread panicked");
+    }
+    output.success = match child.wait() {
+        Ok(status) => status.success(),
+        Err(err) => return Err(format!("failed to wait for subprocess: {err}")),
+    };
+    Ok(output)
+}
+
+/// Spawn a thread that forwards chunks read from the stream to the channel.
+fn forward<R: Read + Send + 'static>(
+    mut reader: R,
+    stream: Stream,
+    tx: mpsc::Sender<(Stream, Vec<u8>)>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut buffer = [0_u8; 4096];
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) | Err(_) => return,
+                Ok(n) => {
+                    if tx.send((stream, buffer[..n].to_vec())).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    })
+}
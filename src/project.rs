@@ -1,12 +1,1196 @@
+use crate::api;
+use crate::approval;
+use crate::audit;
+use crate::backup;
 use crate::config;
+use crate::control;
+use crate::database;
+use crate::email;
 use crate::github;
+use crate::live;
+use crate::metrics;
+use crate::migrations;
+use crate::process;
+use crate::releases;
+use crate::status;
+use crate::trace;
+use crate::verify;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::process::Command;
+use std::sync::mpsc;
+use std::{thread, time};
+
+/// Manages all of the projects the agent is running for.
+///
+/// The manager owns the database and persists the state of each project
+///     under the key `project_manager/projects/<name>`.
+pub struct Manager {
+    db: Box<dyn database::DB>,
+    github_client: github::Client,
+    projects: Vec<Project>,
+    order: Vec<usize>,
+    pages: status::Pages,
+    backups: Option<backup::Backups>,
+    write_failure: Option<WriteFailure>,
+    notifiers: email::Notifiers,
+    notifications: Option<config::NotificationConfig>,
+    templates: Option<email::Templates>,
+    alerts: HashMap<String, Alert>,
+    notification_queue: Vec<QueuedNotification>,
+    /// Notifiers whose most recent attempt to send a notification failed, keyed by name.
+    notifier_failures: HashMap<String, NotifierFailure>,
+    /// Auth tokens that have exhausted their GitHub rate limit and have been notified about.
+    rate_limited_tokens: HashSet<String>,
+    /// For each project, the number of run results and the start time of the first and last
+    ///     run results the last time the full history was published to the status pages.
+    published_run_results: HashMap<String, (usize, String, String)>,
+    approvals: Option<approval::Approvals>,
+    controls: control::Controls,
+    /// The projects that failed to redeploy, or couldn't be polled, in the most recent iteration.
+    failures: Vec<String>,
+}
+
+/// Data shown on the status pages.
+#[derive(serde::Serialize)]
+struct Status<'a> {
+    projects: Vec<ProjectStatus<'a>>,
+    github_client: &'a github::Data,
+    database: DatabaseStatus,
+    notifier_failures: Vec<NotifierFailure>,
+}
+
+/// A project as shown on the status pages.
+#[derive(serde::Serialize)]
+struct ProjectStatus<'a> {
+    #[serde(flatten)]
+    project: &'a Project,
+    /// The most recent run results.
+    ///
+    /// The full history is published separately, as it can be large.
+    run_results: &'a [RunResult],
+    total_run_results: usize,
+}
+
+#[derive(serde::Serialize)]
+struct DatabaseStatus {
+    backups_enabled: bool,
+    last_backup: Option<String>,
+    write_failure: Option<WriteFailure>,
+}
+
+/// The most recent failure notification sent for a project.
+struct Alert {
+    /// Identifies the kind of failure; failures with the same fingerprint are considered identical.
+    fingerprint: String,
+    sent: time::Instant,
+    suppressed: usize,
+}
+
+/// Details of a notifier failing to send notifications.
+#[derive(Clone, serde::Serialize)]
+struct NotifierFailure {
+    notifier: String,
+    error: String,
+    since: String,
+    /// Number of notifications waiting to be retried with the notifier.
+    queued: usize,
+}
+
+impl NotifierFailure {
+    /// Record the outcome of an attempt to send a notification with the notifier.
+    fn record(
+        failures: &mut HashMap<String, NotifierFailure>,
+        notifier: &str,
+        result: &Result<(), String>,
+    ) {
+        match result {
+            Ok(()) => {
+                if failures.remove(notifier).is_some() {
+                    eprintln!("The {notifier} notifier is sending notifications again");
+                }
+            }
+            Err(error) => {
+                let since = match failures.remove(notifier) {
+                    None => chrono::offset::Utc::now()
+                        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                    Some(failure) => failure.since,
+                };
+                failures.insert(
+                    notifier.to_string(),
+                    NotifierFailure {
+                        notifier: notifier.to_string(),
+                        error: error.clone(),
+                        since,
+                        queued: 0,
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// A notification that failed to send and will be retried.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct QueuedNotification {
+    /// Name of the notifier that failed to send the notification.
+    notifier: String,
+    notification: email::Notification,
+    /// Unix timestamp of when the notification was first attempted.
+    queued_at: i64,
+    attempts: u32,
+    /// Unix timestamp of when to next attempt to send the notification.
+    next_attempt_at: i64,
+}
+
+impl QueuedNotification {
+    /// Returns the delay before the next attempt, given the number of attempts so far.
+    fn backoff(attempts: u32) -> i64 {
+        (60 << attempts.saturating_sub(1).min(6)).min(3600)
+    }
+}
+
+/// Details of the database writes failing.
+#[derive(Clone, serde::Serialize)]
+struct WriteFailure {
+    error: String,
+    since: String,
+}
+
+impl Manager {
+    const PROJECTS_PREFIX: &'static str = "project_manager/projects/";
+    const RUN_RESULTS_PREFIX: &'static str = "project_manager/run_results/";
+    const GITHUB_CLIENT_KEY: &'static str = "github_client";
+    const NOTIFICATION_QUEUE_KEY: &'static str = "project_manager/notification_queue";
+    const ADDED_PROJECTS_KEY: &'static str = "project_manager/added_projects";
+    /// Delays between attempts to persist state when writing to the database fails.
+    const WRITE_RETRY_DELAYS: [time::Duration; 3] = [
+        time::Duration::from_secs(1),
+        time::Duration::from_secs(2),
+        time::Duration::from_secs(4),
+    ];
+
+    /// Create a new manager for the projects in the config.
+    ///
+    /// The persisted state and run results of each project are loaded from the database.
+    /// Persisted state for projects that are no longer in the config is deleted.
+    pub fn new(
+        config: &config::Config,
+        mut db: Box<dyn database::DB>,
+        pages: status::Pages,
+        backups: Option<backup::Backups>,
+        notifiers: email::Notifiers,
+        approvals: Option<approval::Approvals>,
+        controls: control::Controls,
+    ) -> Result<Self, String> {
+        let status_page_url = config
+            .notifications
+            .as_ref()
+            .and_then(|n| n.status_page_url.as_deref());
+        check_approvals(&config.projects, approvals.is_some(), status_page_url)?;
+        let github_data = database::get(&*db, Self::GITHUB_CLIENT_KEY)?.unwrap_or_default();
+        let notification_queue =
+            database::get(&*db, Self::NOTIFICATION_QUEUE_KEY)?.unwrap_or_default();
+        pages.audit().load(&*db)?;
+        let mut projects = vec![];
+        for project_config in &config.projects {
+            projects.push(Self::load_project(&*db, project_config, &pages)?);
+        }
+        projects.sort_by_key(|p| p.config.name.clone().to_lowercase());
+        let order = dependency_order(&projects)?;
+
+        let current_keys: HashSet<String> = projects
+            .iter()
+            .map(|p| Self::project_key(&p.config.name))
+            .collect();
+        for key in db.keys(Self::PROJECTS_PREFIX) {
+            if !current_keys.contains(&key) {
+                eprintln!(
+                    "Deleting persisted state {key} for a project that is no longer in the config"
+                );
+                db.delete(&key)?;
+            }
+        }
+        let current_names: HashSet<&str> =
+            projects.iter().map(|p| p.config.name.as_str()).collect();
+        for key in db.keys(Self::RUN_RESULTS_PREFIX) {
+            let name = key[Self::RUN_RESULTS_PREFIX.len()..]
+                .rsplit_once('/')
+                .map(|(name, _)| name);
+            if !name.is_some_and(|name| current_names.contains(name)) {
+                db.delete(&key)?;
+            }
+        }
+
+        let templates = match config
+            .notifications
+            .as_ref()
+            .and_then(|n| n.templates.as_ref())
+        {
+            None => None,
+            Some(templates) => Some(email::Templates::load(templates)?),
+        };
+        // Allow for a full sleep between iterations, and for iterations that only poll GitHub.
+        let max_tick_age = 2 * config.poll_interval() + time::Duration::from_secs(60);
+        pages.update_health(|health| health.max_tick_age = max_tick_age);
+        let mut manager = Self {
+            db,
+            github_client: github::Client::new(github_data),
+            projects,
+            order,
+            pages,
+            backups,
+            write_failure: None,
+            notifiers,
+            notifications: config.notifications.clone(),
+            templates,
+            alerts: Default::default(),
+            notification_queue,
+            notifier_failures: Default::default(),
+            rate_limited_tokens: Default::default(),
+            published_run_results: Default::default(),
+            approvals,
+            controls,
+            failures: vec![],
+        };
+        manager.checkpoint();
+        Ok(manager)
+    }
+
+    /// Load the persisted state and run results of the project from the database.
+    fn load_project(
+        db: &dyn database::DB,
+        project_config: &config::ProjectConfig,
+        pages: &status::Pages,
+    ) -> Result<Project, String> {
+        let mut project =
+            match database::get::<Project>(db, &Self::project_key(&project_config.name))? {
+                None => Project::new(project_config.clone()),
+                Some(mut project) => {
+                    project.config = project_config.clone();
+                    project
+                }
+            };
+        for key in db.keys(&Self::run_results_prefix(&project_config.name)) {
+            if let Some(run_result) = database::get(db, &key)? {
+                project.run_results.push(run_result);
+            }
+        }
+        project.live = pages.live().clone();
+        // Rollbacks redeploy old workflow runs, so key order is not always chronological.
+        project
+            .run_results
+            .sort_by(|a: &RunResult, b| a.started.cmp(&b.started));
+        Ok(project)
+    }
+
+    /// Replace the projects with the projects in a reloaded config.
+    ///
+    /// Projects are matched by name.
+    /// Projects that are still in the config keep their state and run results
+    ///     and only have their config updated,
+    ///     and new projects are loaded from the database like at startup.
+    /// The persisted state of removed projects is kept until the agent restarts,
+    ///     so that a project removed by mistake can be added back without losing its history.
+    ///
+    /// If the config is invalid, the projects are unchanged.
+    pub fn reload(
+        &mut self,
+        config: &config::Config,
+        project_notifiers: HashMap<String, Vec<email::Subscription>>,
+    ) -> Result<(), String> {
+        // Notifications aren't reloaded, so approval requests are sent as configured at startup.
+        check_approvals(
+            &config.projects,
+            self.approvals.is_some(),
+            self.status_page_url(),
+        )?;
+        // The projects are sorted the same way below, so the order applies to them too.
+        let mut configured: Vec<Project> =
+            config.projects.iter().cloned().map(Project::new).collect();
+        configured.sort_by_key(|p| p.config.name.clone().to_lowercase());
+        let order = dependency_order(&configured)?;
+        let mut added = HashMap::new();
+        for project_config in &config.projects {
+            if !self
+                .projects
+                .iter()
+                .any(|p| p.config.name == project_config.name)
+            {
+                let project = Self::load_project(&*self.db, project_config, &self.pages)?;
+                added.insert(project_config.name.clone(), project);
+            }
+        }
+
+        let mut removed: HashMap<String, Project> = std::mem::take(&mut self.projects)
+            .into_iter()
+            .map(|p| (p.config.name.clone(), p))
+            .collect();
+        for project_config in &config.projects {
+            let name = &project_config.name;
+            let project = match removed.remove(name) {
+                Some(mut project) => {
+                    project.config = project_config.clone();
+                    project
+                }
+                None => {
+                    eprintln!("[{name}] Adding project");
+                    added.remove(name).expect("new project was loaded")
+                }
+            };
+            self.projects.push(project);
+        }
+        self.projects
+            .sort_by_key(|p| p.config.name.clone().to_lowercase());
+        self.order = order;
+        for name in removed.keys() {
+            eprintln!("[{name}] Removing project");
+            self.pages.remove_run_results(name);
+            self.published_run_results.remove(name);
+        }
+        self.notifiers.projects = project_notifiers;
+        self.controls.update_projects(config);
+        self.checkpoint();
+        Ok(())
+    }
+
+    /// Returns the configs of the projects added through the HTTP API, as they were submitted.
+    pub fn added_projects(db: &dyn database::DB) -> Result<Vec<config::ProjectConfig>, String> {
+        Ok(database::get(db, Self::ADDED_PROJECTS_KEY)?.unwrap_or_default())
+    }
+
+    /// Persist the configs of the projects added through the HTTP API.
+    pub fn set_added_projects(&mut self, projects: &[config::ProjectConfig]) -> Result<(), String> {
+        database::set(&mut *self.db, Self::ADDED_PROJECTS_KEY, &projects)
+    }
+
+    /// Returns the database key for the project with the provided name.
+    pub fn project_key(name: &str) -> String {
+        format!("{}{name}", Self::PROJECTS_PREFIX)
+    }
+
+    /// Returns the prefix of the database keys for the run results of the project with the provided name.
+    ///
+    /// Run results are stored separately from the rest of the project's state
+    ///     so that persisting the state doesn't require rewriting the results.
+    pub fn run_results_prefix(name: &str) -> String {
+        format!("{}{name}/", Self::RUN_RESULTS_PREFIX)
+    }
+
+    fn run_result_key(name: &str, run_result: &RunResult) -> String {
+        let mut key = format!(
+            "{}{:020}",
+            Self::run_results_prefix(name),
+            run_result.workflow_run.id
+        );
+        // A workflow run can be redeployed more than once by rolling back to it
+        //     or by redeploying it through the HTTP API.
+        if run_result.rollback_of.is_some() {
+            key.push_str(&format!("-rollback-{}", run_result.started));
+        } else if run_result.redeploy {
+            key.push_str(&format!("-redeploy-{}", run_result.started));
+        }
+        key
+    }
+
+    /// Check that every value in the database deserializes into its expected type.
+    ///
+    /// Returns each bad key along with a description of the problem.
+    /// Keys that are not used by the agent are also reported.
+    pub fn check_database(db: &dyn database::DB) -> Vec<(String, String)> {
+        let mut problems = vec![];
+        for key in db.keys("") {
+            let result = if key == migrations::SCHEMA_VERSION_KEY {
+                database::get::<u64>(db, &key).map(|_| ())
+            } else if key == Self::GITHUB_CLIENT_KEY {
+                database::get::<github::Data>(db, &key).map(|_| ())
+            } else if key == Self::NOTIFICATION_QUEUE_KEY {
+                database::get::<Vec<QueuedNotification>>(db, &key).map(|_| ())
+            } else if key == Self::ADDED_PROJECTS_KEY {
+                database::get::<Vec<config::ProjectConfig>>(db, &key).map(|_| ())
+            } else if key.starts_with(Self::RUN_RESULTS_PREFIX) {
+                database::get::<RunResult>(db, &key).map(|_| ())
+            } else if key.starts_with(audit::KEY_PREFIX) {
+                database::get::<audit::Entry>(db, &key).map(|_| ())
+            } else if let Some(name) = key.strip_prefix(Self::PROJECTS_PREFIX) {
+                match database::get::<Project>(db, &key) {
+                    Ok(Some(project)) if project.config.name != name => Err(format!(
+                        "persisted state is for project {}",
+                        project.config.name
+                    )),
+                    result => result.map(|_| ()),
+                }
+            } else {
+                Err("unknown key".to_string())
+            };
+            if let Err(err) = result {
+                problems.push((key, err));
+            }
+        }
+        problems
+    }
+
+    /// Run one iteration for each project, in dependency order.
+    ///
+    /// Returns false if the iteration was interrupted because of a shut down signal.
+    pub fn run_once(&mut self, shutdown: &mpsc::Receiver<()>) -> bool {
+        self.pages
+            .update_health(|health| health.last_tick = Some(time::Instant::now()));
+        let deploy_requests = self.apply_controls();
+        self.retry_notifications();
+        self.failures.clear();
+        let mut interrupted = false;
+        for i in self.order.clone() {
+            let upstreams: Vec<Upstream> = self
+                .projects
+                .iter()
+                .filter(|p| {
+                    self.projects[i]
+                        .config
+                        .upstreams()
+                        .any(|name| *name == p.config.name)
+                })
+                .map(Project::upstream)
+                .collect();
+            let project = &mut self.projects[i];
+            project.prune_run_results();
+            if shutdown.try_recv().is_ok() {
+                eprintln!(
+                    "running project {} interrupted because of shut down signal",
+                    project.config.name
+                );
+                // We don't return immediately but instead try to persist progress in the database
+                // before exiting.
+                interrupted = true;
+                break;
+            }
+            let previously_failed = project.run_results.last().is_some_and(|r| !r.success);
+            let status_page_url =
+                project_status_page_url(&self.notifications, &project.config).map(str::to_string);
+            let status_page_url = status_page_url.as_deref();
+            project.skip_wait = deploy_requests.iter().any(|request| {
+                request.project == project.config.name
+                    && matches!(
+                        request.action,
+                        control::Action::Deploy {
+                            skip_wait: true,
+                            ..
+                        }
+                    )
+            });
+            let rollback = deploy_requests.iter().any(|request| {
+                request.project == project.config.name
+                    && request.action == control::Action::Rollback
+            });
+            let rollback_release = deploy_requests.iter().any(|request| {
+                request.project == project.config.name
+                    && request.action == control::Action::RollbackRelease
+            });
+            // If there are several requests for specific workflow runs, the most recent wins.
+            let target = deploy_requests
+                .iter()
+                .rev()
+                .filter(|request| request.project == project.config.name)
+                .find_map(|request| match &request.action {
+                    control::Action::Deploy { target, .. }
+                        if *target != control::Target::Latest =>
+                    {
+                        Some(target.clone())
+                    }
+                    _ => None,
+                });
+            self.pages.update_health(|health| {
+                health.running = Some((project.config.name.clone(), time::Instant::now()));
+            });
+            let run = match (rollback, target) {
+                _ if rollback_release => project.rollback_to_previous_release(),
+                (true, _) => project.rollback(&mut self.github_client, shutdown),
+                (false, Some(target)) => {
+                    project.redeploy(&mut self.github_client, &target, shutdown)
+                }
+                (false, None) => project.run(
+                    &mut self.github_client,
+                    &upstreams,
+                    self.approvals.as_ref(),
+                    shutdown,
+                ),
+            };
+            self.pages.update_health(|health| {
+                health.running = None;
+                health.last_tick = Some(time::Instant::now());
+            });
+            let success =
+                matches!(run, Ok(true)) && project.run_results.last().is_some_and(|r| r.success);
+            self.pages.live().finish(&project.config.name, success);
+            if run.is_err() || (matches!(run, Ok(true)) && !success) {
+                self.failures.push(project.config.name.clone());
+            }
+            project.skip_wait = false;
+            let deployments = deploy_requests
+                .iter()
+                .filter(|request| request.project == project.config.name)
+                .filter_map(|request| request.deployment);
+            for id in deployments {
+                self.controls.update(
+                    id,
+                    project.deployment_status(&run, rollback || rollback_release),
+                );
+            }
+            let notification = match run {
+                Ok(false) => None,
+                Ok(true) => project.run_results.last().map(|result| {
+                    (
+                        result.notification(
+                            previously_failed,
+                            status_page_url,
+                            self.templates.as_ref(),
+                        ),
+                        result.failure_fingerprint(),
+                    )
+                }),
+                Err(err) => {
+                    eprintln!(
+                        "Failed to run one iteration for project {}: {err}",
+                        project.config.name
+                    );
+                    // Exhausted rate limits are notified once per auth token instead.
+                    if self
+                        .github_client
+                        .exhausted_rate_limit(&project.config.auth_token)
+                        .is_some()
+                    {
+                        None
+                    } else {
+                        let notification = project.error_notification(&err, status_page_url);
+                        Some((notification, Some(format!("error: {}", error_kind(&err)))))
+                    }
+                }
+            };
+            if let Some((notification, fingerprint)) = notification {
+                self.notify_project(i, notification, fingerprint);
+            }
+            self.remind_pending(i);
+            self.request_approval(i);
+            if self.projects[i].interrupted.take() {
+                interrupted = true;
+                break;
+            }
+        }
+        self.check_rate_limits();
+        self.checkpoint();
+        !interrupted
+    }
+
+    /// The projects that failed to redeploy, or couldn't be polled, in the most recent iteration.
+    pub fn failures(&self) -> &[String] {
+        &self.failures
+    }
+
+    /// Apply the changes to projects requested through the HTTP API.
+    ///
+    /// Returns the deploy requests, which are handled when the projects are run.
+    fn apply_controls(&mut self) -> Vec<control::Request> {
+        let mut deploy_requests = vec![];
+        for request in self.controls.take() {
+            let name = &request.project;
+            let Some(project) = self.projects.iter_mut().find(|p| p.config.name == *name) else {
+                continue;
+            };
+            match request.action {
+                control::Action::Pause => {
+                    eprintln!("[{name}] Pausing project as requested through the API");
+                    project.paused = true;
+                }
+                control::Action::Resume => {
+                    eprintln!("[{name}] Resuming project as requested through the API");
+                    project.paused = false;
+                }
+                control::Action::Deploy { ref target, .. } => {
+                    match target {
+                        control::Target::Latest => {
+                            eprintln!("[{name}] Polling project as requested through the API")
+                        }
+                        control::Target::Current => eprintln!(
+                            "[{name}] Redeploying the current workflow run as requested through the API"
+                        ),
+                        control::Target::Commit(sha) => eprintln!(
+                            "[{name}] Redeploying commit {sha} as requested through the API"
+                        ),
+                    }
+                    deploy_requests.push(request);
+                }
+                control::Action::Rollback => {
+                    eprintln!("[{name}] Rolling back project as requested through the API");
+                    deploy_requests.push(request);
+                }
+                control::Action::RollbackRelease => {
+                    eprintln!(
+                        "[{name}] Rolling back to the previous release as requested through the API"
+                    );
+                    deploy_requests.push(request);
+                }
+            }
+        }
+        deploy_requests
+    }
+
+    /// Persist all buffered changes to the database.
+    ///
+    /// This should be called before the agent shuts down.
+    pub fn shutdown(&mut self) {
+        if let Err(err) = self.db.flush() {
+            eprintln!("Failed to persist state to the database before shutting down: {err}");
+        }
+    }
+
+    /// Send a notification that the agent started or shut down.
+    pub fn notify_lifecycle(&mut self, started: bool) {
+        let version = env!("CARGO_PKG_VERSION");
+        let (subject, mut body, event) = if started {
+            let num_projects = self.projects.len();
+            (
+                "[cdagent] Agent started",
+                format!("cdagent {version} started with {num_projects} project(s).\n"),
+                serde_json::json!({
+                    "type": "started",
+                    "version": version,
+                    "projects": num_projects,
+                }),
+            )
+        } else {
+            (
+                "[cdagent] Agent shut down",
+                format!("cdagent {version} shut down cleanly.\n"),
+                serde_json::json!({
+                    "type": "stopped",
+                    "version": version,
+                }),
+            )
+        };
+        if let Some(url) = self.status_page_url() {
+            body.push_str(&format!("\nStatus page: {url}\n"));
+        }
+        self.notify(&email::Notification {
+            subject: subject.to_string(),
+            body,
+            kind: config::NotifyOn::Lifecycle,
+            event,
+            attachments: vec![],
+        });
+    }
+
+    /// Persist the state of all projects, back up the database if due, and update the status pages.
+    ///
+    /// If persisting the state fails it is retried with backoff.
+    /// If it keeps failing the failure is shown on the status pages,
+    ///     and persisting is attempted again at the next checkpoint.
+    fn checkpoint(&mut self) {
+        let mut result = self.persist();
+        for delay in Self::WRITE_RETRY_DELAYS {
+            let Err(err) = &result else {
+                break;
+            };
+            eprintln!("Failed to persist state to the database; retrying in {delay:?}: {err}");
+            thread::sleep(delay);
+            result = self.persist();
+        }
+        match result {
+            Ok(()) => {
+                if self.write_failure.take().is_some() {
+                    eprintln!("Persisting state to the database succeeded after previous failures");
+                }
+            }
+            Err(error) => {
+                eprintln!(
+                    "Failed to persist state to the database; state is no longer durable: {error}"
+                );
+                let since = match self.write_failure.take() {
+                    None => {
+                        self.notify_write_failure(&error);
+                        chrono::offset::Utc::now()
+                            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+                    }
+                    Some(write_failure) => write_failure.since,
+                };
+                self.write_failure = Some(WriteFailure { error, since });
+            }
+        }
+        if let Some(backups) = &mut self.backups {
+            backups.run_if_due(&*self.db);
+        }
+        self.pages.update_health(|health| {
+            health.database_error = self.write_failure.as_ref().map(|f| f.error.clone());
+            health.github = self.github_client.reachable().cloned();
+        });
+        for project in &self.projects {
+            let results = &project.run_results;
+            let fingerprint = (
+                results.len(),
+                results
+                    .first()
+                    .map(|r| r.started.clone())
+                    .unwrap_or_default(),
+                results
+                    .last()
+                    .map(|r| r.started.clone())
+                    .unwrap_or_default(),
+            );
+            let name = &project.config.name;
+            if self.published_run_results.get(name) != Some(&fingerprint) {
+                let values = results
+                    .iter()
+                    .map(|r| serde_json::to_value(r).expect("failed to serialize run result"))
+                    .collect();
+                self.pages.update_run_results(name, values);
+                self.pages
+                    .api()
+                    .update_runs(name, results.iter().map(RunResult::api).collect());
+                self.published_run_results.insert(name.clone(), fingerprint);
+            }
+        }
+        let mut rate_limits: Vec<api::RateLimit> =
+            self.github_client.rate_limits().map(Into::into).collect();
+        rate_limits.sort_by(|a, b| a.resource.cmp(&b.resource));
+        self.pages.api().update(
+            self.projects.iter().map(Project::api).collect(),
+            rate_limits,
+        );
+        self.pages.update(&Status {
+            projects: self
+                .projects
+                .iter()
+                .map(|project| ProjectStatus {
+                    project,
+                    run_results: &project.run_results[project
+                        .run_results
+                        .len()
+                        .saturating_sub(status::RUN_RESULTS_PER_PAGE)..],
+                    total_run_results: project.run_results.len(),
+                })
+                .collect(),
+            github_client: self.github_client.data(),
+            database: DatabaseStatus {
+                backups_enabled: self.backups.is_some(),
+                last_backup: self.backups.as_ref().and_then(backup::Backups::last_backup),
+                write_failure: self.write_failure.clone(),
+            },
+            notifier_failures: self
+                .notifier_failures
+                .values()
+                .map(|failure| NotifierFailure {
+                    queued: self
+                        .notification_queue
+                        .iter()
+                        .filter(|queued| queued.notifier == failure.notifier)
+                        .count(),
+                    ..failure.clone()
+                })
+                .collect(),
+        });
+    }
+
+    fn status_page_url(&self) -> Option<&str> {
+        self.notifications
+            .as_ref()
+            .and_then(|n| n.status_page_url.as_deref())
+    }
+
+    /// Send a notification about the project at the provided index
+    ///     with the notifiers that notifications about it are sent with.
+    fn notify_about(&mut self, i: usize, notification: &email::Notification) {
+        let project_config = &self.projects[i].config;
+        let inherit = project_config
+            .notifications
+            .as_ref()
+            .is_none_or(|notifications| notifications.inherit);
+        let project_notifiers = self
+            .notifiers
+            .projects
+            .get(&project_config.name)
+            .into_iter()
+            .flatten();
+        let notifiers: Vec<&email::Subscription> = match inherit {
+            true => self
+                .notifiers
+                .agent
+                .iter()
+                .chain(project_notifiers)
+                .collect(),
+            false => project_notifiers.collect(),
+        };
+        let queued = send(notifiers, notification, &mut self.notifier_failures);
+        self.notification_queue.extend(queued);
+    }
+
+    /// Send a notification about the project at the provided index.
+    ///
+    /// Failure notifications have a fingerprint.
+    /// A failure identical to the previous failure of the project is only notified
+    ///     if the re-alert interval has elapsed since the previous notification.
+    fn notify_project(
+        &mut self,
+        i: usize,
+        mut notification: email::Notification,
+        fingerprint: Option<String>,
+    ) {
+        let name = self.projects[i].config.name.clone();
+        match fingerprint {
+            None => {
+                self.alerts.remove(&name);
+            }
+            Some(fingerprint) => {
+                let realert_interval = time::Duration::from_secs(
+                    self.notifications
+                        .as_ref()
+                        .and_then(|n| n.realert_interval_seconds)
+                        .unwrap_or(86400),
+                );
+                if let Some(alert) = self.alerts.get_mut(&name) {
+                    if alert.fingerprint == fingerprint {
+                        if alert.sent.elapsed() < realert_interval {
+                            alert.suppressed += 1;
+                            eprintln!(
+                                "[{name}] Not sending notification \"{}\" because an identical failure was recently notified",
+                                notification.subject
+                            );
+                            return;
+                        }
+                        notification.subject = format!("{} (reminder)", notification.subject);
+                        notification.body = format!(
+                            "This failure has recurred {} time(s) since the last notification.\n\n{}",
+                            alert.suppressed + 1,
+                            notification.body
+                        );
+                    }
+                }
+                self.alerts.insert(
+                    name,
+                    Alert {
+                        fingerprint,
+                        sent: time::Instant::now(),
+                        suppressed: 0,
+                    },
+                );
+            }
+        }
+        if let Some(policy) = &self.projects[i].config.notify_on {
+            if !notification.kind.allowed_by(policy) {
+                return;
+            }
+        }
+        self.notify_about(i, &notification);
+    }
+
+    /// Send a reminder if the redeployment of the project at the provided index has been pending
+    ///     for longer than the configured threshold.
+    fn remind_pending(&mut self, i: usize) {
+        let Some(threshold) = self
+            .notifications
+            .as_ref()
+            .and_then(|n| n.pending_reminder_seconds)
+        else {
+            return;
+        };
+        let project = &mut self.projects[i];
+        let status_page_url =
+            project_status_page_url(&self.notifications, &project.config).map(str::to_string);
+        let Some(notification) = project.pending_reminder(
+            chrono::Duration::seconds(threshold as i64),
+            status_page_url.as_deref(),
+        ) else {
+            return;
+        };
+        eprintln!(
+            "[{}] Sending a reminder about the pending redeployment",
+            project.config.name
+        );
+        if let Some(policy) = &project.config.notify_on {
+            if !notification.kind.allowed_by(policy) {
+                return;
+            }
+        }
+        self.notify_about(i, &notification);
+    }
+
+    /// Send an approval request if the project at the provided index has a new redeployment
+    ///     awaiting approval.
+    fn request_approval(&mut self, i: usize) {
+        let project = &mut self.projects[i];
+        let base_url =
+            project_status_page_url(&self.notifications, &project.config).map(str::to_string);
+        let (Some(approvals), Some(base_url)) = (&self.approvals, base_url) else {
+            return;
+        };
+        let Some(notification) = project.approval_request(approvals, &base_url) else {
+            return;
+        };
+        eprintln!(
+            "[{}] Sending a request to approve the redeployment",
+            project.config.name
+        );
+        if let Some(policy) = &project.config.notify_on {
+            if !notification.kind.allowed_by(policy) {
+                return;
+            }
+        }
+        self.notify_about(i, &notification);
+    }
+
+    /// Send a notification with all of the configured notifiers that are subscribed to its kind.
+    ///
+    /// If a notifier fails to send the notification, it is queued to be retried.
+    fn notify(&mut self, notification: &email::Notification) {
+        let queued = send(
+            self.notifiers.agent.iter().collect(),
+            notification,
+            &mut self.notifier_failures,
+        );
+        self.notification_queue.extend(queued);
+    }
+
+    /// Retry sending the queued notifications that are due.
+    ///
+    /// Notifications that have been queued for longer than the retry TTL are dropped.
+    fn retry_notifications(&mut self) {
+        let ttl = self
+            .notifications
+            .as_ref()
+            .and_then(|n| n.retry_ttl_seconds)
+            .unwrap_or(86400) as i64;
+        let now = chrono::offset::Utc::now().timestamp();
+        let notifiers = &self.notifiers;
+        let find_notifier = |name: &str| {
+            notifiers
+                .agent
+                .iter()
+                .chain(notifiers.projects.values().flatten())
+                .find(|s| s.name == name)
+        };
+        self.notification_queue.retain(|queued| {
+            let subject = &queued.notification.subject;
+            if now - queued.queued_at >= ttl {
+                eprintln!(
+                    "Dropping notification \"{subject}\" after {} failed attempt(s) with the {} notifier",
+                    queued.attempts, queued.notifier
+                );
+                return false;
+            }
+            if find_notifier(&queued.notifier).is_none() {
+                eprintln!(
+                    "Dropping notification \"{subject}\" because the {} notifier is no longer configured",
+                    queued.notifier
+                );
+                return false;
+            }
+            true
+        });
+        let due: Vec<usize> = (0..self.notification_queue.len())
+            .filter(|&i| now >= self.notification_queue[i].next_attempt_at)
+            .collect();
+        let sends: Vec<(&email::Subscription, &email::Notification)> = due
+            .iter()
+            .map(|&i| {
+                let queued = &self.notification_queue[i];
+                let subscription = find_notifier(&queued.notifier)
+                    .expect("notifiers that are not configured were dropped");
+                (subscription, &queued.notification)
+            })
+            .collect();
+        let results = email::send_all(&sends);
+        let mut sent = HashSet::new();
+        for (i, result) in due.into_iter().zip(results) {
+            let queued = &mut self.notification_queue[i];
+            let subject = &queued.notification.subject;
+            NotifierFailure::record(&mut self.notifier_failures, &queued.notifier, &result);
+            match result {
+                Ok(()) => {
+                    eprintln!(
+                        "Sent notification \"{subject}\" with the {} notifier after {} failed attempt(s)",
+                        queued.notifier, queued.attempts
+                    );
+                    sent.insert(i);
+                }
+                Err(err) => {
+                    queued.attempts += 1;
+                    queued.next_attempt_at = now + QueuedNotification::backoff(queued.attempts);
+                    eprintln!(
+                        "Failed to send notification \"{subject}\" with the {} notifier (attempt {}): {err}",
+                        queued.notifier, queued.attempts
+                    );
+                }
+            }
+        }
+        let mut i = 0;
+        self.notification_queue.retain(|_| {
+            i += 1;
+            !sent.contains(&(i - 1))
+        });
+    }
+
+    /// Notify once for each auth token that has exhausted its GitHub rate limit.
+    ///
+    /// Redeployments of the projects using the token are paused until the rate limit resets.
+    fn check_rate_limits(&mut self) {
+        let mut tokens: Vec<&str> = self
+            .projects
+            .iter()
+            .map(|p| p.config.auth_token.as_str())
+            .collect();
+        tokens.sort();
+        tokens.dedup();
+        let mut notifications = vec![];
+        for token in tokens {
+            let Some((info, seconds_to_reset)) = self.github_client.exhausted_rate_limit(token)
+            else {
+                self.rate_limited_tokens.remove(token);
+                continue;
+            };
+            if !self.rate_limited_tokens.insert(token.to_string()) {
+                continue;
+            }
+            let projects: Vec<&str> = self
+                .projects
+                .iter()
+                .filter(|p| p.config.auth_token == token)
+                .map(|p| p.config.name.as_str())
+                .collect();
+            let reset = chrono::DateTime::from_timestamp(info.reset as i64, 0)
+                .unwrap_or_default()
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+            eprintln!(
+                "GitHub API rate limit reached for the auth token of {}; requests are paused until {reset}",
+                projects.join(", ")
+            );
+            let mut body = format!(
+                "The GitHub API rate limit has been reached for the auth token used by the following projects: {}.\n\n\
+                Redeployments of these projects are paused until the rate limit resets at {reset} (in {seconds_to_reset} seconds).\n\n\
+                Resource: {}\nLimit: {}\n",
+                projects.join(", "),
+                info.resource,
+                info.limit,
+            );
+            if let Some(url) = self.status_page_url() {
+                body.push_str(&format!("\nStatus page: {url}\n"));
+            }
+            notifications.push(email::Notification {
+                subject: "[cdagent] GitHub API rate limit reached".to_string(),
+                body,
+                kind: config::NotifyOn::Failure,
+                event: serde_json::json!({
+                    "type": "github_rate_limit",
+                    "projects": projects,
+                    "resource": info.resource,
+                    "limit": info.limit,
+                    "reset": reset,
+                }),
+                attachments: vec![],
+            });
+        }
+        for notification in notifications {
+            self.notify(&notification);
+        }
+    }
+
+    fn notify_write_failure(&mut self, error: &str) {
+        let mut body = format!("Writes to the database are failing, so the state of the agent is no longer durable.\n\nError: {error}\n");
+        if let Some(url) = self.status_page_url() {
+            body.push_str(&format!("\nStatus page: {url}\n"));
+        }
+        self.notify(&email::Notification {
+            subject: "[cdagent] Database writes are failing".to_string(),
+            body,
+            kind: config::NotifyOn::Failure,
+            event: serde_json::json!({
+                "type": "database_write_failure",
+                "error": error,
+            }),
+            attachments: vec![],
+        });
+    }
+
+    /// Write the state of all projects to the database.
+    fn persist(&mut self) -> Result<(), String> {
+        database::set(
+            &mut *self.db,
+            Self::GITHUB_CLIENT_KEY,
+            self.github_client.data(),
+        )?;
+        database::set(
+            &mut *self.db,
+            Self::NOTIFICATION_QUEUE_KEY,
+            &self.notification_queue,
+        )?;
+        self.pages.audit().persist(&mut *self.db)?;
+        for project in &self.projects {
+            let name = &project.config.name;
+            // Run results never change once recorded, so only new results are written.
+            let mut stale_keys: HashSet<String> = self
+                .db
+                .keys(&Self::run_results_prefix(name))
+                .into_iter()
+                .collect();
+            for run_result in &project.run_results {
+                let key = Self::run_result_key(name, run_result);
+                if !stale_keys.remove(&key) {
+                    database::set(&mut *self.db, &key, run_result)?;
+                }
+            }
+            for key in stale_keys {
+                self.db.delete(&key)?;
+            }
+            database::set(
+                &mut *self.db,
+                &Self::project_key(name),
+                &PersistedProject {
+                    config: &project.config,
+                    last_workflow_run: &project.last_workflow_run,
+                    promotion: &project.promotion,
+                    pending: &project.pending,
+                    paused: project.paused,
+                },
+            )?;
+        }
+        Ok(())
+    }
+}
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct Project {
     pub config: crate::config::ProjectConfig,
     last_workflow_run: Option<crate::github::WorkflowRun>,
+    /// Only the most recent run results are shown on the status pages.
+    #[serde(default, skip_serializing)]
     run_results: Vec<RunResult>,
+    promotion: Option<PromotionState>,
+    #[serde(default)]
+    pending: Option<PendingRedeployment>,
+    /// If the project was paused through the HTTP API.
+    ///
+    /// The project is also paused if it is paused in the config.
+    #[serde(default)]
+    paused: bool,
+    /// Skip the soak time when promoting, for a deployment requested through the HTTP API.
+    #[serde(skip)]
+    skip_wait: bool,
+    /// Whether a shut down signal was received while the project was redeploying.
+    #[serde(skip)]
+    interrupted: Cell<bool>,
+    /// Where the output of steps is published while they run.
+    #[serde(skip)]
+    live: live::Log,
+}
+
+/// The state of a project that is persisted under the project's key.
+///
+/// This excludes the run results, which are persisted under separate keys.
+#[derive(serde::Serialize)]
+struct PersistedProject<'a> {
+    config: &'a crate::config::ProjectConfig,
+    last_workflow_run: &'a Option<crate::github::WorkflowRun>,
+    promotion: &'a Option<PromotionState>,
+    pending: &'a Option<PendingRedeployment>,
+    paused: bool,
 }
 
 impl Project {
@@ -15,26 +1199,99 @@ impl Project {
             config,
             last_workflow_run: None,
             run_results: Default::default(),
+            promotion: None,
+            pending: None,
+            paused: false,
+            skip_wait: false,
+            interrupted: Cell::new(false),
+            live: Default::default(),
         }
     }
 
-    pub fn run(&mut self, github_client: &mut github::Client) -> Result<(), String> {
+    /// The project as represented in the API.
+    fn api(&self) -> api::Project {
+        api::Project {
+            name: self.config.name.clone(),
+            repository: format!("{}/{}", self.config.github_user, self.config.repo),
+            branch: self.config.mainline_branch.clone(),
+            paused: self.config.paused || self.paused,
+            latest_workflow_run: self.last_workflow_run.as_ref().map(Into::into),
+            last_run: self.run_results.last().map(RunResult::api_summary),
+            pending: self.pending.as_ref().map(|pending| api::Pending {
+                workflow_run: (&pending.workflow_run).into(),
+                since: pending.since.clone(),
+                reason: pending.reason.clone(),
+                needs_approval: pending.needs_approval,
+            }),
+            total_runs: self.run_results.len(),
+        }
+    }
+
+    /// Check for a new workflow run and redeploy the project if there is one.
+    ///
+    /// Returns whether a redeployment was run.
+    pub fn run(
+        &mut self,
+        github_client: &mut github::Client,
+        upstreams: &[Upstream],
+        approvals: Option<&approval::Approvals>,
+        shutdown: &mpsc::Receiver<()>,
+    ) -> Result<bool, String> {
         let started = chrono::offset:: Utc::now();
-        if self.config.paused {
-            return Ok(());
-        }
-        let old_workflow_run = &self.last_workflow_run;
-        let new_workflow_run = github_client.get_latest_successful_workflow_run(
-            &self.config.github_user,
-            &self.config.repo,
-            &self.config.mainline_branch,
-            &self.config.auth_token,
-        )?;
-        if let Some(old_workflow_run) = old_workflow_run {
+        if self.config.paused || self.paused {
+            return Ok(false);
+        }
+        let new_workflow_run = match &self.config.promote_from {
+            None => github_client.get_latest_successful_workflow_run(
+                &self.config.github_user,
+                &self.config.repo,
+                &self.config.mainline_branch,
+                &self.config.auth_token,
+            )?,
+            Some(_) => match self.promotion_candidate(upstreams) {
+                None => return Ok(false),
+                Some(workflow_run) => workflow_run,
+            },
+        };
+        if let Some(old_workflow_run) = &self.last_workflow_run {
             if old_workflow_run.id == new_workflow_run.id {
-                return Ok(());
+                return Ok(false);
+            }
+        }
+        for upstream in upstreams {
+            if let Err(reason) = upstream.check_ready_for(&self.config, &new_workflow_run) {
+                eprintln!(
+                    "[{}] Not redeploying workflow run {} yet: {reason}",
+                    self.config.name, new_workflow_run.id
+                );
+                self.hold(&new_workflow_run, reason, false);
+                return Ok(false);
+            }
+        }
+        if self.config.require_approval {
+            let approvals =
+                approvals.expect("approvals are configured for projects that require them");
+            match approvals.take(&self.config.name, new_workflow_run.id) {
+                None => {
+                    approvals.expect(&self.config.name, new_workflow_run.id);
+                    self.hold(&new_workflow_run, "waiting for approval".to_string(), true);
+                    return Ok(false);
+                }
+                Some(approval::Decision::Reject) => {
+                    eprintln!(
+                        "[{}] Redeployment of workflow run {} was rejected; skipping it",
+                        self.config.name, new_workflow_run.id
+                    );
+                    self.last_workflow_run = Some(new_workflow_run);
+                    self.pending = None;
+                    return Ok(false);
+                }
+                Some(approval::Decision::Approve) => {}
             }
         }
+        self.pending = None;
+        // The lock is held until the end of the redeployment.
+        let _lock = self.lock_working_directory()?;
         eprintln!(
             "[{}] New successful workflow run found: {new_workflow_run:#?}",
             self.config.name
@@ -45,44 +1302,1091 @@ impl Project {
             config: self.config.clone(),
             started: started.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
             finished: "".to_string(),
-            success: true,
+            success: false,
             workflow_run: new_workflow_run,
             steps: vec![],
+            rollback_of: None,
+            redeploy: false,
         };
-        for step in &self.config.steps {
-            let pieces = match shlex::split(&step.run) {
-                None => return Err(format!("invalid run command {}", step.run)),
-                Some(pieces) => pieces,
+        result.success = self.run_phases(github_client, &mut result, shutdown)?;
+        if let Some(promotion) = &mut self.promotion {
+            promotion.workflow_run = Some(result.workflow_run.clone());
+            promotion.status = if result.success {
+                "promoted".to_string()
+            } else {
+                "promotion failed".to_string()
             };
-            let program = match pieces.first() {
-                None => return Err("empty run command".into()),
-                Some(command) => command,
-            };
-            eprintln!("Running program {program} with args {:?}", &pieces[1..]);
-            let mut command = Command::new(program);
-            command.args(&pieces[1..]);
-            if let Some(working_directory) = &self.config.working_directory {
-                command.current_dir(working_directory);
-            }
-            let output = command.output().expect("failed to wait for subprocess");
-            let step_result = StepResult::new(step, &output);
-            result.steps.push(step_result);
-            if !output.status.success() {
-                result.success = false;
-                eprintln!("failed to run command: {:?}", result);
-                break;
-            }
         }
         let finished = chrono::offset:: Utc::now();
         result.finished = finished.to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        self.record_run_result(result);
+        Ok(true)
+    }
+
+    /// Redeploy the workflow run of the most recent successful redeployment
+    ///     before the current one.
+    ///
+    /// Workflow runs that were rolled back from are skipped.
+    /// The last workflow run seen is not changed,
+    ///     so the rolled back workflow run is not redeployed again on the next poll.
+    ///
+    /// Returns false if there is nothing to roll back to.
+    fn rollback(
+        &mut self,
+        github_client: &mut github::Client,
+        shutdown: &mpsc::Receiver<()>,
+    ) -> Result<bool, String> {
+        let Some(current) = self.run_results.last().map(|r| r.workflow_run.clone()) else {
+            return Ok(false);
+        };
+        let reverted: HashSet<u64> = self
+            .run_results
+            .iter()
+            .filter_map(|r| r.rollback_of.as_ref())
+            .map(|workflow_run| workflow_run.id)
+            .collect();
+        let Some(target) = self
+            .run_results
+            .iter()
+            .rev()
+            .find(|r| {
+                r.success
+                    && r.workflow_run.id != current.id
+                    && !reverted.contains(&r.workflow_run.id)
+            })
+            .map(|r| r.workflow_run.clone())
+        else {
+            return Ok(false);
+        };
+        // The lock is held until the end of the redeployment.
+        let _lock = self.lock_working_directory()?;
+        eprintln!(
+            "[{}] Rolling back from workflow run {} to workflow run {}",
+            self.config.name, current.id, target.id
+        );
+        let mut result = RunResult {
+            config: self.config.clone(),
+            started: chrono::offset::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            finished: "".to_string(),
+            success: false,
+            workflow_run: target,
+            steps: vec![],
+            rollback_of: Some(current),
+            redeploy: false,
+        };
+        result.success = self.run_phases(github_client, &mut result, shutdown)?;
+        result.finished =
+            chrono::offset::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        self.record_run_result(result);
+        Ok(true)
+    }
+
+    /// Switch back to the release of the most recent successful redeployment before the current one
+    ///     and run the restart steps, as requested through the HTTP API.
+    ///
+    /// Unlike [Self::rollback], nothing is redeployed: the previous release is still on disk,
+    ///     so only the `current` symlink is switched.
+    ///
+    /// Returns false if there is no previous release to roll back to.
+    fn rollback_to_previous_release(&mut self) -> Result<bool, String> {
+        let Some(releases) = self.config.releases.clone() else {
+            return Err("the project doesn't have managed releases".to_string());
+        };
+        let successful = self
+            .run_results
+            .iter()
+            .rev()
+            .filter(|r| r.success)
+            .map(|r| r.workflow_run.head_sha.as_str());
+        let Some(previous) = releases::previous(&releases.root, successful) else {
+            return Ok(false);
+        };
+        let Some(target) = self
+            .run_results
+            .iter()
+            .rev()
+            .find(|r| r.success && r.workflow_run.head_sha == previous)
+            .map(|r| r.workflow_run.clone())
+        else {
+            return Ok(false);
+        };
+        let current = releases::current(&releases.root);
+        let Some(current) = self
+            .run_results
+            .iter()
+            .rev()
+            .find(|r| Some(&r.workflow_run.head_sha) == current.as_ref())
+            .or(self.run_results.last())
+            .map(|r| r.workflow_run.clone())
+        else {
+            return Ok(false);
+        };
+        // The lock is held until the end of the rollback.
+        let _lock = self.lock_working_directory()?;
+        eprintln!(
+            "[{}] Rolling back from release {} to release {}",
+            self.config.name, current.head_sha, target.head_sha
+        );
+        let mut result = RunResult {
+            config: self.config.clone(),
+            started: chrono::offset::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            finished: "".to_string(),
+            success: false,
+            workflow_run: target,
+            steps: vec![],
+            rollback_of: Some(current),
+            redeploy: false,
+        };
+        let sha = result.workflow_run.head_sha.clone();
+        result.success = self.rollback_release(&releases, &sha, &mut result)?;
+        result.finished =
+            chrono::offset::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        self.record_run_result(result);
+        Ok(true)
+    }
+
+    /// Redeploy a specific workflow run, as requested through the HTTP API.
+    ///
+    /// Like rollbacks, these redeployments don't wait for upstream projects or for approval.
+    /// The last workflow run seen is only changed if the workflow run is newer,
+    ///     so that it is not redeployed again on the next poll.
+    ///
+    /// Returns false if the project is paused.
+    fn redeploy(
+        &mut self,
+        github_client: &mut github::Client,
+        target: &control::Target,
+        shutdown: &mpsc::Receiver<()>,
+    ) -> Result<bool, String> {
+        if self.config.paused || self.paused {
+            return Ok(false);
+        }
+        let workflow_run = match target {
+            control::Target::Latest => {
+                unreachable!("redeployments of the latest workflow run are polled for")
+            }
+            control::Target::Current => match self.run_results.last() {
+                Some(result) => result.workflow_run.clone(),
+                None => return Err("the project has not been redeployed yet".to_string()),
+            },
+            control::Target::Commit(sha) => github_client.get_successful_workflow_run_for_commit(
+                &self.config.github_user,
+                &self.config.repo,
+                sha,
+                &self.config.auth_token,
+            )?,
+        };
+        // The lock is held until the end of the redeployment.
+        let _lock = self.lock_working_directory()?;
+        eprintln!(
+            "[{}] Redeploying workflow run {} for commit {}",
+            self.config.name, workflow_run.id, workflow_run.head_sha
+        );
+        if self
+            .last_workflow_run
+            .as_ref()
+            .is_none_or(|last| last.id < workflow_run.id)
+        {
+            self.last_workflow_run = Some(workflow_run.clone());
+        }
+        if self
+            .pending
+            .as_ref()
+            .is_some_and(|pending| pending.workflow_run.id <= workflow_run.id)
+        {
+            self.pending = None;
+        }
+        let mut result = RunResult {
+            config: self.config.clone(),
+            started: chrono::offset::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            finished: "".to_string(),
+            success: false,
+            workflow_run,
+            steps: vec![],
+            rollback_of: None,
+            redeploy: true,
+        };
+        result.success = self.run_phases(github_client, &mut result, shutdown)?;
+        result.finished =
+            chrono::offset::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        self.record_run_result(result);
+        Ok(true)
+    }
+
+    /// Returns the status of a requested deployment, given the outcome of running the project.
+    fn deployment_status(
+        &self,
+        run: &Result<bool, String>,
+        rollback: bool,
+    ) -> control::DeploymentStatus {
+        match run {
+            Err(error) => control::DeploymentStatus::Error {
+                error: error.clone(),
+            },
+            Ok(true) => {
+                let result = self
+                    .run_results
+                    .last()
+                    .expect("a run result is recorded for every redeployment");
+                let workflow_run = result.workflow_run.id;
+                if result.success {
+                    control::DeploymentStatus::Succeeded { workflow_run }
+                } else {
+                    control::DeploymentStatus::Failed { workflow_run }
+                }
+            }
+            Ok(false) if rollback => control::DeploymentStatus::NoRollbackTarget,
+            Ok(false) if self.config.paused || self.paused => control::DeploymentStatus::Paused,
+            Ok(false) => match (&self.pending, &self.promotion) {
+                (Some(pending), _) => control::DeploymentStatus::Held {
+                    reason: pending.reason.clone(),
+                },
+                (None, Some(promotion))
+                    if !matches!(
+                        promotion.status.as_str(),
+                        "promoting" | "promoted" | "promotion failed"
+                    ) =>
+                {
+                    control::DeploymentStatus::Held {
+                        reason: promotion.status.clone(),
+                    }
+                }
+                _ => control::DeploymentStatus::UpToDate,
+            },
+        }
+    }
+
+    /// Record that the redeployment of the workflow run is being held for the provided reason.
+    fn hold(&mut self, workflow_run: &github::WorkflowRun, reason: String, needs_approval: bool) {
+        match &mut self.pending {
+            Some(pending) if pending.workflow_run.id == workflow_run.id => {
+                pending.reason = reason;
+                pending.needs_approval = needs_approval;
+            }
+            _ => {
+                self.pending = Some(PendingRedeployment {
+                    workflow_run: workflow_run.clone(),
+                    since: chrono::offset::Utc::now()
+                        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                    reason,
+                    reminded: false,
+                    needs_approval,
+                    approval_requested: false,
+                });
+            }
+        }
+    }
+
+    /// Build a request to approve the pending redeployment, if it needs approval and
+    ///     a request hasn't been built already.
+    fn approval_request(
+        &mut self,
+        approvals: &approval::Approvals,
+        base_url: &str,
+    ) -> Option<email::Notification> {
+        let name = &self.config.name;
+        let pending = self.pending.as_mut()?;
+        if !pending.needs_approval || pending.approval_requested {
+            return None;
+        }
+        pending.approval_requested = true;
+        let workflow_run = &pending.workflow_run;
+        let approve_url =
+            approvals.link(base_url, name, workflow_run.id, approval::Decision::Approve);
+        let reject_url =
+            approvals.link(base_url, name, workflow_run.id, approval::Decision::Reject);
+        let body = format!(
+            "A new redeployment of {name} is awaiting approval.\n\nCommit: {}\nWorkflow run: {} ({})\n\n\
+            Approve: {approve_url}\nReject: {reject_url}\n\n\
+            Each link can only be used once, and only while this redeployment is pending.\n",
+            workflow_run.head_sha, workflow_run.display_title, workflow_run.html_url,
+        );
+        Some(email::Notification {
+            subject: format!("[cdagent] Approve redeployment of {name}?"),
+            body,
+            kind: config::NotifyOn::Approval,
+            event: serde_json::json!({
+                "type": "approval",
+                "project": name,
+                "workflow_run": workflow_run,
+                "approve_url": approve_url,
+                "reject_url": reject_url,
+            }),
+            attachments: vec![],
+        })
+    }
+
+    /// Build a reminder about the pending redeployment if it has been held for longer than the threshold.
+    ///
+    /// Only one reminder is built for each pending redeployment.
+    fn pending_reminder(
+        &mut self,
+        threshold: chrono::Duration,
+        status_page_url: Option<&str>,
+    ) -> Option<email::Notification> {
+        let name = &self.config.name;
+        let pending = self.pending.as_mut()?;
+        if pending.reminded {
+            return None;
+        }
+        let since = chrono::DateTime::parse_from_rfc3339(&pending.since).ok()?;
+        if since + threshold > chrono::offset::Utc::now() {
+            return None;
+        }
+        pending.reminded = true;
+        let workflow_run = &pending.workflow_run;
+        let mut body = format!(
+            "The redeployment of {name} has been pending since {}.\n\nReason: {}\n\nCommit: {}\nWorkflow run: {} ({})\n",
+            pending.since,
+            pending.reason,
+            workflow_run.head_sha,
+            workflow_run.display_title,
+            workflow_run.html_url,
+        );
+        if let Some(url) = status_page_url {
+            body.push_str(&format!("\nStatus page: {}\n", project_page_url(url, name)));
+        }
+        Some(email::Notification {
+            subject: format!("[cdagent] Redeployment of {name} is pending"),
+            body,
+            kind: config::NotifyOn::Failure,
+            event: serde_json::json!({
+                "type": "pending",
+                "project": name,
+                "since": pending.since,
+                "reason": pending.reason,
+                "workflow_run": workflow_run,
+            }),
+            attachments: vec![],
+        })
+    }
+
+    /// Build a notification about an error that prevented checking the project for redeployments.
+    fn error_notification(&self, err: &str, status_page_url: Option<&str>) -> email::Notification {
+        let name = &self.config.name;
+        let mut body = format!("Failed to check {name} for redeployments.\n\nError: {err}\n");
+        if let Some(url) = status_page_url {
+            body.push_str(&format!("\nStatus page: {}\n", project_page_url(url, name)));
+        }
+        email::Notification {
+            subject: format!("[cdagent] Failed to check {name} for redeployments"),
+            body,
+            kind: config::NotifyOn::Failure,
+            event: serde_json::json!({
+                "type": "error",
+                "project": name,
+                "error": err,
+            }),
+            attachments: vec![],
+        }
+    }
+
+    /// Record the result of a finished redeployment.
+    fn record_run_result(&mut self, result: RunResult) {
+        let outcome = if result.success {
+            "succeeded"
+        } else {
+            "failed"
+        };
+        metrics::increment(&metrics::DEPLOYMENTS, &[&self.config.name, outcome]);
         self.run_results.push(result);
-        while self.run_results.len() >= self.config.retention {
-            self.run_results.remove(0);
+        self.prune_run_results();
+    }
+
+    /// Remove run results that are beyond the retention limits of the project.
+    fn prune_run_results(&mut self) {
+        if self.run_results.len() > self.config.retention() {
+            let excess = self.run_results.len() - self.config.retention();
+            self.run_results.drain(..excess);
+        }
+        if let Some(retention_days) = self.config.retention_days {
+            let cutoff = chrono::offset::Utc::now() - chrono::Duration::days(retention_days as i64);
+            self.run_results.retain(|result| {
+                match chrono::DateTime::parse_from_rfc3339(&result.started) {
+                    Ok(started) => started >= cutoff,
+                    // Results from old versions of the agent may not have a start time.
+                    Err(_) => true,
+                }
+            });
+        }
+    }
+
+    /// Returns the state of this project as seen by projects that depend on it.
+    pub fn upstream(&self) -> Upstream {
+        Upstream {
+            config: self.config.clone(),
+            last_workflow_run: self.last_workflow_run.clone(),
+            last_run_result: self.run_results.last().cloned(),
+        }
+    }
+
+    /// Run all of the phases of a redeployment, recording it as a trace.
+    ///
+    /// Returns whether the redeployment succeeded.
+    fn run_phases(
+        &self,
+        github_client: &mut github::Client,
+        result: &mut RunResult,
+        shutdown: &mpsc::Receiver<()>,
+    ) -> Result<bool, String> {
+        let mut span = trace::Span::root(&format!("redeploy {}", self.config.name));
+        span.attribute("cdagent.project", self.config.name.as_str());
+        span.attribute("cdagent.workflow_run.id", result.workflow_run.id);
+        span.attribute(
+            "vcs.ref.head.revision",
+            result.workflow_run.head_sha.as_str(),
+        );
+        span.attribute("cdagent.rollback", result.rollback_of.is_some());
+        let deployed = self.run_phases_untraced(github_client, result, shutdown);
+        match &deployed {
+            Ok(true) => {}
+            Ok(false) => span.fail("the redeployment failed"),
+            Err(err) => span.fail(err),
+        }
+        deployed
+    }
+
+    /// [Self::run_phases] without the trace.
+    fn run_phases_untraced(
+        &self,
+        github_client: &mut github::Client,
+        result: &mut RunResult,
+        shutdown: &mpsc::Receiver<()>,
+    ) -> Result<bool, String> {
+        if let Some(verification) = &self.config.verify {
+            if !self.verify(verification, github_client, result) {
+                eprintln!(
+                    "[{}] Verification of the artifacts failed; refusing to redeploy",
+                    self.config.name
+                );
+                return Ok(false);
+            }
+        }
+        if let Some(promotion) = &self.config.promote_from {
+            if !self.run_steps(&promotion.health_checks, Phase::Promotion, result)? {
+                return Ok(false);
+            }
+        }
+        if let Some(releases) = &self.config.releases {
+            let step_result = StepResult::internal(
+                "Create release directory",
+                format!("mkdir -p releases/{}", result.workflow_run.head_sha),
+                Phase::Release,
+                releases::create(&releases.root, &result.workflow_run.head_sha),
+            );
+            if !result.push(step_result) {
+                return Ok(false);
+            }
+        }
+        let deployed = match &self.config.canary {
+            None => self.run_steps(&self.config.steps, Phase::Main, result)?,
+            Some(canary) => {
+                if self.run_steps(&canary.steps, Phase::Canary, result)?
+                    && self.bake(canary, result, shutdown)?
+                {
+                    self.run_steps(&self.config.steps, Phase::Main, result)?
+                } else {
+                    eprintln!("[{}] Canary failed; rolling back", self.config.name);
+                    self.run_steps(&canary.rollback_steps, Phase::Rollback, result)?;
+                    false
+                }
+            }
+        };
+        match &self.config.releases {
+            Some(releases) if deployed => self.activate_release(releases, result),
+            _ => Ok(deployed),
+        }
+    }
+
+    /// Switch the current release to the release being deployed and run the restart steps.
+    ///
+    /// If the restart steps fail, the previous release is restored.
+    /// Returns whether the new release was successfully activated.
+    fn activate_release(
+        &self,
+        releases: &config::Releases,
+        result: &mut RunResult,
+    ) -> Result<bool, String> {
+        let sha = result.workflow_run.head_sha.clone();
+        let previous = releases::current(&releases.root);
+        let step_result = StepResult::internal(
+            "Activate release",
+            format!("ln -sfn releases/{sha} current"),
+            Phase::Release,
+            releases::activate(&releases.root, &sha),
+        );
+        if !result.push(step_result) {
+            return Ok(false);
+        }
+        if self.run_steps(&releases.restart, Phase::Restart, result)? {
+            let keep = match releases.keep {
+                None | Some(0) => 5,
+                Some(keep) => keep,
+            };
+            // Failing to prune old releases doesn't fail the redeployment.
+            result.push(StepResult::internal(
+                "Prune releases",
+                format!("keep the newest {keep} releases"),
+                Phase::Release,
+                releases::prune(&releases.root, keep),
+            ));
+            return Ok(true);
+        }
+        match previous {
+            Some(previous) if previous != sha => {
+                eprintln!(
+                    "[{}] Restart failed; rolling back to release {previous}",
+                    self.config.name
+                );
+                self.rollback_release(releases, &previous, result)?;
+            }
+            _ => eprintln!(
+                "[{}] Restart failed and there is no previous release to roll back to",
+                self.config.name
+            ),
+        }
+        Ok(false)
+    }
+
+    /// Roll back to a previous release.
+    ///
+    /// This switches the current release back to the previous release and re-runs the restart steps.
+    /// Returns whether the rollback succeeded.
+    fn rollback_release(
+        &self,
+        releases: &config::Releases,
+        previous: &str,
+        result: &mut RunResult,
+    ) -> Result<bool, String> {
+        let step_result = StepResult::internal(
+            "Restore previous release",
+            format!("ln -sfn releases/{previous} current"),
+            Phase::Rollback,
+            releases::activate(&releases.root, previous),
+        );
+        if !result.push(step_result) {
+            return Ok(false);
+        }
+        self.run_steps(&releases.restart, Phase::Rollback, result)
+    }
+
+    /// Acquire an advisory lock on the working directory of the project.
+    ///
+    /// This prevents overlapping redeployments in the same directory,
+    ///     both from other projects and from other agent processes.
+    /// The lock is released when the returned file is dropped.
+    ///
+    /// Directories can't be locked on Windows, so there a `.cdagent.lock` file in the directory
+    ///     is locked instead.
+    fn lock_working_directory(&self) -> Result<std::fs::File, String> {
+        let dir = match (&self.config.releases, &self.config.working_directory) {
+            (Some(releases), _) => &releases.root,
+            (None, Some(working_directory)) => working_directory,
+            (None, None) => ".",
+        };
+        if let Some(releases) = &self.config.releases {
+            if let Err(err) = std::fs::create_dir_all(&releases.root) {
+                return Err(format!("failed to create release root {dir}: {err}"));
+            }
+        }
+        #[cfg(unix)]
+        let file = std::fs::File::open(dir);
+        #[cfg(windows)]
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(std::path::Path::new(dir).join(".cdagent.lock"));
+        let file = match file {
+            Ok(file) => file,
+            Err(err) => return Err(format!("failed to open working directory {dir}: {err}")),
+        };
+        match file.try_lock() {
+            Ok(()) => Ok(file),
+            Err(std::fs::TryLockError::WouldBlock) => Err(format!(
+                "working directory {dir} is locked by another redeployment; will try again on the next poll"
+            )),
+            Err(std::fs::TryLockError::Error(err)) => {
+                Err(format!("failed to lock working directory {dir}: {err}"))
+            }
+        }
+    }
+
+    /// Returns the directory to run a step in.
+    ///
+    /// With managed releases, verification and promotion happen before the release directory
+    ///     is created, so they run in the release root.
+    fn working_directory(&self, phase: Phase, sha: &str) -> Option<PathBuf> {
+        match &self.config.releases {
+            None => self.config.working_directory.as_ref().map(PathBuf::from),
+            Some(releases) => Some(match phase {
+                Phase::Restart | Phase::Rollback => releases::current_link(&releases.root),
+                Phase::Verification | Phase::Promotion => PathBuf::from(&releases.root),
+                _ => releases::dir(&releases.root, sha),
+            }),
+        }
+    }
+
+    /// Returns the workflow run to promote from the source project, if there is one.
+    ///
+    /// This also updates the promotion state shown on the status page.
+    fn promotion_candidate(&mut self, upstreams: &[Upstream]) -> Option<github::WorkflowRun> {
+        let promotion = self.config.promote_from.as_ref()?;
+        let source = &promotion.project;
+        let state = self.promotion.get_or_insert_with(|| PromotionState {
+            source: source.clone(),
+            workflow_run: None,
+            status: "".to_string(),
+        });
+        state.source = source.clone();
+        let result = match upstreams
+            .iter()
+            .find(|u| u.config.name == *source)
+            .and_then(|u| u.last_run_result.as_ref())
+        {
+            Some(result) if result.success => result,
+            _ => {
+                if state.workflow_run.is_none() {
+                    state.status = format!("waiting for a successful redeployment of {source}");
+                }
+                return None;
+            }
+        };
+        if let Some(last_workflow_run) = &self.last_workflow_run {
+            if last_workflow_run.id == result.workflow_run.id {
+                return None;
+            }
+        }
+        state.workflow_run = Some(result.workflow_run.clone());
+        if let Ok(finished) = chrono::DateTime::parse_from_rfc3339(&result.finished) {
+            let soaked = finished + chrono::Duration::seconds(promotion.soak_time_seconds as i64);
+            if soaked > chrono::offset::Utc::now() && !self.skip_wait {
+                state.status = format!(
+                    "soaking until {}",
+                    soaked.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+                );
+                return None;
+            }
+        }
+        state.status = "promoting".to_string();
+        Some(result.workflow_run.clone())
+    }
+
+    /// Run the provided steps in order, stopping at the first failure.
+    ///
+    /// Returns whether all of the steps succeeded.
+    fn run_steps(
+        &self,
+        steps: &[config::Step],
+        phase: Phase,
+        result: &mut RunResult,
+    ) -> Result<bool, String> {
+        for step in steps {
+            let command = process::command(&step.run, step.shell)?;
+            if !self.run_command(step, phase, command, result) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Run a single command and record the result.
+    ///
+    /// Returns whether the command succeeded.
+    fn run_command(
+        &self,
+        step: &config::Step,
+        phase: Phase,
+        mut command: Command,
+        result: &mut RunResult,
+    ) -> bool {
+        let args: Vec<_> = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy())
+            .collect();
+        eprintln!(
+            "Running program {} with args {args:?}",
+            command.get_program().to_string_lossy()
+        );
+        if let Some(working_directory) =
+            self.working_directory(phase, &result.workflow_run.head_sha)
+        {
+            command.current_dir(working_directory);
+        }
+        let idle_timeout = step.idle_timeout_seconds.map(time::Duration::from_secs);
+        let project = &self.config.name;
+        self.live
+            .start_step(project, result.workflow_run.id, &result.started, &step.name);
+        let mut span = trace::Span::child(&step.name, trace::Kind::Internal);
+        span.attribute("cdagent.step.command", step.run.as_str());
+        span.attribute(
+            "cdagent.step.phase",
+            serde_json::to_value(phase).unwrap_or_default(),
+        );
+        let start = time::Instant::now();
+        let step_result = match process::run(command, idle_timeout, &mut |chunk| {
+            self.live.output(project, chunk)
+        }) {
+            Ok(output) => StepResult::new(step, phase, &output),
+            Err(err) => StepResult::internal(&step.name, step.run.clone(), phase, Err(err)),
+        };
+        metrics::observe_since(&metrics::STEP_DURATION, &[project, &step.name], start);
+        if !step_result.success {
+            span.fail("the step failed");
+        }
+        if !result.push(step_result) {
+            eprintln!("failed to run command: {:?}", result);
+            return false;
+        }
+        true
+    }
+
+    /// Verify the artifacts being deployed.
+    ///
+    /// Returns whether all of the verifications succeeded.
+    fn verify(
+        &self,
+        verification: &config::Verification,
+        github_client: &mut github::Client,
+        result: &mut RunResult,
+    ) -> bool {
+        if verification.signed_commit {
+            let sha = &result.workflow_run.head_sha;
+            let outcome = github_client
+                .get_commit_verification(
+                    &self.config.github_user,
+                    &self.config.repo,
+                    sha,
+                    &self.config.auth_token,
+                )
+                .and_then(|v| {
+                    if v.verified {
+                        Ok(format!("verified: {}\n", v.reason))
+                    } else {
+                        Err(format!("not verified: {}\n", v.reason))
+                    }
+                });
+            let step_result = StepResult::internal(
+                "Verify commit signature",
+                format!("verify signature of commit {sha}"),
+                Phase::Verification,
+                outcome,
+            );
+            if !result.push(step_result) {
+                return false;
+            }
+        }
+        if let Some(checksum_file) = &verification.checksum_file {
+            let step_result = StepResult::internal(
+                "Verify checksums",
+                format!("sha256sum --check {checksum_file}"),
+                Phase::Verification,
+                verify::checksum_file(
+                    checksum_file,
+                    self.working_directory(Phase::Verification, &result.workflow_run.head_sha)
+                        .as_deref(),
+                ),
+            );
+            if !result.push(step_result) {
+                return false;
+            }
+        }
+        if let Some(cosign) = &verification.cosign {
+            let args = cosign.args();
+            let step = config::Step {
+                name: "Verify image signature".to_string(),
+                run: format!("cosign {}", args.join(" ")),
+                shell: None,
+                idle_timeout_seconds: None,
+            };
+            let mut command = Command::new("cosign");
+            command.args(&args);
+            if !self.run_command(&step, Phase::Verification, command, result) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Wait for the canary bake time, running the health checks periodically.
+    ///
+    /// Returns whether all of the health checks succeeded.
+    /// If a shut down signal is received the bake is cut short and fails,
+    ///     so that the canary is rolled back before the agent exits.
+    fn bake(
+        &self,
+        canary: &config::Canary,
+        result: &mut RunResult,
+        shutdown: &mpsc::Receiver<()>,
+    ) -> Result<bool, String> {
+        let interval = time::Duration::from_secs(match canary.health_check_interval_seconds {
+            None | Some(0) => 30,
+            Some(d) => d,
+        });
+        let deadline = time::Instant::now() + time::Duration::from_secs(canary.bake_time_seconds);
+        eprintln!(
+            "[{}] Baking canary for {} seconds",
+            self.config.name, canary.bake_time_seconds
+        );
+        loop {
+            if !self.run_steps(&canary.health_checks, Phase::Bake, result)? {
+                return Ok(false);
+            }
+            let now = time::Instant::now();
+            if now >= deadline {
+                return Ok(true);
+            }
+            if shutdown.recv_timeout(interval.min(deadline - now)).is_ok() {
+                eprintln!(
+                    "[{}] Baking canary interrupted because of shut down signal",
+                    self.config.name
+                );
+                self.interrupted.set(true);
+                return Ok(result.push(StepResult::internal(
+                    "Bake canary",
+                    format!("wait {} seconds", canary.bake_time_seconds),
+                    Phase::Bake,
+                    Err("interrupted by a shut down signal".to_string()),
+                )));
+            }
+        }
+    }
+}
+
+/// The state of a project that other projects depend on.
+pub struct Upstream {
+    config: config::ProjectConfig,
+    last_workflow_run: Option<github::WorkflowRun>,
+    last_run_result: Option<RunResult>,
+}
+
+impl Upstream {
+    /// Check whether a downstream project can redeploy the provided workflow run.
+    ///
+    /// Returns an error describing what the downstream project is waiting for, if anything.
+    fn check_ready_for(
+        &self,
+        downstream: &config::ProjectConfig,
+        workflow_run: &github::WorkflowRun,
+    ) -> Result<(), String> {
+        let name = &self.config.name;
+        let result = match &self.last_run_result {
+            None => return Err(format!("upstream project {name} has not redeployed yet")),
+            Some(result) => result,
+        };
+        if !result.success {
+            return Err(format!(
+                "the most recent redeployment of upstream project {name} failed"
+            ));
+        }
+        if let Some(last_workflow_run) = &self.last_workflow_run {
+            if last_workflow_run.id != result.workflow_run.id {
+                return Err(format!(
+                    "upstream project {name} has not redeployed its latest workflow run"
+                ));
+            }
+        }
+        let same_branch = self.config.github_user == downstream.github_user
+            && self.config.repo == downstream.repo
+            && self.config.mainline_branch == downstream.mainline_branch;
+        if same_branch && result.workflow_run.created_at < workflow_run.created_at {
+            return Err(format!(
+                "upstream project {name} has not redeployed this or a newer workflow run"
+            ));
         }
         Ok(())
     }
 }
 
+/// Send the notification with the notifiers that are subscribed to its kind.
+///
+/// Returns the notification queued for each notifier that failed to send it, to be retried.
+fn send(
+    notifiers: Vec<&email::Subscription>,
+    notification: &email::Notification,
+    notifier_failures: &mut HashMap<String, NotifierFailure>,
+) -> Vec<QueuedNotification> {
+    let now = chrono::offset::Utc::now().timestamp();
+    let subscriptions: Vec<&email::Subscription> = notifiers
+        .into_iter()
+        .filter(|subscription| notification.kind.allowed_by(&subscription.notify_on))
+        .collect();
+    let sends: Vec<(&email::Subscription, &email::Notification)> = subscriptions
+        .iter()
+        .map(|subscription| (*subscription, notification))
+        .collect();
+    let results = email::send_all(&sends);
+    let mut queued = vec![];
+    for (subscription, result) in subscriptions.into_iter().zip(results) {
+        NotifierFailure::record(notifier_failures, &subscription.name, &result);
+        if let Err(err) = result {
+            eprintln!(
+                "Failed to send notification \"{}\" with the {} notifier; will retry: {err}",
+                notification.subject, subscription.name
+            );
+            queued.push(QueuedNotification {
+                notifier: subscription.name.clone(),
+                notification: notification.clone(),
+                queued_at: now,
+                attempts: 1,
+                next_attempt_at: now + QueuedNotification::backoff(1),
+            });
+        }
+    }
+    queued
+}
+
+/// Returns the URL of the status page to link to from notifications about the project.
+fn project_status_page_url<'a>(
+    notifications: &'a Option<config::NotificationConfig>,
+    project_config: &'a config::ProjectConfig,
+) -> Option<&'a str> {
+    project_config.status_page_url.as_deref().or(notifications
+        .as_ref()
+        .and_then(|n| n.status_page_url.as_deref()))
+}
+
+/// Returns the kind of an error, which stays the same when the error recurs.
+///
+/// Errors describe what failed followed by the cause, like `failed to read GitHub API response: ...`,
+///     and the cause often contains details that change each time, like timestamps or addresses.
+fn error_kind(err: &str) -> &str {
+    err.split([':', ';', '!']).next().unwrap_or(err).trim()
+}
+
+/// Returns the URL of the detail page of the project, given the URL of the status page.
+fn project_page_url(status_page_url: &str, name: &str) -> String {
+    format!(
+        "{}/projects/{}",
+        status_page_url.trim_end_matches('/'),
+        approval::percent_encode(name)
+    )
+}
+
+/// Check that approval can be requested for the projects that require it.
+pub fn check_approvals(
+    projects: &[config::ProjectConfig],
+    approvals_enabled: bool,
+    status_page_url: Option<&str>,
+) -> Result<(), String> {
+    for project_config in projects {
+        let has_status_page_url =
+            project_config.status_page_url.is_some() || status_page_url.is_some();
+        if project_config.require_approval && !(approvals_enabled && has_status_page_url) {
+            return Err(format!(
+                "project {} requires approval, which requires approval_secret and status_page_url to be set in the notifications config, or status_page_url to be set on the project",
+                project_config.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the order in which to run the projects such that every project
+///     is run after the projects it depends on.
+///
+/// Returns an error if a project depends on a project that doesn't exist,
+///     or if there is a dependency cycle.
+pub fn dependency_order(projects: &[Project]) -> Result<Vec<usize>, String> {
+    let name_to_index: HashMap<&str, usize> = projects
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (p.config.name.as_str(), i))
+        .collect();
+    let mut num_dependencies = vec![0_usize; projects.len()];
+    let mut dependents: Vec<Vec<usize>> = vec![vec![]; projects.len()];
+    for (i, project) in projects.iter().enumerate() {
+        for name in project.config.upstreams() {
+            match name_to_index.get(name.as_str()) {
+                None => {
+                    return Err(format!(
+                        "project {} depends on unknown project {name}",
+                        project.config.name
+                    ))
+                }
+                Some(&j) => {
+                    num_dependencies[i] += 1;
+                    dependents[j].push(i);
+                }
+            }
+        }
+    }
+    let mut order: Vec<usize> = (0..projects.len())
+        .filter(|&i| num_dependencies[i] == 0)
+        .collect();
+    let mut next = 0;
+    while let Some(&i) = order.get(next) {
+        next += 1;
+        for &j in &dependents[i] {
+            num_dependencies[j] -= 1;
+            if num_dependencies[j] == 0 {
+                order.push(j);
+            }
+        }
+    }
+    if order.len() != projects.len() {
+        let cycle: Vec<&str> = (0..projects.len())
+            .filter(|&i| num_dependencies[i] > 0)
+            .map(|i| projects[i].config.name.as_str())
+            .collect();
+        return Err(format!(
+            "there is a dependency cycle between the projects {cycle:?}"
+        ));
+    }
+    Ok(order)
+}
+
+/// State of the promotion of deployments from another project.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+struct PromotionState {
+    /// Name of the project deployments are promoted from.
+    source: String,
+    /// The most recent workflow run considered for promotion.
+    workflow_run: Option<github::WorkflowRun>,
+    /// Human readable status of the promotion.
+    status: String,
+}
+
+/// A new successful workflow run that is not being redeployed yet.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct PendingRedeployment {
+    workflow_run: github::WorkflowRun,
+    /// When the redeployment was first held.
+    since: String,
+    /// Why the redeployment is currently being held.
+    reason: String,
+    /// Whether a reminder has been sent about the redeployment.
+    reminded: bool,
+    /// Whether the redeployment is waiting for approval.
+    #[serde(default)]
+    needs_approval: bool,
+    /// Whether a request to approve the redeployment has been sent.
+    #[serde(default)]
+    approval_requested: bool,
+}
+
+/// Phase of a redeployment that a step was run in.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Phase {
+    /// Verifying the artifacts being deployed.
+    Verification,
+    /// Deploying the canary.
+    Canary,
+    /// Running health checks against the canary.
+    Bake,
+    /// Running health checks before promoting a deployment from another project.
+    Promotion,
+    /// The regular redeployment steps.
+    Main,
+    /// Managing release directories.
+    Release,
+    /// Restarting the service after switching to a new release.
+    Restart,
+    /// Rolling back a failed canary or release.
+    Rollback,
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 struct RunResult {
     config: config::ProjectConfig,
@@ -93,23 +2397,212 @@ struct RunResult {
     success: bool,
     workflow_run: github::WorkflowRun,
     steps: Vec<StepResult>,
+    /// For rollbacks, the workflow run that was rolled back from.
+    #[serde(default)]
+    rollback_of: Option<github::WorkflowRun>,
+    /// Whether this was a redeployment requested through the HTTP API.
+    #[serde(default)]
+    redeploy: bool,
+}
+
+impl RunResult {
+    /// The run result as represented in the API.
+    fn api(&self) -> api::Run {
+        api::Run {
+            summary: self.api_summary(),
+            steps: self
+                .steps
+                .iter()
+                .map(|step| api::Step {
+                    name: step.config.name.clone(),
+                    command: step.config.run.clone(),
+                    phase: serde_json::to_value(step.phase)
+                        .ok()
+                        .and_then(|phase| phase.as_str().map(str::to_string))
+                        .unwrap_or_default(),
+                    status: api::Status::new(step.success),
+                    output: step.combined.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    fn api_summary(&self) -> api::RunSummary {
+        api::RunSummary {
+            workflow_run: (&self.workflow_run).into(),
+            status: api::Status::new(self.success),
+            started: self.started.clone(),
+            finished: self.finished.clone(),
+            rollback_of: self.rollback_of.as_ref().map(|w| w.id),
+        }
+    }
+
+    /// The number of lines of output from the failed step to include in notifications.
+    ///
+    /// The full output is attached to notifiers that support attachments.
+    const NOTIFICATION_LOG_LINES: usize = 50;
+
+    /// Build a notification describing the outcome of this redeployment.
+    ///
+    /// A successful redeployment after a failed one is reported as a recovery.
+    fn notification(
+        &self,
+        previously_failed: bool,
+        status_page_url: Option<&str>,
+        templates: Option<&email::Templates>,
+    ) -> email::Notification {
+        let (kind, outcome) = match (self.success, previously_failed) {
+            (false, _) => (config::NotifyOn::Failure, "failed"),
+            (true, true) => (
+                config::NotifyOn::Recovery,
+                "succeeded after a previous failure",
+            ),
+            (true, false) => (config::NotifyOn::Success, "succeeded"),
+        };
+        let what = match self.rollback_of {
+            None => "Redeployment",
+            Some(_) => "Rollback",
+        };
+        let mut body = format!(
+            "{what} of {} {outcome}.\n\nCommit: {}\nWorkflow run: {} ({})\n",
+            self.config.name,
+            self.workflow_run.head_sha,
+            self.workflow_run.display_title,
+            self.workflow_run.html_url,
+        );
+        if let Some(reverted) = &self.rollback_of {
+            body.push_str(&format!(
+                "Rolled back from: {} ({})\n",
+                reverted.display_title, reverted.html_url
+            ));
+        }
+        let failed_step = self.steps.iter().find(|step| !step.success);
+        let mut attachments = vec![];
+        if let Some(step) = failed_step {
+            let phase = serde_json::to_value(step.phase).expect("failed to serialize phase");
+            body.push_str(&format!(
+                "Failed step: {} (phase: {})\n\n",
+                step.config.name,
+                phase.as_str().unwrap_or_default(),
+            ));
+            // Errors are usually at the end of the output, so the start is what gets dropped.
+            let lines: Vec<&str> = step.combined.lines().collect();
+            let skipped = lines.len().saturating_sub(Self::NOTIFICATION_LOG_LINES);
+            if skipped > 0 {
+                body.push_str(&format!(
+                    "Output of the failed step ({skipped} earlier line(s) omitted):\n"
+                ));
+            } else {
+                body.push_str("Output of the failed step:\n");
+            }
+            for line in &lines[skipped..] {
+                body.push_str(line);
+                body.push('\n');
+            }
+            attachments.push(email::Attachment {
+                filename: format!("{}.log", step.config.name.replace(['/', '\\', '"'], "_")),
+                content: step.combined.clone(),
+            });
+        }
+        if let Some(url) = status_page_url {
+            body.push_str(&format!(
+                "\nStatus page: {}\n",
+                project_page_url(url, &self.config.name)
+            ));
+        }
+        let mut notification = email::Notification {
+            subject: format!("[cdagent] {what} of {} {outcome}", self.config.name),
+            body,
+            kind,
+            event: serde_json::json!({
+                "type": "redeployment",
+                "project": self.config.name,
+                "success": self.success,
+                "started": self.started,
+                "finished": self.finished,
+                "workflow_run": self.workflow_run,
+                "rollback_of": self.rollback_of,
+                "failed_step": failed_step.map(|step| serde_json::json!({
+                    "name": step.config.name,
+                    "phase": step.phase,
+                })),
+            }),
+            attachments,
+        };
+        if let Some(templates) = templates {
+            let mut context = serde_json::to_value(self).expect("failed to serialize run result");
+            context["kind"] = serde_json::to_value(kind).expect("failed to serialize kind");
+            context["failed_step"] =
+                serde_json::to_value(failed_step).expect("failed to serialize step result");
+            context["status_page_url"] = status_page_url.into();
+            templates.apply(&mut notification, &context);
+        }
+        notification
+    }
+
+    /// Returns a fingerprint identifying how the redeployment failed, or `None` if it succeeded.
+    fn failure_fingerprint(&self) -> Option<String> {
+        if self.success {
+            return None;
+        }
+        Some(match self.steps.iter().find(|step| !step.success) {
+            None => "redeployment failed".to_string(),
+            Some(step) => format!("step failed: {:?} {}", step.phase, step.config.name),
+        })
+    }
+
+    /// Record the result of a step.
+    ///
+    /// Returns whether the step succeeded.
+    fn push(&mut self, step_result: StepResult) -> bool {
+        let success = step_result.success;
+        self.steps.push(step_result);
+        success
+    }
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 struct StepResult {
     config: config::Step,
+    phase: Phase,
     success: bool,
     stdout: String,
     stderr: String,
+    /// Both stdout and stderr, interleaved in the order the output was produced.
+    combined: String,
 }
 
 impl StepResult {
-    fn new(step: &config::Step, output: &std::process::Output) -> Self {
+    fn new(step: &config::Step, phase: Phase, output: &process::Output) -> Self {
         Self {
             config: step.clone(),
-            success: output.status.success(),
+            phase,
+            success: output.success,
             stdout: vec_to_string(&output.stdout),
             stderr: vec_to_string(&output.stderr),
+            combined: vec_to_string(&output.combined),
+        }
+    }
+
+    /// Build the result of a step performed by the agent itself rather than by a command.
+    fn internal(name: &str, run: String, phase: Phase, outcome: Result<String, String>) -> Self {
+        let (success, stdout, stderr) = match outcome {
+            Ok(stdout) => (true, stdout, "".to_string()),
+            Err(stderr) => (false, "".to_string(), stderr),
+        };
+        let combined = format!("{stdout}{stderr}");
+        Self {
+            config: config::Step {
+                name: name.to_string(),
+                run,
+                shell: None,
+                idle_timeout_seconds: None,
+            },
+            phase,
+            success,
+            stdout,
+            stderr,
+            combined,
         }
     }
 }
@@ -0,0 +1,122 @@
+//! Managed release directories.
+//!
+//! The release root contains a `releases` directory with one subdirectory per release,
+//!     named after the commit SHA of the release,
+//!     and a `current` symlink pointing at the active release.
+
+use std::path::{Path, PathBuf};
+
+/// Returns the directory of the release with the provided SHA.
+pub fn dir(root: &str, sha: &str) -> PathBuf {
+    Path::new(root).join("releases").join(sha)
+}
+
+/// Returns the path of the `current` symlink.
+pub fn current_link(root: &str) -> PathBuf {
+    Path::new(root).join("current")
+}
+
+/// Create the directory for the release with the provided SHA, if it doesn't exist.
+pub fn create(root: &str, sha: &str) -> Result<String, String> {
+    let dir = dir(root, sha);
+    match std::fs::create_dir_all(&dir) {
+        Ok(()) => Ok(format!("created {}\n", dir.display())),
+        Err(err) => Err(format!(
+            "failed to create release directory {}: {err}",
+            dir.display()
+        )),
+    }
+}
+
+/// Returns the SHA of the active release, if there is one.
+pub fn current(root: &str) -> Option<String> {
+    let target = std::fs::read_link(current_link(root)).ok()?;
+    target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+/// Returns the first of the releases with the provided SHAs that still exists
+///     and isn't the active release.
+///
+/// This is the release to roll back to, given the SHAs of the successful releases, newest first.
+pub fn previous<'a>(root: &str, shas: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let current = current(root);
+    shas.into_iter()
+        .find(|sha| current.as_deref() != Some(*sha) && dir(root, sha).is_dir())
+}
+
+/// Make the release with the provided SHA the active release.
+///
+/// The `current` symlink is replaced atomically, except on Windows,
+///     where a symlink to a directory can't be renamed over another one.
+/// Creating symlinks on Windows requires developer mode or administrator rights.
+pub fn activate(root: &str, sha: &str) -> Result<String, String> {
+    let target = Path::new("releases").join(sha);
+    let link = current_link(root);
+    let tmp_link = Path::new(root).join("current.tmp");
+    remove_link(&tmp_link);
+    #[cfg(unix)]
+    let created = std::os::unix::fs::symlink(&target, &tmp_link);
+    #[cfg(windows)]
+    let created = std::os::windows::fs::symlink_dir(&target, &tmp_link);
+    if let Err(err) = created {
+        return Err(format!(
+            "failed to create symlink {}: {err}",
+            tmp_link.display()
+        ));
+    }
+    #[cfg(windows)]
+    remove_link(&link);
+    if let Err(err) = std::fs::rename(&tmp_link, &link) {
+        return Err(format!(
+            "failed to replace symlink {}: {err}",
+            link.display()
+        ));
+    }
+    Ok(format!("{} -> {}\n", link.display(), target.display()))
+}
+
+/// Remove the symlink if it exists.
+fn remove_link(link: &Path) {
+    // Symlinks to directories are removed as directories on Windows.
+    if std::fs::remove_file(link).is_err() {
+        _ = std::fs::remove_dir(link);
+    }
+}
+
+/// Remove all but the newest `keep` releases.
+///
+/// The active release is never removed.
+pub fn prune(root: &str, keep: usize) -> Result<String, String> {
+    let releases_dir = Path::new(root).join("releases");
+    let entries = match std::fs::read_dir(&releases_dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            return Err(format!(
+                "failed to list releases in {}: {err}",
+                releases_dir.display()
+            ))
+        }
+    };
+    let current = current(root);
+    let mut releases: Vec<(std::time::SystemTime, PathBuf)> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_dir())
+        .filter(|entry| Some(entry.file_name().to_string_lossy().into_owned()) != current)
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .collect();
+    releases.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+    let mut report = String::new();
+    // The active release counts towards the number of releases to keep.
+    for (_, path) in releases.iter().skip(keep.saturating_sub(1)) {
+        if let Err(err) = std::fs::remove_dir_all(path) {
+            return Err(format!(
+                "{report}failed to remove release {}: {err}",
+                path.display()
+            ));
+        }
+        report.push_str(&format!("removed {}\n", path.display()));
+    }
+    Ok(report)
+}
@@ -0,0 +1,105 @@
+//! Reloading the config file while the agent runs.
+//!
+//! The config is reloaded when the agent receives SIGHUP or,
+//!     if the agent was started with `--watch-config`, when a config file is modified.
+//! Only the projects are reloaded; other settings take effect when the agent restarts.
+
+use std::path::Path;
+use std::sync::atomic;
+use std::time;
+
+/// Whether SIGHUP has been received since the config was last reloaded.
+static SIGNALLED: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+pub struct Trigger {
+    paths: Vec<String>,
+    watch: bool,
+    /// When each config file was modified as of the last reload.
+    modified: Vec<Option<time::SystemTime>>,
+}
+
+impl Trigger {
+    /// Start listening for SIGHUP and, if `watch` is set, changes to the config files.
+    ///
+    /// This replaces the shut down handler for SIGHUP, so it must be called after that is installed.
+    pub fn new(paths: &[String], watch: bool) -> Self {
+        install_signal_handler();
+        Self {
+            paths: paths.to_vec(),
+            watch,
+            modified: paths.iter().map(|path| modified(path)).collect(),
+        }
+    }
+
+    /// Returns whether the config should be reloaded.
+    pub fn pending(&self) -> bool {
+        SIGNALLED.load(atomic::Ordering::SeqCst)
+            || (self.watch
+                && self
+                    .paths
+                    .iter()
+                    .map(|path| modified(path))
+                    .ne(self.modified.iter().copied()))
+    }
+
+    /// Returns whether the config should be reloaded, and resets the trigger if so.
+    pub fn take(&mut self) -> bool {
+        if !self.pending() {
+            return false;
+        }
+        SIGNALLED.store(false, atomic::Ordering::SeqCst);
+        self.modified = self.paths.iter().map(|path| modified(path)).collect();
+        true
+    }
+}
+
+/// Returns when the config file, or the most recently modified file in a config directory,
+///     was modified.
+///
+/// For a pattern like `configs/*.yml`, every file in the directory is considered,
+///     and the directory itself so that added and removed files are noticed.
+fn modified(path: &str) -> Option<time::SystemTime> {
+    let mut path = Path::new(path);
+    let name = path.file_name().and_then(|name| name.to_str());
+    if name.is_some_and(|name| name.contains(['*', '?'])) {
+        path = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+    }
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return modified;
+    };
+    entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().and_then(|m| m.modified()).ok())
+        .chain(modified)
+        .max()
+}
+
+/// Reload the config as if SIGHUP had been received.
+///
+/// Windows has no SIGHUP, so there the agent's service reloads the config when it is sent
+///     the `paramchange` control.
+#[cfg(windows)]
+pub fn request() {
+    SIGNALLED.store(true, atomic::Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+fn install_signal_handler() {
+    extern "C" fn handle(_: libc::c_int) {
+        SIGNALLED.store(true, atomic::Ordering::SeqCst);
+    }
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe {
+        libc::signal(
+            libc::SIGHUP,
+            handle as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn install_signal_handler() {}
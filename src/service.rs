@@ -0,0 +1,122 @@
+//! Running the agent as a Windows service.
+//!
+//! The service is created with the arguments of `cdagent run` and `--service`, like:
+//!
+//! ```text
+//! sc.exe create cdagent start= auto binPath= "C:\cdagent\cdagent.exe run --service --log-file C:\cdagent\cdagent.log C:\cdagent\config.yml C:\cdagent\db"
+//! ```
+//!
+//! Stopping the service shuts the agent down like Ctrl-C does,
+//!     and `sc.exe control cdagent paramchange` reloads the config like SIGHUP does.
+//! Services have no console, so the log should be written to a file.
+
+use crate::cli;
+use crate::exit;
+#[cfg(windows)]
+use std::sync::{self, mpsc};
+
+#[cfg(windows)]
+const NAME: &str = "cdagent";
+
+/// The arguments to run the agent with, passed from `run` to the service's main function.
+#[cfg(windows)]
+static ARGS: sync::Mutex<Option<cli::RunArgs>> = sync::Mutex::new(None);
+
+/// The result of running the agent, passed back from the service's main function.
+#[cfg(windows)]
+static RESULT: sync::Mutex<Option<Result<(), exit::Error>>> = sync::Mutex::new(None);
+
+#[cfg(windows)]
+windows_service::define_windows_service!(ffi_service_main, service_main);
+
+/// Run the agent as a service, returning once the service has stopped.
+///
+/// This fails if the agent wasn't started by the service control manager.
+#[cfg(windows)]
+pub fn run(args: cli::RunArgs) -> Result<(), exit::Error> {
+    *ARGS.lock().unwrap() = Some(args);
+    if let Err(err) = windows_service::service_dispatcher::start(NAME, ffi_service_main) {
+        return Err(format!(
+            "failed to start the service; --service is only for when the agent is started as a Windows service: {err}"
+        )
+        .into());
+    }
+    RESULT.lock().unwrap().take().unwrap_or_else(|| {
+        Err("the service stopped without running the agent"
+            .to_string()
+            .into())
+    })
+}
+
+#[cfg(not(windows))]
+pub fn run(_: cli::RunArgs) -> Result<(), exit::Error> {
+    Err("running as a service is only supported on Windows"
+        .to_string()
+        .into())
+}
+
+#[cfg(windows)]
+fn service_main(_: Vec<std::ffi::OsString>) {
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+
+    let Some(args) = ARGS.lock().unwrap().take() else {
+        return;
+    };
+    let (tx, stop) = mpsc::channel();
+    let handler = move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            eprintln!("received service stop request");
+            _ = tx.send(());
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::ParamChange => {
+            crate::reload::request();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let status_handle = match service_control_handler::register(NAME, handler) {
+        Ok(status_handle) => status_handle,
+        Err(err) => {
+            *RESULT.lock().unwrap() = Some(Err(format!(
+                "failed to register the service control handler: {err}"
+            )
+            .into()));
+            return;
+        }
+    };
+    let set_status = |current_state, controls_accepted, exit_code| {
+        _ = status_handle.set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state,
+            controls_accepted,
+            exit_code,
+            checkpoint: 0,
+            wait_hint: std::time::Duration::ZERO,
+            process_id: None,
+        });
+    };
+    set_status(
+        ServiceState::Running,
+        ServiceControlAccept::STOP
+            | ServiceControlAccept::SHUTDOWN
+            | ServiceControlAccept::PARAM_CHANGE,
+        ServiceExitCode::NO_ERROR,
+    );
+    let result = crate::run(args, Some(stop));
+    let exit_code = match &result {
+        Ok(()) => ServiceExitCode::NO_ERROR,
+        Err(err) => ServiceExitCode::ServiceSpecific(err.status as u32),
+    };
+    *RESULT.lock().unwrap() = Some(result);
+    set_status(
+        ServiceState::Stopped,
+        ServiceControlAccept::empty(),
+        exit_code,
+    );
+}
@@ -0,0 +1,300 @@
+//! Slack notifications.
+
+use crate::approval;
+use crate::config;
+use crate::email;
+use hmac::Mac;
+use std::time::Duration;
+
+/// Client that sends notifications to Slack.
+///
+/// Messages are formatted using Block Kit and posted either to an incoming webhook,
+///     or by a bot user using the `chat.postMessage` API.
+pub struct Client {
+    agent: ureq::Agent,
+    destination: Destination,
+    /// Whether approval buttons are handled by the agent's interactions endpoint.
+    interactive: bool,
+}
+
+enum Destination {
+    Webhook { url: String },
+    Bot { token: String, channel: String },
+}
+
+impl Client {
+    /// Slack rejects header blocks longer than this many characters.
+    const MAX_HEADER_LEN: usize = 150;
+    /// Slack rejects section blocks longer than this many characters.
+    const MAX_SECTION_LEN: usize = 3000;
+
+    pub fn new(config: config::SlackConfig) -> Result<Self, String> {
+        let interactive = config.signing_secret.is_some();
+        let destination =
+            match config {
+                config::SlackConfig {
+                    webhook_url: Some(url),
+                    bot_token: None,
+                    channel: None,
+                    ..
+                } => Destination::Webhook { url },
+                config::SlackConfig {
+                    webhook_url: None,
+                    bot_token: Some(token),
+                    channel: Some(channel),
+                    ..
+                } => Destination::Bot { token, channel },
+                _ => return Err(
+                    "Slack notifications require either webhook_url, or both bot_token and channel"
+                        .to_string(),
+                ),
+            };
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .build();
+        Ok(Self {
+            agent,
+            destination,
+            interactive,
+        })
+    }
+
+    fn message(&self, notification: &email::Notification) -> serde_json::Value {
+        let emoji = match notification.kind {
+            config::NotifyOn::Failure => ":x:",
+            config::NotifyOn::Approval => ":hourglass_flowing_sand:",
+            _ => ":white_check_mark:",
+        };
+        let header = truncate(
+            &format!("{emoji} {}", notification.subject),
+            Self::MAX_HEADER_LEN,
+        );
+        // The body is preformatted text, so it is shown in a code block.
+        // Six characters are needed for the backticks.
+        let body = truncate(&escape(&notification.body), Self::MAX_SECTION_LEN - 6);
+        let mut blocks = vec![
+            serde_json::json!({
+                "type": "header",
+                "text": {"type": "plain_text", "text": header, "emoji": true},
+            }),
+            serde_json::json!({
+                "type": "section",
+                "text": {"type": "mrkdwn", "text": format!("```{body}```")},
+            }),
+        ];
+        if notification.event["type"] == "approval" {
+            blocks.push(self.approval_buttons(&notification.event));
+        }
+        serde_json::json!({
+            "text": notification.subject,
+            "blocks": blocks,
+        })
+    }
+
+    /// Returns an actions block with buttons to approve or reject the redeployment in the event.
+    fn approval_buttons(&self, event: &serde_json::Value) -> serde_json::Value {
+        let buttons: Vec<serde_json::Value> = [
+            (
+                approval::Decision::Approve,
+                "Approve",
+                "primary",
+                "approve_url",
+            ),
+            (approval::Decision::Reject, "Reject", "danger", "reject_url"),
+        ]
+        .into_iter()
+        .map(|(decision, text, style, url_field)| {
+            let mut button = serde_json::json!({
+                "type": "button",
+                "action_id": decision.as_str(),
+                "text": {"type": "plain_text", "text": text},
+                "style": style,
+            });
+            if self.interactive {
+                button["value"] = serde_json::json!({
+                    "project": event["project"],
+                    "run": event["workflow_run"]["id"],
+                })
+                .to_string()
+                .into();
+            } else {
+                button["url"] = event[url_field].clone();
+            }
+            button
+        })
+        .collect();
+        serde_json::json!({
+            "type": "actions",
+            "elements": buttons,
+        })
+    }
+}
+
+/// Handles clicks on the approval buttons of Slack messages.
+///
+/// Slack sends these to the app's interactivity request URL, which is served by the agent
+///     at `/slack/interactions`.
+pub struct Interactions {
+    agent: ureq::Agent,
+    signing_secrets: Vec<String>,
+    approvals: approval::Approvals,
+}
+
+impl Interactions {
+    /// Slack requests older than this are rejected, to prevent replays.
+    const MAX_AGE_SECONDS: i64 = 300;
+
+    pub fn new(signing_secrets: Vec<String>, approvals: approval::Approvals) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .build();
+        Self {
+            agent,
+            signing_secrets,
+            approvals,
+        }
+    }
+
+    /// Handle a request from Slack.
+    ///
+    /// Returns the status code and body of the response.
+    pub fn handle(&self, timestamp: &str, signature: &str, body: &str) -> (u16, String) {
+        if !self.verify(timestamp, signature, body) {
+            return (401, "invalid signature".to_string());
+        }
+        let payload = body
+            .split('&')
+            .filter_map(|param| param.split_once('='))
+            .find(|(key, _)| *key == "payload")
+            .map(|(_, value)| approval::percent_decode(value));
+        let payload: serde_json::Value = match payload.map(|p| serde_json::from_str(&p)) {
+            Some(Ok(payload)) => payload,
+            _ => return (400, "invalid payload".to_string()),
+        };
+        let action = &payload["actions"][0];
+        let (Some(decision), Some(value)) = (
+            action["action_id"]
+                .as_str()
+                .and_then(approval::Decision::parse),
+            action["value"].as_str(),
+        ) else {
+            // Other interactions, such as clicks on link buttons, don't need handling.
+            return (200, "".to_string());
+        };
+        let value: serde_json::Value = serde_json::from_str(value).unwrap_or_default();
+        let (Some(project), Some(run_id)) = (value["project"].as_str(), value["run"].as_u64())
+        else {
+            return (400, "invalid button value".to_string());
+        };
+        let user = payload["user"]["id"].as_str().unwrap_or("unknown");
+        let actor = match payload["user"]["username"].as_str() {
+            Some(username) => format!("Slack user {username} ({user})"),
+            None => format!("Slack user {user}"),
+        };
+        let decided = self
+            .approvals
+            .decide(project, run_id, decision, &actor, "slack");
+        let project = escape(project);
+        let text = if decided {
+            let outcome = match decision {
+                approval::Decision::Approve => ":white_check_mark: approved",
+                approval::Decision::Reject => ":no_entry: rejected",
+            };
+            format!("Redeployment of {project} (workflow run {run_id}) {outcome} by <@{user}>")
+        } else {
+            format!("The redeployment of {project} (workflow run {run_id}) is no longer awaiting approval")
+        };
+        // Replacing the original message removes the buttons.
+        if let Some(response_url) = payload["response_url"].as_str() {
+            let message = serde_json::json!({
+                "replace_original": true,
+                "text": text,
+            });
+            if let Err(err) = self
+                .agent
+                .post(response_url)
+                .set("content-type", "application/json; charset=utf-8")
+                .send_string(&message.to_string())
+            {
+                eprintln!("Failed to update Slack approval request: {err}");
+            }
+        }
+        (200, "".to_string())
+    }
+
+    /// Verify that the request was sent by Slack.
+    ///
+    /// See <https://api.slack.com/authentication/verifying-requests-from-slack>.
+    fn verify(&self, timestamp: &str, signature: &str, body: &str) -> bool {
+        let Ok(timestamp_seconds) = timestamp.parse::<i64>() else {
+            return false;
+        };
+        if (chrono::offset::Utc::now().timestamp() - timestamp_seconds).abs()
+            > Self::MAX_AGE_SECONDS
+        {
+            return false;
+        }
+        let Some(signature) = signature.strip_prefix("v0=").and_then(approval::hex_decode) else {
+            return false;
+        };
+        self.signing_secrets.iter().any(|secret| {
+            let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes())
+                .expect("HMAC accepts keys of any length");
+            mac.update(format!("v0:{timestamp}:{body}").as_bytes());
+            mac.verify_slice(&signature).is_ok()
+        })
+    }
+}
+
+impl email::Notifier for Client {
+    fn notify(&self, notification: &email::Notification) -> Result<(), String> {
+        let mut message = self.message(notification);
+        let request = match &self.destination {
+            Destination::Webhook { url } => self.agent.post(url),
+            Destination::Bot { token, channel } => {
+                message["channel"] = channel.clone().into();
+                self.agent
+                    .post("https://slack.com/api/chat.postMessage")
+                    .set("authorization", &format!("Bearer {token}"))
+            }
+        };
+        let response = match request
+            .set("content-type", "application/json; charset=utf-8")
+            .send_string(&message.to_string())
+        {
+            Ok(response) => response,
+            Err(err) => return Err(format!("failed to post message to Slack: {err}")),
+        };
+        if let Destination::Bot { .. } = &self.destination {
+            // The Web API returns errors in the body of 200 responses.
+            let body: String = match response.into_string() {
+                Ok(body) => body,
+                Err(err) => return Err(format!("failed to read response from Slack: {err}")),
+            };
+            let body: serde_json::Value = match serde_json::from_str(&body) {
+                Ok(body) => body,
+                Err(err) => return Err(format!("failed to parse response from Slack: {err}")),
+            };
+            if body["ok"] != true {
+                return Err(format!("Slack rejected the message: {}", body["error"]));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Escape the characters that Slack treats as control characters in message text.
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn truncate(s: &str, max_len: usize) -> String {
+    if s.chars().count() <= max_len {
+        return s.to_string();
+    }
+    let mut truncated: String = s.chars().take(max_len - 1).collect();
+    truncated.push('…');
+    truncated
+}
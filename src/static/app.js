@@ -0,0 +1,104 @@
+// Scripts for the status pages.
+
+// Replace the page's content with the latest version whenever the state of a project changes.
+// The page is rendered with the version of the state it shows.
+const updates = new EventSource(`/events?since=${document.currentScript.dataset.version}`);
+updates.addEventListener("update", async () => {
+  const response = await fetch(location.href);
+  if (!response.ok) {
+    return;
+  }
+  const page = new DOMParser().parseFromString(await response.text(), "text/html");
+  const focused = document.activeElement?.name;
+  document.body.replaceWith(page.body);
+  // Keep typing in the filter box if it was being typed in.
+  const filterInput = focused && document.querySelector(`#project-filter [name="${focused}"]`);
+  if (filterInput) {
+    filterInput.focus();
+  }
+  filterProjects(true);
+});
+
+// Filter the projects on the status page by name and status.
+// Statuses come from the JSON API, and are refetched whenever the page updates.
+// The filter is kept in the URL, so that it survives updates and can be linked to.
+let projectStatuses = new Map();
+
+async function filterProjects(refetch) {
+  const form = document.getElementById("project-filter");
+  if (!form) {
+    return;
+  }
+  const params = new URLSearchParams(location.search);
+  const query = params.get("q") ?? "";
+  const status = params.get("status") ?? "";
+  form.elements.q.value = query;
+  form.elements.status.value = status;
+  if (status && (refetch || projectStatuses.size === 0)) {
+    const response = await fetch("/api/v1/projects");
+    if (response.ok) {
+      const { projects } = await response.json();
+      projectStatuses = new Map(projects.map((project) => [project.name, {
+        failed: project.last_run?.status === "failed",
+        pending: project.pending !== null,
+        paused: project.paused,
+      }]));
+    }
+  }
+  let matches = 0;
+  for (const row of document.querySelectorAll("tr[data-project]")) {
+    const name = row.dataset.project;
+    const match = name.toLowerCase().includes(query.toLowerCase())
+      && (!status || projectStatuses.get(name)?.[status] === true);
+    row.hidden = !match;
+    matches += match ? 1 : 0;
+  }
+  document.getElementById("no-matches").hidden = matches > 0;
+}
+
+// The listeners are on the document so that they survive the page's content being replaced.
+document.addEventListener("input", (event) => {
+  const form = event.target.closest("#project-filter");
+  if (!form) {
+    return;
+  }
+  const params = new URLSearchParams(location.search);
+  for (const name of ["q", "status"]) {
+    const value = form.elements[name].value;
+    if (value) {
+      params.set(name, value);
+    } else {
+      params.delete(name);
+    }
+  }
+  const search = params.toString();
+  history.replaceState(null, "", search ? `?${search}` : location.pathname);
+  filterProjects(false);
+});
+
+document.addEventListener("submit", (event) => {
+  if (event.target.closest("#project-filter")) {
+    event.preventDefault();
+  }
+});
+
+document.addEventListener("DOMContentLoaded", () => filterProjects(false));
+
+// Load older run results on project pages without leaving the page.
+// The listener is on the document so that it survives the page's content being replaced.
+document.addEventListener("click", async (event) => {
+  const loadMore = event.target.closest("#load-more");
+  if (!loadMore) {
+    return;
+  }
+  event.preventDefault();
+  const response = await fetch(loadMore.href);
+  const page = new DOMParser().parseFromString(await response.text(), "text/html");
+  document.getElementById("runs").append(...page.getElementById("runs").children);
+  const next = page.getElementById("load-more");
+  if (next) {
+    loadMore.href = next.href;
+  } else {
+    loadMore.remove();
+  }
+});
@@ -0,0 +1,104 @@
+//! Pushing metrics to a StatsD server or Datadog agent.
+//!
+//! Each update to a metric is sent as it happens in a UDP packet, so sending never blocks the agent
+//!     and metrics are lost rather than retried if the server is down.
+//! Metrics are named after their Prometheus names without the `cdagent_` prefix
+//!     and the `_total` and `_seconds` suffixes, following the configured prefix;
+//!     e.g., `cdagent_deployments_total` is sent as `cdagent.deployments`.
+//! Counters are sent as counts, gauges as gauges and histograms as timers in milliseconds.
+//! The labels of a metric, like the project, are sent as tags in the DogStatsD format,
+//!     which Datadog and Telegraf understand.
+
+use crate::config;
+use crate::metrics;
+use std::net;
+
+pub struct Client {
+    socket: net::UdpSocket,
+    prefix: String,
+    tags: Vec<String>,
+}
+
+impl Client {
+    pub fn new(config: &config::StatsdConfig) -> Result<Self, String> {
+        // Bind to the address family of the server so that IPv6 servers can be reached.
+        let socket = net::ToSocketAddrs::to_socket_addrs(config.address.as_str())
+            .and_then(|mut addresses| {
+                addresses.next().ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::NotFound, "no addresses found")
+                })
+            })
+            .and_then(|address| {
+                let local = if address.is_ipv6() {
+                    "[::]:0"
+                } else {
+                    "0.0.0.0:0"
+                };
+                let socket = net::UdpSocket::bind(local)?;
+                socket.connect(address)?;
+                Ok(socket)
+            });
+        let socket = match socket {
+            Ok(socket) => socket,
+            Err(err) => {
+                return Err(format!(
+                    "failed to open a socket to StatsD server {}: {err}",
+                    config.address
+                ))
+            }
+        };
+        // Packets that can't be sent are dropped rather than holding up the agent.
+        if let Err(err) = socket.set_nonblocking(true) {
+            return Err(format!("failed to configure the StatsD socket: {err}"));
+        }
+        Ok(Self {
+            socket,
+            prefix: config.prefix().to_string(),
+            tags: config.tags.iter().map(|tag| sanitize(tag)).collect(),
+        })
+    }
+
+    /// Returns the line sent for the update of the metric.
+    fn line(&self, metric: &metrics::Metric, labels: &[&str], update: metrics::Update) -> String {
+        let name = metric.name.strip_prefix("cdagent_").unwrap_or(metric.name);
+        let name = name
+            .strip_suffix("_total")
+            .or_else(|| name.strip_suffix("_seconds"))
+            .unwrap_or(name);
+        let value = match update {
+            metrics::Update::Increment => "1|c".to_string(),
+            metrics::Update::Set(value) => format!("{value}|g"),
+            metrics::Update::Observe(seconds) => format!("{}|ms", seconds * 1000.0),
+        };
+        let mut line = match self.prefix.as_str() {
+            "" => format!("{name}:{value}"),
+            prefix => format!("{prefix}.{name}:{value}"),
+        };
+        let tags: Vec<String> = metric
+            .labels
+            .iter()
+            .zip(labels)
+            .map(|(name, value)| format!("{name}:{}", sanitize(value)))
+            .chain(self.tags.iter().cloned())
+            .collect();
+        if !tags.is_empty() {
+            line.push_str("|#");
+            line.push_str(&tags.join(","));
+        }
+        line
+    }
+}
+
+impl metrics::Sink for Client {
+    fn push(&mut self, metric: &metrics::Metric, labels: &[&str], update: metrics::Update) {
+        // The error is usually that the server isn't listening, which can't be helped.
+        _ = self
+            .socket
+            .send(self.line(metric, labels, update).as_bytes());
+    }
+}
+
+/// Replace the characters that separate the parts of a DogStatsD line.
+fn sanitize(tag: &str) -> String {
+    tag.replace([',', '|', '#', '\n'], "_")
+}
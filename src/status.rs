@@ -0,0 +1,499 @@
+//! The HTML and JSON status pages served by the agent.
+
+use crate::api;
+use crate::assets;
+use crate::audit;
+use crate::config;
+use crate::csrf;
+use crate::live;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync;
+use std::time;
+
+static STATUS_DOT_HTML: &str = include_str!("status.html");
+static PROJECT_DOT_HTML: &str = include_str!("project.html");
+static HEAD_DOT_HTML: &str = include_str!("head.html");
+static AUDIT_DOT_HTML: &str = include_str!("audit.html");
+
+/// The number of run results shown per page of a project's history.
+pub const RUN_RESULTS_PER_PAGE: usize = 10;
+
+/// The number of audit log entries on each page of the audit log.
+pub const AUDIT_ENTRIES_PER_PAGE: usize = 50;
+
+handlebars::handlebars_helper!(last_helper: |list: Json| {
+    list.as_array().and_then(|list| list.last()).cloned().unwrap_or_default()
+});
+
+handlebars::handlebars_helper!(static_url_helper: |name: str| {
+    assets::url(name).unwrap_or_default()
+});
+
+/// The most recently rendered status pages.
+///
+/// Clones share the same underlying pages.
+#[derive(Clone, Default)]
+pub struct Pages {
+    templates: Templates,
+    html: sync::Arc<sync::Mutex<String>>,
+    /// The data the status page was rendered with,
+    ///     for rendering it for users who can only see some of the projects.
+    data: sync::Arc<sync::Mutex<serde_json::Value>>,
+    /// The first page of the detail page of each project, keyed by project name.
+    project_html: sync::Arc<sync::Mutex<HashMap<String, String>>>,
+    /// The data used to render the detail page of each project, keyed by project name.
+    project_data: sync::Arc<sync::Mutex<HashMap<String, serde_json::Value>>>,
+    /// The full history of run results of each project, oldest first, keyed by project name.
+    run_results: sync::Arc<sync::Mutex<HashMap<String, Vec<serde_json::Value>>>>,
+    health: sync::Arc<sync::Mutex<Health>>,
+    live: live::Log,
+    api: api::Resources,
+    audit: audit::Log,
+    changes: sync::Arc<(sync::Mutex<Changes>, sync::Condvar)>,
+}
+
+/// Changes to the projects shown on the status pages, which are pushed to open pages.
+#[derive(Default)]
+struct Changes {
+    /// The number of times the projects have changed, which identifies their current state.
+    version: u64,
+    /// A hash of the current state of the projects.
+    hash: u64,
+}
+
+/// Liveness and readiness of the agent, served at `/healthz` and `/readyz`.
+#[derive(Default)]
+pub struct Health {
+    /// When the project manager last made progress.
+    pub last_tick: Option<time::Instant>,
+    /// How long the project manager can go without making progress before the agent is unhealthy.
+    pub max_tick_age: time::Duration,
+    /// The project being run, and when it started running.
+    ///
+    /// The project manager doesn't make progress while a redeployment is running,
+    ///     which can take arbitrarily long.
+    pub running: Option<(String, time::Instant)>,
+    /// The error from the most recent attempt to write to the database, if it failed.
+    pub database_error: Option<String>,
+    /// The outcome of the most recent request to the GitHub API, or `None` if none has been made.
+    pub github: Option<Result<(), String>>,
+    /// Whether the agent is shutting down.
+    ///
+    /// The HTTP server keeps serving requests while the agent shuts down,
+    ///     but the agent is no longer ready so that load balancers stop routing to it.
+    pub shutting_down: bool,
+}
+
+impl Pages {
+    pub fn new(config: &config::HttpConfig) -> Result<Self, String> {
+        Ok(Self {
+            templates: Templates::load(config.template_dir.as_deref())?,
+            ..Default::default()
+        })
+    }
+
+    /// Render the status pages using the provided data.
+    ///
+    /// Each element of the `projects` field of the data gets its own detail page.
+    pub fn update<T: serde::Serialize>(&self, data: &T) {
+        let mut data = serde_json::to_value(data).expect("failed to serialize status data");
+        let version = self.record_changes(&data["projects"]);
+        // Pages subscribe to changes made after the version they show.
+        data["version"] = version.into();
+        data["csrf_token"] = csrf::PLACEHOLDER.into();
+        if let Some(agent) = self.api.agent() {
+            let mut agent = serde_json::to_value(agent).expect("failed to serialize agent");
+            if let Some(commit) = agent["git_commit"].as_str() {
+                agent["short_git_commit"] = commit.chars().take(7).collect::<String>().into();
+            }
+            data["agent"] = agent;
+        }
+        let rendered = self.templates.render("status.html", &data);
+        *self.html.lock().unwrap() = rendered;
+
+        let mut project_html = HashMap::new();
+        let mut project_data = HashMap::new();
+        for project in data["projects"].as_array().into_iter().flatten() {
+            let name = project["config"]["name"].as_str().unwrap_or_default();
+            let mut context = project.clone();
+            // The detail page shows the most recent run results first.
+            let run_results: Vec<serde_json::Value> = project["run_results"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .rev()
+                .cloned()
+                .collect();
+            context["run_results"] = run_results.into();
+            context["version"] = version.into();
+            context["csrf_token"] = csrf::PLACEHOLDER.into();
+            if project["total_run_results"].as_u64() > Some(RUN_RESULTS_PER_PAGE as u64) {
+                context["next_page"] = 2.into();
+            }
+            project_html.insert(
+                name.to_string(),
+                self.templates.render("project.html", &context),
+            );
+            let mut project = project.clone();
+            project["version"] = version.into();
+            project["csrf_token"] = csrf::PLACEHOLDER.into();
+            project_data.insert(name.to_string(), project);
+        }
+        *self.project_html.lock().unwrap() = project_html;
+        *self.project_data.lock().unwrap() = project_data;
+        *self.data.lock().unwrap() = data;
+    }
+
+    /// Record the current state of the projects, notifying subscribers if it changed.
+    ///
+    /// Returns the version of the state.
+    fn record_changes(&self, projects: &serde_json::Value) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        projects.to_string().hash(&mut hasher);
+        let hash = hasher.finish();
+        let (changes, changed) = &*self.changes;
+        let mut changes = changes.lock().unwrap();
+        if changes.version == 0 || changes.hash != hash {
+            changes.version += 1;
+            changes.hash = hash;
+            changed.notify_all();
+        }
+        changes.version
+    }
+
+    /// Returns an `update` server-sent event once the projects differ from the provided version.
+    ///
+    /// If they don't already differ, waits up to [live::POLL_TIMEOUT] for them to change.
+    /// Versions don't persist across restarts of the agent,
+    ///     so any other version is out of date, not just older ones.
+    pub fn events(&self, version: Option<u64>) -> String {
+        let (changes, changed) = &*self.changes;
+        let changes = changes.lock().unwrap();
+        let (changes, _) = changed
+            .wait_timeout_while(changes, live::POLL_TIMEOUT, |changes| {
+                Some(changes.version) == version
+            })
+            .unwrap();
+        let mut events = format!("retry: {}\n\n", live::RECONNECTION_DELAY.as_millis());
+        if Some(changes.version) != version {
+            events.push_str(&format!(
+                "event: update\nid: {}\ndata: {{}}\n\n",
+                changes.version
+            ));
+        }
+        events
+    }
+
+    /// Publish the full history of run results of the project, oldest first.
+    pub fn update_run_results(&self, project: &str, run_results: Vec<serde_json::Value>) {
+        self.run_results
+            .lock()
+            .unwrap()
+            .insert(project.to_string(), run_results);
+    }
+
+    /// Stop publishing the run results of a project that was removed from the config.
+    pub fn remove_run_results(&self, project: &str) {
+        self.run_results.lock().unwrap().remove(project);
+        self.api.remove_runs(project);
+    }
+
+    /// Returns a page of the project's run results, most recent first,
+    ///     along with the total number of run results.
+    ///
+    /// Pages are numbered from 1.
+    /// Returns `None` if there is no project with the provided name.
+    pub fn run_results(
+        &self,
+        project: &str,
+        page: usize,
+    ) -> Option<(Vec<serde_json::Value>, usize)> {
+        let run_results = self.run_results.lock().unwrap();
+        let run_results = run_results.get(project)?;
+        let page = run_results
+            .iter()
+            .rev()
+            .skip(page.saturating_sub(1) * RUN_RESULTS_PER_PAGE)
+            .take(RUN_RESULTS_PER_PAGE)
+            .cloned()
+            .collect();
+        Some((page, run_results.len()))
+    }
+
+    /// Returns the most recent run result of the project for the workflow run.
+    pub fn run_result(&self, project: &str, workflow_run: u64) -> Option<serde_json::Value> {
+        self.run_results
+            .lock()
+            .unwrap()
+            .get(project)?
+            .iter()
+            .rev()
+            .find(|run_result| run_result["workflow_run"]["id"].as_u64() == Some(workflow_run))
+            .cloned()
+    }
+
+    /// The live output of redeployments.
+    pub fn live(&self) -> &live::Log {
+        &self.live
+    }
+
+    /// The resources served by the versioned JSON API.
+    pub fn api(&self) -> &api::Resources {
+        &self.api
+    }
+
+    /// The audit log of manual actions.
+    pub fn audit(&self) -> &audit::Log {
+        &self.audit
+    }
+
+    /// Returns a page of the entries of the audit log that match the filter.
+    ///
+    /// Pages are numbered from 1; later pages show older entries.
+    pub fn audit_html(&self, page: usize, filter: impl Fn(&audit::Entry) -> bool) -> String {
+        let (entries, total) = self.audit.entries(page, AUDIT_ENTRIES_PER_PAGE, filter);
+        let mut context = serde_json::json!({
+            "entries": entries,
+            "version": self.changes.0.lock().unwrap().version,
+        });
+        if total > page * AUDIT_ENTRIES_PER_PAGE {
+            context["next_page"] = (page + 1).into();
+        }
+        self.templates.render("audit.html", &context)
+    }
+
+    pub fn html(&self) -> String {
+        self.html.lock().unwrap().clone()
+    }
+
+    /// Returns the status page showing only the projects for which `visible` returns true.
+    pub fn html_for(&self, visible: impl Fn(&str) -> bool) -> String {
+        let mut data = self.data.lock().unwrap().clone();
+        if let Some(projects) = data["projects"].as_array_mut() {
+            projects
+                .retain(|project| visible(project["config"]["name"].as_str().unwrap_or_default()));
+        }
+        self.templates.render("status.html", &data)
+    }
+
+    /// Returns a page of the detail page of the project with the provided name, if there is one.
+    ///
+    /// Pages are numbered from 1; later pages show older run results.
+    pub fn project_html(&self, name: &str, page: usize) -> Option<String> {
+        if page <= 1 {
+            return self.project_html.lock().unwrap().get(name).cloned();
+        }
+        let mut context = self.project_data.lock().unwrap().get(name)?.clone();
+        let (run_results, total) = self.run_results(name, page)?;
+        context["run_results"] = run_results.into();
+        if total > page * RUN_RESULTS_PER_PAGE {
+            context["next_page"] = (page + 1).into();
+        }
+        Some(self.templates.render("project.html", &context))
+    }
+
+    /// Returns a shields-style SVG badge with the outcome and time of the project's
+    ///     most recent redeployment, or `None` if there is no project with the provided name.
+    pub fn badge(&self, project: &str) -> Option<String> {
+        let project = self.api.project(project)?;
+        let (message, color) = match &project.last_run {
+            _ if project.paused => ("paused".to_string(), GREY),
+            None => ("never deployed".to_string(), GREY),
+            Some(run) => {
+                let (outcome, color) = match run.status {
+                    api::Status::Succeeded => ("succeeded", GREEN),
+                    api::Status::Failed => ("failed", RED),
+                };
+                let message = match chrono::DateTime::parse_from_rfc3339(&run.finished) {
+                    Err(_) => outcome.to_string(),
+                    Ok(finished) => format!("{outcome} {}", relative_time(finished)),
+                };
+                (message, color)
+            }
+        };
+        Some(badge(&project.name, &message, color))
+    }
+
+    /// Update the liveness and readiness of the agent.
+    pub fn update_health(&self, f: impl FnOnce(&mut Health)) {
+        f(&mut self.health.lock().unwrap());
+    }
+
+    /// Returns the status code and body of the response to a liveness check.
+    ///
+    /// The agent is live if the project manager made progress recently,
+    ///     or is running a project.
+    pub fn healthz(&self) -> (u16, String) {
+        let health = self.health.lock().unwrap();
+        if let Some((project, since)) = &health.running {
+            return (
+                200,
+                format!(
+                    "ok: running project {project} for {}s\n",
+                    since.elapsed().as_secs()
+                ),
+            );
+        }
+        match health.last_tick {
+            // The HTTP server starts before the project manager's first iteration.
+            None => (200, "ok: starting\n".to_string()),
+            Some(last_tick) if last_tick.elapsed() <= health.max_tick_age => {
+                (200, "ok\n".to_string())
+            }
+            Some(last_tick) => (
+                503,
+                format!(
+                    "unhealthy: no progress for {}s\n",
+                    last_tick.elapsed().as_secs()
+                ),
+            ),
+        }
+    }
+
+    /// Returns the status code and body of the response to a readiness check.
+    ///
+    /// The agent is ready if the config is loaded, the database is writable,
+    ///     the GitHub API is reachable, and the agent is not shutting down.
+    pub fn readyz(&self) -> (u16, String) {
+        let health = self.health.lock().unwrap();
+        // The HTTP server only starts after the config has been loaded.
+        let mut checks = vec![("config", Ok(()))];
+        checks.push((
+            "database",
+            match &health.database_error {
+                None => Ok(()),
+                Some(err) => Err(err.clone()),
+            },
+        ));
+        checks.push((
+            "github",
+            health
+                .github
+                .clone()
+                .unwrap_or(Err("not polled yet".to_string())),
+        ));
+        checks.push((
+            "shutdown",
+            match health.shutting_down {
+                false => Ok(()),
+                true => Err("shutting down".to_string()),
+            },
+        ));
+        let mut status = 200;
+        let mut body = String::new();
+        for (name, check) in checks {
+            match check {
+                Ok(()) => body.push_str(&format!("{name}: ok\n")),
+                Err(err) => {
+                    status = 503;
+                    body.push_str(&format!("{name}: {err}\n"));
+                }
+            }
+        }
+        (status, body)
+    }
+}
+
+/// The templates of the status pages.
+#[derive(Clone)]
+struct Templates(sync::Arc<handlebars::Handlebars<'static>>);
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self::load(None).expect("built-in templates are valid")
+    }
+}
+
+impl Templates {
+    /// Load the templates, replacing built-in templates with those in the directory, if provided.
+    fn load(dir: Option<&str>) -> Result<Self, String> {
+        if let Some(dir) = dir {
+            if !std::path::Path::new(dir).is_dir() {
+                return Err(format!("template directory {dir} does not exist"));
+            }
+        }
+        let mut tt = handlebars::Handlebars::new();
+        for (file_name, built_in) in [
+            ("status.html", STATUS_DOT_HTML),
+            ("project.html", PROJECT_DOT_HTML),
+            ("head.html", HEAD_DOT_HTML),
+            ("audit.html", AUDIT_DOT_HTML),
+        ] {
+            let path = dir.map(|dir| std::path::Path::new(dir).join(file_name));
+            let template = match path {
+                Some(path) if path.exists() => match std::fs::read_to_string(&path) {
+                    Ok(template) => template,
+                    Err(err) => {
+                        return Err(format!("failed to read template {}: {err}", path.display()))
+                    }
+                },
+                _ => built_in.to_string(),
+            };
+            let result = match file_name {
+                "head.html" => tt.register_partial("head", template),
+                _ => tt.register_template_string(file_name, template),
+            };
+            if let Err(err) = result {
+                return Err(format!("invalid template {file_name}: {err}"));
+            }
+        }
+        tt.register_helper("last", Box::new(last_helper));
+        tt.register_helper("static_url", Box::new(static_url_helper));
+        Ok(Self(sync::Arc::new(tt)))
+    }
+
+    /// Render the template, or an error message if rendering fails.
+    fn render(&self, name: &str, data: &serde_json::Value) -> String {
+        self.0.render(name, data).unwrap_or_else(|err| {
+            eprintln!("Failed to render template {name}: {err}");
+            format!(
+                "Failed to render template {name}: {}",
+                handlebars::html_escape(&err.to_string())
+            )
+        })
+    }
+}
+
+const GREEN: &str = "#4c1";
+const RED: &str = "#e05d44";
+const GREY: &str = "#9f9f9f";
+
+/// Returns how long ago the time was; e.g., `3h ago`.
+fn relative_time(time: chrono::DateTime<chrono::FixedOffset>) -> String {
+    let seconds = (chrono::offset::Utc::now() - time.with_timezone(&chrono::Utc)).num_seconds();
+    match seconds {
+        ..=59 => "just now".to_string(),
+        60..=3599 => format!("{}m ago", seconds / 60),
+        3600..=86399 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
+
+/// Render a badge in the style of shields.io.
+///
+/// Text widths are estimated, as the widths of the glyphs are not known.
+fn badge(label: &str, message: &str, color: &str) -> String {
+    let width = |text: &str| text.chars().count() * 7 + 10;
+    let (label_width, message_width) = (width(label), width(message));
+    let total_width = label_width + message_width;
+    let label_x = label_width * 5;
+    let message_x = label_width * 10 + message_width * 5;
+    let (label, message) = (
+        handlebars::html_escape(label),
+        handlebars::html_escape(message),
+    );
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{total_width}" height="20" role="img" aria-label="{label}: {message}">
+<title>{label}: {message}</title>
+<linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient>
+<clipPath id="r"><rect width="{total_width}" height="20" rx="3" fill="#fff"/></clipPath>
+<g clip-path="url(#r)"><rect width="{label_width}" height="20" fill="#555"/><rect x="{label_width}" width="{message_width}" height="20" fill="{color}"/><rect width="{total_width}" height="20" fill="url(#s)"/></g>
+<g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="110">
+<text x="{label_x}" y="150" fill="#010101" fill-opacity=".3" transform="scale(.1)">{label}</text><text x="{label_x}" y="140" transform="scale(.1)">{label}</text>
+<text x="{message_x}" y="150" fill="#010101" fill-opacity=".3" transform="scale(.1)">{message}</text><text x="{message_x}" y="140" transform="scale(.1)">{message}</text>
+</g>
+</svg>
+"##
+    )
+}
@@ -0,0 +1,169 @@
+//! Protection of the HTTP endpoints for changing projects against misbehaving clients.
+//!
+//! Requests can be restricted to allowed IP addresses and rate limited
+//!     both per client IP address and per credential,
+//!     so that a script stuck in a loop can't trigger a storm of redeployments.
+
+use crate::config;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync;
+use std::time;
+
+pub struct Throttle {
+    allowed_ips: Vec<IpRange>,
+    rate_limit: Option<RateLimit>,
+}
+
+impl Throttle {
+    pub fn new(config: &config::ActionsConfig) -> Result<Self, String> {
+        let mut allowed_ips = vec![];
+        for range in &config.allowed_ips {
+            allowed_ips.push(IpRange::parse(range)?);
+        }
+        let rate_limit = match &config.rate_limit {
+            None => None,
+            Some(rate_limit) => {
+                if rate_limit.requests == 0 || rate_limit.period_seconds == 0 {
+                    return Err("the rate limit's requests and period must be positive".to_string());
+                }
+                Some(RateLimit {
+                    requests: rate_limit.requests,
+                    period: time::Duration::from_secs(rate_limit.period_seconds),
+                    history: Default::default(),
+                })
+            }
+        };
+        Ok(Self {
+            allowed_ips,
+            rate_limit,
+        })
+    }
+
+    /// Returns whether requests from the IP address are allowed.
+    pub fn allows(&self, ip: Option<IpAddr>) -> bool {
+        self.allowed_ips.is_empty()
+            || ip.is_some_and(|ip| self.allowed_ips.iter().any(|range| range.contains(ip)))
+    }
+
+    /// Count a request from the IP address with the provided credentials towards the rate limits.
+    ///
+    /// The credentials are the value of the request's `Authorization` header.
+    /// If the client is over a limit the request is not counted,
+    ///     and the error is how long until the client can make another request.
+    pub fn count(
+        &self,
+        ip: Option<IpAddr>,
+        credentials: Option<&str>,
+    ) -> Result<(), time::Duration> {
+        let Some(rate_limit) = &self.rate_limit else {
+            return Ok(());
+        };
+        let mut keys = vec![];
+        if let Some(ip) = ip {
+            keys.push(Key::Ip(ip));
+        }
+        if let Some(credentials) = credentials {
+            keys.push(Key::Credentials(credentials.to_string()));
+        }
+        rate_limit.count(keys)
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+enum Key {
+    Ip(IpAddr),
+    Credentials(String),
+}
+
+struct RateLimit {
+    requests: usize,
+    period: time::Duration,
+    /// When each client's requests within the last period were made, oldest first.
+    history: sync::Mutex<HashMap<Key, VecDeque<time::Instant>>>,
+}
+
+impl RateLimit {
+    fn count(&self, keys: Vec<Key>) -> Result<(), time::Duration> {
+        let now = time::Instant::now();
+        let mut history = self.history.lock().unwrap();
+        history.retain(|_, requests| {
+            while requests
+                .front()
+                .is_some_and(|request| now.duration_since(*request) >= self.period)
+            {
+                requests.pop_front();
+            }
+            !requests.is_empty()
+        });
+        let retry_after = keys
+            .iter()
+            .filter_map(|key| history.get(key))
+            .filter(|requests| requests.len() >= self.requests)
+            .filter_map(|requests| requests.front())
+            .map(|oldest| self.period - now.duration_since(*oldest))
+            .max();
+        if let Some(retry_after) = retry_after {
+            return Err(retry_after);
+        }
+        for key in keys {
+            history.entry(key).or_default().push_back(now);
+        }
+        Ok(())
+    }
+}
+
+/// An IP address or CIDR range of addresses.
+struct IpRange {
+    address: IpAddr,
+    prefix_len: u32,
+}
+
+impl IpRange {
+    fn parse(s: &str) -> Result<Self, String> {
+        let (address, prefix_len) = match s.split_once('/') {
+            None => (s, None),
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+        };
+        let Ok(address) = address.parse::<IpAddr>() else {
+            return Err(format!("invalid IP address or range {s}"));
+        };
+        let max_prefix_len = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match prefix_len.map(str::parse) {
+            None => max_prefix_len,
+            Some(Ok(prefix_len)) if prefix_len <= max_prefix_len => prefix_len,
+            Some(_) => return Err(format!("invalid IP address or range {s}")),
+        };
+        Ok(Self {
+            address,
+            prefix_len,
+        })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack server have IPv4-mapped IPv6 addresses.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            IpAddr::V4(_) => ip,
+        };
+        match (self.address, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => same_prefix(
+                range.to_bits().into(),
+                ip.to_bits().into(),
+                32 - self.prefix_len,
+            ),
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                same_prefix(range.to_bits(), ip.to_bits(), 128 - self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Returns whether the addresses match once the host bits, the lowest `host_bits` bits, are dropped.
+fn same_prefix(a: u128, b: u128, host_bits: u32) -> bool {
+    a.checked_shr(host_bits).unwrap_or(0) == b.checked_shr(host_bits).unwrap_or(0)
+}
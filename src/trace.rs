@@ -0,0 +1,212 @@
+//! Traces of redeployments, exported to an OpenTelemetry collector.
+//!
+//! Each redeployment is a trace whose root span covers the whole redeployment,
+//!     with a child span for each step and each GitHub API request made during it.
+//! Once the redeployment finishes its spans are exported with OTLP over HTTP, using the JSON encoding.
+//! Exports are made on a background thread so that a slow or unreachable collector
+//!     doesn't hold up redeployments; if an export fails the error is logged and the trace is dropped.
+//!
+//! The spans in progress are tracked per thread,
+//!     so a span started while another is in progress on the same thread is its child.
+
+use crate::config;
+use crate::encryption;
+use std::cell::RefCell;
+use std::sync::{self, mpsc};
+use std::{thread, time};
+
+thread_local! {
+    /// The spans in progress on this thread as their trace and span IDs, innermost last.
+    static IN_PROGRESS: RefCell<Vec<(String, String)>> = const { RefCell::new(vec![]) };
+
+    /// The finished spans of the trace in progress on this thread, in OTLP JSON.
+    static FINISHED: RefCell<Vec<serde_json::Value>> = const { RefCell::new(vec![]) };
+}
+
+static EXPORTER: sync::Mutex<Option<Exporter>> = sync::Mutex::new(None);
+
+struct Exporter {
+    traces: mpsc::Sender<Vec<serde_json::Value>>,
+    thread: thread::JoinHandle<()>,
+}
+
+/// Start exporting traces to the collector.
+pub fn start(config: &config::TracingConfig) {
+    let (traces, received) = mpsc::channel::<Vec<serde_json::Value>>();
+    let config = config.clone();
+    let thread = thread::spawn(move || {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(time::Duration::from_secs(10))
+            .build();
+        let resource = serde_json::json!({
+            "attributes": [
+                attribute("service.name", config.service_name().into()),
+                attribute("service.version", env!("CARGO_PKG_VERSION").into()),
+            ],
+        });
+        for spans in received {
+            let body = serde_json::json!({
+                "resourceSpans": [{
+                    "resource": resource,
+                    "scopeSpans": [{
+                        "scope": { "name": "cdagent", "version": env!("CARGO_PKG_VERSION") },
+                        "spans": spans,
+                    }],
+                }],
+            });
+            let mut request = agent
+                .post(&config.endpoint)
+                .set("Content-Type", "application/json");
+            for (name, value) in &config.headers {
+                request = request.set(name, value);
+            }
+            if let Err(err) = request.send_string(&body.to_string()) {
+                eprintln!("Failed to export trace to {}: {err}", config.endpoint);
+            }
+        }
+    });
+    *EXPORTER.lock().unwrap() = Some(Exporter { traces, thread });
+}
+
+/// Wait for the traces that have finished to be exported, and stop exporting traces.
+pub fn finish() {
+    let Some(exporter) = EXPORTER.lock().unwrap().take() else {
+        return;
+    };
+    drop(exporter.traces);
+    _ = exporter.thread.join();
+}
+
+/// The kind of a span, as defined by OpenTelemetry.
+#[derive(Clone, Copy)]
+pub enum Kind {
+    /// An operation within the agent.
+    Internal = 1,
+    /// A request to another service.
+    Client = 3,
+}
+
+/// A span, which is recorded when it is dropped.
+///
+/// Spans are no-ops if traces aren't being exported.
+pub struct Span(Option<Recording>);
+
+struct Recording {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    name: String,
+    kind: Kind,
+    start: time::SystemTime,
+    attributes: Vec<serde_json::Value>,
+    error: Option<String>,
+}
+
+impl Span {
+    /// Start a span that is the root of a new trace.
+    pub fn root(name: &str) -> Self {
+        if EXPORTER.lock().unwrap().is_none() {
+            return Span(None);
+        }
+        Self::start(name, Kind::Internal, encryption::random_hex(16), None)
+    }
+
+    /// Start a span that is a child of the span in progress on this thread.
+    ///
+    /// If there is no span in progress, nothing is recorded.
+    pub fn child(name: &str, kind: Kind) -> Self {
+        match IN_PROGRESS.with_borrow(|spans| spans.last().cloned()) {
+            None => Span(None),
+            Some((trace_id, parent_span_id)) => {
+                Self::start(name, kind, trace_id, Some(parent_span_id))
+            }
+        }
+    }
+
+    fn start(name: &str, kind: Kind, trace_id: String, parent_span_id: Option<String>) -> Self {
+        let span_id = encryption::random_hex(8);
+        IN_PROGRESS.with_borrow_mut(|spans| spans.push((trace_id.clone(), span_id.clone())));
+        Span(Some(Recording {
+            trace_id,
+            span_id,
+            parent_span_id,
+            name: name.to_string(),
+            kind,
+            start: time::SystemTime::now(),
+            attributes: vec![],
+            error: None,
+        }))
+    }
+
+    /// Set an attribute of the span, whose value is a string, an integer or a boolean.
+    pub fn attribute(&mut self, key: &str, value: impl Into<serde_json::Value>) {
+        if let Some(recording) = &mut self.0 {
+            recording.attributes.push(attribute(key, value.into()));
+        }
+    }
+
+    /// Mark the operation the span covers as failed.
+    pub fn fail(&mut self, message: &str) {
+        if let Some(recording) = &mut self.0 {
+            recording.error = Some(message.to_string());
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let Some(recording) = self.0.take() else {
+            return;
+        };
+        let root = IN_PROGRESS.with_borrow_mut(|spans| {
+            spans.retain(|(_, span_id)| *span_id != recording.span_id);
+            spans.is_empty()
+        });
+        let status = match &recording.error {
+            None => serde_json::json!({ "code": 1 }),
+            Some(message) => serde_json::json!({ "code": 2, "message": message }),
+        };
+        let mut span = serde_json::json!({
+            "traceId": recording.trace_id,
+            "spanId": recording.span_id,
+            "name": recording.name,
+            "kind": recording.kind as u8,
+            "startTimeUnixNano": unix_nanos(recording.start),
+            "endTimeUnixNano": unix_nanos(time::SystemTime::now()),
+            "attributes": recording.attributes,
+            "status": status,
+        });
+        if let Some(parent_span_id) = recording.parent_span_id {
+            span["parentSpanId"] = parent_span_id.into();
+        }
+        FINISHED.with_borrow_mut(|finished| finished.push(span));
+        if !root {
+            return;
+        }
+        let spans = FINISHED.take();
+        if let Some(exporter) = &*EXPORTER.lock().unwrap() {
+            _ = exporter.traces.send(spans);
+        }
+    }
+}
+
+/// Returns an attribute in OTLP JSON, in which 64-bit integers are strings.
+fn attribute(key: &str, value: serde_json::Value) -> serde_json::Value {
+    let value = match value {
+        serde_json::Value::Bool(b) => serde_json::json!({ "boolValue": b }),
+        serde_json::Value::Number(n) if n.is_i64() || n.is_u64() => {
+            serde_json::json!({ "intValue": n.to_string() })
+        }
+        serde_json::Value::Number(n) => serde_json::json!({ "doubleValue": n }),
+        serde_json::Value::String(s) => serde_json::json!({ "stringValue": s }),
+        other => serde_json::json!({ "stringValue": other.to_string() }),
+    };
+    serde_json::json!({ "key": key, "value": value })
+}
+
+fn unix_nanos(time: time::SystemTime) -> String {
+    time.duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
@@ -0,0 +1,267 @@
+//! Updating the agent when a new release of it is published on GitHub.
+//!
+//! The agent runs the binary attached to the latest release of its repository.
+//! A release is installed if the checksum attached to it doesn't match the agent's executable,
+//!     by replacing the executable, which running processes are unaffected by.
+//! The check happens between iterations of the main loop,
+//!     so once an update is installed the agent can restart without interrupting a redeployment.
+
+use crate::config;
+use crate::exit;
+use crate::github;
+use crate::logging;
+use sha2::Digest;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time;
+
+pub struct Updater {
+    config: config::SelfUpdateConfig,
+    auth_token: String,
+    github_client: github::Client,
+    /// Downloads the release assets, which can take much longer than the GitHub client allows.
+    agent: ureq::Agent,
+    /// The agent's executable, found before it is replaced.
+    executable: PathBuf,
+    /// The tag of the most recent release that was found to match the executable.
+    current_release: Option<String>,
+    next_check: time::Instant,
+}
+
+/// An update that has been installed, which the agent can restart into.
+pub struct Installed {
+    restart: config::RestartMode,
+    executable: PathBuf,
+    release: String,
+}
+
+impl Updater {
+    pub fn new(
+        config: &config::SelfUpdateConfig,
+        auth_token: Option<&str>,
+    ) -> Result<Self, String> {
+        let executable = match std::env::current_exe() {
+            Ok(executable) => executable,
+            Err(err) => return Err(format!("failed to find the agent's executable: {err}")),
+        };
+        Ok(Self {
+            config: config.clone(),
+            auth_token: auth_token.unwrap_or_default().to_string(),
+            github_client: github::Client::new(Default::default()),
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(time::Duration::from_secs(10))
+                .timeout(time::Duration::from_secs(300))
+                .build(),
+            executable,
+            current_release: None,
+            next_check: time::Instant::now(),
+        })
+    }
+
+    /// Install the latest release if it's time to check for it and it is new.
+    ///
+    /// Failures are logged and the release is checked for again at the next interval.
+    pub fn check(&mut self) -> Option<Installed> {
+        let now = time::Instant::now();
+        if now < self.next_check {
+            return None;
+        }
+        self.next_check = now + self.config.interval();
+        match self.update() {
+            Ok(installed) => installed,
+            Err(err) => {
+                eprintln!("Failed to update the agent: {err}");
+                None
+            }
+        }
+    }
+
+    fn update(&mut self) -> Result<Option<Installed>, String> {
+        let (user, repo) = match self.config.repo() {
+            Some(repo) => repo,
+            None => return Err("the repo is invalid".to_string()),
+        };
+        let release = self
+            .github_client
+            .get_latest_release(user, repo, &self.auth_token)?;
+        if self.current_release.as_ref() == Some(&release.tag_name) {
+            return Ok(None);
+        }
+        let asset = &self.config.asset;
+        let checksum_asset = match &self.config.checksum_asset {
+            Some(checksum_asset) => checksum_asset.clone(),
+            None => format!("{asset}.sha256"),
+        };
+        let checksum = self.download(&release, &checksum_asset)?;
+        let checksum = parse_checksum(&String::from_utf8_lossy(&checksum), asset)
+            .ok_or_else(|| format!("release asset {checksum_asset} doesn't contain a checksum"))?;
+        let current = match fs::read(&self.executable) {
+            Ok(current) => current,
+            Err(err) => {
+                return Err(format!(
+                    "failed to read the agent's executable {}: {err}",
+                    self.executable.display()
+                ))
+            }
+        };
+        if sha256(&current) == checksum {
+            eprintln!("The agent is running release {}", release.tag_name);
+            self.current_release = Some(release.tag_name);
+            return Ok(None);
+        }
+        eprintln!(
+            "Updating the agent to release {} of {user}/{repo}",
+            release.tag_name
+        );
+        let binary = self.download(&release, asset)?;
+        let actual = sha256(&binary);
+        if actual != checksum {
+            return Err(format!(
+                "the checksum of release asset {asset} is {actual} rather than {checksum}"
+            ));
+        }
+        self.install(&binary)?;
+        eprintln!(
+            "Installed release {} at {}",
+            release.tag_name,
+            self.executable.display()
+        );
+        Ok(Some(Installed {
+            restart: self.config.restart,
+            executable: self.executable.clone(),
+            release: release.tag_name,
+        }))
+    }
+
+    fn download(&self, release: &github::Release, name: &str) -> Result<Vec<u8>, String> {
+        let Some(asset) = release.assets.iter().find(|asset| asset.name == name) else {
+            return Err(format!(
+                "release {} has no asset named {name}",
+                release.tag_name
+            ));
+        };
+        let mut request = self
+            .agent
+            .get(&asset.url)
+            .set("Accept", "application/octet-stream");
+        if !self.auth_token.is_empty() {
+            request = request.set("Authorization", &format!["Bearer {}", self.auth_token]);
+        }
+        let response = match request.call() {
+            Ok(response) => response,
+            Err(err) => return Err(format!("failed to download release asset {name}: {err}")),
+        };
+        let mut content = vec![];
+        match response.into_reader().read_to_end(&mut content) {
+            Ok(_) => Ok(content),
+            Err(err) => Err(format!("failed to download release asset {name}: {err}")),
+        }
+    }
+
+    /// Replace the executable with the binary.
+    ///
+    /// The binary is written next to the executable and renamed over it,
+    ///     so the executable is never partially written.
+    fn install(&self, binary: &[u8]) -> Result<(), String> {
+        let mut name = self.executable.file_name().unwrap_or_default().to_owned();
+        name.push(".new");
+        let new = self.executable.with_file_name(name);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o755);
+        let result = options
+            .open(&new)
+            .and_then(|mut file| file.write_all(binary).and_then(|()| file.sync_all()))
+            .and_then(|()| self.move_aside())
+            .and_then(|()| fs::rename(&new, &self.executable));
+        if let Err(err) = result {
+            _ = fs::remove_file(&new);
+            return Err(format!(
+                "failed to replace the agent's executable {}: {err}",
+                self.executable.display()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Windows doesn't allow the executable of a running process to be replaced,
+    ///     but does allow it to be renamed.
+    #[cfg(windows)]
+    fn move_aside(&self) -> std::io::Result<()> {
+        let old = self.executable.with_extension("old");
+        _ = fs::remove_file(&old);
+        fs::rename(&self.executable, old)
+    }
+
+    #[cfg(not(windows))]
+    fn move_aside(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Installed {
+    /// Restart the agent with the new binary, which must happen once the agent has shut down.
+    ///
+    /// This only returns if starting the new binary fails.
+    pub fn restart(self) -> String {
+        match self.restart {
+            config::RestartMode::Exit => {
+                eprintln!("Exiting to be restarted with release {}", self.release);
+                logging::finish();
+                std::process::exit(exit::RESTART);
+            }
+            config::RestartMode::Exec => {
+                eprintln!("Restarting with release {}", self.release);
+                // The new process opens the log file again.
+                logging::finish();
+                exec(&self.executable)
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn exec(executable: &std::path::Path) -> String {
+    use std::os::unix::process::CommandExt;
+    let err = std::process::Command::new(executable)
+        .args(std::env::args_os().skip(1))
+        .exec();
+    format!(
+        "failed to start the new binary {}: {err}",
+        executable.display()
+    )
+}
+
+#[cfg(not(unix))]
+fn exec(_: &std::path::Path) -> String {
+    "restarting the agent in place is only supported on Unix; use the exit restart mode".to_string()
+}
+
+/// Find the checksum of the binary in the contents of a checksum file.
+///
+/// The file is either just the checksum,
+///     or lines of checksums followed by file names as written by `sha256sum`.
+fn parse_checksum(content: &str, asset: &str) -> Option<String> {
+    let is_checksum =
+        |checksum: &str| checksum.len() == 64 && checksum.chars().all(|c| c.is_ascii_hexdigit());
+    let content = content.trim();
+    if is_checksum(content) {
+        return Some(content.to_ascii_lowercase());
+    }
+    content.lines().find_map(|line| {
+        let (checksum, name) = line.trim().split_once(char::is_whitespace)?;
+        // sha256sum marks files read in binary mode with a `*`.
+        let name = name.trim_start();
+        let name = name.strip_prefix('*').unwrap_or(name);
+        (is_checksum(checksum) && name == asset).then(|| checksum.to_ascii_lowercase())
+    })
+}
+
+fn sha256(content: &[u8]) -> String {
+    sha2::Sha256::digest(content)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
@@ -0,0 +1,46 @@
+//! Verification of artifacts before they are deployed.
+
+use sha2::Digest;
+use std::path::Path;
+
+/// Verify the files listed in a checksum file in the format output by `sha256sum`.
+///
+/// Relative paths, both of the checksum file and the files listed in it,
+///     are resolved against the provided directory.
+///
+/// On success returns a report of the files that were verified.
+pub fn checksum_file(path: &str, dir: Option<&Path>) -> Result<String, String> {
+    let dir = dir.unwrap_or(Path::new("."));
+    let content = match std::fs::read_to_string(dir.join(path)) {
+        Ok(content) => content,
+        Err(err) => return Err(format!("failed to read checksum file {path}: {err}")),
+    };
+    let mut report = String::new();
+    for line in content.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (expected, file) = match line.split_once(char::is_whitespace) {
+            None => return Err(format!("invalid line in checksum file: {line}")),
+            Some((expected, file)) => (expected, file.trim_start().trim_start_matches('*')),
+        };
+        let data = match std::fs::read(dir.join(file)) {
+            Ok(data) => data,
+            Err(err) => return Err(format!("{report}{file}: FAILED to read: {err}")),
+        };
+        let actual: String = sha2::Sha256::digest(&data)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(format!(
+                "{report}{file}: FAILED (expected {expected}, got {actual})"
+            ));
+        }
+        report.push_str(&format!("{file}: OK\n"));
+    }
+    if report.is_empty() {
+        return Err(format!("checksum file {path} does not list any files"));
+    }
+    Ok(report)
+}
@@ -0,0 +1,64 @@
+//! Webhook notifications.
+
+use crate::config;
+use crate::email;
+use hmac::Mac;
+use std::time::Duration;
+
+/// Client that sends notifications to a webhook as signed JSON payloads.
+pub struct Client {
+    agent: ureq::Agent,
+    config: config::WebhookConfig,
+}
+
+impl Client {
+    pub fn new(config: config::WebhookConfig) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .build();
+        Self { agent, config }
+    }
+
+    /// Returns the value of the signature header for the body.
+    fn signature(&self, body: &str) -> String {
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(self.config.secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(body.as_bytes());
+        let signature: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        format!("sha256={signature}")
+    }
+}
+
+impl email::Notifier for Client {
+    fn notify(&self, notification: &email::Notification) -> Result<(), String> {
+        let body = serde_json::json!({
+            "subject": notification.subject,
+            "body": notification.body,
+            "kind": notification.kind,
+            "success": matches!(
+                notification.kind,
+                config::NotifyOn::Success | config::NotifyOn::Recovery
+            ),
+            "event": notification.event,
+        })
+        .to_string();
+        let result = self
+            .agent
+            .post(&self.config.url)
+            .set("content-type", "application/json; charset=utf-8")
+            .set("x-cdagent-signature-256", &self.signature(&body))
+            .send_string(&body);
+        if let Err(err) = result {
+            return Err(format!(
+                "failed to send notification to webhook {}: {err}",
+                self.config.url
+            ));
+        }
+        Ok(())
+    }
+}